@@ -0,0 +1,133 @@
+// Ledger with ICRC-1 and ICRC-2 methods used by VTS, it is deployed only for tests.
+// Allowances are not checked, so any caller can transfer tokens from any account.
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use candid::{CandidType, Deserialize, Nat};
+use ic_cdk::api::call::ManualReply;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::{BlockIndex, Memo, TransferArg, TransferError};
+use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
+
+// Fee is burned from the sender account on every transfer.
+const FEE: u64 = 10_000;
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub struct Transfer {
+    pub spender: Account,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub memo: Option<Memo>,
+    pub created_at_time: Option<u64>,
+}
+
+thread_local! {
+    static BALANCES: RefCell<HashMap<Account, Nat>> = RefCell::default();
+    // Block index is the position in this list.
+    static TRANSFERS: RefCell<Vec<Transfer>> = RefCell::default();
+    // Transfers are executed, but callers can't decode replies, like when their callbacks trap.
+    static LOSE_REPLIES: RefCell<bool> = RefCell::default();
+}
+
+#[ic_cdk::update]
+fn set_lose_replies(lose: bool) {
+    LOSE_REPLIES.with(|lose_replies| *lose_replies.borrow_mut() = lose);
+}
+
+fn reply_transfer<T: CandidType>(result: T) -> ManualReply<T> {
+    if LOSE_REPLIES.with(|lose_replies| *lose_replies.borrow()) {
+        return ManualReply::one("reply is lost");
+    }
+    ManualReply::one(result)
+}
+
+#[ic_cdk::update]
+fn mint(to: Account, amount: Nat) {
+    BALANCES.with(|balances| *balances.borrow_mut().entry(to).or_default() += amount);
+}
+
+#[ic_cdk::query]
+fn icrc1_balance_of(account: Account) -> Nat {
+    BALANCES.with(|balances| balances.borrow().get(&account).cloned().unwrap_or_default())
+}
+
+#[ic_cdk::query]
+fn icrc1_fee() -> Nat {
+    Nat::from(FEE)
+}
+
+#[ic_cdk::update(manual_reply = true)]
+fn icrc1_transfer(args: TransferArg) -> ManualReply<Result<BlockIndex, TransferError>> {
+    let caller = Account {
+        owner: ic_cdk::caller(),
+        subaccount: args.from_subaccount,
+    };
+    let transfer = Transfer {
+        spender: caller,
+        from: caller,
+        to: args.to,
+        amount: args.amount,
+        memo: args.memo,
+        created_at_time: args.created_at_time,
+    };
+    reply_transfer(apply_transfer(transfer).map_err(|e| match e {
+        TransferFromError::Duplicate { duplicate_of } => TransferError::Duplicate { duplicate_of },
+        TransferFromError::InsufficientFunds { balance } => TransferError::InsufficientFunds { balance },
+        e => TransferError::GenericError {
+            error_code: Nat::from(0u8),
+            message: format!("{:?}", e),
+        },
+    }))
+}
+
+#[ic_cdk::update(manual_reply = true)]
+fn icrc2_transfer_from(args: TransferFromArgs) -> ManualReply<Result<BlockIndex, TransferFromError>> {
+    let transfer = Transfer {
+        spender: Account {
+            owner: ic_cdk::caller(),
+            subaccount: args.spender_subaccount,
+        },
+        from: args.from,
+        to: args.to,
+        amount: args.amount,
+        memo: args.memo,
+        created_at_time: args.created_at_time,
+    };
+    reply_transfer(apply_transfer(transfer))
+}
+
+fn apply_transfer(transfer: Transfer) -> Result<BlockIndex, TransferFromError> {
+    // Like the real ledger, only transactions with creation time are deduplicated.
+    if transfer.created_at_time.is_some() {
+        let duplicate = TRANSFERS.with(|transfers| transfers.borrow().iter().position(|t| *t == transfer));
+        if let Some(duplicate_of) = duplicate {
+            return Err(TransferFromError::Duplicate {
+                duplicate_of: Nat::from(duplicate_of),
+            });
+        }
+    }
+    BALANCES.with(|balances| {
+        let mut balances = balances.borrow_mut();
+        let balance = balances.get(&transfer.from).cloned().unwrap_or_default();
+        let total = transfer.amount.clone() + Nat::from(FEE);
+        if balance < total {
+            return Err(TransferFromError::InsufficientFunds { balance });
+        }
+        balances.insert(transfer.from, balance - total);
+        *balances.entry(transfer.to).or_default() += transfer.amount.clone();
+        Ok(())
+    })?;
+    TRANSFERS.with(|transfers| {
+        let mut transfers = transfers.borrow_mut();
+        transfers.push(transfer);
+        Ok(Nat::from(transfers.len() - 1))
+    })
+}
+
+#[ic_cdk::query]
+fn get_transfers() -> Vec<Transfer> {
+    TRANSFERS.with(|transfers| transfers.borrow().clone())
+}
+
+ic_cdk::export_candid!();
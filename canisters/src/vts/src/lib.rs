@@ -1,65 +1,118 @@
 use std::borrow::Cow;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
+use std::marker::PhantomData;
+use std::ops::Bound as RangeBound;
 
 use bincode::{Decode as BDecode, Encode as BEncode};
-use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::storable::Bound;
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
-use icrc_ledger_types::icrc1::account::Account;
-use icrc_ledger_types::icrc1::transfer::BlockIndex;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use icrc_ledger_types::icrc1::transfer::{BlockIndex, Memo, TransferArg, TransferError};
 use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
 use k256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
 use k256::pkcs8::DecodePublicKey;
 use rust_decimal::prelude::*;
 use rust_decimal::Decimal;
-use time::{Month, OffsetDateTime};
-
-macro_rules! impl_storable {
-    ($struct_name:ident) => {
-        impl Storable for $struct_name {
-            const BOUND: Bound = Bound::Bounded {
-                max_size: u32::MAX,
-                is_fixed_size: false,
-            };
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use time::{Date, Duration, Month, OffsetDateTime, UtcOffset};
 
-            fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-                Decode!(bytes.as_ref(), Self).unwrap()
-            }
+const ERR_UNAUTHORIZED: &str = "unauthorized";
 
-            fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-                Cow::Owned(Encode!(self).unwrap())
-            }
-        }
-    };
-}
+const ICP_LEDGER: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
 
-const ERR_UNAUTHORIZED: &str = "unauthorized";
+// Ledger amounts are u128, so more decimals leave too little room for prices.
+const MAX_TOKEN_DECIMALS: u8 = 18;
+
+// Ledger rejects transactions which were created earlier than its deduplication window (24h).
+const LEDGER_TX_WINDOW_SECS: u64 = 23 * 3600;
+
+// How often deposits of unpaid invoices are checked.
+const DEPOSIT_SWEEP_INTERVAL_SECS: u64 = 3600;
+
+// How often vehicles with overdue invoices are turned off.
+const OVERDUE_CHECK_INTERVAL_SECS: u64 = 3600;
+
+// Payment terms of agreements which were created before they were introduced.
+const DEFAULT_PAYMENT_DUE_DAYS: u32 = 30;
+const DEFAULT_GRACE_PERIOD_DAYS: u32 = 7;
+
+const DAY_NANOS: u64 = 86400 * 1_000_000_000;
+
+// To fit into the ingress message size limit.
+const MAX_TELEMETRY_BATCH_SIZE: usize = 1000;
+
+// To fit into the response size limit.
+const MAX_TELEMETRY_PAGE_SIZE: usize = 1000;
+const MAX_FIRMWARE_CHUNK_SIZE: u64 = 1024 * 1024;
+
+// Finished upload is hashed and stored in one message, so it should fit into the instructions limit.
+const MAX_FIRMWARE_SIZE: u64 = 64 * 1024 * 1024;
 
-const TOKENS_MULTIPLIER: u128 = 1_000_000_000;
+// Not finished firmware uploads are removed after this time.
+const FIRMWARE_UPLOAD_TTL_SECS: u64 = 3600;
+
+// To fit into the query instructions limit when looking for the specific telemetry kind.
+const MAX_TELEMETRY_SCAN_SIZE: usize = 10 * MAX_TELEMETRY_PAGE_SIZE;
+
+// To fit into the instructions limit of one timer run, the rest is processed by the next runs.
+const TELEMETRY_PROCESSING_BATCH_SIZE: usize = 10_000;
+
+// Raw telemetry is kept long enough to audit invoices for previous year.
+const DEFAULT_TELEMETRY_RETENTION_DAYS: u32 = 2 * 366;
+
+// Version of stored records, it should be increased with every migration.
+const SCHEMA_VERSION: u32 = 8;
+
+// Encoding of stored records, it is written as the first byte of every record.
+const RECORD_ENCODING_VERSION: u8 = 1;
+
+// Memory of records which are migrated between schema versions.
+const VEHICLES_MEMORY_ID: u8 = 5;
+const AGREEMENTS_MEMORY_ID: u8 = 3;
+const INVOICES_MEMORY_ID: u8 = 6;
+const USERS_MEMORY_ID: u8 = 2;
 
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
         RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
 
 
-    static ADMINS: RefCell<StableBTreeMap<Principal, Admin, Memory>> = RefCell::new(
+    static ADMINS: RefCell<StableBTreeMap<Principal, Stored<Admin>, Memory>> = RefCell::new(
         StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))))
     );
 
-    static USERS: RefCell<StableBTreeMap<Principal, User, Memory>> = RefCell::new(
+    static GATEWAYS: RefCell<StableBTreeMap<Principal, Stored<Gateway>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))))
+    );
+
+    static USERS: RefCell<StableBTreeMap<Principal, Stored<User>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(USERS_MEMORY_ID))))
+    );
+    // Organization is a user which members can act on behalf of.
+    static ORGANIZATIONS: RefCell<StableBTreeMap<Principal, Stored<Organization>, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))))
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))))
     );
 
 
-    static AGREEMENT_ID_COUNTER: RefCell<u128> = const { RefCell::new(0) };
-    static AGREEMENTS: RefCell<StableBTreeMap<u128, Agreement, Memory>> = RefCell::new(
+    static AGREEMENTS: RefCell<StableBTreeMap<u128, Stored<Agreement>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(AGREEMENTS_MEMORY_ID))))
+    );
+    // Conditions replaced by signed amendments by agreement and the time until which they are in force.
+    static SUPERSEDED_CONDITIONS: RefCell<StableBTreeMap<(u128, u64), Stored<AgreementConditions>, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))))
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32))))
     );
 
     static FIRMWARE_REQUESTS: RefCell<StableBTreeMap<Principal, (), Memory>> = RefCell::new(
@@ -67,15 +120,44 @@ thread_local! {
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))))
     );
 
-    static VEHICLES: RefCell<StableBTreeMap<Principal, Vehicle, Memory>> = RefCell::new(
+    static VEHICLES: RefCell<StableBTreeMap<Principal, Stored<Vehicle>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(VEHICLES_MEMORY_ID))))
+    );
+
+    // Vehicle data which can be large is kept apart, so reading a vehicle stays cheap.
+    static VEHICLE_TELEMETRY: RefCell<StableBTreeMap<Principal, Stored<AccumulatedTelemetry>, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))))
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))))
+    );
+    // Every change of vehicle state by vehicle and sequence number.
+    static VEHICLE_ON_OFF_LOG: RefCell<StableBTreeMap<(Principal, u64), Stored<OnOffChange>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))))
+    );
+    // Firmware by vehicle and chunk index, chunks have the download size limit except the last one.
+    static FIRMWARE_CHUNKS: RefCell<StableBTreeMap<(Principal, u32), Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30))))
+    );
+    // Size of the firmware, so it is known without reading its chunks.
+    static FIRMWARE_SIZES: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31))))
+    );
+    static VEHICLE_INVOICES: RefCell<StableBTreeMap<(Principal, u128), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))))
+    );
+    // Invoice by vehicle, agreement and the first day of its period, so a period is invoiced once.
+    static INVOICE_PERIODS: RefCell<StableBTreeMap<(Principal, u128, u32), u128, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33))))
     );
 
-    static INVOICE_ID_COUNTER: RefCell<u128> = const { RefCell::new(0) };
-    static INVOICES: RefCell<StableBTreeMap<u128, Invoice, Memory>> = RefCell::new(
+    static INVOICES: RefCell<StableBTreeMap<u128, Stored<Invoice>, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))))
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(INVOICES_MEMORY_ID))))
     );
     // We need to store pending invoices for gateway.
     // Gateway proceed with pending invoice to send some notification for the user.
@@ -89,6 +171,89 @@ thread_local! {
         StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))))
     );
+    // Invoices which are not paid yet, their deposits are checked by timer.
+    static UNPAID_INVOICES: RefCell<StableBTreeMap<u128, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))))
+    );
+    // Discounts, credit notes and late fees by invoice and sequence number.
+    static INVOICE_ADJUSTMENTS: RefCell<StableBTreeMap<(u128, u32), Stored<InvoiceAdjustment>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))))
+    );
+    // Payments received for invoices by invoice and sequence number.
+    static INVOICE_PAYMENTS: RefCell<StableBTreeMap<(u128, u32), Stored<InvoicePayment>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))))
+    );
+    // Payments which are sent to the ledger but not recorded yet.
+    static PENDING_PAYMENTS: RefCell<StableBTreeMap<u128, Stored<PendingPayment>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))))
+    );
+    // Invoices which payment is in progress.
+    static PAYMENT_LOCKS: RefCell<HashSet<u128>> = RefCell::new(HashSet::new());
+    // Disputes by invoice, an invoice can be disputed once.
+    static DISPUTES: RefCell<StableBTreeMap<u128, Stored<Dispute>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))))
+    );
+    // Pending payments which can't be resubmitted as the ledger doesn't deduplicate them anymore.
+    static UNRECONCILED_PAYMENTS: RefCell<StableBTreeMap<u128, Stored<PendingPayment>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))))
+    );
+    // Deposit forwards which are sent to the ledger but not credited yet, by invoice.
+    static PENDING_FORWARDS: RefCell<StableBTreeMap<u128, Stored<PendingForward>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))))
+    );
+    // Unpaid invoices which vehicles were turned off for.
+    static OVERDUE_INVOICES: RefCell<StableBTreeMap<u128, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))))
+    );
+    // Tokens which customer deposited but which were not used to pay invoices yet, by agreement.
+    // They are already forwarded to provider and are used for the next invoices of the agreement.
+    static CREDITS: RefCell<StableBTreeMap<u128, u128, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))))
+    );
+
+    // Raw telemetry is only appended here and removed after retention period.
+    static TELEMETRY_LOG: RefCell<StableBTreeMap<TelemetryKey, Stored<TelemetryRecord>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))))
+    );
+    // Raw telemetry which is not accumulated yet.
+    static TELEMETRY_QUEUE: RefCell<StableBTreeMap<TelemetryKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))))
+    );
+    // Firmware is uploaded by chunks as it doesn't fit into one ingress message.
+    static FIRMWARE_UPLOADS: RefCell<StableBTreeMap<u128, Stored<FirmwareUpload>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))))
+    );
+    // Uploaded chunks by upload and chunk index, so a chunk is appended without reading the previous ones.
+    static FIRMWARE_UPLOAD_CHUNKS: RefCell<StableBTreeMap<(u128, u32), Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))))
+    );
+
+    // Last stored telemetry by vehicle to reject replays and to know when vehicle was online.
+    static VEHICLE_ACTIVITY: RefCell<StableBTreeMap<Principal, Stored<VehicleActivity>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))))
+    );
+
+    // Counters and configuration are kept in stable memory to survive upgrades.
+    static STATE: RefCell<StableCell<State, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))),
+            State::default(),
+        ).expect("failed to init state")
+    );
 }
 
 pub type VTSResult<T> = Result<T, Error>;
@@ -97,11 +262,146 @@ pub type AccumulatedTelemetry = HashMap<TelemetryType, HashMap<i32, AccumulatedT
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
-type Telemetry = HashMap<TelemetryType, HashMap<i32, HashMap<u8, HashMap<u8, Vec<u128>>>>>;
+// Record is kept encoded and decoded only when it is read,
+// so a broken record results in an error instead of trapping the canister.
+struct Stored<T> {
+    bytes: Vec<u8>,
+    record: PhantomData<T>,
+}
+
+impl<T: Serialize + DeserializeOwned> Stored<T> {
+    fn new(record: &T) -> Self {
+        let mut bytes = vec![RECORD_ENCODING_VERSION];
+        bincode::serde::encode_into_std_write(record, &mut bytes, bincode::config::standard())
+            .expect("failed to encode record");
+        Self {
+            bytes,
+            record: PhantomData,
+        }
+    }
+
+    fn decode(&self) -> VTSResult<T> {
+        let Some((&RECORD_ENCODING_VERSION, bytes)) = self.bytes.split_first() else {
+            return Err(decode_error(format!("unknown record encoding version: {:?}", self.bytes.first())));
+        };
+        let (record, _) = bincode::serde::decode_from_slice(bytes, bincode::config::standard())
+            .map_err(|e| decode_error(format!("failed to decode record: {}", e)))?;
+        Ok(record)
+    }
+}
+
+fn decode_error(message: String) -> Error {
+    // Canister API is not available when records are decoded in unit tests.
+    #[cfg(target_arch = "wasm32")]
+    ic_cdk::println!("{}", message);
+    #[cfg(not(target_arch = "wasm32"))]
+    eprintln!("{}", message);
+    Error::Internal
+}
+
+impl<T> Storable for Stored<T> {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self {
+            bytes: bytes.into_owned(),
+            record: PhantomData,
+        }
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.bytes)
+    }
+}
+
+// Access to maps of stored records which decodes them.
+trait RecordMap<K, V> {
+    // Returns NotFound error if there is no record.
+    fn load(&self, key: &K) -> VTSResult<V>;
+    fn try_load(&self, key: &K) -> VTSResult<Option<V>>;
+    fn store(&mut self, key: K, record: &V);
+}
+
+impl<K, V> RecordMap<K, V> for StableBTreeMap<K, Stored<V>, Memory>
+where
+    K: Storable + Ord + Clone,
+    V: Serialize + DeserializeOwned,
+{
+    fn load(&self, key: &K) -> VTSResult<V> {
+        self.try_load(key)?.ok_or(Error::NotFound)
+    }
+
+    fn try_load(&self, key: &K) -> VTSResult<Option<V>> {
+        self.get(key).map(|record| record.decode()).transpose()
+    }
+
+    fn store(&mut self, key: K, record: &V) {
+        self.insert(key, Stored::new(record));
+    }
+}
 
-#[derive(BEncode, BDecode, PartialEq, Eq, Hash, CandidType, Deserialize, Debug, Clone, Copy)]
+#[derive(BEncode, BDecode, PartialEq, Eq, Hash, CandidType, Serialize, Deserialize, Debug, Clone, Copy)]
 pub enum TelemetryType {
+    // Consumed fuel in litres.
     Gas,
+    // Odometer reading in kilometres.
+    Distance,
+    // Engine hours counter.
+    EngineHours,
+    // Battery state of charge in percents.
+    BatteryCharge,
+    // Consumed electric energy in kWh.
+    ElectricEnergy,
+}
+
+impl TelemetryType {
+    pub const ALL: [TelemetryType; 5] = [
+        TelemetryType::Gas,
+        TelemetryType::Distance,
+        TelemetryType::EngineHours,
+        TelemetryType::BatteryCharge,
+        TelemetryType::ElectricEnergy,
+    ];
+
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Self::Gas => "l",
+            Self::Distance => "km",
+            Self::EngineHours => "h",
+            Self::BatteryCharge => "%",
+            Self::ElectricEnergy => "kWh",
+        }
+    }
+
+    pub fn aggregation_rule(&self) -> AggregationRule {
+        match self {
+            Self::Gas | Self::ElectricEnergy => AggregationRule::Sum,
+            // Counters only grow, so summing them makes no sense.
+            Self::Distance | Self::EngineHours => AggregationRule::Max,
+            Self::BatteryCharge => AggregationRule::Last,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum AggregationRule {
+    // Values are added up, e.g. consumed gas.
+    Sum,
+    // The highest value is kept, e.g. odometer.
+    Max,
+    // The most recent value is kept, e.g. battery state of charge.
+    Last,
+}
+
+impl AggregationRule {
+    // Merging into zero gives the value itself for every rule.
+    pub fn merge(&self, current: u128, value: u128) -> VTSResult<u128> {
+        match self {
+            Self::Sum => current.checked_add(value).ok_or(Error::InvalidData),
+            Self::Max => Ok(current.max(value)),
+            Self::Last => Ok(value),
+        }
+    }
 }
 
 #[derive(CandidType, Deserialize, Default, PartialEq, Debug)]
@@ -116,6 +416,10 @@ pub enum Error {
     InvalidSignatureFormat,
     DecodeTelemetry,
     InvalidData,
+    // Another payment of the invoice is not finished yet.
+    PaymentInProgress,
+    // Outcome of the previous payment is unknown until an admin reconciles it with the ledger.
+    PaymentNeedsReconciliation,
 }
 
 impl Display for Error {
@@ -136,7 +440,7 @@ impl From<String> for Error {
     }
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
 pub enum StoreTelemetryResponse {
     // Vehicle can continue to work.
     On,
@@ -144,22 +448,86 @@ pub enum StoreTelemetryResponse {
     Off,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
 enum AgreementState {
     Unsigned,
     Signed,
+    // Customer declined to sign the agreement.
+    Rejected,
+    // Provider withdrew the agreement before it was signed.
+    Cancelled,
+    // Vehicles are turned off until provider resumes the agreement.
+    Suspended,
+    // One of the parties ended the agreement before its end date.
+    Terminated,
+    // End date of the agreement has passed.
+    Expired,
 }
 
-#[derive(CandidType, Deserialize)]
-enum InvoiceStatus {
+impl AgreementState {
+    // Agreement in these states can't be changed anymore.
+    fn is_final(&self) -> bool {
+        matches!(self, Self::Rejected | Self::Cancelled | Self::Terminated | Self::Expired)
+    }
+
+    // Usage is billed only for agreements which were in effect.
+    fn is_billable(&self) -> bool {
+        matches!(self, Self::Signed | Self::Suspended | Self::Terminated | Self::Expired)
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Debug)]
+pub enum InvoiceStatus {
     Unpaid,
     Paid,
+    // Some payments are received, but the outstanding balance is not zero yet.
+    PartiallyPaid,
+    // Customer contested the invoice, it can't be paid until the dispute is settled.
+    Disputed,
 }
 
 #[derive(BEncode, BDecode)]
 pub struct StoreTelemetryRequest {
     pub value: u128,
     pub t_type: TelemetryType,
+    // Unix time in nanoseconds when the value was measured on the device.
+    pub timestamp: u64,
+    // Should grow with every reading, so the same payload cannot be stored twice.
+    pub seq: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SignedTelemetry {
+    pub vehicle: Principal,
+    // Encoded StoreTelemetryRequest.
+    pub data: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, PartialEq, Debug)]
+pub struct StoreTelemetryBatchResponse {
+    // Result for every telemetry in the same order as in the batch.
+    pub results: Vec<VTSResult<()>>,
+    // Current state of every known vehicle from the batch.
+    pub vehicles: HashMap<Principal, StoreTelemetryResponse>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct TelemetrySkewWindow {
+    // How far device time can be behind canister time, e.g. data buffered offline.
+    // Billing period is invoiced only after this time has passed since its end.
+    pub past_secs: u64,
+    // How far device time can be ahead of canister time.
+    pub future_secs: u64,
+}
+
+impl Default for TelemetrySkewWindow {
+    fn default() -> Self {
+        Self {
+            past_secs: 7 * 86400,
+            future_secs: 300,
+        }
+    }
 }
 
 #[derive(CandidType, Deserialize)]
@@ -169,522 +537,2926 @@ pub struct PendingInvoice {
     pub vehicle: Principal,
 }
 
-#[derive(CandidType, Deserialize, Default, PartialEq, Debug)]
+#[derive(CandidType, Deserialize, PartialEq, Debug)]
+pub struct TelemetryTypeInfo {
+    pub t_type: TelemetryType,
+    pub unit: String,
+    pub aggregation_rule: AggregationRule,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Default, PartialEq, Debug)]
 pub struct AccumulatedTelemetryYearly {
     pub value: u128,
     pub monthly: HashMap<u8, AccumulatedTelemetryMonthy>,
 }
 
-#[derive(CandidType, Deserialize, Default, PartialEq, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Default, PartialEq, Debug)]
 pub struct AccumulatedTelemetryMonthy {
     pub value: u128,
     pub daily: HashMap<u8, u128>,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Serialize, Deserialize)]
 struct Admin {}
-impl_storable!(Admin);
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Serialize, Deserialize)]
+struct Gateway {}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct State {
+    // Canister installed before versioning has schema version 0.
+    schema_version: u32,
+    agreement_id_counter: u128,
+    invoice_id_counter: u128,
+    firmware_upload_id_counter: u128,
+    telemetry_skew_window: TelemetrySkewWindow,
+    telemetry_retention_days: u32,
+    // Last processed keys of the telemetry queue and log while they are processed by batches.
+    telemetry_queue_cursor: Option<TelemetryKey>,
+    telemetry_prune_cursor: Option<TelemetryKey>,
+}
+
+// State is read before migrations, so it is kept in Candid which allows to add optional fields.
+impl Storable for State {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("failed to decode state")
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode state"))
+    }
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            schema_version: 0,
+            agreement_id_counter: 0,
+            invoice_id_counter: 0,
+            firmware_upload_id_counter: 0,
+            telemetry_skew_window: TelemetrySkewWindow::default(),
+            telemetry_retention_days: DEFAULT_TELEMETRY_RETENTION_DAYS,
+            telemetry_queue_cursor: None,
+            telemetry_prune_cursor: None,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
 struct User {
     vehicles: HashMap<Principal, ()>,
     agreements: HashMap<u128, ()>,
     email: Option<String>,
+    roles: Vec<Role>,
 }
-impl_storable!(User);
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Role {
+    // Creates agreements and turns vehicles on or off.
+    Provider,
+    // Signs agreements and pays invoices.
+    Customer,
+    // Requests firmware and links vehicles to agreements.
+    FleetOperator,
+    // Can see all agreements, vehicles and invoices but can't change them.
+    Auditor,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+pub struct Organization {
+    pub name: String,
+    pub members: HashMap<Principal, Vec<Permission>>,
+}
+
+// What organization member can do on behalf of the organization.
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Permission {
+    // See organization agreements, vehicles and invoices.
+    View,
+    // Create and sign agreements.
+    Sign,
+    // Pay for invoices from the organization account.
+    Pay,
+    // Link vehicles to agreements and turn them on or off.
+    ManageVehicles,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
 struct Vehicle {
     provider: Option<Principal>,
     customer: Principal,
     agreement: Option<u128>,
     public_key: Vec<u8>,
     arch: String,
-    firmware: Vec<u8>,
+    firmware_sha256: Vec<u8>,
     on_off: bool,
-    telemetry: Telemetry,
-    accumulated_telemetry: AccumulatedTelemetry,
-    invoices: Vec<u128>,
+    // All agreements vehicle was linked to, the last one can be the current.
+    links: Vec<VehicleLink>,
 }
-impl_storable!(Vehicle);
 
-#[derive(CandidType, Deserialize)]
-struct Invoice {
-    id: u128,
-    status: InvoiceStatus,
-    vehicle: Principal,
-    agreement: u128,
-    period: (i32, u8), // year + month
-    total_cost: u128,
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct VehicleLink {
+    pub agreement: u128,
+    // Unix time in nanoseconds, usage is billed to the agreement only between these times.
+    pub linked_at: u64,
+    pub unlinked_at: Option<u64>,
 }
-impl_storable!(Invoice);
 
-#[derive(CandidType, Deserialize)]
-struct Agreement {
-    id: u128, // we need to store it here to be able to use it on frontend
-    name: String,
-    vh_provider: Principal,
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum OnOffReason {
+    // Provider turned vehicle on or off.
+    Manual,
+    // Vehicle was linked or unlinked, or agreement state was changed.
+    Agreement(u128),
+    InvoiceOverdue(u128),
+    InvoicePaid(u128),
+    InvoiceDisputed(u128),
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OnOffChange {
+    pub on_off: bool,
+    pub reason: OnOffReason,
+    // It is the canister itself for changes made by timer.
+    pub changed_by: Principal,
+    pub changed_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+struct FirmwareUpload {
+    // Only gateway which started the upload can continue it.
+    uploader: Principal,
     vh_customer: Principal,
-    state: AgreementState,
-    conditions: AgreementConditions,
-    vehicles: HashMap<Principal, ()>,
+    public_key: Vec<u8>,
+    arch: String,
+    // Size and count of the uploaded chunks.
+    size: u64,
+    chunk_count: u32,
+    started_at: u64,
 }
-impl_storable!(Agreement);
 
-#[derive(CandidType, Deserialize)]
-struct AgreementConditions {
-    gas_price: String,
+#[derive(CandidType, Serialize, Deserialize)]
+struct VehicleActivity {
+    // Sequence number of the last stored telemetry.
+    last_seq: u64,
+    // Canister time when the last telemetry was stored.
+    last_seen: u64,
 }
 
-#[ic_cdk::init]
-fn init() {
-    // Every day or 24h.
-    ic_cdk_timers::set_timer_interval(std::time::Duration::from_secs(86400), || {
-        if let Err(e) = accumulate_telemetry_data() {
-            ic_cdk::println!("failed to accumulate telemetry data: {}", e)
-        }
-    });
+// Vehicle without firmware and telemetry to be cheap to list.
+#[derive(CandidType, Deserialize, Debug)]
+pub struct VehicleSummary {
+    pub principal: Principal,
+    pub customer: Principal,
+    pub provider: Option<Principal>,
+    pub agreement: Option<u128>,
+    pub arch: String,
+    pub on_off: bool,
+    pub last_seen: Option<u64>,
+    pub invoice_count: u64,
 }
 
-#[ic_cdk::query(guard = is_user)]
-fn get_invoice(invoice_id: u128) -> Result<Invoice, Error> {
-    // todo: restrict by get provider or customer
-    INVOICES.with(|invoices| {
-        let invoices = invoices.borrow();
-        invoices.get(&invoice_id).ok_or(Error::NotFound)
-    })
+#[derive(CandidType, Deserialize, Debug)]
+pub struct FirmwareChunk {
+    pub data: Vec<u8>,
+    // Size of the whole firmware to know when to stop downloading.
+    pub total_size: u64,
+    // Hash of the whole firmware to check it after downloading.
+    pub sha256: Vec<u8>,
 }
 
-#[ic_cdk::update(guard = is_canister)]
-fn accumulate_telemetry_data() -> VTSResult<()> {
-    ic_cdk::println!("starting to accumulate telemetry data");
+// Key of raw telemetry log ordered by vehicle and then by device time.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct TelemetryKey {
+    vehicle: Principal,
+    timestamp: u64,
+    seq: u64,
+}
 
-    let mut accumulated_telemetry: HashMap<Principal, AccumulatedTelemetry> =
-        HashMap::with_capacity(VEHICLES.with(|vehicles| vehicles.borrow().len() as usize));
+impl TelemetryKey {
+    // Principal is padded to have keys of the same vehicle next to each other.
+    const PRINCIPAL_SIZE: usize = 29;
+    const SIZE: usize = Self::PRINCIPAL_SIZE + 1 + 8 + 8;
 
-    VEHICLES.with(|vehicles| -> VTSResult<()> {
-        let vehicles = vehicles.borrow();
-        for (principal, mut vehicle) in vehicles.iter() {
-            for (telemetry_type, telemetry_data) in vehicle.telemetry.iter_mut() {
-                vehicle.accumulated_telemetry.entry(*telemetry_type).or_default();
-                for (year, year_data) in telemetry_data.iter_mut() {
-                    for (month, month_data) in year_data.iter_mut() {
-                        for (day, day_data) in month_data.iter_mut() {
-                            for value in day_data.iter() {
-                                vehicle
-                                    .accumulated_telemetry
-                                    .get_mut(telemetry_type)
-                                    .ok_or(Error::NotFound)?
-                                    .entry(*year)
-                                    .and_modify(|v| v.value += *value)
-                                    .or_insert(AccumulatedTelemetryYearly {
-                                        value: *value,
-                                        monthly: HashMap::new(),
-                                    });
-                                vehicle
-                                    .accumulated_telemetry
-                                    .get_mut(telemetry_type)
-                                    .ok_or(Error::NotFound)?
-                                    .get_mut(year)
-                                    .ok_or(Error::NotFound)?
-                                    .monthly
-                                    .entry(*month)
-                                    .and_modify(|v| v.value += *value)
-                                    .or_insert(AccumulatedTelemetryMonthy {
-                                        value: *value,
-                                        daily: HashMap::new(),
-                                    });
-                                vehicle
-                                    .accumulated_telemetry
-                                    .get_mut(telemetry_type)
-                                    .ok_or(Error::NotFound)?
-                                    .get_mut(year)
-                                    .ok_or(Error::NotFound)?
-                                    .monthly
-                                    .entry(*month)
-                                    .or_default()
-                                    .daily
-                                    .entry(*day)
-                                    .and_modify(|v| *v += *value)
-                                    .or_insert(*value);
-                            }
-                            day_data.clear();
-                        }
-                        month_data.clear();
-                    }
-                    year_data.clear();
-                }
-            }
-            accumulated_telemetry.insert(principal, vehicle.accumulated_telemetry);
+    fn first(vehicle: Principal) -> Self {
+        Self {
+            vehicle,
+            timestamp: 0,
+            seq: 0,
         }
+    }
 
-        Ok(())
-    })?;
+    fn last(vehicle: Principal) -> Self {
+        Self {
+            vehicle,
+            timestamp: u64::MAX,
+            seq: u64::MAX,
+        }
+    }
+}
 
-    VEHICLES.with(|vehicles| -> VTSResult<()> {
-        let mut vehicles = vehicles.borrow_mut();
-        for (v_principal, vat) in accumulated_telemetry {
-            let mut vehicle = vehicles.get(&v_principal).ok_or(Error::NotFound)?;
-            vehicle.accumulated_telemetry = vat;
-            vehicles.insert(v_principal, vehicle);
+impl Storable for TelemetryKey {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: Self::SIZE as u32,
+        is_fixed_size: true,
+    };
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let principal_len = bytes[Self::PRINCIPAL_SIZE] as usize;
+        let timestamp = Self::PRINCIPAL_SIZE + 1;
+        let seq = timestamp + 8;
+        Self {
+            vehicle: Principal::from_slice(&bytes[..principal_len]),
+            timestamp: u64::from_be_bytes(bytes[timestamp..seq].try_into().unwrap()),
+            seq: u64::from_be_bytes(bytes[seq..].try_into().unwrap()),
         }
-        Ok(())
-    })?;
+    }
 
-    // Check if it's the first day of the month.
-    let timestamp = ic_cdk::api::time();
-    let timestamp =
-        OffsetDateTime::from_unix_timestamp_nanos(timestamp as i128).map_err(|_| Error::InvalidData)?;
-    if timestamp.day() == 1 {
-        let (previous_year, previous_month) = if timestamp.month() == Month::January {
-            (timestamp.year() - 1, Month::December)
-        } else {
-            (timestamp.year(), timestamp.month().previous())
-        };
-        VEHICLES.with(|vehicles| -> VTSResult<()> {
-            let vehicles = vehicles.borrow();
-            for (vehicle_id, _) in vehicles.iter() {
-                create_invoice(
-                    vehicle_id,
-                    previous_year,
-                    previous_month as u8,
-                    &get_aggregated_data(vehicle_id)?,
-                )?;
-            }
-            Ok(())
-        })?;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        // Big endian is used to keep byte order the same as numeric order.
+        let principal = self.vehicle.as_slice();
+        let mut bytes = Vec::with_capacity(Self::SIZE);
+        bytes.extend_from_slice(principal);
+        bytes.resize(Self::PRINCIPAL_SIZE, 0);
+        bytes.push(principal.len() as u8);
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.seq.to_be_bytes());
+        Cow::Owned(bytes)
     }
+}
 
-    ic_cdk::println!("accumulating telemetry data is finished");
-    Ok(())
+#[derive(CandidType, Serialize, Deserialize)]
+struct TelemetryRecord {
+    t_type: TelemetryType,
+    value: u128,
+    // Signature of the device is kept to be able to check that reading is not modified.
+    signature: Vec<u8>,
 }
 
-#[ic_cdk::query(guard = is_user)]
-fn get_aggregated_data(vehicle_id: Principal) -> VTSResult<AccumulatedTelemetry> {
-    VEHICLES.with(|vehicles| {
-        let vehicles = vehicles.borrow();
-        let vehicle = vehicles.get(&vehicle_id).ok_or(Error::NotFound)?;
-        Ok(vehicle.accumulated_telemetry)
-    })
+#[derive(CandidType, Deserialize, Debug)]
+pub struct RawTelemetry {
+    pub timestamp: u64,
+    pub seq: u64,
+    pub t_type: TelemetryType,
+    pub value: u128,
+    pub signature: Vec<u8>,
 }
 
-#[ic_cdk::update]
-fn add_admin(new_admin: Principal) -> VTSResult<()> {
-    let caller = ic_cdk::api::caller();
-    ADMINS.with(|admins| {
-        // If we just deployed canister we can add first admin to it.
-        if admins.borrow().is_empty() {
-            admins.borrow_mut().insert(caller, Admin {});
-            Ok(())
-        } else {
-            is_admin()?;
-            admins.borrow_mut().insert(new_admin, Admin {});
-            Ok(())
-        }
-    })
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub struct TelemetryCursor {
+    pub timestamp: u64,
+    pub seq: u64,
 }
 
-#[ic_cdk::update(guard = is_admin)]
-fn delete_admin(admin: Principal) -> VTSResult<()> {
-    let caller = ic_cdk::api::caller();
-    ADMINS.with(|admins| {
-        if !admins.borrow().contains_key(&admin) {
-            return Err(Error::NotFound);
-        }
-        if admin == caller {
-            return Err(Error::InvalidSigner);
-        }
-        admins.borrow_mut().remove(&admin);
-        Ok(())
-    })
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum TelemetryGranularity {
+    Raw,
+    Day,
+    Month,
+    Year,
 }
 
-#[ic_cdk::update(guard = is_admin)]
-fn register_user(user: Principal, email: Option<String>) -> VTSResult<()> {
-    if USERS.with(|users| users.borrow().contains_key(&user)) {
-        return Err(Error::AlreadyExists);
-    }
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub struct TelemetryQuery {
+    pub vehicle: Principal,
+    pub t_type: TelemetryType,
+    // Unix time in nanoseconds, start is inclusive and end is exclusive.
+    pub from: u64,
+    pub to: u64,
+    pub granularity: TelemetryGranularity,
+    pub cursor: Option<TelemetryCursor>,
+    // Page size, it can't be larger than the canister limit.
+    pub limit: Option<u32>,
+}
 
-    USERS.with(|users| {
-        users.borrow_mut().insert(
-            user,
-            User {
-                vehicles: HashMap::new(),
-                agreements: HashMap::new(),
-                email,
-            },
-        );
-    });
+#[derive(CandidType, Deserialize, PartialEq, Debug)]
+pub struct TelemetryPoint {
+    // Device time for raw telemetry and start of the period in the agreement time zone otherwise.
+    pub timestamp: u64,
+    pub value: u128,
+}
 
-    Ok(())
+#[derive(CandidType, Deserialize, Debug)]
+pub struct TelemetryPage {
+    pub points: Vec<TelemetryPoint>,
+    // Pass it to get the next page, it is empty if there is no more data.
+    pub next: Option<TelemetryCursor>,
 }
 
-#[ic_cdk::update(guard = is_admin)]
-fn delete_user(user: Principal) -> VTSResult<()> {
-    // Check if the user to be deleted exists.
-    USERS.with(|users| {
-        if !users.borrow().contains_key(&user) {
-            return Err(Error::NotFound);
-        }
-        Ok(())
-    })?;
+#[derive(CandidType, Deserialize, Debug)]
+pub struct RawTelemetryPage {
+    pub telemetry: Vec<RawTelemetry>,
+    // Pass it to get the next page, it is empty if there is no more data.
+    pub next: Option<TelemetryCursor>,
+}
 
-    // Remove the user.
-    USERS.with(|users| users.borrow_mut().remove(&user));
-    Ok(())
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+pub struct Invoice {
+    pub id: u128,
+    pub status: InvoiceStatus,
+    pub vehicle: Principal,
+    pub agreement: u128,
+    pub period: InvoicePeriod,
+    // Amount in the smallest units of the token.
+    pub total_cost: u128,
+    pub items: Vec<InvoiceItem>,
+    // Token is copied from the agreement when invoice is issued.
+    pub token: PaymentToken,
+    // Unix time in nanoseconds of the first payment attempt.
+    // It is reused for retries, so ledger can deduplicate the transfer.
+    pub payment_created_at: Option<u64>,
+    // Unix time in nanoseconds when invoice should be paid.
+    pub due_at: u64,
 }
 
-#[ic_cdk::query(guard = is_user)]
-fn get_user() -> VTSResult<User> {
-    let caller = ic_cdk::api::caller();
-    USERS.with(|users| users.borrow().get(&caller).ok_or(Error::NotFound))
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct InvoicePeriod {
+    // First day of the period: year + month + day.
+    pub start: (i32, u8, u8),
+    // First day after the period: year + month + day.
+    pub end: (i32, u8, u8),
 }
 
-#[ic_cdk::update(guard = is_user)]
-fn request_firmware() -> VTSResult<()> {
-    let caller = ic_cdk::api::caller();
-    ic_cdk::println!("{} is requested firmware", caller);
-    FIRMWARE_REQUESTS.with(|requests| {
-        if requests.borrow_mut().contains_key(&caller) {
-            return Err(Error::AlreadyExists);
+impl InvoicePeriod {
+    fn new(start: Date, end: Date) -> Self {
+        Self {
+            start: (start.year(), start.month() as u8, start.day()),
+            end: (end.year(), end.month() as u8, end.day()),
         }
-        requests.borrow_mut().insert(caller, ());
-        Ok(())
-    })?;
-    Ok(())
+    }
+
+    fn dates(&self) -> VTSResult<(Date, Date)> {
+        Ok((to_date(self.start.0, self.start.1, self.start.2)?, to_date(self.end.0, self.end.1, self.end.2)?))
+    }
 }
 
-// This method can return first available firmware request.
-#[ic_cdk::query(guard = is_gateway)]
-fn get_firmware_requests() -> VTSResult<Principal> {
-    let (identity, _) =
-        FIRMWARE_REQUESTS.with(|requests| requests.borrow().first_key_value().ok_or(Error::NotFound))?;
-    Ok(identity)
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum InvoiceItemKind {
+    Usage(TelemetryType),
+    Fee(String),
+    // Top-up to reach agreement daily minimum when usage costs less.
+    DailyMinimum,
 }
 
-// By this method we can check active firmware requests for the particular user.
-#[ic_cdk::query(guard = is_user)]
-fn get_firmware_requests_by_user() -> VTSResult<()> {
-    let caller = ic_cdk::api::caller();
-    FIRMWARE_REQUESTS.with(|requests| requests.borrow().get(&caller).ok_or(Error::NotFound))?;
-    Ok(())
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct InvoiceItem {
+    pub kind: InvoiceItemKind,
+    pub quantity: u128,
+    // Price is copied from the agreement when invoice is issued.
+    pub unit_price: String,
+    // Amount in the smallest units of the token.
+    pub subtotal: u128,
 }
 
-#[ic_cdk::update(guard = is_gateway)]
-fn upload_firmware(
+#[derive(CandidType, Serialize, Deserialize)]
+struct Agreement {
+    id: u128, // we need to store it here to be able to use it on frontend
+    name: String,
+    vh_provider: Principal,
     vh_customer: Principal,
-    public_key: Vec<u8>,
-    arch: String,
-    firmware: Vec<u8>,
-) -> VTSResult<()> {
-    let vehicle = Principal::self_authenticating(&public_key);
-    FIRMWARE_REQUESTS.with(|requests| requests.borrow_mut().remove(&vh_customer));
-    VEHICLES.with(|vehicles| {
-        vehicles.borrow_mut().insert(
-            vehicle,
-            Vehicle {
-                provider: None,
-                customer: vh_customer,
-                agreement: None,
-                public_key,
-                arch,
-                firmware,
-                telemetry: HashMap::new(),
-                on_off: true,
-                accumulated_telemetry: HashMap::new(),
-                invoices: Vec::new(),
-            },
-        )
-    });
-    USERS.with(|users| -> VTSResult<()> {
-        let mut user = users.borrow_mut().get(&vh_customer).ok_or(Error::NotFound)?;
-        user.vehicles.insert(vehicle, ());
-        users.borrow_mut().insert(vh_customer, user);
-        Ok(())
-    })
+    state: AgreementState,
+    conditions: AgreementConditions,
+    vehicles: HashMap<Principal, ()>,
+    // New conditions proposed by provider which customer should sign.
+    amendment: Option<AgreementConditions>,
+    // Unix time in nanoseconds when agreement was terminated or expired.
+    ended_at: Option<u64>,
 }
 
-#[ic_cdk::query(guard = is_user)]
-fn get_vehicle(vehicle: Principal) -> VTSResult<Vehicle> {
-    let caller = ic_cdk::api::caller();
-    let vehicle = VEHICLES.with(|vehicles| vehicles.borrow().get(&vehicle).ok_or(Error::NotFound))?;
-    match vehicle.provider {
-        Some(provider) => {
-            if provider != caller && vehicle.customer != caller {
-                return Err(Error::InvalidSigner);
-            }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AgreementConditions {
+    // Prices per unit of the particular telemetry kind, e.g. per km or per litre.
+    pub prices: Vec<PriceItem>,
+    // Fees which are charged once per invoice regardless of usage.
+    pub fees: Vec<Fee>,
+    // Usage cannot cost less than this value multiplied by days in the period.
+    pub daily_minimum: Option<String>,
+    pub billing_cycle: BillingCycle,
+    // Unix time in nanoseconds when agreement comes into effect, usage before it is not billed.
+    pub starts_at: Option<u64>,
+    // Unix time in nanoseconds when agreement expires.
+    pub ends_at: Option<u64>,
+    // Token in which invoices are issued and paid.
+    pub token: PaymentToken,
+    // Invoice should be paid within these days after it is issued.
+    pub payment_due_days: u32,
+    // Vehicle is turned off when invoice is not paid within these days after its due date.
+    pub grace_period_days: u32,
+    // Charges added to invoices which are not paid by their due date.
+    pub late_fees: LateFees,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BillingCycle {
+    // Offset from UTC in minutes; telemetry is split by days in this time zone.
+    pub utc_offset_minutes: i16,
+    // Day of the month (1-28) for monthly periods or day of the week (1-7, Monday is 1) for weekly.
+    pub start_day: u8,
+    pub period: BillingPeriod,
+}
+
+impl Default for BillingCycle {
+    fn default() -> Self {
+        Self {
+            utc_offset_minutes: 0,
+            start_day: 1,
+            period: BillingPeriod::Monthly,
         }
-        None => {
-            if vehicle.customer != caller {
-                return Err(Error::InvalidSigner);
+    }
+}
+
+impl BillingCycle {
+    fn validate(&self) -> VTSResult<()> {
+        self.offset()?;
+        let max_start_day = match self.period {
+            BillingPeriod::Monthly => 28,
+            BillingPeriod::Weekly => 7,
+        };
+        if self.start_day == 0 || self.start_day > max_start_day {
+            return Err(Error::InvalidData);
+        }
+        Ok(())
+    }
+
+    fn offset(&self) -> VTSResult<UtcOffset> {
+        UtcOffset::from_whole_seconds(self.utc_offset_minutes as i32 * 60).map_err(|_| Error::InvalidData)
+    }
+
+    // Returns the first day of the period which contains the date and the first day after it.
+    fn period_of(&self, date: Date) -> VTSResult<(Date, Date)> {
+        match self.period {
+            BillingPeriod::Monthly => {
+                let (mut year, mut month) = (date.year(), date.month());
+                if date.day() < self.start_day {
+                    (year, month) = previous_month(year, month);
+                }
+                let start =
+                    Date::from_calendar_date(year, month, self.start_day).map_err(|_| Error::Internal)?;
+                let (year, month) = next_month(year, month);
+                let end =
+                    Date::from_calendar_date(year, month, self.start_day).map_err(|_| Error::Internal)?;
+                Ok((start, end))
+            }
+            BillingPeriod::Weekly => {
+                let days_since_start =
+                    (date.weekday().number_from_monday() as i64 - self.start_day as i64).rem_euclid(7);
+                let start = date - Duration::days(days_since_start);
+                Ok((start, start + Duration::days(7)))
             }
         }
     }
-    Ok(vehicle)
 }
 
-#[ic_cdk::update(guard = is_user)]
-fn create_agreement(name: String, vh_customer: Principal, gas_price: String) -> VTSResult<u128> {
-    let caller = ic_cdk::api::caller();
-    ic_cdk::println!("requested agreement creation by {}", caller);
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum BillingPeriod {
+    Monthly,
+    Weekly,
+}
 
-    // Veryfy that user passed ok gas price.
-    Decimal::from_str(&gas_price).map_err(|_| Error::InvalidData)?;
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PriceItem {
+    pub t_type: TelemetryType,
+    pub price: String,
+}
 
-    let next_agreement_id = AGREEMENT_ID_COUNTER.with(|counter| {
-        let mut counter = counter.borrow_mut();
-        *counter += 1;
-        *counter
-    });
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Fee {
+    pub name: String,
+    pub amount: String,
+}
 
-    AGREEMENTS.with(|agreements| {
-        let agreement = Agreement {
-            id: next_agreement_id,
-            name,
-            vh_provider: caller,
-            vh_customer,
-            state: AgreementState::Unsigned,
-            conditions: AgreementConditions { gas_price },
-            vehicles: HashMap::new(),
-        };
-        let mut agreements = agreements.borrow_mut();
-        agreements.insert(next_agreement_id, agreement);
-    });
+#[derive(CandidType, Serialize, Deserialize, Clone, Default, Debug)]
+pub struct LateFees {
+    // Amount in tokens which is charged once when invoice is not paid by its due date.
+    pub fixed: Option<String>,
+    // Percent of the outstanding balance which is charged for every full day after the due date.
+    pub daily_interest_percent: Option<String>,
+}
 
-    USERS.with(|users| -> VTSResult<()> {
-        let mut vh_provider_user = users.borrow_mut().get(&caller).ok_or(Error::NotFound)?;
-        let mut vh_customer_user = users.borrow_mut().get(&vh_customer).ok_or(Error::NotFound)?;
-        vh_provider_user.agreements.insert(next_agreement_id, ());
-        vh_customer_user.agreements.insert(next_agreement_id, ());
-        users.borrow_mut().insert(caller, vh_provider_user);
-        users.borrow_mut().insert(vh_customer, vh_customer_user);
-        Ok(())
-    })?;
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum AdjustmentKind {
+    // Reduces amount to pay, it is issued by provider for unpaid invoice.
+    Discount,
+    // Issued by provider, it becomes agreement credit if invoice is paid already.
+    CreditNote,
+    // Charged by canister when invoice is not paid by its due date.
+    LateFee,
+    LateInterest { days: u32 },
+}
 
-    Ok(next_agreement_id)
+impl AdjustmentKind {
+    fn is_charge(&self) -> bool {
+        matches!(self, AdjustmentKind::LateFee | AdjustmentKind::LateInterest { .. })
+    }
 }
 
-#[ic_cdk::update(guard = is_user)]
-fn sign_agreement(agreement_id: u128) -> VTSResult<()> {
-    let caller = ic_cdk::api::caller();
-    ic_cdk::println!("requested agreement signing by {}", caller);
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct InvoicePayment {
+    // Amount in the smallest units of the invoice token.
+    pub amount: u128,
+    // Ledger block of the transfer, it is empty when invoice is paid with the agreement credit.
+    pub block_index: Option<u128>,
+    pub paid_by: Principal,
+    pub paid_at: u64,
+}
 
-    AGREEMENTS.with(|agreements| {
-        let mut agreements = agreements.borrow_mut();
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum DisputeState {
+    // Waiting for the provider to accept or reject.
+    Open,
+    Accepted,
+    Rejected,
+    // Waiting for the arbitrator.
+    Escalated,
+    Resolved,
+}
 
-        if let Some(mut agreement) = agreements.get(&agreement_id) {
-            if agreement.vh_customer != caller {
-                return Err(Error::InvalidSigner);
-            }
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum DisputeAction {
+    Open,
+    // Credit note for the amount in the smallest units of the invoice token is issued.
+    Accept { credit: u128 },
+    Reject,
+    Escalate,
+    Resolve { credit: u128 },
+}
 
-            match agreement.state {
-                AgreementState::Signed => Err(Error::AlreadyExists),
-                _ => {
-                    agreement.state = AgreementState::Signed;
-                    agreements.insert(agreement_id, agreement);
-                    Ok(())
-                }
-            }
-        } else {
-            Err(Error::NotFound)
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DisputeEntry {
+    pub action: DisputeAction,
+    pub author: Principal,
+    pub message: String,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Dispute {
+    pub invoice: u128,
+    pub state: DisputeState,
+    // All messages of customer, provider and arbitrator in order.
+    pub thread: Vec<DisputeEntry>,
+}
+
+// Transfer from customer to provider which is sent to the ledger.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PendingPayment {
+    pub payer: Principal,
+    pub provider: Principal,
+    pub amount: u128,
+    pub memo: Vec<u8>,
+    pub created_at_time: u64,
+}
+
+// Transfer of the invoice deposit to provider which is sent to the ledger.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PendingForward {
+    pub provider: Principal,
+    pub amount: u128,
+    pub fee: u128,
+    pub created_at_time: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct InvoiceAdjustment {
+    pub kind: AdjustmentKind,
+    // Amount in the smallest units of the invoice token.
+    pub amount: u128,
+    pub reason: String,
+    // Provider who issued the adjustment or the canister itself for late fees.
+    pub issued_by: Principal,
+    pub issued_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PaymentToken {
+    // ICRC-1 ledger with ICRC-2 support, e.g. ICP, ckBTC or ckUSDC ledger.
+    pub ledger: Principal,
+    pub symbol: String,
+    // Prices are set in tokens and invoices are issued in the smallest ledger units.
+    pub decimals: u8,
+}
+
+impl Default for PaymentToken {
+    fn default() -> Self {
+        Self {
+            ledger: Principal::from_text(ICP_LEDGER).unwrap(),
+            symbol: String::from("ICP"),
+            decimals: 8,
         }
-    })
+    }
 }
 
-#[ic_cdk::update(guard = is_user)]
-fn link_vehicle(agreement_id: u128, vehicle_identity: Principal) -> VTSResult<()> {
-    let caller = ic_cdk::api::caller();
-    ic_cdk::println!("requested vehicle linking by {}", caller);
+impl PaymentToken {
+    fn validate(&self) -> VTSResult<()> {
+        if self.symbol.is_empty() || self.decimals > MAX_TOKEN_DECIMALS {
+            return Err(Error::InvalidData);
+        }
+        Ok(())
+    }
 
-    let vh_provider: Principal = AGREEMENTS.with(|agreements| {
-        let mut agreements = agreements.borrow_mut();
-        let mut agreement = agreements.get(&agreement_id).ok_or(Error::NotFound)?;
-        let vh_provider = agreement.vh_provider;
+    // Converts amount in tokens to the smallest ledger units, fractions of units are dropped.
+    fn to_units(&self, amount: Decimal) -> VTSResult<u128> {
+        let multiplier = Decimal::from_u128(10u128.pow(self.decimals as u32)).ok_or(Error::Internal)?;
+        amount.checked_mul(multiplier).and_then(|units| units.trunc().to_u128()).ok_or(Error::InvalidData)
+    }
+}
 
-        if agreement.vehicles.contains_key(&vehicle_identity) {
-            return Err(Error::AlreadyExists);
+impl AgreementConditions {
+    fn validate(&self) -> VTSResult<()> {
+        for (i, item) in self.prices.iter().enumerate() {
+            parse_amount(&item.price)?;
+            // Every telemetry kind can be priced only once.
+            if self.prices[..i].iter().any(|prev| prev.t_type == item.t_type) {
+                return Err(Error::InvalidData);
+            }
         }
-        if caller != agreement.vh_customer {
-            ic_cdk::println!("vehicle provider tried to link vehicle to its own agreement");
-            return Err(Error::InvalidSigner);
+        for fee in &self.fees {
+            parse_amount(&fee.amount)?;
+        }
+        if let Some(daily_minimum) = &self.daily_minimum {
+            parse_amount(daily_minimum)?;
+        }
+        for amount in [&self.late_fees.fixed, &self.late_fees.daily_interest_percent].into_iter().flatten() {
+            parse_amount(amount)?;
+        }
+        if let (Some(starts_at), Some(ends_at)) = (self.starts_at, self.ends_at) {
+            if starts_at >= ends_at {
+                return Err(Error::InvalidData);
+            }
         }
+        self.token.validate()?;
+        self.billing_cycle.validate()
+    }
+}
 
-        agreement.vehicles.insert(vehicle_identity, ());
-        agreements.insert(agreement_id, agreement);
+#[ic_cdk::init]
+fn init() {
+    update_state(|state| state.schema_version = SCHEMA_VERSION);
+    set_timers();
+}
 
-        Ok(vh_provider)
-    })?;
+// All state is kept in stable memory, so there is nothing to save.
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    ic_cdk::println!("upgrading canister with schema version {}", get_state().schema_version);
+}
 
-    VEHICLES.with(|vehicles| {
-        let mut vehicle = vehicles.borrow_mut().get(&vehicle_identity).ok_or(Error::NotFound)?;
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    migrate();
+    // Timers are not kept after upgrade.
+    set_timers();
+}
 
-        if caller != vehicle.customer {
-            return Err(Error::InvalidSigner);
+fn set_timers() {
+    // Every day or 24h.
+    ic_cdk_timers::set_timer_interval(std::time::Duration::from_secs(86400), || {
+        if let Err(e) = accumulate_telemetry_data() {
+            ic_cdk::println!("failed to accumulate telemetry data: {}", e)
         }
-        if vehicle.agreement.is_some() {
-            return Err(Error::AlreadyExists);
+    });
+    ic_cdk_timers::set_timer_interval(std::time::Duration::from_secs(DEPOSIT_SWEEP_INTERVAL_SECS), || {
+        ic_cdk::spawn(async {
+            recover_pending_payments().await;
+            sweep_invoice_deposits().await;
+        })
+    });
+    ic_cdk_timers::set_timer_interval(std::time::Duration::from_secs(OVERDUE_CHECK_INTERVAL_SECS), || {
+        if let Err(e) = check_overdue_invoices() {
+            ic_cdk::println!("failed to check overdue invoices: {}", e)
         }
+    });
+}
 
-        vehicle.agreement = Some(agreement_id);
-        vehicle.provider = Some(vh_provider);
-        vehicles.borrow_mut().insert(vehicle_identity, vehicle);
-
-        Ok(())
-    })?;
+fn get_state() -> State {
+    STATE.with(|state| state.borrow().get().clone())
+}
 
-    USERS.with(|users| -> VTSResult<()> {
-        let mut provider = users.borrow().get(&vh_provider).ok_or(Error::NotFound)?;
-        provider.vehicles.insert(vehicle_identity, ());
-        users.borrow_mut().insert(vh_provider, provider);
-        Ok(())
+fn update_state<R>(f: impl FnOnce(&mut State) -> R) -> R {
+    STATE.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let mut state = cell.get().clone();
+        let result = f(&mut state);
+        cell.set(state).expect("failed to save state");
+        result
     })
 }
 
-#[ic_cdk::update(guard = is_user)]
-async fn pay_for_invoice(id: u128) -> VTSResult<()> {
-    let mut invoice = INVOICES.with(|invoices| invoices.borrow().get(&id).ok_or(Error::NotFound))?;
-    if let InvoiceStatus::Paid = invoice.status {
+#[ic_cdk::query(guard = is_user)]
+fn get_invoice(invoice_id: u128) -> Result<Invoice, Error> {
+    get_visible_invoice(invoice_id)
+}
+
+// Only agreement provider and customer can see the invoice.
+fn get_visible_invoice(invoice_id: u128) -> VTSResult<Invoice> {
+    let invoice = INVOICES.with(|invoices| invoices.borrow().load(&invoice_id))?;
+    get_visible_agreement(invoice.agreement)?;
+    Ok(invoice)
+}
+
+#[ic_cdk::update(guard = is_canister)]
+fn accumulate_telemetry_data() -> VTSResult<()> {
+    ic_cdk::println!("starting to accumulate telemetry data");
+
+    if !accumulate_telemetry_batch() {
+        ic_cdk_timers::set_timer(std::time::Duration::ZERO, || {
+            if let Err(e) = accumulate_telemetry_data() {
+                ic_cdk::println!("failed to accumulate telemetry data: {}", e)
+            }
+        });
         return Ok(());
     }
-    let provider = VEHICLES.with(|vehicles| {
-        vehicles.borrow().get(&invoice.vehicle).ok_or(Error::NotFound)?.provider.ok_or(Error::NotFound)
-    })?;
 
-    let transfer_from_args: TransferFromArgs = TransferFromArgs {
-        amount: invoice.total_cost.into(),
-        from: Account::from(ic_cdk::caller()),
-        to: Account::from(provider),
-        memo: None,
-        spender_subaccount: None,
-        fee: None,
-        created_at_time: None,
-    };
-    ic_cdk::call::<(TransferFromArgs,), (Result<BlockIndex, TransferFromError>,)>(
-        Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap(),
-        "icrc2_transfer_from",
-        (transfer_from_args,),
-    )
-    .await
-    .map_err(|_| Error::Internal)?
-    .0
-    .map_err(|_| Error::Internal)?;
+    // Issue invoices for every closed billing period which doesn't have one yet.
+    // So it doesn't matter on which day the timer is executed or if it missed some days.
+    let now = ic_cdk::api::time();
+    expire_agreements(now);
+    // Readings can be buffered offline, so period is invoiced only when they can't be accepted anymore.
+    let billable_until = now.saturating_sub(get_state().telemetry_skew_window.past_secs * 1_000_000_000);
+    let vehicles: Vec<Principal> =
+        VEHICLES.with(|vehicles| vehicles.borrow().iter().map(|(vehicle_id, _)| vehicle_id).collect());
+    for vehicle_id in vehicles {
+        if let Err(e) = issue_missing_invoices(vehicle_id, billable_until) {
+            ic_cdk::println!("failed to issue invoices for {}: {:?}", vehicle_id, e);
+        }
+    }
 
-    invoice.status = InvoiceStatus::Paid;
-    INVOICES.with(|invoices| invoices.borrow_mut().insert(id, invoice));
-    PAID_INVOICES.with(|invoices| invoices.borrow_mut().insert(id, ()));
+    prune_telemetry_log();
 
+    ic_cdk::println!("accumulating telemetry data is finished");
     Ok(())
 }
 
-#[ic_cdk::query(guard = is_user)]
-fn get_user_agreements() -> VTSResult<Vec<Agreement>> {
-    let caller = ic_cdk::api::caller();
-    let user = USERS.with(|users| users.borrow().get(&caller).ok_or(Error::NotFound))?;
-    let mut agreements = Vec::with_capacity(user.agreements.len());
-    AGREEMENTS.with(|agreements_storage| -> VTSResult<()> {
-        let agreements_storage = agreements_storage.borrow();
+// Accumulates a batch of the queue after the cursor, returns false if there is more to accumulate.
+fn accumulate_telemetry_batch() -> bool {
+    let cursor = get_state().telemetry_queue_cursor;
+    let mut queued: HashMap<Principal, Vec<TelemetryKey>> = HashMap::new();
+    let mut last = None;
+    let mut count = 0;
+    TELEMETRY_QUEUE.with(|queue| {
+        let start = cursor.map_or(RangeBound::Unbounded, RangeBound::Excluded);
+        for (key, _) in
+            queue.borrow().range((start, RangeBound::Unbounded)).take(TELEMETRY_PROCESSING_BATCH_SIZE)
+        {
+            queued.entry(key.vehicle).or_default().push(key);
+            last = Some(key);
+            count += 1;
+        }
+    });
+    for (vehicle_id, keys) in queued {
+        if let Err(e) = accumulate_vehicle_telemetry(vehicle_id, &keys) {
+            ic_cdk::println!("failed to accumulate telemetry for {}: {:?}", vehicle_id, e);
+            continue;
+        }
+        // Raw telemetry is already accumulated so we don't need to count it again.
+        TELEMETRY_QUEUE.with(|queue| {
+            let mut queue = queue.borrow_mut();
+            for key in keys {
+                queue.remove(&key);
+            }
+        });
+    }
+    // Telemetry which failed to accumulate is kept in the queue and retried by the next run.
+    let done = count < TELEMETRY_PROCESSING_BATCH_SIZE;
+    update_state(|state| state.telemetry_queue_cursor = if done { None } else { last });
+    done
+}
+
+fn accumulate_vehicle_telemetry(vehicle_id: Principal, keys: &[TelemetryKey]) -> VTSResult<()> {
+    let vehicle = VEHICLES.with(|vehicles| vehicles.borrow().load(&vehicle_id))?;
+    let mut accumulated_telemetry = get_accumulated_telemetry(vehicle_id)?;
+    // Telemetry is split by days of device time in the time zone of the agreement billing cycle.
+    let offset = get_billing_cycle(vehicle.agreement)?.offset()?;
+    // Keys are sorted by device time, so values are merged in chronological order,
+    // otherwise rules like "last value" produce random results.
+    for key in keys {
+        let record = TELEMETRY_LOG.with(|log| log.borrow().load(key))?;
+        let timestamp = OffsetDateTime::from_unix_timestamp_nanos(key.timestamp as i128)
+            .map_err(|_| Error::InvalidData)?
+            .to_offset(offset);
+        let rule = record.t_type.aggregation_rule();
+        let value = record.value;
+        // Yearly value includes monthly and daily ones, so they can't overflow if it doesn't.
+        let current = accumulated_telemetry
+            .get(&record.t_type)
+            .and_then(|yearly| yearly.get(&timestamp.year()))
+            .map_or(0, |yearly| yearly.value);
+        if let Err(e) = rule.merge(current, value) {
+            ic_cdk::println!("telemetry {:?} is not accumulated: {:?}", key, e);
+            continue;
+        }
+        let yearly =
+            accumulated_telemetry.entry(record.t_type).or_default().entry(timestamp.year()).or_default();
+        yearly.value = rule.merge(yearly.value, value)?;
+        let monthly = yearly.monthly.entry(timestamp.month() as u8).or_default();
+        monthly.value = rule.merge(monthly.value, value)?;
+        let daily = monthly.daily.entry(timestamp.day()).or_default();
+        *daily = rule.merge(*daily, value)?;
+    }
+    VEHICLE_TELEMETRY.with(|telemetry| telemetry.borrow_mut().store(vehicle_id, &accumulated_telemetry));
+    Ok(())
+}
+
+fn get_accumulated_telemetry(vehicle_id: Principal) -> VTSResult<AccumulatedTelemetry> {
+    VEHICLE_TELEMETRY
+        .with(|telemetry| telemetry.borrow().try_load(&vehicle_id))
+        .map(Option::unwrap_or_default)
+}
+
+// Remove raw telemetry which is older than retention period.
+fn prune_telemetry_log() {
+    if !prune_telemetry_batch(ic_cdk::api::time()) {
+        ic_cdk_timers::set_timer(std::time::Duration::ZERO, prune_telemetry_log);
+    }
+}
+
+// Prunes a batch of the log after the cursor, returns false if there is more to prune.
+fn prune_telemetry_batch(now: u64) -> bool {
+    let retention_days = get_state().telemetry_retention_days as u64;
+    let expire_before = now.saturating_sub(retention_days * 86400 * 1_000_000_000);
+    let mut start = get_state().telemetry_prune_cursor.map_or(RangeBound::Unbounded, RangeBound::Excluded);
+    let done = TELEMETRY_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        for _ in 0..TELEMETRY_PROCESSING_BATCH_SIZE {
+            let Some((key, _)) = log.range((start, RangeBound::Unbounded)).next() else {
+                return true;
+            };
+            // Telemetry is ordered by time, so the rest of the vehicle telemetry is skipped.
+            if key.timestamp >= expire_before {
+                start = RangeBound::Excluded(TelemetryKey::last(key.vehicle));
+                continue;
+            }
+            // Not accumulated telemetry is kept until it is counted.
+            if !TELEMETRY_QUEUE.with(|queue| queue.borrow().contains_key(&key)) {
+                log.remove(&key);
+            }
+            start = RangeBound::Excluded(key);
+        }
+        false
+    });
+    update_state(|state| {
+        state.telemetry_prune_cursor = match start {
+            RangeBound::Excluded(key) if !done => Some(key),
+            _ => None,
+        }
+    });
+    done
+}
+
+#[ic_cdk::query(guard = is_user)]
+fn get_aggregated_data(vehicle_id: Principal) -> VTSResult<AccumulatedTelemetry> {
+    get_visible_vehicle(vehicle_id)?;
+    get_accumulated_telemetry(vehicle_id)
+}
+
+// Raw telemetry which was used to calculate the invoice, so it can be audited.
+#[ic_cdk::query(guard = is_user)]
+fn get_invoice_telemetry(invoice_id: u128, cursor: Option<TelemetryCursor>) -> VTSResult<RawTelemetryPage> {
+    let invoice = get_visible_invoice(invoice_id)?;
+    let offset = get_billing_cycle(Some(invoice.agreement))?.offset()?;
+    let (start, end) = invoice.period.dates()?;
+    let start = TelemetryKey {
+        vehicle: invoice.vehicle,
+        timestamp: to_timestamp(start, offset),
+        seq: 0,
+    };
+    let end = TelemetryKey {
+        vehicle: invoice.vehicle,
+        timestamp: to_timestamp(end, offset),
+        seq: 0,
+    };
+    let cursor = cursor.map(|cursor| TelemetryKey {
+        vehicle: invoice.vehicle,
+        timestamp: cursor.timestamp,
+        seq: cursor.seq,
+    });
+    let start = range_start(start, cursor);
+    get_raw_telemetry_page((start, RangeBound::Excluded(end)))
+}
+
+// Page starts after the cursor, or at the range start if the cursor is before it.
+fn range_start(start: TelemetryKey, cursor: Option<TelemetryKey>) -> RangeBound<TelemetryKey> {
+    match cursor {
+        Some(cursor) if cursor >= start => RangeBound::Excluded(cursor),
+        _ => RangeBound::Included(start),
+    }
+}
+
+fn get_raw_telemetry_page(
+    range: (RangeBound<TelemetryKey>, RangeBound<TelemetryKey>),
+) -> VTSResult<RawTelemetryPage> {
+    TELEMETRY_LOG.with(|log| {
+        let log = log.borrow();
+        // One more item is read to know if there is a next page.
+        let mut telemetry: Vec<RawTelemetry> = log
+            .range(range)
+            .take(MAX_TELEMETRY_PAGE_SIZE + 1)
+            .map(|(key, record)| {
+                let record = record.decode()?;
+                Ok(RawTelemetry {
+                    timestamp: key.timestamp,
+                    seq: key.seq,
+                    t_type: record.t_type,
+                    value: record.value,
+                    signature: record.signature,
+                })
+            })
+            .collect::<VTSResult<_>>()?;
+        let mut next = None;
+        if telemetry.len() > MAX_TELEMETRY_PAGE_SIZE {
+            telemetry.truncate(MAX_TELEMETRY_PAGE_SIZE);
+            next = telemetry.last().map(|last| TelemetryCursor {
+                timestamp: last.timestamp,
+                seq: last.seq,
+            });
+        }
+        Ok(RawTelemetryPage { telemetry, next })
+    })
+}
+
+// Time series of a single telemetry kind. Day, month and year points contain only accumulated telemetry.
+#[ic_cdk::query(guard = is_user)]
+fn get_telemetry(query: TelemetryQuery) -> VTSResult<TelemetryPage> {
+    if query.from >= query.to {
+        return Err(Error::InvalidData);
+    }
+    let limit =
+        query.limit.map_or(MAX_TELEMETRY_PAGE_SIZE, |limit| (limit as usize).min(MAX_TELEMETRY_PAGE_SIZE));
+    if limit == 0 {
+        return Err(Error::InvalidData);
+    }
+    let vehicle = get_visible_vehicle(query.vehicle)?;
+    match query.granularity {
+        TelemetryGranularity::Raw => get_raw_telemetry_points(&query, limit),
+        granularity => {
+            let offset = get_billing_cycle(vehicle.agreement)?.offset()?;
+            let accumulated_telemetry = get_accumulated_telemetry(query.vehicle)?;
+            let accumulated = accumulated_telemetry.get(&query.t_type);
+            let mut points = accumulated_telemetry_points(accumulated, granularity, offset)?;
+            // Cursor points to the last returned period.
+            let from =
+                query.cursor.map_or(query.from, |cursor| query.from.max(cursor.timestamp.saturating_add(1)));
+            points.retain(|point| point.timestamp >= from && point.timestamp < query.to);
+            let mut next = None;
+            if points.len() > limit {
+                points.truncate(limit);
+                next = points.last().map(|last| TelemetryCursor {
+                    timestamp: last.timestamp,
+                    seq: 0,
+                });
+            }
+            Ok(TelemetryPage { points, next })
+        }
+    }
+}
+
+fn get_raw_telemetry_points(query: &TelemetryQuery, limit: usize) -> VTSResult<TelemetryPage> {
+    let start = TelemetryKey {
+        vehicle: query.vehicle,
+        timestamp: query.from,
+        seq: 0,
+    };
+    let end = TelemetryKey {
+        vehicle: query.vehicle,
+        timestamp: query.to,
+        seq: 0,
+    };
+    let cursor = query.cursor.map(|cursor| TelemetryKey {
+        vehicle: query.vehicle,
+        timestamp: cursor.timestamp,
+        seq: cursor.seq,
+    });
+    let start = range_start(start, cursor);
+    TELEMETRY_LOG.with(|log| {
+        let log = log.borrow();
+        let mut points = Vec::new();
+        let mut last_key = None;
+        let mut scanned = 0;
+        let mut range = log.range((start, RangeBound::Excluded(end)));
+        for (key, record) in range.by_ref() {
+            let record = record.decode()?;
+            if record.t_type == query.t_type {
+                // Next page should start right after the last point.
+                if points.len() == limit {
+                    return Ok(TelemetryPage {
+                        points,
+                        next: last_key,
+                    });
+                }
+                points.push(TelemetryPoint {
+                    timestamp: key.timestamp,
+                    value: record.value,
+                });
+            }
+            last_key = Some(TelemetryCursor {
+                timestamp: key.timestamp,
+                seq: key.seq,
+            });
+            scanned += 1;
+            // Page can contain less points than limit if the kind is rare.
+            if scanned == MAX_TELEMETRY_SCAN_SIZE {
+                let next = if range.next().is_some() { last_key } else { None };
+                return Ok(TelemetryPage { points, next });
+            }
+        }
+        Ok(TelemetryPage { points, next: None })
+    })
+}
+
+// Returns points of accumulated telemetry sorted by time.
+fn accumulated_telemetry_points(
+    accumulated: Option<&HashMap<i32, AccumulatedTelemetryYearly>>,
+    granularity: TelemetryGranularity,
+    offset: UtcOffset,
+) -> VTSResult<Vec<TelemetryPoint>> {
+    let mut points = Vec::new();
+    for (year, yearly) in accumulated.into_iter().flatten() {
+        if granularity == TelemetryGranularity::Year {
+            points.push(TelemetryPoint {
+                timestamp: to_timestamp(to_date(*year, 1, 1)?, offset),
+                value: yearly.value,
+            });
+            continue;
+        }
+        for (month, monthly) in &yearly.monthly {
+            if granularity == TelemetryGranularity::Month {
+                points.push(TelemetryPoint {
+                    timestamp: to_timestamp(to_date(*year, *month, 1)?, offset),
+                    value: monthly.value,
+                });
+                continue;
+            }
+            for (day, value) in &monthly.daily {
+                points.push(TelemetryPoint {
+                    timestamp: to_timestamp(to_date(*year, *month, *day)?, offset),
+                    value: *value,
+                });
+            }
+        }
+    }
+    points.sort_by_key(|point| point.timestamp);
+    Ok(points)
+}
+
+#[ic_cdk::query]
+fn get_telemetry_types() -> Vec<TelemetryTypeInfo> {
+    TelemetryType::ALL
+        .iter()
+        .map(|t_type| TelemetryTypeInfo {
+            t_type: *t_type,
+            unit: t_type.unit().to_string(),
+            aggregation_rule: t_type.aggregation_rule(),
+        })
+        .collect()
+}
+
+#[ic_cdk::update]
+fn add_admin(new_admin: Principal) -> VTSResult<()> {
+    let caller = ic_cdk::api::caller();
+    ADMINS.with(|admins| {
+        // If we just deployed canister we can add first admin to it.
+        if admins.borrow().is_empty() {
+            admins.borrow_mut().store(caller, &Admin {});
+            Ok(())
+        } else {
+            is_admin()?;
+            admins.borrow_mut().store(new_admin, &Admin {});
+            Ok(())
+        }
+    })
+}
+
+#[ic_cdk::update(guard = is_admin)]
+fn delete_admin(admin: Principal) -> VTSResult<()> {
+    let caller = ic_cdk::api::caller();
+    ADMINS.with(|admins| {
+        if !admins.borrow().contains_key(&admin) {
+            return Err(Error::NotFound);
+        }
+        if admin == caller {
+            return Err(Error::InvalidSigner);
+        }
+        admins.borrow_mut().remove(&admin);
+        Ok(())
+    })
+}
+
+#[ic_cdk::update(guard = is_admin)]
+fn add_gateway(gateway: Principal) -> VTSResult<()> {
+    GATEWAYS.with(|gateways| {
+        if gateways.borrow().contains_key(&gateway) {
+            return Err(Error::AlreadyExists);
+        }
+        gateways.borrow_mut().store(gateway, &Gateway {});
+        Ok(())
+    })
+}
+
+#[ic_cdk::update(guard = is_admin)]
+fn remove_gateway(gateway: Principal) -> VTSResult<()> {
+    GATEWAYS.with(|gateways| gateways.borrow_mut().remove(&gateway).map(|_| ()).ok_or(Error::NotFound))
+}
+
+#[ic_cdk::query(guard = is_admin)]
+fn list_gateways() -> Vec<Principal> {
+    GATEWAYS.with(|gateways| gateways.borrow().iter().map(|(gateway, _)| gateway).collect())
+}
+
+#[ic_cdk::update(guard = is_admin)]
+fn register_user(user: Principal, email: Option<String>, roles: Vec<Role>) -> VTSResult<()> {
+    if USERS.with(|users| users.borrow().contains_key(&user)) {
+        return Err(Error::AlreadyExists);
+    }
+    if roles.is_empty() {
+        return Err(Error::InvalidData);
+    }
+
+    USERS.with(|users| {
+        users.borrow_mut().store(
+            user,
+            &User {
+                vehicles: HashMap::new(),
+                agreements: HashMap::new(),
+                email,
+                roles,
+            },
+        );
+    });
+
+    Ok(())
+}
+
+#[ic_cdk::update(guard = is_admin)]
+fn set_user_roles(user: Principal, roles: Vec<Role>) -> VTSResult<()> {
+    if roles.is_empty() {
+        return Err(Error::InvalidData);
+    }
+    USERS.with(|users| {
+        let mut user_data = users.borrow().load(&user)?;
+        user_data.roles = roles;
+        users.borrow_mut().store(user, &user_data);
+        Ok(())
+    })
+}
+
+#[ic_cdk::update(guard = is_admin)]
+fn delete_user(user: Principal) -> VTSResult<()> {
+    // Check if the user to be deleted exists.
+    USERS.with(|users| {
+        if !users.borrow().contains_key(&user) {
+            return Err(Error::NotFound);
+        }
+        Ok(())
+    })?;
+
+    // Remove the user.
+    USERS.with(|users| users.borrow_mut().remove(&user));
+    ORGANIZATIONS.with(|organizations| organizations.borrow_mut().remove(&user));
+    Ok(())
+}
+
+// Registered user becomes organization and can add members to act on its behalf.
+#[ic_cdk::update(guard = is_admin)]
+fn create_organization(organization: Principal, name: String) -> VTSResult<()> {
+    USERS.with(|users| users.borrow().load(&organization))?;
+    ORGANIZATIONS.with(|organizations| {
+        if organizations.borrow().contains_key(&organization) {
+            return Err(Error::AlreadyExists);
+        }
+        organizations.borrow_mut().store(
+            organization,
+            &Organization {
+                name,
+                members: HashMap::new(),
+            },
+        );
+        Ok(())
+    })
+}
+
+// Adds member or replaces its permissions.
+#[ic_cdk::update(guard = is_user)]
+fn set_organization_member(
+    organization: Principal,
+    member: Principal,
+    permissions: Vec<Permission>,
+) -> VTSResult<()> {
+    if permissions.is_empty() {
+        return Err(Error::InvalidData);
+    }
+    USERS.with(|users| users.borrow().load(&member))?;
+    let mut organization_data = get_managed_organization(organization)?;
+    organization_data.members.insert(member, permissions);
+    ORGANIZATIONS.with(|organizations| organizations.borrow_mut().store(organization, &organization_data));
+    Ok(())
+}
+
+#[ic_cdk::update(guard = is_user)]
+fn remove_organization_member(organization: Principal, member: Principal) -> VTSResult<()> {
+    let mut organization_data = get_managed_organization(organization)?;
+    organization_data.members.remove(&member).ok_or(Error::NotFound)?;
+    ORGANIZATIONS.with(|organizations| organizations.borrow_mut().store(organization, &organization_data));
+    Ok(())
+}
+
+// Only organization itself and admins can manage members.
+fn get_managed_organization(organization: Principal) -> VTSResult<Organization> {
+    let organization_data = ORGANIZATIONS.with(|organizations| organizations.borrow().load(&organization))?;
+    if ic_cdk::caller() != organization && is_admin().is_err() {
+        return Err(Error::Unauthorized);
+    }
+    Ok(organization_data)
+}
+
+#[ic_cdk::query(guard = is_user)]
+fn get_organization(organization: Principal) -> VTSResult<Organization> {
+    let organization_data = ORGANIZATIONS.with(|organizations| organizations.borrow().load(&organization))?;
+    let caller = ic_cdk::api::caller();
+    if caller != organization
+        && !organization_data.members.contains_key(&caller)
+        && !has_role(caller, &[Role::Auditor])
+    {
+        return Err(Error::InvalidSigner);
+    }
+    Ok(organization_data)
+}
+
+#[ic_cdk::query(guard = is_user)]
+fn get_user() -> VTSResult<User> {
+    let caller = ic_cdk::api::caller();
+    USERS.with(|users| users.borrow().load(&caller))
+}
+
+#[ic_cdk::update(guard = is_vehicle_manager)]
+fn request_firmware() -> VTSResult<()> {
+    let caller = ic_cdk::api::caller();
+    ic_cdk::println!("{} is requested firmware", caller);
+    FIRMWARE_REQUESTS.with(|requests| {
+        if requests.borrow_mut().contains_key(&caller) {
+            return Err(Error::AlreadyExists);
+        }
+        requests.borrow_mut().insert(caller, ());
+        Ok(())
+    })?;
+    Ok(())
+}
+
+// This method can return first available firmware request.
+#[ic_cdk::query(guard = is_gateway)]
+fn get_firmware_requests() -> VTSResult<Principal> {
+    let (identity, _) =
+        FIRMWARE_REQUESTS.with(|requests| requests.borrow().first_key_value().ok_or(Error::NotFound))?;
+    Ok(identity)
+}
+
+// By this method we can check active firmware requests for the particular user.
+#[ic_cdk::query(guard = is_user)]
+fn get_firmware_requests_by_user() -> VTSResult<()> {
+    let caller = ic_cdk::api::caller();
+    FIRMWARE_REQUESTS.with(|requests| requests.borrow().get(&caller).ok_or(Error::NotFound))?;
+    Ok(())
+}
+
+// Firmware which fits into one ingress message can be uploaded at once.
+#[ic_cdk::update(guard = is_gateway)]
+fn upload_firmware(
+    vh_customer: Principal,
+    public_key: Vec<u8>,
+    arch: String,
+    firmware: Vec<u8>,
+) -> VTSResult<()> {
+    if firmware.len() as u64 > MAX_FIRMWARE_SIZE {
+        return Err(Error::InvalidData);
+    }
+    let firmware_sha256 = Sha256::digest(&firmware).to_vec();
+    save_firmware(vh_customer, public_key, arch, firmware_sha256, std::iter::once(firmware))
+}
+
+#[ic_cdk::update(guard = is_gateway)]
+fn begin_firmware_upload(vh_customer: Principal, public_key: Vec<u8>, arch: String) -> VTSResult<u128> {
+    USERS.with(|users| users.borrow().load(&vh_customer))?;
+    let now = ic_cdk::api::time();
+    FIRMWARE_UPLOADS.with(|uploads| {
+        let mut uploads = uploads.borrow_mut();
+        // Upload which can't be decoded can't be finished either, so it is removed too.
+        let expired: Vec<u128> = uploads
+            .iter()
+            .filter(|(_, upload)| {
+                !upload
+                    .decode()
+                    .is_ok_and(|upload| upload.started_at + FIRMWARE_UPLOAD_TTL_SECS * 1_000_000_000 >= now)
+            })
+            .map(|(id, _)| id)
+            .collect();
+        for id in expired {
+            uploads.remove(&id);
+            remove_upload_chunks(id);
+        }
+    });
+    let id = update_state(|state| {
+        state.firmware_upload_id_counter += 1;
+        state.firmware_upload_id_counter
+    });
+    FIRMWARE_UPLOADS.with(|uploads| {
+        uploads.borrow_mut().store(
+            id,
+            &FirmwareUpload {
+                uploader: ic_cdk::api::caller(),
+                vh_customer,
+                public_key,
+                arch,
+                size: 0,
+                chunk_count: 0,
+                started_at: now,
+            },
+        )
+    });
+    Ok(id)
+}
+
+// Offset should be equal to already uploaded size, so the same chunk can't be appended twice.
+#[ic_cdk::update(guard = is_gateway)]
+fn append_firmware_chunk(upload_id: u128, offset: u64, chunk: Vec<u8>) -> VTSResult<()> {
+    let mut upload = FIRMWARE_UPLOADS.with(|uploads| uploads.borrow().load(&upload_id))?;
+    if upload.uploader != ic_cdk::api::caller() {
+        return Err(Error::Unauthorized);
+    }
+    if offset != upload.size || upload.size + chunk.len() as u64 > MAX_FIRMWARE_SIZE {
+        return Err(Error::InvalidData);
+    }
+    upload.size += chunk.len() as u64;
+    FIRMWARE_UPLOAD_CHUNKS.with(|chunks| chunks.borrow_mut().insert((upload_id, upload.chunk_count), chunk));
+    upload.chunk_count += 1;
+    FIRMWARE_UPLOADS.with(|uploads| uploads.borrow_mut().store(upload_id, &upload));
+    Ok(())
+}
+
+#[ic_cdk::update(guard = is_gateway)]
+fn finish_firmware_upload(upload_id: u128, sha256: Vec<u8>) -> VTSResult<()> {
+    let upload = FIRMWARE_UPLOADS.with(|uploads| uploads.borrow().load(&upload_id))?;
+    if upload.uploader != ic_cdk::api::caller() {
+        return Err(Error::Unauthorized);
+    }
+    let mut hasher = Sha256::new();
+    FIRMWARE_UPLOAD_CHUNKS.with(|chunks| {
+        for (_, chunk) in chunks.borrow().range((upload_id, 0)..(upload_id, upload.chunk_count)) {
+            hasher.update(&chunk);
+        }
+    });
+    let firmware_sha256 = hasher.finalize().to_vec();
+    // Upload is kept to be able to retry finishing it with the correct hash.
+    if firmware_sha256 != sha256 {
+        return Err(Error::InvalidData);
+    }
+    // Chunks are copied one by one, so the whole firmware is never in the heap.
+    let chunks = (0..upload.chunk_count)
+        .filter_map(|index| FIRMWARE_UPLOAD_CHUNKS.with(|chunks| chunks.borrow().get(&(upload_id, index))));
+    save_firmware(upload.vh_customer, upload.public_key, upload.arch, firmware_sha256, chunks)?;
+    // Upload is removed only when firmware is saved, so it isn't lost if saving fails.
+    FIRMWARE_UPLOADS.with(|uploads| uploads.borrow_mut().remove(&upload_id));
+    remove_upload_chunks(upload_id);
+    Ok(())
+}
+
+fn remove_upload_chunks(upload_id: u128) {
+    FIRMWARE_UPLOAD_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        let keys: Vec<(u128, u32)> =
+            chunks.range((upload_id, 0)..=(upload_id, u32::MAX)).map(|(key, _)| key).collect();
+        for key in keys {
+            chunks.remove(&key);
+        }
+    });
+}
+
+fn save_firmware(
+    vh_customer: Principal,
+    public_key: Vec<u8>,
+    arch: String,
+    firmware_sha256: Vec<u8>,
+    firmware: impl Iterator<Item = Vec<u8>>,
+) -> VTSResult<()> {
+    let vehicle = Principal::self_authenticating(&public_key);
+    // Customer is checked before anything is changed, so failed saving can be retried.
+    let mut user = USERS.with(|users| users.borrow().load(&vh_customer))?;
+    FIRMWARE_REQUESTS.with(|requests| requests.borrow_mut().remove(&vh_customer));
+    store_firmware(vehicle, firmware);
+    VEHICLES.with(|vehicles| {
+        vehicles.borrow_mut().store(
+            vehicle,
+            &Vehicle {
+                provider: None,
+                customer: vh_customer,
+                agreement: None,
+                public_key,
+                arch,
+                firmware_sha256,
+                on_off: true,
+                links: Vec::new(),
+            },
+        )
+    });
+    user.vehicles.insert(vehicle, ());
+    USERS.with(|users| users.borrow_mut().store(vh_customer, &user));
+    Ok(())
+}
+
+#[ic_cdk::query(guard = is_user)]
+fn get_vehicle(vehicle_id: Principal) -> VTSResult<VehicleSummary> {
+    let vehicle = get_visible_vehicle(vehicle_id)?;
+    let last_seen =
+        VEHICLE_ACTIVITY.with(|activity| activity.borrow().try_load(&vehicle_id))?.map(|a| a.last_seen);
+    Ok(VehicleSummary {
+        principal: vehicle_id,
+        customer: vehicle.customer,
+        provider: vehicle.provider,
+        agreement: vehicle.agreement,
+        arch: vehicle.arch,
+        on_off: vehicle.on_off,
+        last_seen,
+        invoice_count: vehicle_invoice_ids(vehicle_id).len() as u64,
+    })
+}
+
+#[ic_cdk::query(guard = is_user)]
+fn get_vehicle_invoices(vehicle_id: Principal) -> VTSResult<Vec<u128>> {
+    get_visible_vehicle(vehicle_id)?;
+    Ok(vehicle_invoice_ids(vehicle_id))
+}
+
+fn vehicle_invoice_ids(vehicle_id: Principal) -> Vec<u128> {
+    VEHICLE_INVOICES.with(|invoices| {
+        invoices.borrow().range((vehicle_id, 0)..=(vehicle_id, u128::MAX)).map(|((_, id), _)| id).collect()
+    })
+}
+
+// Firmware can be larger than the response size limit so it is downloaded by chunks.
+#[ic_cdk::query(guard = is_user)]
+fn get_firmware(vehicle_id: Principal, offset: u64, length: u64) -> VTSResult<FirmwareChunk> {
+    let vehicle = VEHICLES.with(|vehicles| vehicles.borrow().load(&vehicle_id))?;
+    // Firmware contains vehicle secret key so even auditors can't download it.
+    let caller = ic_cdk::api::caller();
+    let parties = [Some(vehicle.customer), vehicle.provider];
+    if !parties.iter().flatten().any(|party| acts_for(caller, *party, Permission::ManageVehicles)) {
+        return Err(Error::InvalidSigner);
+    }
+    let total_size = FIRMWARE_SIZES.with(|sizes| sizes.borrow().get(&vehicle_id)).ok_or(Error::NotFound)?;
+    if offset > total_size {
+        return Err(Error::InvalidData);
+    }
+    let end = offset.saturating_add(length.min(MAX_FIRMWARE_CHUNK_SIZE)).min(total_size);
+    Ok(FirmwareChunk {
+        data: read_firmware(vehicle_id, offset, end),
+        total_size,
+        sha256: vehicle.firmware_sha256,
+    })
+}
+
+// Replaces firmware of the vehicle, the given pieces are split into chunks of the download size limit.
+fn store_firmware(vehicle: Principal, firmware: impl Iterator<Item = Vec<u8>>) {
+    let chunk_size = MAX_FIRMWARE_CHUNK_SIZE as usize;
+    FIRMWARE_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        let keys: Vec<(Principal, u32)> =
+            chunks.range((vehicle, 0)..=(vehicle, u32::MAX)).map(|(key, _)| key).collect();
+        for key in keys {
+            chunks.remove(&key);
+        }
+        let mut size = 0;
+        let mut index = 0;
+        let mut chunk = Vec::new();
+        for piece in firmware {
+            size += piece.len() as u64;
+            let mut piece = piece.as_slice();
+            while !piece.is_empty() {
+                let len = (chunk_size - chunk.len()).min(piece.len());
+                chunk.extend_from_slice(&piece[..len]);
+                piece = &piece[len..];
+                if chunk.len() == chunk_size {
+                    chunks.insert((vehicle, index), std::mem::take(&mut chunk));
+                    index += 1;
+                }
+            }
+        }
+        if !chunk.is_empty() {
+            chunks.insert((vehicle, index), chunk);
+        }
+        FIRMWARE_SIZES.with(|sizes| sizes.borrow_mut().insert(vehicle, size));
+    });
+}
+
+// Reads only the chunks which overlap with the range.
+fn read_firmware(vehicle: Principal, offset: u64, end: u64) -> Vec<u8> {
+    let mut data = Vec::new();
+    if offset >= end {
+        return data;
+    }
+    let first = (offset / MAX_FIRMWARE_CHUNK_SIZE) as u32;
+    let last = ((end - 1) / MAX_FIRMWARE_CHUNK_SIZE) as u32;
+    FIRMWARE_CHUNKS.with(|chunks| {
+        for ((_, index), chunk) in chunks.borrow().range((vehicle, first)..=(vehicle, last)) {
+            let start = index as u64 * MAX_FIRMWARE_CHUNK_SIZE;
+            let from = offset.saturating_sub(start) as usize;
+            let to = (end - start).min(chunk.len() as u64) as usize;
+            data.extend_from_slice(&chunk[from..to]);
+        }
+    });
+    data
+}
+
+// Only vehicle provider and customer can see the vehicle.
+fn get_visible_vehicle(vehicle_id: Principal) -> VTSResult<Vehicle> {
+    let vehicle = VEHICLES.with(|vehicles| vehicles.borrow().load(&vehicle_id))?;
+    check_visibility(&[Some(vehicle.customer), vehicle.provider])?;
+    Ok(vehicle)
+}
+
+// Only agreement provider and customer can see the agreement.
+fn get_visible_agreement(agreement_id: u128) -> VTSResult<Agreement> {
+    let agreement = AGREEMENTS.with(|agreements| agreements.borrow().load(&agreement_id))?;
+    check_visibility(&[Some(agreement.vh_provider), Some(agreement.vh_customer)])?;
+    Ok(agreement)
+}
+
+// Auditors can see everything and organization members can see what organization can see.
+fn check_visibility(parties: &[Option<Principal>]) -> VTSResult<()> {
+    let caller = ic_cdk::api::caller();
+    if parties.iter().flatten().any(|party| acts_for(caller, *party, Permission::View))
+        || has_role(caller, &[Role::Auditor])
+    {
+        return Ok(());
+    }
+    Err(Error::InvalidSigner)
+}
+
+#[ic_cdk::update(guard = is_user)]
+fn create_agreement(
+    name: String,
+    vh_customer: Principal,
+    conditions: AgreementConditions,
+    organization: Option<Principal>,
+) -> VTSResult<u128> {
+    let caller = acting_principal(organization, Permission::Sign, &[Role::Provider])?;
+    ic_cdk::println!("requested agreement creation by {}", caller);
+
+    // Veryfy that user passed ok prices and fees.
+    conditions.validate()?;
+    if !has_role(vh_customer, &[Role::Customer]) {
+        return Err(Error::InvalidData);
+    }
+
+    let next_agreement_id = update_state(|state| {
+        state.agreement_id_counter += 1;
+        state.agreement_id_counter
+    });
+
+    AGREEMENTS.with(|agreements| {
+        let agreement = Agreement {
+            id: next_agreement_id,
+            name,
+            vh_provider: caller,
+            vh_customer,
+            state: AgreementState::Unsigned,
+            conditions,
+            vehicles: HashMap::new(),
+            amendment: None,
+            ended_at: None,
+        };
+        let mut agreements = agreements.borrow_mut();
+        agreements.store(next_agreement_id, &agreement);
+    });
+
+    USERS.with(|users| -> VTSResult<()> {
+        let mut vh_provider_user = users.borrow_mut().load(&caller)?;
+        let mut vh_customer_user = users.borrow_mut().load(&vh_customer)?;
+        vh_provider_user.agreements.insert(next_agreement_id, ());
+        vh_customer_user.agreements.insert(next_agreement_id, ());
+        users.borrow_mut().store(caller, &vh_provider_user);
+        users.borrow_mut().store(vh_customer, &vh_customer_user);
+        Ok(())
+    })?;
+
+    Ok(next_agreement_id)
+}
+
+#[ic_cdk::update(guard = is_user)]
+fn sign_agreement(agreement_id: u128, organization: Option<Principal>) -> VTSResult<()> {
+    let caller = acting_principal(organization, Permission::Sign, &[Role::Customer])?;
+    ic_cdk::println!("requested agreement signing by {}", caller);
+
+    AGREEMENTS.with(|agreements| {
+        let mut agreements = agreements.borrow_mut();
+
+        if let Some(mut agreement) = agreements.try_load(&agreement_id)? {
+            if agreement.vh_customer != caller {
+                return Err(Error::InvalidSigner);
+            }
+            if agreement.conditions.ends_at.is_some_and(|ends_at| ends_at <= ic_cdk::api::time()) {
+                return Err(Error::InvalidData);
+            }
+
+            match agreement.state {
+                AgreementState::Signed => Err(Error::AlreadyExists),
+                AgreementState::Unsigned => {
+                    agreement.state = AgreementState::Signed;
+                    agreements.store(agreement_id, &agreement);
+                    // Vehicles linked before signing start working now.
+                    turn_on_off_agreement_vehicles(&agreement, true);
+                    Ok(())
+                }
+                _ => Err(Error::InvalidData),
+            }
+        } else {
+            Err(Error::NotFound)
+        }
+    })
+}
+
+#[ic_cdk::update(guard = is_user)]
+fn reject_agreement(agreement_id: u128, organization: Option<Principal>) -> VTSResult<()> {
+    let caller = acting_principal(organization, Permission::Sign, &[Role::Customer])?;
+    let mut agreement = get_customer_agreement(agreement_id, caller)?;
+    if agreement.state != AgreementState::Unsigned {
+        return Err(Error::InvalidData);
+    }
+    agreement.state = AgreementState::Rejected;
+    AGREEMENTS.with(|agreements| agreements.borrow_mut().store(agreement_id, &agreement));
+    Ok(())
+}
+
+#[ic_cdk::update(guard = is_user)]
+fn cancel_agreement(agreement_id: u128, organization: Option<Principal>) -> VTSResult<()> {
+    let caller = acting_principal(organization, Permission::Sign, &[Role::Provider])?;
+    let mut agreement = get_provider_agreement(agreement_id, caller)?;
+    if agreement.state != AgreementState::Unsigned {
+        return Err(Error::InvalidData);
+    }
+    agreement.state = AgreementState::Cancelled;
+    AGREEMENTS.with(|agreements| agreements.borrow_mut().store(agreement_id, &agreement));
+    Ok(())
+}
+
+// Signed agreement is changed only after customer signs the amendment.
+#[ic_cdk::update(guard = is_user)]
+fn propose_amendment(
+    agreement_id: u128,
+    conditions: AgreementConditions,
+    organization: Option<Principal>,
+) -> VTSResult<()> {
+    let caller = acting_principal(organization, Permission::Sign, &[Role::Provider])?;
+    conditions.validate()?;
+    let mut agreement = get_provider_agreement(agreement_id, caller)?;
+    if !matches!(agreement.state, AgreementState::Signed | AgreementState::Suspended) {
+        return Err(Error::InvalidData);
+    }
+    // Periods of already issued invoices depend on billing cycle and amounts depend on token.
+    if conditions.billing_cycle != agreement.conditions.billing_cycle
+        || conditions.token != agreement.conditions.token
+    {
+        return Err(Error::InvalidData);
+    }
+    agreement.amendment = Some(conditions);
+    AGREEMENTS.with(|agreements| agreements.borrow_mut().store(agreement_id, &agreement));
+    Ok(())
+}
+
+#[ic_cdk::update(guard = is_user)]
+fn sign_amendment(agreement_id: u128, organization: Option<Principal>) -> VTSResult<()> {
+    let caller = acting_principal(organization, Permission::Sign, &[Role::Customer])?;
+    let mut agreement = get_customer_agreement(agreement_id, caller)?;
+    let amendment = agreement.amendment.take().ok_or(Error::NotFound)?;
+    // Amendment comes into effect from the next billing period, so the current one is not repriced.
+    let billing_cycle = &agreement.conditions.billing_cycle;
+    let offset = billing_cycle.offset()?;
+    let (_, next_period) = billing_cycle.period_of(to_local_date(ic_cdk::api::time(), offset)?)?;
+    let effective_from = to_timestamp(next_period, offset);
+    let conditions = std::mem::replace(&mut agreement.conditions, amendment);
+    SUPERSEDED_CONDITIONS.with(|superseded| {
+        let mut superseded = superseded.borrow_mut();
+        // Conditions in force stay so until the period ends even if another amendment is signed.
+        if !superseded.contains_key(&(agreement_id, effective_from)) {
+            superseded.store((agreement_id, effective_from), &conditions);
+        }
+    });
+    AGREEMENTS.with(|agreements| agreements.borrow_mut().store(agreement_id, &agreement));
+    Ok(())
+}
+
+#[ic_cdk::update(guard = is_user)]
+fn reject_amendment(agreement_id: u128, organization: Option<Principal>) -> VTSResult<()> {
+    let caller = acting_principal(organization, Permission::Sign, &[Role::Customer])?;
+    let mut agreement = get_customer_agreement(agreement_id, caller)?;
+    agreement.amendment.take().ok_or(Error::NotFound)?;
+    AGREEMENTS.with(|agreements| agreements.borrow_mut().store(agreement_id, &agreement));
+    Ok(())
+}
+
+#[ic_cdk::update(guard = is_user)]
+fn suspend_agreement(agreement_id: u128, organization: Option<Principal>) -> VTSResult<()> {
+    let caller = acting_principal(organization, Permission::Sign, &[Role::Provider])?;
+    let mut agreement = get_provider_agreement(agreement_id, caller)?;
+    if agreement.state != AgreementState::Signed {
+        return Err(Error::InvalidData);
+    }
+    agreement.state = AgreementState::Suspended;
+    turn_on_off_agreement_vehicles(&agreement, false);
+    AGREEMENTS.with(|agreements| agreements.borrow_mut().store(agreement_id, &agreement));
+    Ok(())
+}
+
+#[ic_cdk::update(guard = is_user)]
+fn resume_agreement(agreement_id: u128, organization: Option<Principal>) -> VTSResult<()> {
+    let caller = acting_principal(organization, Permission::Sign, &[Role::Provider])?;
+    let mut agreement = get_provider_agreement(agreement_id, caller)?;
+    if agreement.state != AgreementState::Suspended {
+        return Err(Error::InvalidData);
+    }
+    agreement.state = AgreementState::Signed;
+    turn_on_off_agreement_vehicles(&agreement, true);
+    AGREEMENTS.with(|agreements| agreements.borrow_mut().store(agreement_id, &agreement));
+    Ok(())
+}
+
+// Any party can terminate agreement which is in effect.
+#[ic_cdk::update(guard = is_user)]
+fn terminate_agreement(agreement_id: u128, organization: Option<Principal>) -> VTSResult<()> {
+    let caller = acting_principal(organization, Permission::Sign, &[Role::Provider, Role::Customer])?;
+    let mut agreement = AGREEMENTS.with(|agreements| agreements.borrow().load(&agreement_id))?;
+    if agreement.vh_provider != caller && agreement.vh_customer != caller {
+        return Err(Error::InvalidSigner);
+    }
+    if !matches!(agreement.state, AgreementState::Signed | AgreementState::Suspended) {
+        return Err(Error::InvalidData);
+    }
+    end_agreement(&mut agreement, AgreementState::Terminated, ic_cdk::api::time());
+    AGREEMENTS.with(|agreements| agreements.borrow_mut().store(agreement_id, &agreement));
+    Ok(())
+}
+
+fn get_provider_agreement(agreement_id: u128, vh_provider: Principal) -> VTSResult<Agreement> {
+    let agreement = AGREEMENTS.with(|agreements| agreements.borrow().load(&agreement_id))?;
+    if agreement.vh_provider != vh_provider {
+        return Err(Error::InvalidSigner);
+    }
+    Ok(agreement)
+}
+
+fn get_customer_agreement(agreement_id: u128, vh_customer: Principal) -> VTSResult<Agreement> {
+    let agreement = AGREEMENTS.with(|agreements| agreements.borrow().load(&agreement_id))?;
+    if agreement.vh_customer != vh_customer {
+        return Err(Error::InvalidSigner);
+    }
+    Ok(agreement)
+}
+
+// Vehicles stay linked to the ended agreement to bill usage before the end.
+fn end_agreement(agreement: &mut Agreement, state: AgreementState, ended_at: u64) {
+    agreement.state = state;
+    agreement.ended_at = Some(ended_at);
+    agreement.amendment = None;
+    turn_on_off_agreement_vehicles(agreement, false);
+}
+
+fn turn_on_off_agreement_vehicles(agreement: &Agreement, on_off: bool) {
+    VEHICLES.with(|vehicles| {
+        let mut vehicles = vehicles.borrow_mut();
+        for vehicle_id in agreement.vehicles.keys() {
+            // Vehicles with overdue invoices stay off until the invoices are paid.
+            if on_off && has_overdue_invoices(*vehicle_id, agreement.id) {
+                continue;
+            }
+            if let Ok(Some(mut vehicle)) = vehicles.try_load(vehicle_id) {
+                set_on_off(*vehicle_id, &mut vehicle, on_off, OnOffReason::Agreement(agreement.id));
+                vehicles.store(*vehicle_id, &vehicle);
+            }
+        }
+    });
+}
+
+// Changes vehicle state and records the change, vehicle is saved by the caller.
+fn set_on_off(vehicle_id: Principal, vehicle: &mut Vehicle, on_off: bool, reason: OnOffReason) {
+    if vehicle.on_off == on_off {
+        return;
+    }
+    vehicle.on_off = on_off;
+    VEHICLE_ON_OFF_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        let seq = match log.iter_upper_bound(&(vehicle_id, u64::MAX)).next() {
+            Some(((id, seq), _)) if id == vehicle_id => seq + 1,
+            _ => 0,
+        };
+        log.store(
+            (vehicle_id, seq),
+            &OnOffChange {
+                on_off,
+                reason,
+                changed_by: ic_cdk::api::caller(),
+                changed_at: ic_cdk::api::time(),
+            },
+        );
+    });
+}
+
+#[ic_cdk::query(guard = is_user)]
+fn get_vehicle_on_off_history(vehicle_id: Principal) -> VTSResult<Vec<OnOffChange>> {
+    get_visible_vehicle(vehicle_id)?;
+    VEHICLE_ON_OFF_LOG.with(|log| {
+        log.borrow()
+            .range((vehicle_id, 0)..=(vehicle_id, u64::MAX))
+            .map(|(_, change)| change.decode())
+            .collect()
+    })
+}
+
+// Agreements are expired by timer, so vehicles can work a bit longer than agreement end date.
+fn expire_agreements(now: u64) {
+    AGREEMENTS.with(|agreements| {
+        let mut agreements = agreements.borrow_mut();
+        let expired: Vec<u128> = agreements
+            .iter()
+            .filter(|(_, agreement)| {
+                agreement.decode().is_ok_and(|agreement| {
+                    matches!(agreement.state, AgreementState::Signed | AgreementState::Suspended)
+                        && agreement.conditions.ends_at.is_some_and(|ends_at| ends_at <= now)
+                })
+            })
+            .map(|(agreement_id, _)| agreement_id)
+            .collect();
+        for agreement_id in expired {
+            let Ok(mut agreement) = agreements.load(&agreement_id) else {
+                continue;
+            };
+            let ends_at = agreement.conditions.ends_at.unwrap_or(now);
+            end_agreement(&mut agreement, AgreementState::Expired, ends_at);
+            ic_cdk::println!("agreement {} is expired", agreement_id);
+            agreements.store(agreement_id, &agreement);
+        }
+    });
+}
+
+#[ic_cdk::update(guard = is_user)]
+fn link_vehicle(
+    agreement_id: u128,
+    vehicle_identity: Principal,
+    organization: Option<Principal>,
+) -> VTSResult<()> {
+    let caller =
+        acting_principal(organization, Permission::ManageVehicles, &[Role::Customer, Role::FleetOperator])?;
+    ic_cdk::println!("requested vehicle linking by {}", caller);
+
+    let agreement = get_linkable_agreement(agreement_id, vehicle_identity, caller)?;
+    let mut vehicle = VEHICLES.with(|vehicles| vehicles.borrow().load(&vehicle_identity))?;
+    if caller != vehicle.customer {
+        return Err(Error::InvalidSigner);
+    }
+    if vehicle.agreement.is_some() {
+        return Err(Error::AlreadyExists);
+    }
+    link(vehicle_identity, &mut vehicle, agreement, ic_cdk::api::time())?;
+    VEHICLES.with(|vehicles| vehicles.borrow_mut().store(vehicle_identity, &vehicle));
+    Ok(())
+}
+
+// Vehicle can be returned by its customer or provider.
+#[ic_cdk::update(guard = is_user)]
+fn unlink_vehicle(vehicle_identity: Principal, organization: Option<Principal>) -> VTSResult<()> {
+    let caller = acting_principal(
+        organization,
+        Permission::ManageVehicles,
+        &[Role::Provider, Role::Customer, Role::FleetOperator],
+    )?;
+    let mut vehicle = VEHICLES.with(|vehicles| vehicles.borrow().load(&vehicle_identity))?;
+    if caller != vehicle.customer && Some(caller) != vehicle.provider {
+        return Err(Error::InvalidSigner);
+    }
+    unlink(vehicle_identity, &mut vehicle, ic_cdk::api::time())?;
+    VEHICLES.with(|vehicles| vehicles.borrow_mut().store(vehicle_identity, &vehicle));
+    Ok(())
+}
+
+// Moves vehicle to another agreement of the same customer.
+#[ic_cdk::update(guard = is_user)]
+fn transfer_vehicle(
+    vehicle_identity: Principal,
+    agreement_id: u128,
+    organization: Option<Principal>,
+) -> VTSResult<()> {
+    let caller =
+        acting_principal(organization, Permission::ManageVehicles, &[Role::Customer, Role::FleetOperator])?;
+    let mut vehicle = VEHICLES.with(|vehicles| vehicles.borrow().load(&vehicle_identity))?;
+    if caller != vehicle.customer {
+        return Err(Error::InvalidSigner);
+    }
+    // New agreement is checked first to not leave vehicle unlinked if it can't be linked.
+    let agreement = get_linkable_agreement(agreement_id, vehicle_identity, caller)?;
+    let now = ic_cdk::api::time();
+    unlink(vehicle_identity, &mut vehicle, now)?;
+    link(vehicle_identity, &mut vehicle, agreement, now)?;
+    VEHICLES.with(|vehicles| vehicles.borrow_mut().store(vehicle_identity, &vehicle));
+    Ok(())
+}
+
+#[ic_cdk::query(guard = is_user)]
+fn get_vehicle_links(vehicle_identity: Principal) -> VTSResult<Vec<VehicleLink>> {
+    Ok(get_visible_vehicle(vehicle_identity)?.links)
+}
+
+fn get_linkable_agreement(
+    agreement_id: u128,
+    vehicle_identity: Principal,
+    caller: Principal,
+) -> VTSResult<Agreement> {
+    let agreement = AGREEMENTS.with(|agreements| agreements.borrow().load(&agreement_id))?;
+    if agreement.vehicles.contains_key(&vehicle_identity) {
+        return Err(Error::AlreadyExists);
+    }
+    if agreement.state.is_final() {
+        return Err(Error::InvalidData);
+    }
+    if caller != agreement.vh_customer {
+        ic_cdk::println!("vehicle provider tried to link vehicle to its own agreement");
+        return Err(Error::InvalidSigner);
+    }
+    Ok(agreement)
+}
+
+// Vehicle is saved by the caller.
+fn link(
+    vehicle_identity: Principal,
+    vehicle: &mut Vehicle,
+    mut agreement: Agreement,
+    now: u64,
+) -> VTSResult<()> {
+    let vh_provider = agreement.vh_provider;
+    vehicle.agreement = Some(agreement.id);
+    vehicle.provider = Some(vh_provider);
+    vehicle.links.push(VehicleLink {
+        agreement: agreement.id,
+        linked_at: now,
+        unlinked_at: None,
+    });
+    // Re-linking doesn't turn on vehicle which is off for overdue invoices under the agreement.
+    let on_off =
+        agreement.state == AgreementState::Signed && !has_overdue_invoices(vehicle_identity, agreement.id);
+    set_on_off(vehicle_identity, vehicle, on_off, OnOffReason::Agreement(agreement.id));
+    agreement.vehicles.insert(vehicle_identity, ());
+    AGREEMENTS.with(|agreements| agreements.borrow_mut().store(agreement.id, &agreement));
+
+    USERS.with(|users| -> VTSResult<()> {
+        let mut provider = users.borrow().load(&vh_provider)?;
+        provider.vehicles.insert(vehicle_identity, ());
+        users.borrow_mut().store(vh_provider, &provider);
+        Ok(())
+    })
+}
+
+// Vehicle is saved by the caller.
+fn unlink(vehicle_identity: Principal, vehicle: &mut Vehicle, now: u64) -> VTSResult<()> {
+    let agreement_id = vehicle.agreement.take().ok_or(Error::NotFound)?;
+    let vh_provider = vehicle.provider.take();
+    if let Some(link) = vehicle.links.last_mut() {
+        link.unlinked_at = Some(now);
+    }
+    // Nobody pays for vehicle without agreement.
+    set_on_off(vehicle_identity, vehicle, false, OnOffReason::Agreement(agreement_id));
+    AGREEMENTS.with(|agreements| {
+        let mut agreements = agreements.borrow_mut();
+        if let Some(mut agreement) = agreements.try_load(&agreement_id)? {
+            agreement.vehicles.remove(&vehicle_identity);
+            agreements.store(agreement_id, &agreement);
+        }
+        Ok::<_, Error>(())
+    })?;
+    // Provider can be the customer as well, so customer vehicles are not changed.
+    if let Some(vh_provider) = vh_provider.filter(|vh_provider| *vh_provider != vehicle.customer) {
+        USERS.with(|users| {
+            if let Some(mut provider) = users.borrow().try_load(&vh_provider)? {
+                provider.vehicles.remove(&vehicle_identity);
+                users.borrow_mut().store(vh_provider, &provider);
+            }
+            Ok::<_, Error>(())
+        })?;
+    }
+    Ok(())
+}
+
+// Organization members pay from the organization account.
+#[ic_cdk::update(guard = is_user)]
+async fn pay_for_invoice(id: u128, amount: Option<u128>, organization: Option<Principal>) -> VTSResult<()> {
+    let payer = acting_principal(organization, Permission::Pay, &[Role::Customer])?;
+    let invoice = INVOICES.with(|invoices| invoices.borrow().load(&id))?;
+    // Only customer of the agreement can be charged for the invoice.
+    let agreement = get_customer_agreement(invoice.agreement, payer)?;
+    // Lock is released when the call finishes, even if the callback traps.
+    let _lock = PaymentLock::acquire(id)?;
+
+    if UNRECONCILED_PAYMENTS.with(|payments| payments.borrow().contains_key(&id)) {
+        return Err(Error::PaymentNeedsReconciliation);
+    }
+    // Payment of the previous call which trapped is completed first. It can be the installment
+    // which the caller retries, so another transfer is not started after it.
+    if complete_pending_payment(id).await? {
+        return Ok(());
+    }
+    let mut invoice = INVOICES.with(|invoices| invoices.borrow().load(&id))?;
+    match invoice.status {
+        InvoiceStatus::Paid => return Ok(()),
+        InvoiceStatus::Disputed => return Err(Error::InvalidData),
+        _ => (),
+    }
+    // Credit of the agreement is used before the customer is charged.
+    pay_with_credit(&mut invoice)?;
+    if invoice.status == InvoiceStatus::Paid {
+        return Ok(());
+    }
+    let outstanding = invoice_outstanding_balance(&invoice)?;
+    // Outstanding balance is paid when amount is not specified.
+    let amount = amount.unwrap_or(outstanding);
+    if amount == 0 || amount > outstanding {
+        return Err(Error::InvalidData);
+    }
+
+    // The same creation time and memo make retries of the failed payment idempotent on the ledger.
+    let now = ic_cdk::api::time();
+    let created_at_time = match invoice.payment_created_at {
+        Some(created_at) if now.saturating_sub(created_at) < LEDGER_TX_WINDOW_SECS * 1_000_000_000 => {
+            created_at
+        }
+        _ => now,
+    };
+    invoice.payment_created_at = Some(created_at_time);
+    INVOICES.with(|invoices| invoices.borrow_mut().store(id, &invoice));
+
+    let pending = PendingPayment {
+        payer,
+        // Vehicle can be linked to another agreement already.
+        provider: agreement.vh_provider,
+        amount,
+        // Every installment has its own key, so equal installments are not duplicates of each other.
+        memo: payment_memo(id, invoice_payments(id)?.len() as u32),
+        created_at_time,
+    };
+    // Payment is kept until the ledger responds, so it can be recovered if the callback traps.
+    PENDING_PAYMENTS.with(|payments| payments.borrow_mut().store(id, &pending));
+    submit_invoice_payment(id, pending).await
+}
+
+// Dedup key of the payment: invoice id and sequence number of the payment.
+fn payment_memo(invoice_id: u128, seq: u32) -> Vec<u8> {
+    let mut memo = invoice_id.to_be_bytes().to_vec();
+    memo.extend_from_slice(&seq.to_be_bytes());
+    memo
+}
+
+// Transfers pending payment from the customer to the provider.
+// Resubmitted payment is recognized by the ledger as a duplicate and it is not charged twice.
+async fn submit_invoice_payment(id: u128, pending: PendingPayment) -> VTSResult<()> {
+    let invoice = INVOICES.with(|invoices| invoices.borrow().load(&id))?;
+    let transfer_from_args: TransferFromArgs = TransferFromArgs {
+        amount: pending.amount.into(),
+        from: Account::from(pending.payer),
+        to: Account::from(pending.provider),
+        memo: Some(Memo::from(pending.memo.clone())),
+        spender_subaccount: None,
+        fee: None,
+        created_at_time: Some(pending.created_at_time),
+    };
+    // Payment stays pending when ledger is not reachable, as the transfer could be executed.
+    let result: Result<BlockIndex, TransferFromError> =
+        call_ledger(invoice.token.ledger, "icrc2_transfer_from", (transfer_from_args,)).await?;
+    PENDING_PAYMENTS.with(|payments| payments.borrow_mut().remove(&id));
+    let block_index = match result {
+        Ok(block_index) => block_index,
+        // Previous attempt succeeded but invoice wasn't updated.
+        Err(TransferFromError::Duplicate { duplicate_of }) => duplicate_of,
+        Err(e) => {
+            ic_cdk::println!("failed to transfer tokens for invoice {}: {:?}", id, e);
+            return Err(Error::Internal);
+        }
+    };
+    let block_index = u128::try_from(block_index.0).map_err(|_| Error::Internal)?;
+    if invoice_payments(id)?.iter().any(|payment| payment.block_index == Some(block_index)) {
+        return Ok(());
+    }
+
+    // Invoice is read again as it could be changed during the call.
+    let mut invoice = INVOICES.with(|invoices| invoices.borrow().load(&id))?;
+    // Next payment is a new transfer, not a retry of this one.
+    invoice.payment_created_at = None;
+    record_invoice_payment(
+        &mut invoice,
+        InvoicePayment {
+            amount: pending.amount,
+            block_index: Some(block_index),
+            paid_by: pending.payer,
+            paid_at: ic_cdk::api::time(),
+        },
+    )
+}
+
+// Completes payments which calls trapped after the transfer to the ledger.
+async fn recover_pending_payments() {
+    let pending: Vec<u128> =
+        PENDING_PAYMENTS.with(|payments| payments.borrow().iter().map(|(id, _)| id).collect());
+    for invoice_id in pending {
+        if let Err(e) = recover_pending_payment(invoice_id).await {
+            ic_cdk::println!("failed to recover payment of invoice {}: {:?}", invoice_id, e);
+        }
+    }
+}
+
+async fn recover_pending_payment(invoice_id: u128) -> VTSResult<()> {
+    // Payment which is in progress is not pending for recovery.
+    let _lock = PaymentLock::acquire(invoice_id)?;
+    complete_pending_payment(invoice_id).await.map(|_| ())
+}
+
+// Returns false if there is no pending payment of the invoice.
+async fn complete_pending_payment(invoice_id: u128) -> VTSResult<bool> {
+    let Some(pending) = PENDING_PAYMENTS.with(|payments| payments.borrow().try_load(&invoice_id))? else {
+        return Ok(false);
+    };
+    // Ledger doesn't deduplicate transfers older than its window, so resubmitted payment could be charged twice.
+    let now = ic_cdk::api::time();
+    if now.saturating_sub(pending.created_at_time) >= LEDGER_TX_WINDOW_SECS * 1_000_000_000 {
+        ic_cdk::println!("payment of invoice {} needs reconciliation", invoice_id);
+        PENDING_PAYMENTS.with(|payments| payments.borrow_mut().remove(&invoice_id));
+        UNRECONCILED_PAYMENTS.with(|payments| payments.borrow_mut().store(invoice_id, &pending));
+        return Err(Error::PaymentNeedsReconciliation);
+    }
+    submit_invoice_payment(invoice_id, pending).await?;
+    Ok(true)
+}
+
+#[ic_cdk::query(guard = is_admin)]
+fn get_unreconciled_payments() -> VTSResult<Vec<(u128, PendingPayment)>> {
+    UNRECONCILED_PAYMENTS.with(|payments| {
+        payments.borrow().iter().map(|(invoice_id, payment)| Ok((invoice_id, payment.decode()?))).collect()
+    })
+}
+
+// Admin looks up the memo and creation time of the payment in the ledger,
+// block index is empty if the transfer wasn't executed.
+#[ic_cdk::update(guard = is_admin)]
+fn reconcile_payment(invoice_id: u128, block_index: Option<u128>) -> VTSResult<()> {
+    let _lock = PaymentLock::acquire(invoice_id)?;
+    let pending = UNRECONCILED_PAYMENTS.with(|payments| payments.borrow().load(&invoice_id))?;
+    UNRECONCILED_PAYMENTS.with(|payments| payments.borrow_mut().remove(&invoice_id));
+    let Some(block_index) = block_index else {
+        return Ok(());
+    };
+    if invoice_payments(invoice_id)?.iter().any(|payment| payment.block_index == Some(block_index)) {
+        return Ok(());
+    }
+    let mut invoice = INVOICES.with(|invoices| invoices.borrow().load(&invoice_id))?;
+    // Invoice could be paid otherwise in the meantime, overpayment becomes agreement credit.
+    let excess = pending.amount.saturating_sub(invoice_outstanding_balance(&invoice)?);
+    if excess > 0 {
+        add_agreement_credit(invoice.agreement, excess);
+    }
+    invoice.payment_created_at = None;
+    record_invoice_payment(
+        &mut invoice,
+        InvoicePayment {
+            amount: pending.amount,
+            block_index: Some(block_index),
+            paid_by: pending.payer,
+            paid_at: ic_cdk::api::time(),
+        },
+    )
+}
+
+// Moves creation time of pending payments and deposit forwards out of the ledger deduplication window.
+#[cfg(feature = "expire_pending_payments")]
+#[ic_cdk::update(guard = is_admin)]
+fn expire_pending_payments() {
+    PENDING_PAYMENTS.with(|payments| {
+        let mut payments = payments.borrow_mut();
+        let pending: Vec<(u128, PendingPayment)> = payments
+            .iter()
+            .filter_map(|(invoice_id, payment)| Some((invoice_id, payment.decode().ok()?)))
+            .collect();
+        for (invoice_id, mut payment) in pending {
+            payment.created_at_time =
+                payment.created_at_time.saturating_sub(LEDGER_TX_WINDOW_SECS * 1_000_000_000);
+            payments.store(invoice_id, &payment);
+        }
+    });
+    PENDING_FORWARDS.with(|forwards| {
+        let mut forwards = forwards.borrow_mut();
+        let pending: Vec<(u128, PendingForward)> = forwards
+            .iter()
+            .filter_map(|(invoice_id, forward)| Some((invoice_id, forward.decode().ok()?)))
+            .collect();
+        for (invoice_id, mut forward) in pending {
+            forward.created_at_time =
+                forward.created_at_time.saturating_sub(LEDGER_TX_WINDOW_SECS * 1_000_000_000);
+            forwards.store(invoice_id, &forward);
+        }
+    });
+}
+
+// Prevents concurrent payments of the same invoice.
+struct PaymentLock(u128);
+
+impl PaymentLock {
+    fn acquire(invoice_id: u128) -> VTSResult<Self> {
+        PAYMENT_LOCKS.with(|locks| {
+            if !locks.borrow_mut().insert(invoice_id) {
+                return Err(Error::PaymentInProgress);
+            }
+            Ok(PaymentLock(invoice_id))
+        })
+    }
+}
+
+impl Drop for PaymentLock {
+    fn drop(&mut self) {
+        PAYMENT_LOCKS.with(|locks| locks.borrow_mut().remove(&self.0));
+    }
+}
+
+#[ic_cdk::query(guard = is_user)]
+fn get_invoice_payments(invoice_id: u128) -> VTSResult<Vec<InvoicePayment>> {
+    get_visible_invoice(invoice_id)?;
+    invoice_payments(invoice_id)
+}
+
+fn invoice_payments(invoice_id: u128) -> VTSResult<Vec<InvoicePayment>> {
+    INVOICE_PAYMENTS.with(|payments| {
+        payments
+            .borrow()
+            .range((invoice_id, 0)..=(invoice_id, u32::MAX))
+            .map(|(_, payment)| payment.decode())
+            .collect()
+    })
+}
+
+// Stores the payment and updates invoice status according to the outstanding balance.
+fn record_invoice_payment(invoice: &mut Invoice, payment: InvoicePayment) -> VTSResult<()> {
+    INVOICE_PAYMENTS.with(|payments| {
+        let mut payments = payments.borrow_mut();
+        let seq = match payments.iter_upper_bound(&(invoice.id, u32::MAX)).next() {
+            Some(((id, seq), _)) if id == invoice.id => seq + 1,
+            _ => 0,
+        };
+        payments.store((invoice.id, seq), &payment);
+    });
+    // Status of disputed invoice is updated when the dispute is settled.
+    if matches!(invoice.status, InvoiceStatus::Paid | InvoiceStatus::Disputed) {
+        INVOICES.with(|invoices| invoices.borrow_mut().store(invoice.id, invoice));
+        return Ok(());
+    }
+    if invoice_outstanding_balance(invoice)? == 0 {
+        mark_invoice_paid(invoice);
+    } else {
+        invoice.status = InvoiceStatus::PartiallyPaid;
+        INVOICES.with(|invoices| invoices.borrow_mut().store(invoice.id, invoice));
+    }
+    Ok(())
+}
+
+fn mark_invoice_paid(invoice: &mut Invoice) {
+    invoice.status = InvoiceStatus::Paid;
+    INVOICES.with(|invoices| invoices.borrow_mut().store(invoice.id, invoice));
+    UNPAID_INVOICES.with(|invoices| invoices.borrow_mut().remove(&invoice.id));
+    PAID_INVOICES.with(|invoices| invoices.borrow_mut().insert(invoice.id, ()));
+    if OVERDUE_INVOICES.with(|invoices| invoices.borrow_mut().remove(&invoice.id)).is_some() {
+        unblock_vehicle(invoice, OnOffReason::InvoicePaid(invoice.id));
+    }
+}
+
+// Turns off vehicles of invoices which are not paid within grace period after due date.
+#[ic_cdk::update(guard = is_canister)]
+fn check_overdue_invoices() -> VTSResult<()> {
+    let now = ic_cdk::api::time();
+    let unpaid: Vec<u128> =
+        UNPAID_INVOICES.with(|invoices| invoices.borrow().iter().map(|(id, _)| id).collect());
+    for invoice_id in unpaid {
+        if let Err(e) = check_overdue_invoice(invoice_id, now) {
+            ic_cdk::println!("failed to check if invoice {} is overdue: {:?}", invoice_id, e);
+        }
+    }
+    Ok(())
+}
+
+fn check_overdue_invoice(invoice_id: u128, now: u64) -> VTSResult<()> {
+    let invoice = INVOICES.with(|invoices| invoices.borrow().load(&invoice_id))?;
+    // Disputed invoice is not enforced until the dispute is settled.
+    if invoice.status == InvoiceStatus::Disputed {
+        return Ok(());
+    }
+    let agreement = AGREEMENTS.with(|agreements| agreements.borrow().load(&invoice.agreement))?;
+    charge_late_fees(&invoice, &agreement.conditions.late_fees, now)?;
+    if OVERDUE_INVOICES.with(|invoices| invoices.borrow().contains_key(&invoice_id)) {
+        return Ok(());
+    }
+    let grace_period = agreement.conditions.grace_period_days as u64 * DAY_NANOS;
+    if invoice.due_at.saturating_add(grace_period) > now {
+        return Ok(());
+    }
+    OVERDUE_INVOICES.with(|invoices| invoices.borrow_mut().insert(invoice_id, ()));
+    ic_cdk::println!("invoice {} is overdue", invoice_id);
+    VEHICLES.with(|vehicles| {
+        let mut vehicle = vehicles.borrow().load(&invoice.vehicle)?;
+        // Vehicle linked to another agreement is not blocked for debts under the previous one.
+        if vehicle.agreement != Some(invoice.agreement) {
+            return Ok(());
+        }
+        set_on_off(invoice.vehicle, &mut vehicle, false, OnOffReason::InvoiceOverdue(invoice_id));
+        vehicles.borrow_mut().store(invoice.vehicle, &vehicle);
+        Ok(())
+    })
+}
+
+// Turns vehicle back on when its last overdue invoice is paid.
+fn unblock_vehicle(invoice: &Invoice, reason: OnOffReason) {
+    if has_overdue_invoices(invoice.vehicle, invoice.agreement) {
+        return;
+    }
+    VEHICLES.with(|vehicles| {
+        let Ok(mut vehicle) = vehicles.borrow().load(&invoice.vehicle) else {
+            return;
+        };
+        // Vehicle can work only while agreement is in effect.
+        if vehicle.agreement != Some(invoice.agreement)
+            || !get_agreement_state(vehicle.agreement)
+                .is_ok_and(|state| state == Some(AgreementState::Signed))
+        {
+            return;
+        }
+        set_on_off(invoice.vehicle, &mut vehicle, true, reason);
+        vehicles.borrow_mut().store(invoice.vehicle, &vehicle);
+    });
+}
+
+// Vehicle is blocked only for debts under the agreement it is linked to.
+fn has_overdue_invoices(vehicle_id: Principal, agreement_id: u128) -> bool {
+    VEHICLE_INVOICES.with(|invoices| {
+        invoices.borrow().range((vehicle_id, 0)..=(vehicle_id, u128::MAX)).any(|((_, invoice_id), _)| {
+            OVERDUE_INVOICES.with(|overdue| overdue.borrow().contains_key(&invoice_id))
+                && INVOICES
+                    .with(|invoices| invoices.borrow().try_load(&invoice_id))
+                    .is_ok_and(|invoice| invoice.is_some_and(|invoice| invoice.agreement == agreement_id))
+        })
+    })
+}
+
+async fn call_ledger<A, R>(ledger: Principal, method: &str, args: A) -> VTSResult<R>
+where
+    A: candid::utils::ArgumentEncoder,
+    R: CandidType + for<'de> Deserialize<'de>,
+{
+    let (result,): (R,) = ic_cdk::call(ledger, method, args).await.map_err(|(code, message)| {
+        ic_cdk::println!("failed to call {} of ledger {}: {:?} {}", method, ledger, code, message);
+        Error::Internal
+    })?;
+    Ok(result)
+}
+
+// Account of the canister where tokens for the invoice can be sent with a plain transfer.
+#[ic_cdk::query(guard = is_user)]
+fn get_invoice_deposit_account(invoice_id: u128) -> VTSResult<Account> {
+    get_visible_invoice(invoice_id)?;
+    Ok(invoice_deposit_account(invoice_id))
+}
+
+fn invoice_deposit_account(invoice_id: u128) -> Account {
+    // Invoice IDs start from one, so deposits don't share the default account of the canister.
+    let mut subaccount: Subaccount = [0; 32];
+    subaccount[16..].copy_from_slice(&invoice_id.to_be_bytes());
+    Account {
+        owner: ic_cdk::api::id(),
+        subaccount: Some(subaccount),
+    }
+}
+
+#[ic_cdk::query(guard = is_user)]
+fn get_agreement_credit(agreement_id: u128) -> VTSResult<u128> {
+    get_visible_agreement(agreement_id)?;
+    Ok(CREDITS.with(|credits| credits.borrow().get(&agreement_id).unwrap_or_default()))
+}
+
+// Forwards tokens from the invoice deposit to provider and pays for the invoice if there is enough credit.
+#[ic_cdk::update(guard = is_user)]
+async fn check_invoice_payment(invoice_id: u128) -> VTSResult<InvoiceStatus> {
+    get_visible_invoice(invoice_id)?;
+    collect_invoice_deposit(invoice_id).await
+}
+
+async fn sweep_invoice_deposits() {
+    let mut invoice_ids: Vec<u128> =
+        UNPAID_INVOICES.with(|invoices| invoices.borrow().iter().map(|(id, _)| id).collect());
+    // Invoice could be paid otherwise while its deposit forward was pending.
+    let forwarded: Vec<u128> = PENDING_FORWARDS.with(|forwards| {
+        forwards
+            .borrow()
+            .iter()
+            .map(|(id, _)| id)
+            .filter(|id| !UNPAID_INVOICES.with(|invoices| invoices.borrow().contains_key(id)))
+            .collect()
+    });
+    invoice_ids.extend(forwarded);
+    for invoice_id in invoice_ids {
+        if let Err(e) = collect_invoice_deposit(invoice_id).await {
+            ic_cdk::println!("failed to collect deposit of invoice {}: {:?}", invoice_id, e);
+        }
+    }
+}
+
+async fn collect_invoice_deposit(invoice_id: u128) -> VTSResult<InvoiceStatus> {
+    // Deposit isn't collected while customer pays, so the invoice is not paid twice.
+    let _lock = PaymentLock::acquire(invoice_id)?;
+    let invoice = INVOICES.with(|invoices| invoices.borrow().load(&invoice_id))?;
+    let agreement = AGREEMENTS.with(|agreements| agreements.borrow().load(&invoice.agreement))?;
+    let ledger = invoice.token.ledger;
+    let deposit = invoice_deposit_account(invoice_id);
+
+    // Balance of the deposit is known only after the previous forward is completed.
+    if let Some(pending) = PENDING_FORWARDS.with(|forwards| forwards.borrow().try_load(&invoice_id))? {
+        // Ledger doesn't deduplicate transfers older than its window, so resubmitted forward could fail.
+        let now = ic_cdk::api::time();
+        if now.saturating_sub(pending.created_at_time) >= LEDGER_TX_WINDOW_SECS * 1_000_000_000 {
+            ic_cdk::println!("deposit forward of invoice {} needs reconciliation", invoice_id);
+            return Err(Error::PaymentNeedsReconciliation);
+        }
+        submit_deposit_forward(invoice_id, ledger, pending).await?;
+    }
+
+    let balance: Nat = call_ledger(ledger, "icrc1_balance_of", (deposit,)).await?;
+    let balance = u128::try_from(balance.0).map_err(|_| Error::Internal)?;
+    let fee: Nat = call_ledger(ledger, "icrc1_fee", ()).await?;
+    let fee = u128::try_from(fee.0).map_err(|_| Error::Internal)?;
+    // Ledger fee of forwarding is paid from the deposit.
+    if balance > fee {
+        let pending = PendingForward {
+            provider: agreement.vh_provider,
+            amount: balance - fee,
+            fee,
+            created_at_time: ic_cdk::api::time(),
+        };
+        // Forward is kept until the ledger responds, so it can be resubmitted if the callback traps.
+        PENDING_FORWARDS.with(|forwards| forwards.borrow_mut().store(invoice_id, &pending));
+        submit_deposit_forward(invoice_id, ledger, pending).await?;
+    }
+
+    // Invoice is read again as it could be changed during the calls.
+    let mut invoice = INVOICES.with(|invoices| invoices.borrow().load(&invoice_id))?;
+    pay_with_credit(&mut invoice)?;
+    Ok(invoice.status)
+}
+
+// Transfers pending forward from the invoice deposit to provider and credits the agreement.
+// Resubmitted forward is recognized by the ledger as a duplicate and it is not credited twice.
+async fn submit_deposit_forward(
+    invoice_id: u128,
+    ledger: Principal,
+    pending: PendingForward,
+) -> VTSResult<()> {
+    let transfer_args = TransferArg {
+        from_subaccount: invoice_deposit_account(invoice_id).subaccount,
+        to: Account::from(pending.provider),
+        fee: Some(pending.fee.into()),
+        created_at_time: Some(pending.created_at_time),
+        memo: Some(Memo::from(invoice_id.to_be_bytes().to_vec())),
+        amount: pending.amount.into(),
+    };
+    // Forward stays pending when ledger is not reachable, as the transfer could be executed.
+    let result: Result<BlockIndex, TransferError> =
+        call_ledger(ledger, "icrc1_transfer", (transfer_args,)).await?;
+    PENDING_FORWARDS.with(|forwards| forwards.borrow_mut().remove(&invoice_id));
+    match result {
+        // Previous attempt succeeded but the agreement wasn't credited.
+        Ok(_) | Err(TransferError::Duplicate { .. }) => (),
+        Err(e) => {
+            ic_cdk::println!("failed to forward deposit of invoice {}: {:?}", invoice_id, e);
+            return Err(Error::Internal);
+        }
+    }
+    // Deposit is credited even if it is not enough for the invoice or the invoice is paid already.
+    let invoice = INVOICES.with(|invoices| invoices.borrow().load(&invoice_id))?;
+    add_agreement_credit(invoice.agreement, pending.amount);
+    Ok(())
+}
+
+#[ic_cdk::query(guard = is_admin)]
+fn get_pending_forwards() -> VTSResult<Vec<(u128, PendingForward)>> {
+    PENDING_FORWARDS.with(|forwards| {
+        forwards.borrow().iter().map(|(invoice_id, forward)| Ok((invoice_id, forward.decode()?))).collect()
+    })
+}
+
+// Admin looks up the memo and creation time of the forward in the ledger,
+// the agreement is credited if the transfer was executed.
+#[ic_cdk::update(guard = is_admin)]
+fn reconcile_deposit_forward(invoice_id: u128, executed: bool) -> VTSResult<()> {
+    let _lock = PaymentLock::acquire(invoice_id)?;
+    let pending = PENDING_FORWARDS.with(|forwards| forwards.borrow().load(&invoice_id))?;
+    PENDING_FORWARDS.with(|forwards| forwards.borrow_mut().remove(&invoice_id));
+    let mut invoice = INVOICES.with(|invoices| invoices.borrow().load(&invoice_id))?;
+    if executed {
+        add_agreement_credit(invoice.agreement, pending.amount);
+    }
+    pay_with_credit(&mut invoice)
+}
+
+// Pays for the invoice with the agreement credit, partially if the credit is not enough.
+fn pay_with_credit(invoice: &mut Invoice) -> VTSResult<()> {
+    if matches!(invoice.status, InvoiceStatus::Paid | InvoiceStatus::Disputed) {
+        return Ok(());
+    }
+    let outstanding = invoice_outstanding_balance(invoice)?;
+    if outstanding == 0 {
+        mark_invoice_paid(invoice);
+        return Ok(());
+    }
+    let amount = CREDITS.with(|credits| {
+        let mut credits = credits.borrow_mut();
+        let credit = credits.get(&invoice.agreement).unwrap_or_default();
+        let amount = credit.min(outstanding);
+        credits.insert(invoice.agreement, credit - amount);
+        amount
+    });
+    if amount == 0 {
+        return Ok(());
+    }
+    record_invoice_payment(
+        invoice,
+        InvoicePayment {
+            amount,
+            block_index: None,
+            paid_by: ic_cdk::api::id(),
+            paid_at: ic_cdk::api::time(),
+        },
+    )
+}
+
+fn add_agreement_credit(agreement_id: u128, amount: u128) {
+    CREDITS.with(|credits| {
+        let mut credits = credits.borrow_mut();
+        let credit = credits.get(&agreement_id).unwrap_or_default();
+        credits.insert(agreement_id, credit + amount);
+    });
+}
+
+// Provider can reduce amount of the invoice with a discount or a credit note.
+#[ic_cdk::update(guard = is_user)]
+fn adjust_invoice(
+    invoice_id: u128,
+    kind: AdjustmentKind,
+    amount: u128,
+    reason: String,
+    organization: Option<Principal>,
+) -> VTSResult<()> {
+    let caller = acting_principal(organization, Permission::Sign, &[Role::Provider])?;
+    let mut invoice = INVOICES.with(|invoices| invoices.borrow().load(&invoice_id))?;
+    get_provider_agreement(invoice.agreement, caller)?;
+    // Late fees are charged only by canister.
+    if kind.is_charge() || amount == 0 {
+        return Err(Error::InvalidData);
+    }
+    if kind == AdjustmentKind::Discount && invoice.status == InvoiceStatus::Paid {
+        return Err(Error::InvalidData);
+    }
+    let limit = match invoice.status {
+        InvoiceStatus::Paid => invoice_adjusted_total(&invoice)?,
+        _ => invoice_outstanding_balance(&invoice)?,
+    };
+    if amount > limit {
+        return Err(Error::InvalidData);
+    }
+    add_invoice_adjustment(
+        invoice_id,
+        InvoiceAdjustment {
+            kind,
+            amount,
+            reason,
+            issued_by: caller,
+            issued_at: ic_cdk::api::time(),
+        },
+    );
+    // Customer already paid this amount to provider.
+    if invoice.status == InvoiceStatus::Paid {
+        add_agreement_credit(invoice.agreement, amount);
+        return Ok(());
+    }
+    pay_with_credit(&mut invoice)
+}
+
+#[ic_cdk::query(guard = is_user)]
+fn get_invoice_adjustments(invoice_id: u128) -> VTSResult<Vec<InvoiceAdjustment>> {
+    get_visible_invoice(invoice_id)?;
+    invoice_adjustments(invoice_id)
+}
+
+// Amount which is left to pay for the invoice.
+#[ic_cdk::query(guard = is_user)]
+fn get_invoice_outstanding_balance(invoice_id: u128) -> VTSResult<u128> {
+    invoice_outstanding_balance(&get_visible_invoice(invoice_id)?)
+}
+
+fn invoice_adjustments(invoice_id: u128) -> VTSResult<Vec<InvoiceAdjustment>> {
+    INVOICE_ADJUSTMENTS.with(|adjustments| {
+        adjustments
+            .borrow()
+            .range((invoice_id, 0)..=(invoice_id, u32::MAX))
+            .map(|(_, adjustment)| adjustment.decode())
+            .collect()
+    })
+}
+
+fn add_invoice_adjustment(invoice_id: u128, adjustment: InvoiceAdjustment) {
+    INVOICE_ADJUSTMENTS.with(|adjustments| {
+        let mut adjustments = adjustments.borrow_mut();
+        let seq = match adjustments.iter_upper_bound(&(invoice_id, u32::MAX)).next() {
+            Some(((id, seq), _)) if id == invoice_id => seq + 1,
+            _ => 0,
+        };
+        adjustments.store((invoice_id, seq), &adjustment);
+    });
+}
+
+// Total cost with all late fees minus all discounts and credit notes.
+fn invoice_adjusted_total(invoice: &Invoice) -> VTSResult<u128> {
+    let (charges, reductions) = invoice_adjustments(invoice.id)?
+        .iter()
+        .try_fold((0u128, 0u128), |(charges, reductions), adjustment| {
+            if adjustment.kind.is_charge() {
+                Some((charges.checked_add(adjustment.amount)?, reductions))
+            } else {
+                Some((charges, reductions.checked_add(adjustment.amount)?))
+            }
+        })
+        .ok_or(Error::InvalidData)?;
+    let total = invoice.total_cost.checked_add(charges).ok_or(Error::InvalidData)?;
+    Ok(total.saturating_sub(reductions))
+}
+
+fn invoice_outstanding_balance(invoice: &Invoice) -> VTSResult<u128> {
+    if invoice.status == InvoiceStatus::Paid {
+        return Ok(0);
+    }
+    let paid: u128 = invoice_payments(invoice.id)?.iter().map(|payment| payment.amount).sum();
+    Ok(invoice_adjusted_total(invoice)?.saturating_sub(paid))
+}
+
+// Charges late fees of the agreement for the time invoice is not paid after its due date.
+fn charge_late_fees(invoice: &Invoice, late_fees: &LateFees, now: u64) -> VTSResult<()> {
+    if invoice.status == InvoiceStatus::Paid || now <= invoice.due_at {
+        return Ok(());
+    }
+    let adjustments = invoice_adjustments(invoice.id)?;
+    // Interest is charged only on what is left to pay, before the fees of this check.
+    let outstanding = invoice_outstanding_balance(invoice)?;
+    let canister = ic_cdk::api::id();
+    if let Some(fixed) = &late_fees.fixed {
+        if !adjustments.iter().any(|adjustment| adjustment.kind == AdjustmentKind::LateFee) {
+            add_invoice_adjustment(
+                invoice.id,
+                InvoiceAdjustment {
+                    kind: AdjustmentKind::LateFee,
+                    amount: invoice.token.to_units(parse_amount(fixed)?)?,
+                    reason: String::from("Invoice is not paid by its due date"),
+                    issued_by: canister,
+                    issued_at: now,
+                },
+            );
+        }
+    }
+    if let Some(percent) = &late_fees.daily_interest_percent {
+        let days_overdue = ((now - invoice.due_at) / DAY_NANOS) as u32;
+        let charged_days: u32 = adjustments
+            .iter()
+            .map(|adjustment| match adjustment.kind {
+                AdjustmentKind::LateInterest { days } => days,
+                _ => 0,
+            })
+            .sum();
+        if days_overdue > charged_days {
+            let days = days_overdue - charged_days;
+            let outstanding = Decimal::from_u128(outstanding).ok_or(Error::Internal)?;
+            let amount = outstanding
+                .checked_mul(parse_amount(percent)?)
+                .and_then(|amount| amount.checked_mul(Decimal::from(days)))
+                .map(|amount| amount / Decimal::ONE_HUNDRED)
+                .and_then(|amount| amount.trunc().to_u128())
+                .ok_or(Error::InvalidData)?;
+            add_invoice_adjustment(
+                invoice.id,
+                InvoiceAdjustment {
+                    kind: AdjustmentKind::LateInterest { days },
+                    amount,
+                    reason: format!("Interest for {} days after due date", days),
+                    issued_by: canister,
+                    issued_at: now,
+                },
+            );
+        }
+    }
+    Ok(())
+}
+
+// Customer contests the invoice, enforcement and late fees are paused until the dispute is settled.
+#[ic_cdk::update(guard = is_user)]
+fn dispute_invoice(invoice_id: u128, reason: String, organization: Option<Principal>) -> VTSResult<()> {
+    let caller = acting_principal(organization, Permission::Sign, &[Role::Customer])?;
+    let mut invoice = INVOICES.with(|invoices| invoices.borrow().load(&invoice_id))?;
+    get_customer_agreement(invoice.agreement, caller)?;
+    if invoice.status == InvoiceStatus::Paid {
+        return Err(Error::InvalidData);
+    }
+    if DISPUTES.with(|disputes| disputes.borrow().contains_key(&invoice_id)) {
+        return Err(Error::AlreadyExists);
+    }
+    let dispute = Dispute {
+        invoice: invoice_id,
+        state: DisputeState::Open,
+        thread: vec![DisputeEntry {
+            action: DisputeAction::Open,
+            author: caller,
+            message: reason,
+            created_at: ic_cdk::api::time(),
+        }],
+    };
+    DISPUTES.with(|disputes| disputes.borrow_mut().store(invoice_id, &dispute));
+    pause_invoice_enforcement(&mut invoice);
+    Ok(())
+}
+
+// Provider accepts the dispute and issues a credit note for the invoice.
+#[ic_cdk::update(guard = is_user)]
+fn accept_dispute(
+    invoice_id: u128,
+    credit: u128,
+    message: String,
+    organization: Option<Principal>,
+) -> VTSResult<()> {
+    let caller = acting_principal(organization, Permission::Sign, &[Role::Provider])?;
+    let mut invoice = INVOICES.with(|invoices| invoices.borrow().load(&invoice_id))?;
+    get_provider_agreement(invoice.agreement, caller)?;
+    if credit == 0 || credit > invoice_outstanding_balance(&invoice)? {
+        return Err(Error::InvalidData);
+    }
+    settle_dispute(
+        &mut invoice,
+        DisputeState::Open,
+        DisputeState::Accepted,
+        DisputeEntry {
+            action: DisputeAction::Accept { credit },
+            author: caller,
+            message,
+            created_at: ic_cdk::api::time(),
+        },
+    )
+}
+
+// Provider rejects the dispute, customer can escalate it to arbitration.
+#[ic_cdk::update(guard = is_user)]
+fn reject_dispute(invoice_id: u128, message: String, organization: Option<Principal>) -> VTSResult<()> {
+    let caller = acting_principal(organization, Permission::Sign, &[Role::Provider])?;
+    let mut invoice = INVOICES.with(|invoices| invoices.borrow().load(&invoice_id))?;
+    get_provider_agreement(invoice.agreement, caller)?;
+    settle_dispute(
+        &mut invoice,
+        DisputeState::Open,
+        DisputeState::Rejected,
+        DisputeEntry {
+            action: DisputeAction::Reject,
+            author: caller,
+            message,
+            created_at: ic_cdk::api::time(),
+        },
+    )
+}
+
+#[ic_cdk::update(guard = is_user)]
+fn escalate_dispute(invoice_id: u128, message: String, organization: Option<Principal>) -> VTSResult<()> {
+    let caller = acting_principal(organization, Permission::Sign, &[Role::Customer])?;
+    let mut invoice = INVOICES.with(|invoices| invoices.borrow().load(&invoice_id))?;
+    get_customer_agreement(invoice.agreement, caller)?;
+    let mut dispute = DISPUTES.with(|disputes| disputes.borrow().load(&invoice_id))?;
+    if dispute.state != DisputeState::Rejected || invoice.status == InvoiceStatus::Paid {
+        return Err(Error::InvalidData);
+    }
+    dispute.state = DisputeState::Escalated;
+    dispute.thread.push(DisputeEntry {
+        action: DisputeAction::Escalate,
+        author: caller,
+        message,
+        created_at: ic_cdk::api::time(),
+    });
+    DISPUTES.with(|disputes| disputes.borrow_mut().store(invoice_id, &dispute));
+    pause_invoice_enforcement(&mut invoice);
+    Ok(())
+}
+
+// Arbitrator settles the escalated dispute, credit is zero when the dispute is resolved in favor of provider.
+#[ic_cdk::update(guard = is_admin)]
+fn resolve_dispute(invoice_id: u128, credit: u128, message: String) -> VTSResult<()> {
+    let mut invoice = INVOICES.with(|invoices| invoices.borrow().load(&invoice_id))?;
+    if credit > invoice_outstanding_balance(&invoice)? {
+        return Err(Error::InvalidData);
+    }
+    settle_dispute(
+        &mut invoice,
+        DisputeState::Escalated,
+        DisputeState::Resolved,
+        DisputeEntry {
+            action: DisputeAction::Resolve { credit },
+            author: ic_cdk::caller(),
+            message,
+            created_at: ic_cdk::api::time(),
+        },
+    )
+}
+
+#[ic_cdk::query(guard = is_user)]
+fn get_invoice_dispute(invoice_id: u128) -> VTSResult<Dispute> {
+    get_visible_invoice(invoice_id)?;
+    DISPUTES.with(|disputes| disputes.borrow().load(&invoice_id))
+}
+
+#[ic_cdk::query(guard = is_admin)]
+fn get_escalated_disputes() -> VTSResult<Vec<Dispute>> {
+    let disputes: Vec<Dispute> = DISPUTES.with(|disputes| {
+        disputes.borrow().iter().map(|(_, dispute)| dispute.decode()).collect::<VTSResult<_>>()
+    })?;
+    Ok(disputes.into_iter().filter(|dispute| dispute.state == DisputeState::Escalated).collect())
+}
+
+fn pause_invoice_enforcement(invoice: &mut Invoice) {
+    invoice.status = InvoiceStatus::Disputed;
+    INVOICES.with(|invoices| invoices.borrow_mut().store(invoice.id, invoice));
+    if OVERDUE_INVOICES.with(|invoices| invoices.borrow_mut().remove(&invoice.id)).is_some() {
+        unblock_vehicle(invoice, OnOffReason::InvoiceDisputed(invoice.id));
+    }
+}
+
+// Records the answer to the dispute and restores the invoice status according to its balance.
+fn settle_dispute(
+    invoice: &mut Invoice,
+    from: DisputeState,
+    to: DisputeState,
+    entry: DisputeEntry,
+) -> VTSResult<()> {
+    let mut dispute = DISPUTES.with(|disputes| disputes.borrow().load(&invoice.id))?;
+    if dispute.state != from {
+        return Err(Error::InvalidData);
+    }
+    let credit = match entry.action {
+        DisputeAction::Accept { credit } | DisputeAction::Resolve { credit } => credit,
+        _ => 0,
+    };
+    if credit > 0 {
+        add_invoice_adjustment(
+            invoice.id,
+            InvoiceAdjustment {
+                kind: AdjustmentKind::CreditNote,
+                amount: credit,
+                reason: entry.message.clone(),
+                issued_by: entry.author,
+                issued_at: entry.created_at,
+            },
+        );
+    }
+    // Due date is moved by the time the invoice was disputed, so late fees are not charged for it.
+    let paused_since = dispute.thread.last().map(|entry| entry.created_at).unwrap_or(entry.created_at);
+    invoice.due_at = invoice.due_at.saturating_add(entry.created_at.saturating_sub(paused_since));
+    invoice.status = if invoice_payments(invoice.id)?.is_empty() {
+        InvoiceStatus::Unpaid
+    } else {
+        InvoiceStatus::PartiallyPaid
+    };
+    INVOICES.with(|invoices| invoices.borrow_mut().store(invoice.id, invoice));
+
+    dispute.state = to;
+    dispute.thread.push(entry);
+    DISPUTES.with(|disputes| disputes.borrow_mut().store(invoice.id, &dispute));
+    pay_with_credit(invoice)
+}
+
+#[ic_cdk::query(guard = is_user)]
+fn get_user_agreements() -> VTSResult<Vec<Agreement>> {
+    get_agreements_of(ic_cdk::api::caller())
+}
+
+#[ic_cdk::query(guard = is_user)]
+fn get_organization_agreements(organization: Principal) -> VTSResult<Vec<Agreement>> {
+    if !acts_for(ic_cdk::api::caller(), organization, Permission::View) {
+        return Err(Error::Unauthorized);
+    }
+    get_agreements_of(organization)
+}
+
+fn get_agreements_of(user: Principal) -> VTSResult<Vec<Agreement>> {
+    let user = USERS.with(|users| users.borrow().load(&user))?;
+    let mut agreements = Vec::with_capacity(user.agreements.len());
+    AGREEMENTS.with(|agreements_storage| -> VTSResult<()> {
+        let agreements_storage = agreements_storage.borrow();
         for (user_agreement_id, _) in user.agreements {
-            let agreement = agreements_storage.get(&user_agreement_id).ok_or(Error::NotFound)?;
+            let agreement = agreements_storage.load(&user_agreement_id)?;
             agreements.push(agreement)
         }
         Ok(())
@@ -692,13 +3464,14 @@ fn get_user_agreements() -> VTSResult<Vec<Agreement>> {
     Ok(agreements)
 }
 
+#[ic_cdk::query(guard = is_user)]
+fn get_agreement(agreement_id: u128) -> VTSResult<Agreement> {
+    get_visible_agreement(agreement_id)
+}
+
 #[ic_cdk::query(guard = is_user)]
 fn get_vehicles_by_agreement(agreement_id: u128) -> VTSResult<HashMap<Principal, ()>> {
-    AGREEMENTS.with(|agreements| {
-        let agreements = agreements.borrow();
-        let agreement = agreements.get(&agreement_id).ok_or(Error::NotFound)?;
-        Ok(agreement.vehicles)
-    })
+    Ok(get_visible_agreement(agreement_id)?.vehicles)
 }
 
 #[ic_cdk::update]
@@ -707,37 +3480,124 @@ fn store_telemetry(
     data: Vec<u8>,
     signature: Vec<u8>,
 ) -> VTSResult<StoreTelemetryResponse> {
-    let signature = Signature::from_slice(&signature).map_err(|_| Error::InvalidSignatureFormat)?;
-    let mut vehicle = VEHICLES.with(|vehicles| vehicles.borrow().get(&principal).ok_or(Error::NotFound))?;
+    store_signed_telemetry(principal, &data, &signature)
+}
+
+#[ic_cdk::update]
+fn store_telemetry_batch(batch: Vec<SignedTelemetry>) -> VTSResult<StoreTelemetryBatchResponse> {
+    if batch.len() > MAX_TELEMETRY_BATCH_SIZE {
+        return Err(Error::InvalidData);
+    }
+    let mut results = Vec::with_capacity(batch.len());
+    let mut vehicles = HashMap::new();
+    for telemetry in batch {
+        let result = store_signed_telemetry(telemetry.vehicle, &telemetry.data, &telemetry.signature);
+        results.push(result.map(|_| ()));
+        if let Entry::Vacant(entry) = vehicles.entry(telemetry.vehicle) {
+            // Vehicle state is returned even if its telemetry is rejected.
+            let vehicle = VEHICLES.with(|vehicles| vehicles.borrow().try_load(&telemetry.vehicle));
+            if let Ok(Some(Vehicle { on_off, .. })) = vehicle {
+                entry.insert(if on_off {
+                    StoreTelemetryResponse::On
+                } else {
+                    StoreTelemetryResponse::Off
+                });
+            }
+        }
+    }
+    Ok(StoreTelemetryBatchResponse { results, vehicles })
+}
+
+fn store_signed_telemetry(
+    principal: Principal,
+    data: &[u8],
+    signature_bytes: &[u8],
+) -> VTSResult<StoreTelemetryResponse> {
+    let signature = Signature::from_slice(signature_bytes).map_err(|_| Error::InvalidSignatureFormat)?;
+    let vehicle = VEHICLES.with(|vehicles| vehicles.borrow().load(&principal))?;
     let verifying_key =
         VerifyingKey::from_public_key_der(&vehicle.public_key).map_err(|_| Error::Internal)?;
-    verifying_key.verify(&data, &signature).map_err(|_| Error::InvalidSignature)?;
-    let telemetry: StoreTelemetryRequest = bincode::decode_from_slice(&data, bincode::config::standard())
-        .map_err(|_| Error::DecodeTelemetry)?
-        .0;
-    ic_cdk::println!("received new telemetry: value={}; type={:?}", telemetry.value, telemetry.t_type);
-    let timestamp = ic_cdk::api::time();
-    let timestamp =
-        OffsetDateTime::from_unix_timestamp_nanos(timestamp as i128).map_err(|_| Error::InvalidSigner)?;
-    let on_off = vehicle.on_off;
-    vehicle
-        .telemetry
-        .get_mut(&telemetry.t_type)
-        .get_or_insert(&mut HashMap::new())
-        .get_mut(&timestamp.year())
-        .get_or_insert(&mut HashMap::new())
-        .get_mut(&(timestamp.month() as u8))
-        .get_or_insert(&mut HashMap::new())
-        .get_mut(&timestamp.day())
-        .get_or_insert(&mut Vec::new())
-        .push(telemetry.value);
-    VEHICLES.with(|vehicles| vehicles.borrow_mut().insert(principal, vehicle));
-    if !on_off {
+    verifying_key.verify(data, &signature).map_err(|_| Error::InvalidSignature)?;
+    let telemetry: StoreTelemetryRequest =
+        bincode::decode_from_slice(data, bincode::config::standard()).map_err(|_| Error::DecodeTelemetry)?.0;
+    ic_cdk::println!(
+        "received new telemetry: value={}{}; type={:?}; seq={}",
+        telemetry.value,
+        telemetry.t_type.unit(),
+        telemetry.t_type,
+        telemetry.seq
+    );
+    // Signed payload can be sent again by anyone, so we accept only newer readings.
+    let activity = VEHICLE_ACTIVITY.with(|activity| activity.borrow().try_load(&principal))?;
+    if activity.is_some_and(|activity| telemetry.seq <= activity.last_seq) {
+        return Err(Error::AlreadyExists);
+    }
+    // Device clock can drift and device can buffer data while offline,
+    // but we shouldn't accept readings from the far past or the future.
+    let now = ic_cdk::api::time();
+    let skew_window = get_state().telemetry_skew_window;
+    if telemetry.timestamp < now.saturating_sub(skew_window.past_secs * 1_000_000_000)
+        || telemetry.timestamp > now.saturating_add(skew_window.future_secs * 1_000_000_000)
+    {
+        return Err(Error::InvalidData);
+    }
+    let key = TelemetryKey {
+        vehicle: principal,
+        timestamp: telemetry.timestamp,
+        seq: telemetry.seq,
+    };
+    TELEMETRY_LOG.with(|log| {
+        log.borrow_mut().store(
+            key,
+            &TelemetryRecord {
+                t_type: telemetry.t_type,
+                value: telemetry.value,
+                signature: signature_bytes.to_vec(),
+            },
+        )
+    });
+    TELEMETRY_QUEUE.with(|queue| queue.borrow_mut().insert(key, ()));
+    VEHICLE_ACTIVITY.with(|activity| {
+        activity.borrow_mut().store(
+            principal,
+            &VehicleActivity {
+                last_seq: telemetry.seq,
+                last_seen: now,
+            },
+        )
+    });
+    if !vehicle.on_off {
         return Ok(StoreTelemetryResponse::Off);
     }
     Ok(StoreTelemetryResponse::On)
 }
 
+#[ic_cdk::update(guard = is_admin)]
+fn set_telemetry_skew_window(skew_window: TelemetrySkewWindow) {
+    update_state(|state| state.telemetry_skew_window = skew_window);
+}
+
+#[ic_cdk::query]
+fn get_telemetry_skew_window() -> TelemetrySkewWindow {
+    get_state().telemetry_skew_window
+}
+
+#[ic_cdk::update(guard = is_admin)]
+fn set_telemetry_retention(days: u32) -> VTSResult<()> {
+    // Telemetry shouldn't be removed while it still can be accepted.
+    let skew_window = get_state().telemetry_skew_window;
+    if (days as u64) * 86400 <= skew_window.past_secs {
+        return Err(Error::InvalidData);
+    }
+    update_state(|state| state.telemetry_retention_days = days);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_telemetry_retention() -> u32 {
+    get_state().telemetry_retention_days
+}
+
 #[ic_cdk::query(guard = is_gateway)]
 fn get_pending_invoices() -> VTSResult<Vec<PendingInvoice>> {
     let pending_invoices = PENDING_INVOICES
@@ -773,18 +3633,23 @@ fn delete_pending_invoices(ids: Vec<u128>) {
 }
 
 #[ic_cdk::update(guard = is_user)]
-fn turn_on_off_vehicle(vehicle: Principal, on_off: bool) -> VTSResult<()> {
+fn turn_on_off_vehicle(vehicle: Principal, on_off: bool, organization: Option<Principal>) -> VTSResult<()> {
+    let caller = acting_principal(organization, Permission::ManageVehicles, &[Role::Provider])?;
     VEHICLES.with(|vehicles| -> VTSResult<()> {
-        let mut v = vehicles.borrow().get(&vehicle).ok_or(Error::NotFound)?;
+        let mut v = vehicles.borrow().load(&vehicle)?;
         if let Some(provider) = v.provider {
-            if provider != ic_cdk::caller() {
+            if provider != caller {
                 return Err(Error::InvalidSigner);
             }
         } else {
             return Err(Error::InvalidSigner);
         }
-        v.on_off = on_off;
-        vehicles.borrow_mut().insert(vehicle, v);
+        // Vehicle can work only while agreement is in effect.
+        if on_off && get_agreement_state(v.agreement)? != Some(AgreementState::Signed) {
+            return Err(Error::InvalidData);
+        }
+        set_on_off(vehicle, &mut v, on_off, OnOffReason::Manual);
+        vehicles.borrow_mut().store(vehicle, &v);
         Ok(())
     })
 }
@@ -794,12 +3659,45 @@ fn turn_on_off_vehicle(vehicle: Principal, on_off: bool) -> VTSResult<()> {
 #[cfg(feature = "clean_state")]
 #[ic_cdk::update]
 fn clean_state() {
-    AGREEMENT_ID_COUNTER.set(0);
+    // Configuration and schema version are kept.
+    update_state(|state| {
+        state.agreement_id_counter = 0;
+        state.invoice_id_counter = 0;
+        state.firmware_upload_id_counter = 0;
+        state.telemetry_queue_cursor = None;
+        state.telemetry_prune_cursor = None;
+    });
     FIRMWARE_REQUESTS.with(|firmware_requests| firmware_requests.borrow_mut().clear_new());
     USERS.with(|users| users.borrow_mut().clear_new());
     VEHICLES.with(|vehicles| vehicles.borrow_mut().clear_new());
+    VEHICLE_TELEMETRY.with(|telemetry| telemetry.borrow_mut().clear_new());
+    FIRMWARE_CHUNKS.with(|chunks| chunks.borrow_mut().clear_new());
+    FIRMWARE_SIZES.with(|sizes| sizes.borrow_mut().clear_new());
+    VEHICLE_INVOICES.with(|invoices| invoices.borrow_mut().clear_new());
+    INVOICE_PERIODS.with(|periods| periods.borrow_mut().clear_new());
     AGREEMENTS.with(|agreements| agreements.borrow_mut().clear_new());
+    SUPERSEDED_CONDITIONS.with(|superseded| superseded.borrow_mut().clear_new());
     ADMINS.with(|admins| admins.borrow_mut().clear_new());
+    GATEWAYS.with(|gateways| gateways.borrow_mut().clear_new());
+    ORGANIZATIONS.with(|organizations| organizations.borrow_mut().clear_new());
+    INVOICES.with(|invoices| invoices.borrow_mut().clear_new());
+    PENDING_INVOICES.with(|invoices| invoices.borrow_mut().clear_new());
+    PAID_INVOICES.with(|invoices| invoices.borrow_mut().clear_new());
+    UNPAID_INVOICES.with(|invoices| invoices.borrow_mut().clear_new());
+    OVERDUE_INVOICES.with(|invoices| invoices.borrow_mut().clear_new());
+    INVOICE_ADJUSTMENTS.with(|adjustments| adjustments.borrow_mut().clear_new());
+    INVOICE_PAYMENTS.with(|payments| payments.borrow_mut().clear_new());
+    PENDING_PAYMENTS.with(|payments| payments.borrow_mut().clear_new());
+    UNRECONCILED_PAYMENTS.with(|payments| payments.borrow_mut().clear_new());
+    PENDING_FORWARDS.with(|forwards| forwards.borrow_mut().clear_new());
+    DISPUTES.with(|disputes| disputes.borrow_mut().clear_new());
+    VEHICLE_ON_OFF_LOG.with(|log| log.borrow_mut().clear_new());
+    CREDITS.with(|credits| credits.borrow_mut().clear_new());
+    TELEMETRY_LOG.with(|log| log.borrow_mut().clear_new());
+    TELEMETRY_QUEUE.with(|queue| queue.borrow_mut().clear_new());
+    VEHICLE_ACTIVITY.with(|activity| activity.borrow_mut().clear_new());
+    FIRMWARE_UPLOADS.with(|uploads| uploads.borrow_mut().clear_new());
+    FIRMWARE_UPLOAD_CHUNKS.with(|chunks| chunks.borrow_mut().clear_new());
 }
 
 // We use this method only in tests to not restart dfx node.
@@ -817,109 +3715,220 @@ fn fill_predefined_telemetry(vh_provider: Principal, vh_customer: Principal, veh
 
     // Add provider and customer to users storage.
     USERS.with(|users| {
-        users.borrow_mut().insert(
+        users.borrow_mut().store(
             vh_provider,
-            User {
+            &User {
                 vehicles: HashMap::from_iter(vec![(vehicle, ())]),
                 agreements: HashMap::from_iter(vec![(SIGNED_AGREEMENT_ID, ()), (UNSIGNED_AGREEMENT_ID, ())]),
                 email: Some(String::from("provider@staex.io")),
+                roles: vec![Role::Provider],
             },
         );
-        users.borrow_mut().insert(
+        users.borrow_mut().store(
             vh_customer,
-            User {
+            &User {
                 vehicles: HashMap::from_iter(vec![(vehicle, ())]),
                 agreements: HashMap::from_iter(vec![(SIGNED_AGREEMENT_ID, ()), (UNSIGNED_AGREEMENT_ID, ())]),
                 email: Some(String::from("customer@staex.io")),
+                // Tests can use the same principal for both sides of agreement.
+                roles: if vh_provider == vh_customer {
+                    vec![Role::Provider, Role::Customer]
+                } else {
+                    vec![Role::Customer]
+                },
             },
         );
     });
 
     // Initialize agreement.
     AGREEMENTS.with(|agreements| {
-        agreements.borrow_mut().insert(
+        agreements.borrow_mut().store(
             SIGNED_AGREEMENT_ID,
-            Agreement {
+            &Agreement {
                 id: SIGNED_AGREEMENT_ID,
                 name: String::from("Solar Energy GmbH"),
                 vh_provider,
                 vh_customer,
                 state: AgreementState::Signed,
                 conditions: AgreementConditions {
-                    gas_price: String::from("1.35"),
+                    prices: vec![
+                        PriceItem {
+                            t_type: TelemetryType::Gas,
+                            price: String::from("1.35"),
+                        },
+                        PriceItem {
+                            t_type: TelemetryType::Distance,
+                            price: String::from("0.5"),
+                        },
+                    ],
+                    fees: Vec::new(),
+                    daily_minimum: None,
+                    billing_cycle: BillingCycle::default(),
+                    starts_at: None,
+                    ends_at: None,
+                    token: PaymentToken::default(),
+                    payment_due_days: DEFAULT_PAYMENT_DUE_DAYS,
+                    grace_period_days: DEFAULT_GRACE_PERIOD_DAYS,
+                    late_fees: LateFees::default(),
                 },
                 vehicles: HashMap::from_iter(vec![(vehicle, ())]),
+                amendment: None,
+                ended_at: None,
             },
         );
-        agreements.borrow_mut().insert(
+        agreements.borrow_mut().store(
             UNSIGNED_AGREEMENT_ID,
-            Agreement {
+            &Agreement {
                 id: UNSIGNED_AGREEMENT_ID,
                 name: String::from("Super Duper Vehicles inc."),
                 vh_provider,
                 vh_customer,
                 state: AgreementState::Unsigned,
                 conditions: AgreementConditions {
-                    gas_price: String::from("4.71"),
+                    prices: vec![PriceItem {
+                        t_type: TelemetryType::Gas,
+                        price: String::from("4.71"),
+                    }],
+                    fees: Vec::new(),
+                    daily_minimum: None,
+                    billing_cycle: BillingCycle::default(),
+                    starts_at: None,
+                    ends_at: None,
+                    token: PaymentToken::default(),
+                    payment_due_days: DEFAULT_PAYMENT_DUE_DAYS,
+                    grace_period_days: DEFAULT_GRACE_PERIOD_DAYS,
+                    late_fees: LateFees::default(),
                 },
                 vehicles: HashMap::new(),
+                amendment: None,
+                ended_at: None,
             },
         );
     });
-    AGREEMENT_ID_COUNTER.set(UNSIGNED_AGREEMENT_ID);
+    update_state(|state| state.agreement_id_counter = UNSIGNED_AGREEMENT_ID);
 
     // Add one pending firmware request.
     FIRMWARE_REQUESTS.with(|requests| requests.borrow_mut().insert(vh_customer, ()));
 
+    let token = PaymentToken::default();
+    let token_units = 10u128.pow(token.decimals as u32);
+    let due_at = ic_cdk::api::time() + DEFAULT_PAYMENT_DUE_DAYS as u64 * 86400 * 1_000_000_000;
     INVOICES.with(|invoices| {
-        invoices.borrow_mut().insert(
+        invoices.borrow_mut().store(
             PAID_VEHICLE_INVOICE_ID,
-            Invoice {
+            &Invoice {
                 id: PAID_VEHICLE_INVOICE_ID,
                 status: InvoiceStatus::Paid,
                 vehicle,
                 agreement: SIGNED_AGREEMENT_ID,
-                period: (2024, 6),
-                total_cost: 67 * TOKENS_MULTIPLIER,
+                period: InvoicePeriod {
+                    start: (2024, 6, 1),
+                    end: (2024, 7, 1),
+                },
+                total_cost: 67 * token_units,
+                items: vec![InvoiceItem {
+                    kind: InvoiceItemKind::Fee(String::from("Monthly rent")),
+                    quantity: 1,
+                    unit_price: String::from("67"),
+                    subtotal: 67 * token_units,
+                }],
+                token: token.clone(),
+                payment_created_at: None,
+                due_at,
             },
         );
-        invoices.borrow_mut().insert(
+        invoices.borrow_mut().store(
             UNPAID_VEHICLE_INVOICE_ID,
-            Invoice {
+            &Invoice {
                 id: UNPAID_VEHICLE_INVOICE_ID,
                 status: InvoiceStatus::Unpaid,
                 vehicle,
                 agreement: SIGNED_AGREEMENT_ID,
-                period: (2024, 7),
-                total_cost: 23 * TOKENS_MULTIPLIER,
+                period: InvoicePeriod {
+                    start: (2024, 7, 1),
+                    end: (2024, 8, 1),
+                },
+                total_cost: 23 * token_units,
+                items: vec![InvoiceItem {
+                    kind: InvoiceItemKind::Fee(String::from("Monthly rent")),
+                    quantity: 1,
+                    unit_price: String::from("23"),
+                    subtotal: 23 * token_units,
+                }],
+                token: token.clone(),
+                payment_created_at: None,
+                due_at,
             },
         );
     });
-    INVOICE_ID_COUNTER.set(UNPAID_VEHICLE_INVOICE_ID);
+    update_state(|state| state.invoice_id_counter = UNPAID_VEHICLE_INVOICE_ID);
 
     // Initialize vehicle.
     VEHICLES.with(|vehicles| {
-        vehicles.borrow_mut().insert(
+        vehicles.borrow_mut().store(
             vehicle,
-            Vehicle {
+            &Vehicle {
                 provider: Some(vh_provider),
                 customer: vh_customer,
                 agreement: Some(SIGNED_AGREEMENT_ID),
                 public_key: vehicle_public_key,
                 arch: String::from("amd64"),
-                firmware: Vec::new(),
-                telemetry: HashMap::from_iter(vec![(
-                    TelemetryType::Gas,
+                firmware_sha256: Sha256::digest([]).to_vec(),
+                on_off: true,
+                links: vec![VehicleLink {
+                    agreement: SIGNED_AGREEMENT_ID,
+                    linked_at: 0,
+                    unlinked_at: None,
+                }],
+            },
+        )
+    });
+    store_firmware(vehicle, std::iter::empty());
+    VEHICLE_INVOICES.with(|invoices| {
+        let mut invoices = invoices.borrow_mut();
+        invoices.insert((vehicle, PAID_VEHICLE_INVOICE_ID), ());
+        invoices.insert((vehicle, UNPAID_VEHICLE_INVOICE_ID), ());
+    });
+    INVOICE_PERIODS.with(|periods| {
+        let mut periods = periods.borrow_mut();
+        periods
+            .insert(invoice_period_key(vehicle, SIGNED_AGREEMENT_ID, (2024, 6, 1)), PAID_VEHICLE_INVOICE_ID);
+        periods.insert(
+            invoice_period_key(vehicle, SIGNED_AGREEMENT_ID, (2024, 7, 1)),
+            UNPAID_VEHICLE_INVOICE_ID,
+        );
+    });
+    UNPAID_INVOICES.with(|invoices| invoices.borrow_mut().insert(UNPAID_VEHICLE_INVOICE_ID, ()));
+    VEHICLE_TELEMETRY.with(|telemetry| {
+        telemetry.borrow_mut().store(
+            vehicle,
+            &HashMap::from_iter(vec![
+                (
+                    TelemetryType::Distance,
                     HashMap::from_iter(vec![(
-                        2023,
-                        HashMap::from_iter(vec![(
-                            time::Month::June as u8,
-                            HashMap::from_iter(vec![(15, vec![96])]),
-                        )]),
+                        2024,
+                        AccumulatedTelemetryYearly {
+                            value: 2100,
+                            monthly: HashMap::from_iter(vec![
+                                (
+                                    8,
+                                    AccumulatedTelemetryMonthy {
+                                        value: 1500,
+                                        daily: HashMap::from_iter(vec![(3, 1200), (28, 1500)]),
+                                    },
+                                ),
+                                (
+                                    9,
+                                    AccumulatedTelemetryMonthy {
+                                        value: 2100,
+                                        daily: HashMap::from_iter(vec![(10, 1800), (30, 2100)]),
+                                    },
+                                ),
+                            ]),
+                        },
                     )]),
-                )]),
-                on_off: true,
-                accumulated_telemetry: HashMap::from_iter(vec![(
+                ),
+                (
                     TelemetryType::Gas,
                     HashMap::from_iter(vec![
                         (
@@ -987,72 +3996,386 @@ fn fill_predefined_telemetry(vh_provider: Principal, vh_customer: Principal, veh
                             },
                         ),
                     ]),
-                )]),
-                invoices: vec![PAID_VEHICLE_INVOICE_ID, UNPAID_VEHICLE_INVOICE_ID],
+                ),
+            ]),
+        )
+    });
+
+    // Raw telemetry which is not accumulated yet.
+    let key = TelemetryKey {
+        vehicle,
+        timestamp: Date::from_calendar_date(2023, Month::June, 15)
+            .unwrap()
+            .with_hms(12, 0, 0)
+            .unwrap()
+            .assume_utc()
+            .unix_timestamp_nanos() as u64,
+        seq: 1,
+    };
+    TELEMETRY_LOG.with(|log| {
+        log.borrow_mut().store(
+            key,
+            &TelemetryRecord {
+                t_type: TelemetryType::Gas,
+                value: 96,
+                signature: Vec::new(),
             },
         )
     });
+    TELEMETRY_QUEUE.with(|queue| queue.borrow_mut().insert(key, ()));
 }
 
-fn create_invoice(
+// We use this method only in tests to not wait for the timer.
+// To issue invoice for the particular period of predefined telemetry.
+#[cfg(feature = "predefined_telemetry")]
+#[ic_cdk::update]
+fn create_predefined_invoice(vehicle: Principal, year: i32, month: u8) -> VTSResult<u128> {
+    let month = Month::try_from(month).map_err(|_| Error::InvalidData)?;
+    let start = Date::from_calendar_date(year, month, 1).map_err(|_| Error::InvalidData)?;
+    let (year, month) = next_month(year, month);
+    let end = Date::from_calendar_date(year, month, 1).map_err(|_| Error::InvalidData)?;
+    let agreement = get_visible_vehicle(vehicle)?.agreement.ok_or(Error::NotFound)?;
+    create_invoice(vehicle, agreement, InvoicePeriod::new(start, end), &get_aggregated_data(vehicle)?)
+}
+
+// Issues invoices for periods which end before the time, invoice is never changed after it is issued.
+fn issue_missing_invoices(vehicle_id: Principal, billable_until: u64) -> VTSResult<()> {
+    let vehicle = VEHICLES.with(|vehicles| vehicles.borrow().load(&vehicle_id))?;
+    // Telemetry which is not accumulated yet is not counted by invoices.
+    let queued = TELEMETRY_QUEUE.with(|queue| {
+        queue.borrow().range(TelemetryKey::first(vehicle_id)..=TelemetryKey::last(vehicle_id)).next()
+    });
+    let billable_until = queued.map_or(billable_until, |(key, _)| billable_until.min(key.timestamp));
+    let accumulated_telemetry = get_accumulated_telemetry(vehicle_id)?;
+    // Billing starts from the period with the first accumulated telemetry.
+    let Some(first_date) = first_telemetry_date(&accumulated_telemetry)? else {
+        return Ok(());
+    };
+    for link in &vehicle.links {
+        issue_link_invoices(vehicle_id, link, &accumulated_telemetry, first_date, billable_until)?;
+    }
+    Ok(())
+}
+
+// Agreement is billed only for days when vehicle was linked to it and agreement was in effect.
+// Day when vehicle is moved to another agreement is billed to the new one.
+fn issue_link_invoices(
     vehicle_id: Principal,
-    year: i32,
-    month: u8,
-    aggregated_data: &AccumulatedTelemetry,
+    link: &VehicleLink,
+    accumulated_telemetry: &AccumulatedTelemetry,
+    first_date: Date,
+    billable_until: u64,
 ) -> VTSResult<()> {
-    let existing_invoice = INVOICES.with(|invoices| {
-        let invoices = invoices.borrow();
-        invoices.iter().any(|invoice| {
-            invoice.1.vehicle == vehicle_id && invoice.1.period.0 == year && invoice.1.period.1 == month
-        })
-    });
-    if existing_invoice {
+    let agreement = AGREEMENTS.with(|agreements| agreements.borrow().load(&link.agreement))?;
+    if !agreement.state.is_billable() {
         return Ok(());
     }
+    let billing_cycle = agreement.conditions.billing_cycle;
+    let offset = billing_cycle.offset()?;
 
-    let mut vehicle = VEHICLES
-        .with(|vehicles| {
-            let vehicles = vehicles.borrow();
-            vehicles.get(&vehicle_id)
-        })
-        .ok_or(Error::NotFound)?;
-    let agreement_id = vehicle.agreement.ok_or(Error::NotFound)?;
-    let agreement_conditions = AGREEMENTS
-        .with(|agreements| {
-            let agreements = agreements.borrow();
-            agreements.get(&agreement_id).map(|agreement| agreement.conditions)
-        })
-        .ok_or(Error::NotFound)?;
-    let gas_price = Decimal::from_str(&agreement_conditions.gas_price).map_err(|_| Error::InvalidData)?;
+    let linked_at =
+        agreement.conditions.starts_at.map_or(link.linked_at, |starts_at| starts_at.max(link.linked_at));
+    let window_start = to_local_date(linked_at, offset)?;
+    let mut window_end = match link.unlinked_at {
+        Some(unlinked_at) => Some(to_local_date(unlinked_at, offset)?),
+        None => None,
+    };
+    // The last day of ended agreement is billed as vehicle is not linked to another one.
+    if let Some(ended_at) = agreement.ended_at {
+        let mut ended = to_local_date(ended_at, offset)?;
+        if to_timestamp(ended, offset) < ended_at {
+            ended = ended.next_day().ok_or(Error::Internal)?;
+        }
+        window_end = Some(window_end.map_or(ended, |window_end| window_end.min(ended)));
+    }
 
-    let mut total_cost = Decimal::new(0, 0);
-    if let Some(aggregated_data) = aggregated_data.get(&TelemetryType::Gas) {
-        for usage in aggregated_data.values().map(|v| Decimal::from_u128(v.value)) {
-            let usage = usage.ok_or(Error::Internal)?;
-            total_cost += usage * gas_price;
+    let (mut start, mut end) = billing_cycle.period_of(window_start.max(first_date))?;
+    while to_timestamp(end, offset) <= billable_until {
+        if window_end.is_some_and(|window_end| start >= window_end) {
+            break;
         }
+        let period_start = start.max(window_start);
+        let period_end = window_end.map_or(end, |window_end| end.min(window_end));
+        if period_start < period_end {
+            create_invoice(
+                vehicle_id,
+                link.agreement,
+                InvoicePeriod::new(period_start, period_end),
+                accumulated_telemetry,
+            )?;
+        }
+        (start, end) = billing_cycle.period_of(end)?;
+    }
+    Ok(())
+}
+
+fn create_invoice(
+    vehicle_id: Principal,
+    agreement_id: u128,
+    period: InvoicePeriod,
+    aggregated_data: &AccumulatedTelemetry,
+) -> VTSResult<u128> {
+    let period_key = invoice_period_key(vehicle_id, agreement_id, period.start);
+    if let Some(invoice_id) = INVOICE_PERIODS.with(|periods| periods.borrow().get(&period_key)) {
+        return Ok(invoice_id);
     }
 
-    let invoice_id = INVOICE_ID_COUNTER.with(|counter| {
-        let mut counter = counter.borrow_mut();
-        *counter += 1;
-        *counter
+    VEHICLES.with(|vehicles| vehicles.borrow().load(&vehicle_id))?;
+    let agreement = AGREEMENTS.with(|agreements| agreements.borrow().load(&agreement_id))?;
+    let agreement_conditions = conditions_in_force(agreement, period.dates()?.0)?;
+    let items = prepare_invoice_items(&agreement_conditions, period, aggregated_data)?;
+    let total_cost = items
+        .iter()
+        .try_fold(0u128, |total, item| total.checked_add(item.subtotal))
+        .ok_or(Error::InvalidData)?;
+
+    let invoice_id = update_state(|state| {
+        state.invoice_id_counter += 1;
+        state.invoice_id_counter
     });
-    let invoice = Invoice {
+    let mut invoice = Invoice {
         id: invoice_id,
         status: InvoiceStatus::Unpaid,
         vehicle: vehicle_id,
         agreement: agreement_id,
-        period: (year, month),
-        total_cost: total_cost.to_u128().ok_or(Error::Internal)?,
+        period,
+        total_cost,
+        items,
+        payment_created_at: None,
+        due_at: ic_cdk::api::time() + agreement_conditions.payment_due_days as u64 * 86400 * 1_000_000_000,
+        token: agreement_conditions.token,
     };
-    vehicle.invoices.push(invoice_id);
 
-    INVOICES.with(|invoices| invoices.borrow_mut().insert(invoice_id, invoice));
+    INVOICES.with(|invoices| invoices.borrow_mut().store(invoice_id, &invoice));
     PENDING_INVOICES.with(|pending| pending.borrow_mut().insert(invoice_id, ()));
-    VEHICLES.with(|vehicles| vehicles.borrow_mut().insert(vehicle_id, vehicle));
+    UNPAID_INVOICES.with(|unpaid| unpaid.borrow_mut().insert(invoice_id, ()));
+    VEHICLE_INVOICES.with(|invoices| invoices.borrow_mut().insert((vehicle_id, invoice_id), ()));
+    INVOICE_PERIODS.with(|periods| periods.borrow_mut().insert(period_key, invoice_id));
+    // Overpayment of previous invoices is used first.
+    pay_with_credit(&mut invoice)?;
+
+    Ok(invoice_id)
+}
+
+// Conditions of the agreement in force on the day, amendments come into effect from the start of a period.
+fn conditions_in_force(agreement: Agreement, date: Date) -> VTSResult<AgreementConditions> {
+    let timestamp = to_timestamp(date, agreement.conditions.billing_cycle.offset()?);
+    let superseded = SUPERSEDED_CONDITIONS.with(|superseded| {
+        superseded
+            .borrow()
+            .range((agreement.id, timestamp.saturating_add(1))..=(agreement.id, u64::MAX))
+            .next()
+            .map(|(_, conditions)| conditions.decode())
+            .transpose()
+    })?;
+    Ok(superseded.unwrap_or(agreement.conditions))
+}
+
+// First day of the period is encoded as YYYYMMDD.
+fn invoice_period_key(
+    vehicle_id: Principal,
+    agreement_id: u128,
+    start: (i32, u8, u8),
+) -> (Principal, u128, u32) {
+    let (year, month, day) = start;
+    (vehicle_id, agreement_id, year as u32 * 10000 + month as u32 * 100 + day as u32)
+}
+
+fn prepare_invoice_items(
+    conditions: &AgreementConditions,
+    period: InvoicePeriod,
+    aggregated_data: &AccumulatedTelemetry,
+) -> VTSResult<Vec<InvoiceItem>> {
+    let (start, end) = period.dates()?;
+    let mut items = Vec::with_capacity(conditions.prices.len() + conditions.fees.len() + 1);
+    let mut usage_cost = Decimal::new(0, 0);
+    for price_item in &conditions.prices {
+        let price = parse_amount(&price_item.price)?;
+        let quantity = match aggregated_data.get(&price_item.t_type) {
+            Some(aggregated_data) => period_usage(price_item.t_type, aggregated_data, start, end)?,
+            None => 0,
+        };
+        // Quantity is reported by the vehicle, so it can be too large to be billed.
+        let subtotal = Decimal::from_u128(quantity)
+            .and_then(|quantity| quantity.checked_mul(price))
+            .ok_or(Error::InvalidData)?;
+        usage_cost = usage_cost.checked_add(subtotal).ok_or(Error::InvalidData)?;
+        items.push(InvoiceItem {
+            kind: InvoiceItemKind::Usage(price_item.t_type),
+            quantity,
+            unit_price: price_item.price.clone(),
+            subtotal: conditions.token.to_units(subtotal)?,
+        });
+    }
+    if let Some(daily_minimum) = &conditions.daily_minimum {
+        let days = (end - start).whole_days();
+        let minimum_cost =
+            parse_amount(daily_minimum)?.checked_mul(Decimal::from(days)).ok_or(Error::InvalidData)?;
+        if minimum_cost > usage_cost {
+            items.push(InvoiceItem {
+                kind: InvoiceItemKind::DailyMinimum,
+                quantity: days as u128,
+                unit_price: daily_minimum.clone(),
+                subtotal: conditions.token.to_units(minimum_cost - usage_cost)?,
+            });
+        }
+    }
+    for fee in &conditions.fees {
+        items.push(InvoiceItem {
+            kind: InvoiceItemKind::Fee(fee.name.clone()),
+            quantity: 1,
+            unit_price: fee.amount.clone(),
+            subtotal: conditions.token.to_units(parse_amount(&fee.amount)?)?,
+        });
+    }
+    Ok(items)
+}
+
+// Usage of the particular telemetry kind inside of the period, end day is not included.
+fn period_usage(
+    t_type: TelemetryType,
+    aggregated_data: &HashMap<i32, AccumulatedTelemetryYearly>,
+    start: Date,
+    end: Date,
+) -> VTSResult<u128> {
+    // Periods which are exactly one calendar month are billed from monthly values.
+    let (next_year, next_month) = next_month(start.year(), start.month());
+    if start.day() == 1
+        && end == Date::from_calendar_date(next_year, next_month, 1).map_err(|_| Error::Internal)?
+    {
+        return Ok(monthly_usage(t_type, aggregated_data, start.year(), start.month() as u8));
+    }
+
+    // Otherwise we need daily values to cut the period.
+    let mut daily: Vec<(Date, u128)> = Vec::new();
+    for (year, yearly) in aggregated_data {
+        for (month, monthly) in &yearly.monthly {
+            for (day, value) in &monthly.daily {
+                daily.push((to_date(*year, *month, *day)?, *value));
+            }
+        }
+    }
+    daily.sort_by_key(|(date, _)| *date);
+    let inside: Vec<u128> =
+        daily.iter().filter(|(date, _)| *date >= start && *date < end).map(|(_, value)| *value).collect();
+    let usage = match t_type.aggregation_rule() {
+        AggregationRule::Sum => {
+            inside.iter().try_fold(0u128, |sum, value| sum.checked_add(*value)).ok_or(Error::InvalidData)?
+        }
+        AggregationRule::Last => inside.last().copied().unwrap_or_default(),
+        AggregationRule::Max => {
+            let Some(max) = inside.iter().max() else {
+                return Ok(0);
+            };
+            let baseline = daily
+                .iter()
+                .rev()
+                .find(|(date, _)| *date < start)
+                .map(|(_, value)| *value)
+                .unwrap_or(inside[0]);
+            max.saturating_sub(baseline)
+        }
+    };
+    Ok(usage)
+}
+
+// Usage of the particular telemetry kind only inside of the given month.
+fn monthly_usage(
+    t_type: TelemetryType,
+    aggregated_data: &HashMap<i32, AccumulatedTelemetryYearly>,
+    year: i32,
+    month: u8,
+) -> u128 {
+    let Some(monthly) = aggregated_data.get(&year).and_then(|yearly| yearly.monthly.get(&month)) else {
+        return 0;
+    };
+    match t_type.aggregation_rule() {
+        AggregationRule::Sum | AggregationRule::Last => monthly.value,
+        // Counters (like odometer) only grow so usage is the difference with the last
+        // reading before the month. If there is no such reading we start from the first day.
+        AggregationRule::Max => {
+            let baseline = aggregated_data
+                .iter()
+                .flat_map(|(y, yearly)| yearly.monthly.iter().map(move |(m, v)| ((*y, *m), v.value)))
+                .filter(|(period, _)| *period < (year, month))
+                .max_by_key(|(period, _)| *period)
+                .map(|(_, value)| value)
+                .or_else(|| monthly.daily.iter().min_by_key(|(day, _)| **day).map(|(_, value)| *value))
+                .unwrap_or(monthly.value);
+            monthly.value.saturating_sub(baseline)
+        }
+    }
+}
+
+fn get_agreement_state(agreement: Option<u128>) -> VTSResult<Option<AgreementState>> {
+    let Some(agreement_id) = agreement else {
+        return Ok(None);
+    };
+    let agreement = AGREEMENTS.with(|agreements| agreements.borrow().try_load(&agreement_id))?;
+    Ok(agreement.map(|agreement| agreement.state))
+}
+
+fn get_billing_cycle(agreement: Option<u128>) -> VTSResult<BillingCycle> {
+    let Some(agreement_id) = agreement else {
+        return Ok(BillingCycle::default());
+    };
+    let agreement = AGREEMENTS.with(|agreements| agreements.borrow().try_load(&agreement_id))?;
+    Ok(agreement.map(|agreement| agreement.conditions.billing_cycle).unwrap_or_default())
+}
+
+fn first_telemetry_date(accumulated_telemetry: &AccumulatedTelemetry) -> VTSResult<Option<Date>> {
+    let mut first_date: Option<Date> = None;
+    for aggregated_data in accumulated_telemetry.values() {
+        for (year, yearly) in aggregated_data {
+            for (month, monthly) in &yearly.monthly {
+                // Some months can have only monthly value so we start from its first day.
+                let day = monthly.daily.keys().min().copied().unwrap_or(1);
+                let date = to_date(*year, *month, day)?;
+                first_date = Some(first_date.map_or(date, |first_date| first_date.min(date)));
+            }
+        }
+    }
+    Ok(first_date)
+}
+
+fn to_local_date(timestamp: u64, offset: UtcOffset) -> VTSResult<Date> {
+    Ok(OffsetDateTime::from_unix_timestamp_nanos(timestamp as i128)
+        .map_err(|_| Error::InvalidData)?
+        .to_offset(offset)
+        .date())
+}
+
+fn to_timestamp(date: Date, offset: UtcOffset) -> u64 {
+    date.midnight().assume_offset(offset).unix_timestamp_nanos().clamp(0, u64::MAX as i128) as u64
+}
+
+fn to_date(year: i32, month: u8, day: u8) -> VTSResult<Date> {
+    let month = Month::try_from(month).map_err(|_| Error::InvalidData)?;
+    Date::from_calendar_date(year, month, day).map_err(|_| Error::InvalidData)
+}
+
+fn next_month(year: i32, month: Month) -> (i32, Month) {
+    if month == Month::December {
+        (year + 1, Month::January)
+    } else {
+        (year, month.next())
+    }
+}
+
+fn previous_month(year: i32, month: Month) -> (i32, Month) {
+    if month == Month::January {
+        (year - 1, Month::December)
+    } else {
+        (year, month.previous())
+    }
+}
 
-    Ok(())
+fn parse_amount(amount: &str) -> VTSResult<Decimal> {
+    let amount = Decimal::from_str(amount).map_err(|_| Error::InvalidData)?;
+    if amount.is_sign_negative() {
+        return Err(Error::InvalidData);
+    }
+    Ok(amount)
 }
 
 fn prepare_pending_invoices(
@@ -1072,13 +4395,11 @@ fn prepare_pending_invoices(
         INVOICES.with(|invoices| -> VTSResult<Vec<PendingInvoice>> {
             let mut pending_invoices: Vec<PendingInvoice> = Vec::new();
             for pending_invoice_id in pending_invoices_ids {
-                let invoice = invoices.borrow().get(&pending_invoice_id).ok_or(Error::NotFound)?;
-                let vehicle = VEHICLES.with(|vehicles| -> VTSResult<Vehicle> {
-                    vehicles.borrow().get(&invoice.vehicle).ok_or(Error::NotFound)
-                })?;
-                let customer: User = USERS.with(|users| -> VTSResult<User> {
-                    users.borrow().get(&vehicle.customer).ok_or(Error::NotFound)
-                })?;
+                let invoice = invoices.borrow().load(&pending_invoice_id)?;
+                let vehicle = VEHICLES
+                    .with(|vehicles| -> VTSResult<Vehicle> { vehicles.borrow().load(&invoice.vehicle) })?;
+                let customer: User =
+                    USERS.with(|users| -> VTSResult<User> { users.borrow().load(&vehicle.customer) })?;
                 pending_invoices.push(PendingInvoice {
                     id: pending_invoice_id,
                     customer_email: customer.email,
@@ -1108,15 +4429,906 @@ fn is_user() -> Result<(), String> {
     })
 }
 
-fn is_gateway() -> Result<(), String> {
-    // todo: implement it
+fn is_vehicle_manager() -> Result<(), String> {
+    check_role(&[Role::Customer, Role::FleetOperator])
+}
+
+fn check_role(roles: &[Role]) -> Result<(), String> {
+    if !has_role(ic_cdk::caller(), roles) {
+        return Err(Error::Unauthorized.to_string());
+    }
     Ok(())
 }
 
+// Returns true if user has any of the roles.
+fn has_role(user: Principal, roles: &[Role]) -> bool {
+    USERS.with(|users| {
+        users
+            .borrow()
+            .try_load(&user)
+            .ok()
+            .flatten()
+            .is_some_and(|user| user.roles.iter().any(|r| roles.contains(r)))
+    })
+}
+
+// Returns principal on behalf of which caller acts: caller itself or organization where it is a member.
+// Roles are checked for this principal, so member acts with organization roles.
+fn acting_principal(
+    organization: Option<Principal>,
+    permission: Permission,
+    roles: &[Role],
+) -> VTSResult<Principal> {
+    let caller = ic_cdk::api::caller();
+    let principal = organization.unwrap_or(caller);
+    if !acts_for(caller, principal, permission) || !has_role(principal, roles) {
+        return Err(Error::Unauthorized);
+    }
+    Ok(principal)
+}
+
+// Returns true if user is the principal itself or its organization member with the permission.
+fn acts_for(user: Principal, principal: Principal, permission: Permission) -> bool {
+    if user == principal {
+        return true;
+    }
+    ORGANIZATIONS.with(|organizations| {
+        organizations.borrow().try_load(&principal).ok().flatten().is_some_and(|organization| {
+            organization.members.get(&user).is_some_and(|permissions| permissions.contains(&permission))
+        })
+    })
+}
+
+fn is_gateway() -> Result<(), String> {
+    GATEWAYS.with(|gateways| {
+        if !gateways.borrow().contains_key(&ic_cdk::caller()) {
+            return Err(Error::Unauthorized.to_string());
+        }
+        Ok(())
+    })
+}
+
+// Canister calls itself from timers, but admin can also trigger these methods manually.
 fn is_canister() -> Result<(), String> {
-    // todo: implement it
-    Ok(())
+    if ic_cdk::caller() == ic_cdk::api::id() {
+        return Ok(());
+    }
+    is_admin()
+}
+
+// Runs migrations of stored records from the stored schema version to the current one.
+fn migrate() {
+    let version = get_state().schema_version;
+    if version > SCHEMA_VERSION {
+        ic_cdk::trap(&format!("schema version {version} is newer than {SCHEMA_VERSION}"));
+    }
+    for version in version..SCHEMA_VERSION {
+        ic_cdk::println!("migrating schema from version {} to {}", version, version + 1);
+        match version {
+            0 => migrate_to_v1(),
+            1 => migrate_to_v2(),
+            2 => migrate_to_v3(),
+            3 => migrate_to_v4(),
+            4 => migrate_to_v5(),
+            5 => migrate_to_v6(),
+            6 => migrate_to_v7(),
+            7 => migrate_to_v8(),
+            _ => unreachable!(),
+        }
+        update_state(|state| state.schema_version = version + 1);
+    }
+}
+
+// Record in the form it is stored, so it can be decoded with the previous schema.
+struct RawRecord(Vec<u8>);
+
+impl Storable for RawRecord {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self(bytes.into_owned())
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+}
+
+// Decodes every record of the map with the previous schema and stores it with the current one.
+// It should be called before the map is used, as the map is opened second time here.
+fn migrate_records<K, Old, New>(
+    memory_id: u8,
+    decode: impl Fn(&[u8]) -> Old,
+    mut migrate: impl FnMut(&K, Old) -> New,
+    encode: impl Fn(&New) -> Vec<u8>,
+) where
+    K: Storable + Ord + Clone,
+{
+    let mut records = open_raw_records::<K>(memory_id);
+    let keys: Vec<K> = records.iter().map(|(key, _)| key).collect();
+    for key in keys {
+        let Some(record) = records.get(&key) else {
+            continue;
+        };
+        let new = migrate(&key, decode(&record.0));
+        records.insert(key, RawRecord(encode(&new)));
+    }
+}
+
+fn open_raw_records<K: Storable + Ord + Clone>(memory_id: u8) -> StableBTreeMap<K, RawRecord, Memory> {
+    StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id))))
+}
+
+fn decode_candid<T: CandidType + for<'de> Deserialize<'de>>(bytes: &[u8]) -> T {
+    Decode!(bytes, T).expect("failed to decode record with previous schema")
+}
+
+fn decode_stored<T: Serialize + DeserializeOwned>(bytes: &[u8]) -> T {
+    Stored::<T>::from_bytes(Cow::Borrowed(bytes))
+        .decode()
+        .expect("failed to decode record with previous schema")
+}
+
+fn encode_candid<T: CandidType>(record: &T) -> Vec<u8> {
+    Encode!(record).expect("failed to encode migrated record")
+}
+
+fn encode_stored<T: Serialize + DeserializeOwned>(record: &T) -> Vec<u8> {
+    Stored::new(record).bytes
+}
+
+// Records of the first release.
+#[derive(CandidType, Deserialize)]
+struct UserV0 {
+    vehicles: HashMap<Principal, ()>,
+    agreements: HashMap<u128, ()>,
+    email: Option<String>,
+}
+
+// Not accumulated telemetry values by year, month and day.
+type TelemetryV0 = HashMap<TelemetryType, HashMap<i32, HashMap<u8, HashMap<u8, Vec<u128>>>>>;
+
+#[derive(CandidType, Deserialize)]
+struct VehicleV0 {
+    provider: Option<Principal>,
+    customer: Principal,
+    agreement: Option<u128>,
+    public_key: Vec<u8>,
+    arch: String,
+    firmware: Vec<u8>,
+    on_off: bool,
+    telemetry: TelemetryV0,
+    accumulated_telemetry: AccumulatedTelemetry,
+    invoices: Vec<u128>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct AgreementV0 {
+    id: u128,
+    name: String,
+    vh_provider: Principal,
+    vh_customer: Principal,
+    state: AgreementState,
+    conditions: AgreementConditionsV0,
+    vehicles: HashMap<Principal, ()>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct AgreementConditionsV0 {
+    gas_price: String,
+}
+
+#[derive(CandidType, Deserialize)]
+struct InvoiceV0 {
+    id: u128,
+    status: InvoiceStatus,
+    vehicle: Principal,
+    agreement: u128,
+    // Year and month.
+    period: (i32, u8),
+    total_cost: u128,
+}
+
+fn migrate_to_v1() {
+    // Users could do everything before roles were introduced.
+    migrate_records::<Principal, UserV0, User>(
+        USERS_MEMORY_ID,
+        decode_candid,
+        |_, user| User {
+            vehicles: user.vehicles,
+            agreements: user.agreements,
+            email: user.email,
+            roles: vec![Role::Provider, Role::Customer, Role::FleetOperator],
+        },
+        encode_candid,
+    );
+    migrate_records::<Principal, VehicleV0, VehicleV1>(
+        VEHICLES_MEMORY_ID,
+        decode_candid,
+        |_, vehicle| {
+            let mut accumulated_telemetry = vehicle.accumulated_telemetry;
+            for (t_type, yearly) in vehicle.telemetry {
+                let rule = t_type.aggregation_rule();
+                for (year, monthly) in yearly {
+                    for (month, daily) in monthly {
+                        for (day, values) in daily {
+                            for value in values {
+                                // Values accepted before overflow was checked are capped.
+                                let merge = |current| rule.merge(current, value).unwrap_or(u128::MAX);
+                                let yearly =
+                                    accumulated_telemetry.entry(t_type).or_default().entry(year).or_default();
+                                yearly.value = merge(yearly.value);
+                                let monthly = yearly.monthly.entry(month).or_default();
+                                monthly.value = merge(monthly.value);
+                                let daily = monthly.daily.entry(day).or_default();
+                                *daily = merge(*daily);
+                            }
+                        }
+                    }
+                }
+            }
+            VehicleV1 {
+                provider: vehicle.provider,
+                customer: vehicle.customer,
+                // Vehicle was billed from its first telemetry.
+                links: vehicle
+                    .agreement
+                    .map(|agreement| VehicleLink {
+                        agreement,
+                        linked_at: 0,
+                        unlinked_at: None,
+                    })
+                    .into_iter()
+                    .collect(),
+                agreement: vehicle.agreement,
+                public_key: vehicle.public_key,
+                arch: vehicle.arch,
+                firmware_sha256: Sha256::digest(&vehicle.firmware).to_vec(),
+                firmware: vehicle.firmware,
+                on_off: vehicle.on_off,
+                accumulated_telemetry,
+                invoices: vehicle.invoices,
+            }
+        },
+        encode_candid,
+    );
+    migrate_records::<u128, AgreementV0, AgreementV2>(
+        AGREEMENTS_MEMORY_ID,
+        decode_candid,
+        |_, agreement| AgreementV2 {
+            id: agreement.id,
+            name: agreement.name,
+            vh_provider: agreement.vh_provider,
+            vh_customer: agreement.vh_customer,
+            state: agreement.state,
+            conditions: AgreementConditionsV2 {
+                prices: vec![PriceItem {
+                    t_type: TelemetryType::Gas,
+                    price: agreement.conditions.gas_price,
+                }],
+                fees: Vec::new(),
+                daily_minimum: None,
+                billing_cycle: BillingCycle::default(),
+                starts_at: None,
+                ends_at: None,
+            },
+            vehicles: agreement.vehicles,
+            amendment: None,
+            ended_at: None,
+        },
+        encode_candid,
+    );
+    // Items of old invoices are unknown, only the total cost is kept.
+    migrate_records::<u128, InvoiceV0, InvoiceV2>(
+        INVOICES_MEMORY_ID,
+        decode_candid,
+        |_, invoice| {
+            let (year, month) = invoice.period;
+            let (next_year, next_month) = if month == 12 {
+                (year + 1, 1)
+            } else {
+                (year, month + 1)
+            };
+            InvoiceV2 {
+                id: invoice.id,
+                status: invoice.status,
+                vehicle: invoice.vehicle,
+                agreement: invoice.agreement,
+                period: InvoicePeriod {
+                    start: (year, month, 1),
+                    end: (next_year, next_month, 1),
+                },
+                total_cost: invoice.total_cost,
+                items: Vec::new(),
+            }
+        },
+        encode_candid,
+    );
+    // Counters were kept in heap memory and were reset by every upgrade.
+    let agreement_id_counter =
+        open_raw_records::<u128>(AGREEMENTS_MEMORY_ID).last_key_value().map(|(id, _)| id);
+    let invoice_id_counter = open_raw_records::<u128>(INVOICES_MEMORY_ID).last_key_value().map(|(id, _)| id);
+    update_state(|state| {
+        state.agreement_id_counter = agreement_id_counter.unwrap_or_default();
+        state.invoice_id_counter = invoice_id_counter.unwrap_or_default();
+    });
+}
+
+// Vehicle before its telemetry, firmware and invoices were moved to separate maps.
+#[derive(CandidType, Deserialize)]
+struct VehicleV1 {
+    provider: Option<Principal>,
+    customer: Principal,
+    agreement: Option<u128>,
+    public_key: Vec<u8>,
+    arch: String,
+    firmware: Vec<u8>,
+    firmware_sha256: Vec<u8>,
+    on_off: bool,
+    accumulated_telemetry: AccumulatedTelemetry,
+    invoices: Vec<u128>,
+    links: Vec<VehicleLink>,
+}
+
+// Records were encoded with Candid before.
+fn migrate_to_v2() {
+    migrate_records::<Principal, Admin, Admin>(1, decode_candid, |_, admin| admin, encode_stored);
+    migrate_records::<Principal, Gateway, Gateway>(13, decode_candid, |_, gateway| gateway, encode_stored);
+    migrate_records::<Principal, User, User>(USERS_MEMORY_ID, decode_candid, |_, user| user, encode_stored);
+    migrate_records::<Principal, Organization, Organization>(
+        14,
+        decode_candid,
+        |_, organization| organization,
+        encode_stored,
+    );
+    migrate_records::<u128, AgreementV2, AgreementV2>(
+        AGREEMENTS_MEMORY_ID,
+        decode_candid,
+        |_, agreement| agreement,
+        encode_stored,
+    );
+    migrate_records::<u128, InvoiceV2, InvoiceV2>(
+        INVOICES_MEMORY_ID,
+        decode_candid,
+        |_, invoice| invoice,
+        encode_stored,
+    );
+    migrate_records::<u128, FirmwareUploadV6, FirmwareUploadV6>(
+        12,
+        decode_candid,
+        |_, upload| upload,
+        encode_stored,
+    );
+    migrate_records::<Principal, VehicleActivity, VehicleActivity>(
+        11,
+        decode_candid,
+        |_, activity| activity,
+        encode_stored,
+    );
+    migrate_records::<TelemetryKey, TelemetryRecord, TelemetryRecord>(
+        9,
+        decode_candid,
+        |_, record| record,
+        encode_stored,
+    );
+    let mut firmwares = open_firmwares_v6();
+    migrate_records::<Principal, VehicleV1, Vehicle>(
+        VEHICLES_MEMORY_ID,
+        decode_candid,
+        |vehicle_id, vehicle| {
+            firmwares.insert(*vehicle_id, vehicle.firmware);
+            VEHICLE_TELEMETRY
+                .with(|telemetry| telemetry.borrow_mut().store(*vehicle_id, &vehicle.accumulated_telemetry));
+            VEHICLE_INVOICES.with(|invoices| {
+                let mut invoices = invoices.borrow_mut();
+                for invoice_id in vehicle.invoices {
+                    invoices.insert((*vehicle_id, invoice_id), ());
+                }
+            });
+            Vehicle {
+                provider: vehicle.provider,
+                customer: vehicle.customer,
+                agreement: vehicle.agreement,
+                public_key: vehicle.public_key,
+                arch: vehicle.arch,
+                firmware_sha256: vehicle.firmware_sha256,
+                on_off: vehicle.on_off,
+                links: vehicle.links,
+            }
+        },
+        encode_stored,
+    );
+}
+
+// Agreement and invoice before amounts were set in the agreement token.
+#[derive(CandidType, Serialize, Deserialize)]
+struct AgreementV2 {
+    id: u128,
+    name: String,
+    vh_provider: Principal,
+    vh_customer: Principal,
+    state: AgreementState,
+    conditions: AgreementConditionsV2,
+    vehicles: HashMap<Principal, ()>,
+    amendment: Option<AgreementConditionsV2>,
+    ended_at: Option<u64>,
+}
+
+// Prices, fees and daily minimum were set in e8s of ICP.
+#[derive(CandidType, Serialize, Deserialize)]
+struct AgreementConditionsV2 {
+    prices: Vec<PriceItem>,
+    fees: Vec<Fee>,
+    daily_minimum: Option<String>,
+    billing_cycle: BillingCycle,
+    starts_at: Option<u64>,
+    ends_at: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+struct InvoiceV2 {
+    id: u128,
+    status: InvoiceStatus,
+    vehicle: Principal,
+    agreement: u128,
+    period: InvoicePeriod,
+    total_cost: u128,
+    items: Vec<InvoiceItem>,
+}
+
+// Every agreement was paid in ICP before, amounts are converted from e8s to tokens.
+fn migrate_to_v3() {
+    let token = PaymentToken::default();
+    let to_tokens = |amount: String| match Decimal::from_str(&amount) {
+        Ok(units) => (units / Decimal::from(10u128.pow(token.decimals as u32))).normalize().to_string(),
+        // Invalid amounts were rejected when agreement was created.
+        Err(_) => amount,
+    };
+    let migrate_conditions = |conditions: AgreementConditionsV2| AgreementConditionsV4 {
+        prices: conditions
+            .prices
+            .into_iter()
+            .map(|item| PriceItem {
+                t_type: item.t_type,
+                price: to_tokens(item.price),
+            })
+            .collect(),
+        fees: conditions
+            .fees
+            .into_iter()
+            .map(|fee| Fee {
+                name: fee.name,
+                amount: to_tokens(fee.amount),
+            })
+            .collect(),
+        daily_minimum: conditions.daily_minimum.map(to_tokens),
+        billing_cycle: conditions.billing_cycle,
+        starts_at: conditions.starts_at,
+        ends_at: conditions.ends_at,
+        token: token.clone(),
+    };
+    migrate_records::<u128, AgreementV2, AgreementV4>(
+        AGREEMENTS_MEMORY_ID,
+        decode_stored,
+        |_, agreement| AgreementV4 {
+            id: agreement.id,
+            name: agreement.name,
+            vh_provider: agreement.vh_provider,
+            vh_customer: agreement.vh_customer,
+            state: agreement.state,
+            conditions: migrate_conditions(agreement.conditions),
+            vehicles: agreement.vehicles,
+            amendment: agreement.amendment.map(migrate_conditions),
+            ended_at: agreement.ended_at,
+        },
+        encode_stored,
+    );
+    migrate_records::<u128, InvoiceV2, InvoiceV4>(
+        INVOICES_MEMORY_ID,
+        decode_stored,
+        |_, invoice| InvoiceV4 {
+            id: invoice.id,
+            status: invoice.status,
+            vehicle: invoice.vehicle,
+            agreement: invoice.agreement,
+            period: invoice.period,
+            total_cost: invoice.total_cost,
+            items: invoice
+                .items
+                .into_iter()
+                .map(|item| InvoiceItem {
+                    unit_price: to_tokens(item.unit_price),
+                    ..item
+                })
+                .collect(),
+            token: token.clone(),
+            payment_created_at: None,
+        },
+        encode_stored,
+    );
+}
+
+// Unpaid invoices were not indexed before.
+fn migrate_to_v4() {
+    let unpaid: Vec<u128> = open_raw_records::<u128>(INVOICES_MEMORY_ID)
+        .iter()
+        .filter(|(_, invoice)| decode_stored::<InvoiceV4>(&invoice.0).status == InvoiceStatus::Unpaid)
+        .map(|(id, _)| id)
+        .collect();
+    UNPAID_INVOICES.with(|invoices| {
+        let mut invoices = invoices.borrow_mut();
+        for id in unpaid {
+            invoices.insert(id, ());
+        }
+    });
+}
+
+// Agreement and invoice before payment terms were introduced.
+#[derive(Serialize, Deserialize)]
+struct AgreementV4 {
+    id: u128,
+    name: String,
+    vh_provider: Principal,
+    vh_customer: Principal,
+    state: AgreementState,
+    conditions: AgreementConditionsV4,
+    vehicles: HashMap<Principal, ()>,
+    amendment: Option<AgreementConditionsV4>,
+    ended_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AgreementConditionsV4 {
+    prices: Vec<PriceItem>,
+    fees: Vec<Fee>,
+    daily_minimum: Option<String>,
+    billing_cycle: BillingCycle,
+    starts_at: Option<u64>,
+    ends_at: Option<u64>,
+    token: PaymentToken,
+}
+
+#[derive(Serialize, Deserialize)]
+struct InvoiceV4 {
+    id: u128,
+    status: InvoiceStatus,
+    vehicle: Principal,
+    agreement: u128,
+    period: InvoicePeriod,
+    total_cost: u128,
+    items: Vec<InvoiceItem>,
+    token: PaymentToken,
+    payment_created_at: Option<u64>,
+}
+
+// Issued invoices are due in default payment terms from now, so vehicles are not turned off right away.
+fn migrate_to_v5() {
+    let due_at = ic_cdk::api::time() + DEFAULT_PAYMENT_DUE_DAYS as u64 * 86400 * 1_000_000_000;
+    let migrate_conditions = |conditions: AgreementConditionsV4| AgreementConditionsV5 {
+        prices: conditions.prices,
+        fees: conditions.fees,
+        daily_minimum: conditions.daily_minimum,
+        billing_cycle: conditions.billing_cycle,
+        starts_at: conditions.starts_at,
+        ends_at: conditions.ends_at,
+        token: conditions.token,
+        payment_due_days: DEFAULT_PAYMENT_DUE_DAYS,
+        grace_period_days: DEFAULT_GRACE_PERIOD_DAYS,
+    };
+    migrate_records::<u128, AgreementV4, AgreementV5>(
+        AGREEMENTS_MEMORY_ID,
+        decode_stored,
+        |_, agreement| AgreementV5 {
+            id: agreement.id,
+            name: agreement.name,
+            vh_provider: agreement.vh_provider,
+            vh_customer: agreement.vh_customer,
+            state: agreement.state,
+            conditions: migrate_conditions(agreement.conditions),
+            vehicles: agreement.vehicles,
+            amendment: agreement.amendment.map(migrate_conditions),
+            ended_at: agreement.ended_at,
+        },
+        encode_stored,
+    );
+    migrate_records::<u128, InvoiceV4, Invoice>(
+        INVOICES_MEMORY_ID,
+        decode_stored,
+        |_, invoice| Invoice {
+            id: invoice.id,
+            status: invoice.status,
+            vehicle: invoice.vehicle,
+            agreement: invoice.agreement,
+            period: invoice.period,
+            total_cost: invoice.total_cost,
+            items: invoice.items,
+            token: invoice.token,
+            payment_created_at: invoice.payment_created_at,
+            due_at,
+        },
+        encode_stored,
+    );
+}
+
+// Agreement before late fees were introduced.
+#[derive(Serialize, Deserialize)]
+struct AgreementV5 {
+    id: u128,
+    name: String,
+    vh_provider: Principal,
+    vh_customer: Principal,
+    state: AgreementState,
+    conditions: AgreementConditionsV5,
+    vehicles: HashMap<Principal, ()>,
+    amendment: Option<AgreementConditionsV5>,
+    ended_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AgreementConditionsV5 {
+    prices: Vec<PriceItem>,
+    fees: Vec<Fee>,
+    daily_minimum: Option<String>,
+    billing_cycle: BillingCycle,
+    starts_at: Option<u64>,
+    ends_at: Option<u64>,
+    token: PaymentToken,
+    payment_due_days: u32,
+    grace_period_days: u32,
+}
+
+// Existing agreements have no late fees.
+fn migrate_to_v6() {
+    let migrate_conditions = |conditions: AgreementConditionsV5| AgreementConditions {
+        prices: conditions.prices,
+        fees: conditions.fees,
+        daily_minimum: conditions.daily_minimum,
+        billing_cycle: conditions.billing_cycle,
+        starts_at: conditions.starts_at,
+        ends_at: conditions.ends_at,
+        token: conditions.token,
+        payment_due_days: conditions.payment_due_days,
+        grace_period_days: conditions.grace_period_days,
+        late_fees: LateFees::default(),
+    };
+    migrate_records::<u128, AgreementV5, Agreement>(
+        AGREEMENTS_MEMORY_ID,
+        decode_stored,
+        |_, agreement| Agreement {
+            id: agreement.id,
+            name: agreement.name,
+            vh_provider: agreement.vh_provider,
+            vh_customer: agreement.vh_customer,
+            state: agreement.state,
+            conditions: migrate_conditions(agreement.conditions),
+            vehicles: agreement.vehicles,
+            amendment: agreement.amendment.map(migrate_conditions),
+            ended_at: agreement.ended_at,
+        },
+        encode_stored,
+    );
+}
+
+// Firmware was stored as a whole and firmware upload kept the uploaded part before.
+fn migrate_to_v7() {
+    let mut firmwares = open_firmwares_v6();
+    let vehicles: Vec<Principal> = firmwares.iter().map(|(vehicle, _)| vehicle).collect();
+    for vehicle in vehicles {
+        if let Some(firmware) = firmwares.remove(&vehicle) {
+            store_firmware(vehicle, std::iter::once(firmware));
+        }
+    }
+    migrate_records::<u128, FirmwareUploadV6, FirmwareUpload>(
+        12,
+        decode_stored,
+        |upload_id, upload| {
+            let size = upload.firmware.len() as u64;
+            let chunk_count = if upload.firmware.is_empty() {
+                0
+            } else {
+                FIRMWARE_UPLOAD_CHUNKS
+                    .with(|chunks| chunks.borrow_mut().insert((*upload_id, 0), upload.firmware));
+                1
+            };
+            FirmwareUpload {
+                uploader: upload.uploader,
+                vh_customer: upload.vh_customer,
+                public_key: upload.public_key,
+                arch: upload.arch,
+                size,
+                chunk_count,
+                started_at: upload.started_at,
+            }
+        },
+        encode_stored,
+    );
+}
+
+// Invoices were looked up by period by reading every invoice of the vehicle before.
+fn migrate_to_v8() {
+    INVOICES.with(|invoices| {
+        for (invoice_id, invoice) in invoices.borrow().iter() {
+            let invoice = invoice.decode().expect("failed to decode invoice");
+            let key = invoice_period_key(invoice.vehicle, invoice.agreement, invoice.period.start);
+            INVOICE_PERIODS.with(|periods| periods.borrow_mut().insert(key, invoice_id));
+        }
+    });
+}
+
+fn open_firmwares_v6() -> StableBTreeMap<Principal, Vec<u8>, Memory> {
+    StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))))
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+struct FirmwareUploadV6 {
+    uploader: Principal,
+    vh_customer: Principal,
+    public_key: Vec<u8>,
+    arch: String,
+    firmware: Vec<u8>,
+    started_at: u64,
 }
 
 // Enable Candid export (see https://internetcomputer.org/docs/current/developer-docs/backend/rust/generating-candid)
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Record {
+        id: u128,
+        name: String,
+    }
+
+    fn record() -> Record {
+        Record {
+            id: 42,
+            name: String::from("vehicle"),
+        }
+    }
+
+    #[test]
+    fn test_stored_round_trip() {
+        let stored = Stored::new(&record());
+        assert_eq!(RECORD_ENCODING_VERSION, stored.bytes[0]);
+        let stored = Stored::<Record>::from_bytes(stored.to_bytes());
+        assert_eq!(Ok(record()), stored.decode());
+    }
+
+    #[test]
+    fn test_stored_unknown_version() {
+        let mut bytes = Stored::new(&record()).bytes;
+        bytes[0] = RECORD_ENCODING_VERSION + 1;
+        assert_eq!(Err(Error::Internal), Stored::<Record>::from_bytes(Cow::Owned(bytes)).decode());
+        assert_eq!(Err(Error::Internal), Stored::<Record>::from_bytes(Cow::Owned(Vec::new())).decode());
+    }
+
+    #[test]
+    fn test_stored_garbage() {
+        let bytes = vec![RECORD_ENCODING_VERSION, 0xff, 0xff, 0xff];
+        assert_eq!(Err(Error::Internal), Stored::<Record>::from_bytes(Cow::Owned(bytes)).decode());
+        // Record of another type doesn't decode either.
+        let bytes = Stored::new(&String::from("vehicle")).bytes;
+        assert_eq!(Err(Error::Internal), Stored::<Record>::from_bytes(Cow::Owned(bytes)).decode());
+    }
+
+    #[test]
+    fn test_range_start_with_cursor() {
+        let key = |timestamp| TelemetryKey {
+            vehicle: Principal::anonymous(),
+            timestamp,
+            seq: 0,
+        };
+        assert_eq!(RangeBound::Included(key(10)), range_start(key(10), None));
+        // Point at the range start is not skipped when cursor is before the range.
+        assert_eq!(RangeBound::Included(key(10)), range_start(key(10), Some(key(5))));
+        assert_eq!(RangeBound::Excluded(key(10)), range_start(key(10), Some(key(10))));
+        assert_eq!(RangeBound::Excluded(key(15)), range_start(key(10), Some(key(15))));
+    }
+
+    #[test]
+    fn test_prune_telemetry_by_batches() {
+        let now = 1000 * DAY_NANOS;
+        let fresh = now - DAY_NANOS;
+        let store = |vehicle: u8, timestamp: u64, seq: u64| {
+            let key = TelemetryKey {
+                vehicle: Principal::from_slice(&[vehicle]),
+                timestamp,
+                seq,
+            };
+            let record = TelemetryRecord {
+                t_type: TelemetryType::Gas,
+                value: 1,
+                signature: Vec::new(),
+            };
+            TELEMETRY_LOG.with(|log| log.borrow_mut().store(key, &record));
+            key
+        };
+        for seq in 0..TELEMETRY_PROCESSING_BATCH_SIZE as u64 + 5 {
+            store(1, 1, seq);
+        }
+        store(1, fresh, 0);
+        let queued = store(2, 1, 0);
+        TELEMETRY_QUEUE.with(|queue| queue.borrow_mut().insert(queued, ()));
+        store(2, 2, 0);
+        store(2, fresh, 0);
+
+        assert!(!prune_telemetry_batch(now));
+        assert!(get_state().telemetry_prune_cursor.is_some());
+        assert!(prune_telemetry_batch(now));
+        assert_eq!(None, get_state().telemetry_prune_cursor);
+        // Fresh and not accumulated telemetry is kept.
+        let kept: Vec<TelemetryKey> =
+            TELEMETRY_LOG.with(|log| log.borrow().iter().map(|(key, _)| key).collect());
+        assert_eq!(vec![store(1, fresh, 0), queued, store(2, fresh, 0)], kept);
+    }
+
+    #[test]
+    fn test_firmware_chunks() {
+        let vehicle = Principal::anonymous();
+        let chunk_size = MAX_FIRMWARE_CHUNK_SIZE as usize;
+        let firmware: Vec<u8> = (0..chunk_size * 2 + 10).map(|i| i as u8).collect();
+        // Uploaded pieces don't match the stored chunks.
+        let pieces = [
+            &firmware[..7],
+            &firmware[7..chunk_size + 3],
+            &firmware[chunk_size + 3..],
+        ];
+        store_firmware(vehicle, pieces.iter().map(|piece| piece.to_vec()));
+        assert_eq!(Some(firmware.len() as u64), FIRMWARE_SIZES.with(|sizes| sizes.borrow().get(&vehicle)));
+        assert_eq!(3, FIRMWARE_CHUNKS.with(|chunks| chunks.borrow().len()));
+
+        let read = |offset: usize, end: usize| read_firmware(vehicle, offset as u64, end as u64);
+        assert_eq!(firmware[..5], read(0, 5));
+        assert_eq!(firmware[chunk_size - 5..chunk_size + 5], read(chunk_size - 5, chunk_size + 5));
+        assert_eq!(firmware[chunk_size * 2..], read(chunk_size * 2, firmware.len()));
+        assert!(read(chunk_size, chunk_size).is_empty());
+
+        // Shorter firmware replaces all chunks of the previous one.
+        store_firmware(vehicle, std::iter::once(vec![1, 2, 3]));
+        assert_eq!(1, FIRMWARE_CHUNKS.with(|chunks| chunks.borrow().len()));
+        assert_eq!(vec![2, 3], read(1, 3));
+    }
+
+    #[test]
+    fn test_invoice_items_overflow() {
+        assert_eq!(Err(Error::InvalidData), AggregationRule::Sum.merge(u128::MAX, 1));
+        assert_eq!(Ok(u128::MAX), AggregationRule::Max.merge(u128::MAX, 1));
+
+        let conditions = AgreementConditions {
+            prices: vec![PriceItem {
+                t_type: TelemetryType::Gas,
+                price: String::from("2"),
+            }],
+            fees: Vec::new(),
+            daily_minimum: None,
+            billing_cycle: BillingCycle::default(),
+            starts_at: None,
+            ends_at: None,
+            token: PaymentToken::default(),
+            payment_due_days: 30,
+            grace_period_days: 7,
+            late_fees: LateFees::default(),
+        };
+        let start = Date::from_calendar_date(2024, Month::May, 1).unwrap();
+        let period = InvoicePeriod::new(start, Date::from_calendar_date(2024, Month::June, 1).unwrap());
+        let usage = |value: u128| -> AccumulatedTelemetry {
+            let monthly = AccumulatedTelemetryMonthy {
+                value,
+                daily: HashMap::from([(1, value)]),
+            };
+            let yearly = AccumulatedTelemetryYearly {
+                value,
+                monthly: HashMap::from([(5, monthly)]),
+            };
+            HashMap::from([(TelemetryType::Gas, HashMap::from([(2024, yearly)]))])
+        };
+        // Vehicle can report usage which can't be billed, it shouldn't trap invoicing.
+        let err = prepare_invoice_items(&conditions, period, &usage(u128::MAX)).unwrap_err();
+        assert_eq!(Error::InvalidData, err);
+        let items = prepare_invoice_items(&conditions, period, &usage(10)).unwrap();
+        assert_eq!(20 * 100_000_000, items[0].subtotal);
+    }
+}
@@ -2,11 +2,12 @@ use candid::{Decode, Encode, Principal};
 use ic_agent::{identity::Secp256k1Identity, Agent, Identity};
 use k256::ecdsa::SigningKey;
 use serde::Deserialize;
-use vts::VTSResult;
+use vts::{Role, VTSResult};
 
 #[derive(Deserialize)]
 struct CanisterIds {
     vts: CanisterId,
+    mock_ledger: CanisterId,
 }
 
 #[derive(Deserialize)]
@@ -14,16 +15,9 @@ struct CanisterId {
     local: String,
 }
 
+#[allow(dead_code)]
 pub async fn init_agent() -> (Agent, Principal) {
-    let secret_key = k256::SecretKey::random(&mut rand::thread_rng());
-    let identity = Secp256k1Identity::from_private_key(secret_key);
-    eprintln!("\nAgent sender is: {:?}", identity.sender().unwrap().to_string());
-    let agent = Agent::builder().with_url("http://127.0.0.1:7777").with_identity(identity).build().unwrap();
-    agent.fetch_root_key().await.unwrap();
-    let canisters_ids: CanisterIds =
-        serde_json::from_str(&std::fs::read_to_string("../../.dfx/local/canister_ids.json").unwrap())
-            .unwrap();
-    let canister_id = Principal::from_text(canisters_ids.vts.local).unwrap();
+    let (agent, canister_id) = init_unprivileged_agent().await;
 
     // Clean state to not restart dfx node.
     agent
@@ -44,15 +38,64 @@ pub async fn init_agent() -> (Agent, Principal) {
         .unwrap();
     Decode!(res.as_slice(), VTSResult<()>).unwrap().unwrap();
 
+    // Tests act as gateway too.
+    let res = agent
+        .update(&canister_id, "add_gateway")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&agent.get_principal().unwrap()).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(res.as_slice(), VTSResult<()>).unwrap().unwrap();
+
+    (agent, canister_id)
+}
+
+// Agent with new identity which is not admin, gateway or user in canister.
+#[allow(dead_code)]
+pub async fn init_unprivileged_agent() -> (Agent, Principal) {
+    let secret_key = k256::SecretKey::random(&mut rand::thread_rng());
+    let identity = Secp256k1Identity::from_private_key(secret_key);
+    eprintln!("\nAgent sender is: {:?}", identity.sender().unwrap().to_string());
+    let agent = Agent::builder().with_url("http://127.0.0.1:7777").with_identity(identity).build().unwrap();
+    agent.fetch_root_key().await.unwrap();
+    let canister_id = Principal::from_text(read_canister_ids().vts.local).unwrap();
     (agent, canister_id)
 }
 
+// Ledger which tests use instead of ICP ledger.
+#[allow(dead_code)]
+pub fn mock_ledger_id() -> Principal {
+    Principal::from_text(read_canister_ids().mock_ledger.local).unwrap()
+}
+
+fn read_canister_ids() -> CanisterIds {
+    serde_json::from_str(&std::fs::read_to_string("../../.dfx/local/canister_ids.json").unwrap()).unwrap()
+}
+
+// Register user with all roles except auditor.
 #[allow(dead_code)]
 pub async fn register_user(agent: &Agent, canister_id: Principal, user: Principal) {
+    register_user_with_roles(
+        agent,
+        canister_id,
+        user,
+        vec![Role::Provider, Role::Customer, Role::FleetOperator],
+    )
+    .await
+}
+
+#[allow(dead_code)]
+pub async fn register_user_with_roles(
+    agent: &Agent,
+    canister_id: Principal,
+    user: Principal,
+    roles: Vec<Role>,
+) {
     let res = agent
         .update(&canister_id, "register_user")
         .with_effective_canister_id(canister_id)
-        .with_arg(Encode!(&user).unwrap())
+        .with_arg(Encode!(&user, &None::<String>, &roles).unwrap())
         .call_and_wait()
         .await
         .unwrap();
@@ -89,3 +132,35 @@ pub fn generate_vehicle() -> (SigningKey, Secp256k1Identity) {
     let identity = Secp256k1Identity::from_private_key(signing_key.clone().into());
     (signing_key, identity)
 }
+
+// Fill canister with predefined telemetry and return vehicle identity.
+#[allow(dead_code)]
+pub async fn fill_predefined_telemetry(
+    agent: &Agent,
+    canister_id: Principal,
+    vh_provider: Principal,
+    vh_customer: Principal,
+) -> Principal {
+    let (_, identity) = generate_vehicle();
+    fill_predefined_vehicle(agent, canister_id, vh_provider, vh_customer, &identity).await;
+    identity.sender().unwrap()
+}
+
+// Fill canister with predefined telemetry of the vehicle which telemetry can be signed by the test.
+#[allow(dead_code)]
+pub async fn fill_predefined_vehicle(
+    agent: &Agent,
+    canister_id: Principal,
+    vh_provider: Principal,
+    vh_customer: Principal,
+    identity: &Secp256k1Identity,
+) {
+    let vehicle_public_key = hex::encode(identity.public_key().unwrap());
+    agent
+        .update(&canister_id, "fill_predefined_telemetry")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&vh_provider, &vh_customer, &vehicle_public_key).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+}
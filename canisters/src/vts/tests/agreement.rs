@@ -1,9 +1,18 @@
 use std::collections::HashMap;
+use std::time::SystemTime;
 
-use agent::{generate_vehicle, register_user, upload_firmware};
+use agent::{
+    fill_predefined_telemetry, fill_predefined_vehicle, generate_vehicle, register_user, upload_firmware,
+};
 use candid::{Decode, Encode, Principal};
 use ic_agent::{Agent, Identity};
-use vts::{Error, VTSResult};
+use k256::ecdsa::{signature::SignerMut, Signature, SigningKey};
+use time::{Month, OffsetDateTime};
+use vts::{
+    AgreementConditions, BillingCycle, BillingPeriod, Error, Fee, Invoice, InvoiceItemKind, LateFees,
+    PaymentToken, PriceItem, RawTelemetryPage, StoreTelemetryRequest, StoreTelemetryResponse,
+    TelemetryCursor, TelemetrySkewWindow, TelemetryType, VTSResult, VehicleLink,
+};
 
 use crate::agent::init_agent;
 
@@ -19,7 +28,7 @@ async fn test_create_agreement() {
         canister_id,
         "test",
         Principal::anonymous(),
-        "10",
+        gas_conditions("10"),
         identity.public_key().unwrap(),
     )
     .await
@@ -37,7 +46,7 @@ async fn test_sign_agreement() {
         canister_id,
         "test",
         agent.get_principal().unwrap(),
-        "10",
+        gas_conditions("10"),
         identity.public_key().unwrap(),
     )
     .await
@@ -65,7 +74,7 @@ async fn test_sign_agreement_twice() {
         canister_id,
         "test",
         agent.get_principal().unwrap(),
-        "100",
+        gas_conditions("100"),
         identity.public_key().unwrap(),
     )
     .await
@@ -88,7 +97,7 @@ async fn test_create_duplicate_agreements() {
         canister_id,
         "test_1",
         agent.get_principal().unwrap(),
-        "10",
+        gas_conditions("10"),
         identity.public_key().unwrap(),
     )
     .await
@@ -99,7 +108,7 @@ async fn test_create_duplicate_agreements() {
         canister_id,
         "test_2",
         agent.get_principal().unwrap(),
-        "10",
+        gas_conditions("10"),
         identity.public_key().unwrap(),
     )
     .await
@@ -115,10 +124,16 @@ async fn test_link_vehicle_to_agreement_success() {
     let vehicle = identity.sender().unwrap();
     let public_key = identity.public_key().unwrap();
 
-    let agreement_id =
-        create_agreement(&agent, canister_id, "test", agent.get_principal().unwrap(), "10", public_key)
-            .await
-            .unwrap();
+    let agreement_id = create_agreement(
+        &agent,
+        canister_id,
+        "test",
+        agent.get_principal().unwrap(),
+        gas_conditions("10"),
+        public_key,
+    )
+    .await
+    .unwrap();
 
     let result = link_vehicle(&agent, canister_id, &agreement_id, &vehicle).await;
     assert!(result.is_ok(), "should successfully link the vehicle to the agreement");
@@ -143,10 +158,16 @@ async fn test_get_vehicles_by_agreement() {
     let vehicle = identity.sender().unwrap();
     let public_key = identity.public_key().unwrap();
 
-    let agreement_id =
-        create_agreement(&agent, canister_id, "test", agent.get_principal().unwrap(), "10", public_key)
-            .await
-            .unwrap();
+    let agreement_id = create_agreement(
+        &agent,
+        canister_id,
+        "test",
+        agent.get_principal().unwrap(),
+        gas_conditions("10"),
+        public_key,
+    )
+    .await
+    .unwrap();
 
     link_vehicle(&agent, canister_id, &agreement_id, &vehicle).await.unwrap();
 
@@ -165,12 +186,467 @@ async fn test_get_vehicles_by_nonexistent_agreement() {
     assert_eq!(Error::NotFound, result);
 }
 
+#[tokio::test]
+async fn test_create_agreement_with_multiple_prices() {
+    let (agent, canister_id) = init_agent().await;
+    let (_, identity) = generate_vehicle();
+
+    let conditions = AgreementConditions {
+        prices: vec![
+            PriceItem {
+                t_type: TelemetryType::Gas,
+                price: "1.5".to_string(),
+            },
+            PriceItem {
+                t_type: TelemetryType::Distance,
+                price: "0.3".to_string(),
+            },
+        ],
+        fees: vec![Fee {
+            name: "base fee".to_string(),
+            amount: "100".to_string(),
+        }],
+        daily_minimum: Some("5".to_string()),
+        billing_cycle: BillingCycle {
+            utc_offset_minutes: 120,
+            start_day: 15,
+            period: BillingPeriod::Monthly,
+        },
+        starts_at: None,
+        ends_at: None,
+        token: PaymentToken::default(),
+        payment_due_days: 30,
+        grace_period_days: 7,
+        late_fees: LateFees::default(),
+    };
+    create_agreement(
+        &agent,
+        canister_id,
+        "test",
+        agent.get_principal().unwrap(),
+        conditions,
+        identity.public_key().unwrap(),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_create_agreement_with_invalid_conditions() {
+    let (agent, canister_id) = init_agent().await;
+    let (_, identity) = generate_vehicle();
+
+    let err = create_agreement(
+        &agent,
+        canister_id,
+        "test",
+        agent.get_principal().unwrap(),
+        gas_conditions("not a number"),
+        identity.public_key().unwrap(),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(Error::InvalidData, err);
+
+    let err = create_agreement(
+        &agent,
+        canister_id,
+        "test",
+        agent.get_principal().unwrap(),
+        gas_conditions("-1"),
+        identity.public_key().unwrap(),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(Error::InvalidData, err);
+
+    let mut conditions = gas_conditions("1");
+    conditions.prices.push(PriceItem {
+        t_type: TelemetryType::Gas,
+        price: "2".to_string(),
+    });
+    let err = create_agreement(
+        &agent,
+        canister_id,
+        "test",
+        agent.get_principal().unwrap(),
+        conditions,
+        identity.public_key().unwrap(),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(Error::InvalidData, err, "telemetry kind can be priced only once");
+
+    let mut conditions = gas_conditions("1");
+    conditions.billing_cycle = BillingCycle {
+        utc_offset_minutes: 0,
+        start_day: 8,
+        period: BillingPeriod::Weekly,
+    };
+    let err = create_agreement(
+        &agent,
+        canister_id,
+        "test",
+        agent.get_principal().unwrap(),
+        conditions,
+        identity.public_key().unwrap(),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(Error::InvalidData, err, "week has only 7 days");
+}
+
+#[tokio::test]
+async fn test_invoice_items() {
+    let (agent, canister_id) = init_agent().await;
+    let principal = agent.get_principal().unwrap();
+    let vehicle = fill_predefined_telemetry(&agent, canister_id, principal, principal).await;
+
+    let invoice_id = create_predefined_invoice(&agent, canister_id, &vehicle, 2024, 5).await.unwrap();
+    let invoice = get_invoice(&agent, canister_id, &invoice_id).await.unwrap();
+
+    assert_eq!(2, invoice.items.len(), "agreement has gas and distance prices");
+    let item =
+        invoice.items.iter().find(|item| item.kind == InvoiceItemKind::Usage(TelemetryType::Gas)).unwrap();
+    assert_eq!("1.35", item.unit_price, "unit price should be taken from the agreement");
+    assert_eq!(invoice.total_cost, invoice.items.iter().map(|item| item.subtotal).sum::<u128>());
+}
+
+#[tokio::test]
+async fn test_invoice_bills_only_its_month() {
+    let (agent, canister_id) = init_agent().await;
+    let principal = agent.get_principal().unwrap();
+    let vehicle = fill_predefined_telemetry(&agent, canister_id, principal, principal).await;
+
+    // Predefined gas usage: 2023-07 is 265, 2024-05 is 104.
+    // Before the fix every invoice was billed for the whole lifetime usage.
+    let invoice_id = create_predefined_invoice(&agent, canister_id, &vehicle, 2024, 5).await.unwrap();
+    let invoice = get_invoice(&agent, canister_id, &invoice_id).await.unwrap();
+    assert_eq!((2024, 5, 1), invoice.period.start);
+    assert_eq!((2024, 6, 1), invoice.period.end);
+    assert_eq!(104, usage_quantity(&invoice, TelemetryType::Gas));
+
+    let invoice_id = create_predefined_invoice(&agent, canister_id, &vehicle, 2023, 7).await.unwrap();
+    let invoice = get_invoice(&agent, canister_id, &invoice_id).await.unwrap();
+    assert_eq!(265, usage_quantity(&invoice, TelemetryType::Gas));
+    assert_eq!(0, usage_quantity(&invoice, TelemetryType::Distance));
+    // 265 * 1.35 = 357.75 ICP in e8s.
+    assert_eq!(35_775_000_000, invoice.total_cost);
+
+    // There is no usage in this month at all.
+    let invoice_id = create_predefined_invoice(&agent, canister_id, &vehicle, 2024, 11).await.unwrap();
+    let invoice = get_invoice(&agent, canister_id, &invoice_id).await.unwrap();
+    assert_eq!(0, invoice.total_cost);
+}
+
+#[tokio::test]
+async fn test_invoice_bills_counter_difference() {
+    let (agent, canister_id) = init_agent().await;
+    let principal = agent.get_principal().unwrap();
+    let vehicle = fill_predefined_telemetry(&agent, canister_id, principal, principal).await;
+
+    // Predefined odometer: 1200 on 2024-08-03, 1500 at the end of August, 2100 at the end of September.
+    let invoice_id = create_predefined_invoice(&agent, canister_id, &vehicle, 2024, 8).await.unwrap();
+    let invoice = get_invoice(&agent, canister_id, &invoice_id).await.unwrap();
+    assert_eq!(300, usage_quantity(&invoice, TelemetryType::Distance));
+
+    let invoice_id = create_predefined_invoice(&agent, canister_id, &vehicle, 2024, 9).await.unwrap();
+    let invoice = get_invoice(&agent, canister_id, &invoice_id).await.unwrap();
+    assert_eq!(600, usage_quantity(&invoice, TelemetryType::Distance));
+}
+
+#[tokio::test]
+async fn test_missed_invoices_catch_up() {
+    let (agent, canister_id) = init_agent().await;
+    let principal = agent.get_principal().unwrap();
+    let vehicle = fill_predefined_telemetry(&agent, canister_id, principal, principal).await;
+
+    accumulate_telemetry_data(&agent, canister_id).await;
+
+    // Predefined telemetry starts in June 2023 and there are already invoices for June and July 2024.
+    // Every other closed month should be invoiced no matter on which day timer is executed.
+    let invoices = get_all_invoices(&agent, canister_id).await;
+    let periods: Vec<(i32, u8, u8)> = invoices.iter().map(|invoice| invoice.period.start).collect();
+    assert!(invoices.iter().all(|invoice| invoice.vehicle == vehicle));
+    for period in [
+        (2023, 6, 1),
+        (2023, 7, 1),
+        (2023, 12, 1),
+        (2024, 1, 1),
+        (2024, 6, 1),
+        (2024, 9, 1),
+    ] {
+        assert!(periods.contains(&period), "{:?} should be invoiced", period);
+    }
+    assert_eq!(
+        1,
+        periods.iter().filter(|period| **period == (2024, 6, 1)).count(),
+        "existing invoice should not be duplicated"
+    );
+
+    // The second pass shouldn't issue anything new.
+    accumulate_telemetry_data(&agent, canister_id).await;
+    assert_eq!(invoices.len(), get_all_invoices(&agent, canister_id).await.len());
+}
+
+#[tokio::test]
+async fn test_late_telemetry_in_closed_period() {
+    let (agent, canister_id) = init_agent().await;
+    let principal = agent.get_principal().unwrap();
+    let (mut signing_key, identity) = generate_vehicle();
+    let vehicle = identity.sender().unwrap();
+    fill_predefined_vehicle(&agent, canister_id, principal, principal, &identity).await;
+
+    // The previous month is closed by the current one in the agreement billing cycle.
+    let now = now_nanos();
+    let month_start =
+        OffsetDateTime::from_unix_timestamp_nanos(now as i128).unwrap().date().replace_day(1).unwrap();
+    let previous_month = month_start.previous_day().unwrap();
+    let previous_period = (previous_month.year(), previous_month.month() as u8, 1);
+    let period_end = month_start.midnight().assume_utc().unix_timestamp_nanos() as u64;
+    let is_invoiced =
+        |invoices: Vec<Invoice>| invoices.iter().any(|invoice| invoice.period.start == previous_period);
+
+    // Previous month is not invoiced while its readings still can be buffered by the vehicle.
+    set_telemetry_skew_window(&agent, canister_id, 62 * 86400).await;
+    accumulate_telemetry_data(&agent, canister_id).await;
+    assert!(!is_invoiced(get_all_invoices(&agent, canister_id).await));
+    let late = StoreTelemetryRequest {
+        value: 7,
+        t_type: TelemetryType::Gas,
+        timestamp: period_end - 3600 * 1_000_000_000,
+        seq: 1,
+    };
+    store_telemetry(&agent, canister_id, &mut signing_key, vehicle, late).await.unwrap();
+    accumulate_telemetry_data(&agent, canister_id).await;
+    assert!(!is_invoiced(get_all_invoices(&agent, canister_id).await));
+
+    // Once the window has passed after the end of the period, it is invoiced with the late reading.
+    let past_secs = ((now - period_end) / 1_000_000_000).saturating_sub(60);
+    set_telemetry_skew_window(&agent, canister_id, past_secs).await;
+    accumulate_telemetry_data(&agent, canister_id).await;
+    let invoices = get_all_invoices(&agent, canister_id).await;
+    let invoice = invoices.iter().find(|invoice| invoice.period.start == previous_period).unwrap();
+    assert_eq!(7, usage_quantity(invoice, TelemetryType::Gas));
+
+    // Invoiced period doesn't accept readings anymore.
+    let late = StoreTelemetryRequest {
+        value: 5,
+        t_type: TelemetryType::Gas,
+        timestamp: period_end - 1_000_000_000,
+        seq: 2,
+    };
+    let err = store_telemetry(&agent, canister_id, &mut signing_key, vehicle, late).await.unwrap_err();
+    assert_eq!(Error::InvalidData, err);
+
+    set_telemetry_skew_window(&agent, canister_id, TelemetrySkewWindow::default().past_secs).await;
+}
+
+#[tokio::test]
+async fn test_invoice_telemetry() {
+    let (agent, canister_id) = init_agent().await;
+    let principal = agent.get_principal().unwrap();
+    let vehicle = fill_predefined_telemetry(&agent, canister_id, principal, principal).await;
+
+    // Raw telemetry is kept after accumulation but counted only once.
+    accumulate_telemetry_data(&agent, canister_id).await;
+    accumulate_telemetry_data(&agent, canister_id).await;
+
+    let invoice_id = create_predefined_invoice(&agent, canister_id, &vehicle, 2023, 6).await.unwrap();
+    let invoice = get_invoice(&agent, canister_id, &invoice_id).await.unwrap();
+    assert_eq!(96, usage_quantity(&invoice, TelemetryType::Gas));
+
+    let page = get_invoice_telemetry(&agent, canister_id, invoice_id, None).await.unwrap();
+    assert_eq!(1, page.telemetry.len());
+    assert_eq!(TelemetryType::Gas, page.telemetry[0].t_type);
+    assert_eq!(96, page.telemetry[0].value);
+    assert!(page.next.is_none());
+
+    // There is no raw telemetry in other months.
+    let invoice_id = create_predefined_invoice(&agent, canister_id, &vehicle, 2023, 7).await.unwrap();
+    let page = get_invoice_telemetry(&agent, canister_id, invoice_id, None).await.unwrap();
+    assert!(page.telemetry.is_empty());
+}
+
+fn usage_quantity(invoice: &Invoice, t_type: TelemetryType) -> u128 {
+    invoice.items.iter().find(|item| item.kind == InvoiceItemKind::Usage(t_type)).unwrap().quantity
+}
+
+#[tokio::test]
+async fn test_agreement_lifecycle() {
+    let (agent, canister_id) = init_agent().await;
+    let (_, identity) = generate_vehicle();
+    let vehicle = identity.sender().unwrap();
+    let principal = agent.get_principal().unwrap();
+
+    // Customer can reject and provider can cancel only unsigned agreement.
+    let rejected_id = create_agreement(
+        &agent,
+        canister_id,
+        "test",
+        principal,
+        gas_conditions("10"),
+        identity.public_key().unwrap(),
+    )
+    .await
+    .unwrap();
+    update_agreement(&agent, canister_id, "reject_agreement", rejected_id).await.unwrap();
+    assert_eq!(Error::InvalidData, sign_agreement(&agent, canister_id, &rejected_id).await.unwrap_err());
+    let err = update_agreement(&agent, canister_id, "cancel_agreement", rejected_id).await.unwrap_err();
+    assert_eq!(Error::InvalidData, err);
+    let cancelled_id = create_agreement(&agent, canister_id, "test", principal, gas_conditions("10"), vec![])
+        .await
+        .unwrap();
+    update_agreement(&agent, canister_id, "cancel_agreement", cancelled_id).await.unwrap();
+    let err = link_vehicle(&agent, canister_id, &cancelled_id, &vehicle).await.unwrap_err();
+    assert_eq!(Error::InvalidData, err);
+
+    let agreement_id = create_agreement(&agent, canister_id, "test", principal, gas_conditions("10"), vec![])
+        .await
+        .unwrap();
+    sign_agreement(&agent, canister_id, &agreement_id).await.unwrap();
+    link_vehicle(&agent, canister_id, &agreement_id, &vehicle).await.unwrap();
+
+    // Billing cycle can't be amended but prices can.
+    let mut conditions = gas_conditions("12");
+    conditions.billing_cycle.start_day = 2;
+    let err = propose_amendment(&agent, canister_id, agreement_id, conditions).await.unwrap_err();
+    assert_eq!(Error::InvalidData, err);
+    propose_amendment(&agent, canister_id, agreement_id, gas_conditions("12")).await.unwrap();
+    update_agreement(&agent, canister_id, "sign_amendment", agreement_id).await.unwrap();
+    let err = update_agreement(&agent, canister_id, "sign_amendment", agreement_id).await.unwrap_err();
+    assert_eq!(Error::NotFound, err);
+    propose_amendment(&agent, canister_id, agreement_id, gas_conditions("15")).await.unwrap();
+    update_agreement(&agent, canister_id, "reject_amendment", agreement_id).await.unwrap();
+
+    // Vehicles can't be turned on while agreement is suspended.
+    update_agreement(&agent, canister_id, "suspend_agreement", agreement_id).await.unwrap();
+    let err = update_agreement(&agent, canister_id, "suspend_agreement", agreement_id).await.unwrap_err();
+    assert_eq!(Error::InvalidData, err);
+    assert_eq!(
+        Error::InvalidData,
+        turn_on_off_vehicle(&agent, canister_id, vehicle, true).await.unwrap_err()
+    );
+    update_agreement(&agent, canister_id, "resume_agreement", agreement_id).await.unwrap();
+    turn_on_off_vehicle(&agent, canister_id, vehicle, true).await.unwrap();
+
+    update_agreement(&agent, canister_id, "terminate_agreement", agreement_id).await.unwrap();
+    let err = update_agreement(&agent, canister_id, "terminate_agreement", agreement_id).await.unwrap_err();
+    assert_eq!(Error::InvalidData, err);
+    assert_eq!(
+        Error::InvalidData,
+        turn_on_off_vehicle(&agent, canister_id, vehicle, true).await.unwrap_err()
+    );
+}
+
+#[tokio::test]
+async fn test_amendment_signed_mid_period() {
+    let (agent, canister_id) = init_agent().await;
+    let principal = agent.get_principal().unwrap();
+    let vehicle = fill_predefined_telemetry(&agent, canister_id, principal, principal).await;
+    // Predefined agreement prices gas at 1.35.
+    let agreement_id = 1;
+    propose_amendment(&agent, canister_id, agreement_id, gas_conditions("2")).await.unwrap();
+    update_agreement(&agent, canister_id, "sign_amendment", agreement_id).await.unwrap();
+
+    // Closed periods and the current one are priced with the conditions which were in force.
+    let today = OffsetDateTime::from_unix_timestamp_nanos(now_nanos() as i128).unwrap().date();
+    let unit_price = |invoice: Invoice| {
+        invoice
+            .items
+            .into_iter()
+            .find(|item| item.kind == InvoiceItemKind::Usage(TelemetryType::Gas))
+            .unwrap()
+            .unit_price
+    };
+    for (year, month) in [(2024, 8), (today.year(), today.month() as u8)] {
+        let invoice_id = create_predefined_invoice(&agent, canister_id, &vehicle, year, month).await.unwrap();
+        let invoice = get_invoice(&agent, canister_id, &invoice_id).await.unwrap();
+        assert_eq!("1.35", unit_price(invoice), "{}-{} should not be repriced", year, month);
+    }
+
+    // Amendment comes into effect from the next period.
+    let next_month = today.month().next();
+    let next_year = if next_month == Month::January {
+        today.year() + 1
+    } else {
+        today.year()
+    };
+    let invoice_id =
+        create_predefined_invoice(&agent, canister_id, &vehicle, next_year, next_month as u8).await.unwrap();
+    let invoice = get_invoice(&agent, canister_id, &invoice_id).await.unwrap();
+    assert_eq!("2", unit_price(invoice));
+}
+
+#[tokio::test]
+async fn test_unlink_and_transfer_vehicle() {
+    let (agent, canister_id) = init_agent().await;
+    let (_, identity) = generate_vehicle();
+    let vehicle = identity.sender().unwrap();
+    let principal = agent.get_principal().unwrap();
+
+    let first_id = create_agreement(
+        &agent,
+        canister_id,
+        "test",
+        principal,
+        gas_conditions("10"),
+        identity.public_key().unwrap(),
+    )
+    .await
+    .unwrap();
+    let second_id = create_agreement(&agent, canister_id, "test", principal, gas_conditions("12"), vec![])
+        .await
+        .unwrap();
+    link_vehicle(&agent, canister_id, &first_id, &vehicle).await.unwrap();
+
+    transfer_vehicle(&agent, canister_id, vehicle, second_id).await.unwrap();
+    assert_eq!(
+        Error::AlreadyExists,
+        transfer_vehicle(&agent, canister_id, vehicle, second_id).await.unwrap_err()
+    );
+    assert!(get_vehicles_by_agreement(&agent, canister_id, &first_id).await.unwrap().is_empty());
+    assert!(get_vehicles_by_agreement(&agent, canister_id, &second_id).await.unwrap().contains_key(&vehicle));
+
+    unlink_vehicle(&agent, canister_id, vehicle).await.unwrap();
+    assert_eq!(Error::NotFound, unlink_vehicle(&agent, canister_id, vehicle).await.unwrap_err());
+    assert!(get_vehicles_by_agreement(&agent, canister_id, &second_id).await.unwrap().is_empty());
+
+    // History keeps when vehicle was linked to every agreement.
+    let links = get_vehicle_links(&agent, canister_id, vehicle).await.unwrap();
+    assert_eq!(vec![first_id, second_id], links.iter().map(|link| link.agreement).collect::<Vec<u128>>());
+    assert_eq!(Some(links[1].linked_at), links[0].unlinked_at);
+    assert!(links[1].unlinked_at.is_some());
+
+    // Unlinked vehicle can be linked again.
+    link_vehicle(&agent, canister_id, &first_id, &vehicle).await.unwrap();
+    assert_eq!(3, get_vehicle_links(&agent, canister_id, vehicle).await.unwrap().len());
+}
+
+fn gas_conditions(gas_price: &str) -> AgreementConditions {
+    AgreementConditions {
+        prices: vec![PriceItem {
+            t_type: TelemetryType::Gas,
+            price: gas_price.to_string(),
+        }],
+        fees: Vec::new(),
+        daily_minimum: None,
+        billing_cycle: BillingCycle::default(),
+        starts_at: None,
+        ends_at: None,
+        token: PaymentToken::default(),
+        payment_due_days: 30,
+        grace_period_days: 7,
+        late_fees: LateFees::default(),
+    }
+}
+
 async fn create_agreement(
     agent: &Agent,
     canister_id: Principal,
     name: &str,
     vh_customer: Principal,
-    gas_price: &str,
+    conditions: AgreementConditions,
     public_key: Vec<u8>,
 ) -> VTSResult<u128> {
     register_user(agent, canister_id, agent.get_principal().unwrap()).await;
@@ -180,7 +656,7 @@ async fn create_agreement(
     let response = agent
         .update(&canister_id, "create_agreement")
         .with_effective_canister_id(canister_id)
-        .with_arg(Encode!(&name.to_string(), &vh_customer, &gas_price.to_string()).unwrap())
+        .with_arg(Encode!(&name.to_string(), &vh_customer, &conditions, &None::<Principal>).unwrap())
         .call_and_wait()
         .await
         .unwrap();
@@ -191,7 +667,7 @@ async fn sign_agreement(agent: &Agent, canister_id: Principal, agreement_id: &u1
     let response = agent
         .update(&canister_id, "sign_agreement")
         .with_effective_canister_id(canister_id)
-        .with_arg(Encode!(&agreement_id).unwrap())
+        .with_arg(Encode!(&agreement_id, &None::<Principal>).unwrap())
         .call_and_wait()
         .await
         .unwrap();
@@ -207,7 +683,7 @@ async fn link_vehicle(
     let response = agent
         .update(&canister_id, "link_vehicle")
         .with_effective_canister_id(canister_id)
-        .with_arg(Encode!(&agreement_id, &vehicle).unwrap())
+        .with_arg(Encode!(&agreement_id, &vehicle, &None::<Principal>).unwrap())
         .call_and_wait()
         .await
         .unwrap();
@@ -228,3 +704,197 @@ async fn get_vehicles_by_agreement(
         .unwrap();
     Decode!(response.as_slice(), VTSResult<HashMap<Principal, ()>>).unwrap()
 }
+
+async fn create_predefined_invoice(
+    agent: &Agent,
+    canister_id: Principal,
+    vehicle: &Principal,
+    year: i32,
+    month: u8,
+) -> VTSResult<u128> {
+    let response = agent
+        .update(&canister_id, "create_predefined_invoice")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&vehicle, &year, &month).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<u128>).unwrap()
+}
+
+async fn get_invoice(agent: &Agent, canister_id: Principal, invoice_id: &u128) -> VTSResult<Invoice> {
+    let response = agent
+        .query(&canister_id, "get_invoice")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&invoice_id).unwrap())
+        .call()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<Invoice>).unwrap()
+}
+
+async fn accumulate_telemetry_data(agent: &Agent, canister_id: Principal) {
+    let response = agent
+        .update(&canister_id, "accumulate_telemetry_data")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&()).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<()>).unwrap().unwrap()
+}
+
+// Invoice IDs are sequential so we can fetch them until the first missing one.
+async fn set_telemetry_skew_window(agent: &Agent, canister_id: Principal, past_secs: u64) {
+    let skew_window = TelemetrySkewWindow {
+        past_secs,
+        ..TelemetrySkewWindow::default()
+    };
+    agent
+        .update(&canister_id, "set_telemetry_skew_window")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&skew_window).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+}
+
+async fn store_telemetry(
+    agent: &Agent,
+    canister_id: Principal,
+    signing_key: &mut SigningKey,
+    vehicle: Principal,
+    telemetry: StoreTelemetryRequest,
+) -> VTSResult<StoreTelemetryResponse> {
+    let telemetry = bincode::encode_to_vec(telemetry, bincode::config::standard()).unwrap();
+    let signature: Signature = signing_key.sign(&telemetry);
+    let signature = signature.to_vec();
+    let response = agent
+        .update(&canister_id, "store_telemetry")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&vehicle, &telemetry, &signature).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<StoreTelemetryResponse>).unwrap()
+}
+
+async fn get_all_invoices(agent: &Agent, canister_id: Principal) -> Vec<Invoice> {
+    let mut invoices = Vec::new();
+    let mut invoice_id = 1;
+    while let Ok(invoice) = get_invoice(agent, canister_id, &invoice_id).await {
+        invoices.push(invoice);
+        invoice_id += 1;
+    }
+    invoices
+}
+
+async fn get_invoice_telemetry(
+    agent: &Agent,
+    canister_id: Principal,
+    invoice_id: u128,
+    cursor: Option<TelemetryCursor>,
+) -> VTSResult<RawTelemetryPage> {
+    let response = agent
+        .query(&canister_id, "get_invoice_telemetry")
+        .with_arg(Encode!(&invoice_id, &cursor).unwrap())
+        .call()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<RawTelemetryPage>).unwrap()
+}
+
+// Call agreement method which has agreement id and optional organization as arguments.
+async fn update_agreement(
+    agent: &Agent,
+    canister_id: Principal,
+    method: &str,
+    agreement_id: u128,
+) -> VTSResult<()> {
+    let response = agent
+        .update(&canister_id, method)
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&agreement_id, &None::<Principal>).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<()>).unwrap()
+}
+
+async fn propose_amendment(
+    agent: &Agent,
+    canister_id: Principal,
+    agreement_id: u128,
+    conditions: AgreementConditions,
+) -> VTSResult<()> {
+    let response = agent
+        .update(&canister_id, "propose_amendment")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&agreement_id, &conditions, &None::<Principal>).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<()>).unwrap()
+}
+
+async fn turn_on_off_vehicle(
+    agent: &Agent,
+    canister_id: Principal,
+    vehicle: Principal,
+    on_off: bool,
+) -> VTSResult<()> {
+    let response = agent
+        .update(&canister_id, "turn_on_off_vehicle")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&vehicle, &on_off, &None::<Principal>).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<()>).unwrap()
+}
+
+async fn unlink_vehicle(agent: &Agent, canister_id: Principal, vehicle: Principal) -> VTSResult<()> {
+    let response = agent
+        .update(&canister_id, "unlink_vehicle")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&vehicle, &None::<Principal>).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<()>).unwrap()
+}
+
+async fn transfer_vehicle(
+    agent: &Agent,
+    canister_id: Principal,
+    vehicle: Principal,
+    agreement_id: u128,
+) -> VTSResult<()> {
+    let response = agent
+        .update(&canister_id, "transfer_vehicle")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&vehicle, &agreement_id, &None::<Principal>).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<()>).unwrap()
+}
+
+async fn get_vehicle_links(
+    agent: &Agent,
+    canister_id: Principal,
+    vehicle: Principal,
+) -> VTSResult<Vec<VehicleLink>> {
+    let response = agent
+        .query(&canister_id, "get_vehicle_links")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&vehicle).unwrap())
+        .call()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<Vec<VehicleLink>>).unwrap()
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos() as u64
+}
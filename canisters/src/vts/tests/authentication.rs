@@ -1,8 +1,10 @@
 use candid::{Decode, Encode, Principal};
-use ic_agent::Agent;
-use vts::VTSResult;
+use ic_agent::{Agent, AgentError};
+use vts::{AgreementConditions, BillingCycle, Error, Invoice, LateFees, PaymentToken, Role, VTSResult};
 
-use crate::agent::init_agent;
+use crate::agent::{
+    fill_predefined_telemetry, init_agent, init_unprivileged_agent, register_user_with_roles,
+};
 
 mod agent;
 
@@ -28,6 +30,168 @@ async fn test_delete_admin() {
     assert!(result_2.is_ok(), "should delete existing admin");
 }
 
+#[tokio::test]
+async fn test_gateways() {
+    let (agent, canister_id) = init_agent().await;
+    let (gateway, _) = init_unprivileged_agent().await;
+    let gateway = gateway.get_principal().unwrap();
+
+    add_gateway(&agent, canister_id, gateway).await.unwrap().unwrap();
+    let err = add_gateway(&agent, canister_id, gateway).await.unwrap().unwrap_err();
+    assert_eq!(Error::AlreadyExists, err);
+    assert!(list_gateways(&agent, canister_id).await.unwrap().contains(&gateway));
+
+    remove_gateway(&agent, canister_id, gateway).await.unwrap().unwrap();
+    let err = remove_gateway(&agent, canister_id, gateway).await.unwrap().unwrap_err();
+    assert_eq!(Error::NotFound, err);
+    assert!(!list_gateways(&agent, canister_id).await.unwrap().contains(&gateway));
+}
+
+#[tokio::test]
+async fn test_gateways_rejects_not_admin() {
+    let (_, canister_id) = init_agent().await;
+    let (agent, _) = init_unprivileged_agent().await;
+    let principal = agent.get_principal().unwrap();
+
+    // Not admin can't register itself as gateway.
+    assert!(add_gateway(&agent, canister_id, principal).await.is_err());
+    assert!(remove_gateway(&agent, canister_id, principal).await.is_err());
+    assert!(list_gateways(&agent, canister_id).await.is_err());
+}
+
+#[tokio::test]
+async fn test_gateway_methods_reject_not_gateway() {
+    let (admin, canister_id) = init_agent().await;
+    let (agent, _) = init_unprivileged_agent().await;
+
+    assert!(call(&agent, canister_id, "get_firmware_requests", Encode!(&()).unwrap()).await.is_err());
+    assert!(call(&agent, canister_id, "get_pending_invoices", Encode!(&()).unwrap()).await.is_err());
+    assert!(call(&agent, canister_id, "delete_pending_invoices", Encode!(&Vec::<u128>::new()).unwrap())
+        .await
+        .is_err());
+    let upload_firmware_args = Encode!(&Principal::anonymous(), &vec![0u8], &"amd64", &vec![0u8]).unwrap();
+    assert!(call(&agent, canister_id, "upload_firmware", upload_firmware_args).await.is_err());
+
+    // Admin is not gateway until it is registered.
+    let principal = agent.get_principal().unwrap();
+    add_gateway(&admin, canister_id, principal).await.unwrap().unwrap();
+    call(&agent, canister_id, "get_pending_invoices", Encode!(&()).unwrap()).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_canister_methods_reject_not_admin() {
+    let (admin, canister_id) = init_agent().await;
+    let (agent, _) = init_unprivileged_agent().await;
+
+    assert!(call(&agent, canister_id, "accumulate_telemetry_data", Encode!(&()).unwrap()).await.is_err());
+    // Admin can trigger it manually.
+    call(&admin, canister_id, "accumulate_telemetry_data", Encode!(&()).unwrap()).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_roles() {
+    let (admin, canister_id) = init_agent().await;
+    let (agent, _) = init_unprivileged_agent().await;
+    let principal = agent.get_principal().unwrap();
+
+    // User without roles can't be registered.
+    let res = call(
+        &admin,
+        canister_id,
+        "register_user",
+        Encode!(&principal, &None::<String>, &Vec::<Role>::new()).unwrap(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(Err(Error::InvalidData), Decode!(res.as_slice(), VTSResult<()>).unwrap());
+
+    // Customer can't create agreements.
+    register_user_with_roles(&admin, canister_id, principal, vec![Role::Customer]).await;
+    let conditions = AgreementConditions {
+        prices: Vec::new(),
+        fees: Vec::new(),
+        daily_minimum: None,
+        billing_cycle: BillingCycle::default(),
+        starts_at: None,
+        ends_at: None,
+        token: PaymentToken::default(),
+        payment_due_days: 30,
+        grace_period_days: 7,
+        late_fees: LateFees::default(),
+    };
+    let create_agreement_args =
+        Encode!(&"agreement".to_string(), &principal, &conditions, &None::<Principal>).unwrap();
+    let res = call(&agent, canister_id, "create_agreement", create_agreement_args.clone()).await.unwrap();
+    assert_eq!(Err(Error::Unauthorized), Decode!(res.as_slice(), VTSResult<u128>).unwrap());
+
+    // But it can after admin grants the provider role.
+    set_user_roles(&admin, canister_id, principal, vec![Role::Provider, Role::Customer]).await.unwrap();
+    let res = call(&agent, canister_id, "create_agreement", create_agreement_args).await.unwrap();
+    Decode!(res.as_slice(), VTSResult<u128>).unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_invoice_visibility() {
+    let (admin, canister_id) = init_agent().await;
+    let (agent, _) = init_unprivileged_agent().await;
+    let party = admin.get_principal().unwrap();
+    let principal = agent.get_principal().unwrap();
+    fill_predefined_telemetry(&admin, canister_id, party, party).await;
+
+    // Parties can see their invoice.
+    get_invoice(&admin, canister_id, 1).await.unwrap();
+
+    // Customer which is not a party of the agreement can't.
+    register_user_with_roles(&admin, canister_id, principal, vec![Role::Customer]).await;
+    assert_eq!(Error::InvalidSigner, get_invoice(&agent, canister_id, 1).await.unwrap_err());
+
+    // Auditor can see all invoices.
+    set_user_roles(&admin, canister_id, principal, vec![Role::Auditor]).await.unwrap();
+    get_invoice(&agent, canister_id, 1).await.unwrap();
+
+    // Only admin can change roles.
+    assert!(call(&agent, canister_id, "set_user_roles", Encode!(&principal, &vec![Role::Provider]).unwrap())
+        .await
+        .is_err());
+}
+
+async fn call(
+    agent: &Agent,
+    canister_id: Principal,
+    method: &str,
+    arg: Vec<u8>,
+) -> Result<Vec<u8>, AgentError> {
+    agent
+        .update(&canister_id, method)
+        .with_effective_canister_id(canister_id)
+        .with_arg(arg)
+        .call_and_wait()
+        .await
+}
+
+async fn add_gateway(
+    agent: &Agent,
+    canister_id: Principal,
+    gateway: Principal,
+) -> Result<VTSResult<()>, AgentError> {
+    let response = call(agent, canister_id, "add_gateway", Encode!(&gateway).unwrap()).await?;
+    Ok(Decode!(response.as_slice(), VTSResult<()>).unwrap())
+}
+
+async fn remove_gateway(
+    agent: &Agent,
+    canister_id: Principal,
+    gateway: Principal,
+) -> Result<VTSResult<()>, AgentError> {
+    let response = call(agent, canister_id, "remove_gateway", Encode!(&gateway).unwrap()).await?;
+    Ok(Decode!(response.as_slice(), VTSResult<()>).unwrap())
+}
+
+async fn list_gateways(agent: &Agent, canister_id: Principal) -> Result<Vec<Principal>, AgentError> {
+    let response = agent.query(&canister_id, "list_gateways").with_arg(Encode!(&()).unwrap()).call().await?;
+    Ok(Decode!(response.as_slice(), Vec<Principal>).unwrap())
+}
+
 async fn add_admin(agent: &Agent, canister_id: Principal, new_admin: Principal) -> VTSResult<()> {
     let response = agent
         .update(&canister_id, "add_admin")
@@ -49,3 +213,23 @@ async fn delete_admin(agent: &Agent, canister_id: Principal, admin: &Principal)
         .unwrap();
     Decode!(response.as_slice(), VTSResult<()>).unwrap()
 }
+
+async fn set_user_roles(
+    agent: &Agent,
+    canister_id: Principal,
+    user: Principal,
+    roles: Vec<Role>,
+) -> VTSResult<()> {
+    let response = call(agent, canister_id, "set_user_roles", Encode!(&user, &roles).unwrap()).await.unwrap();
+    Decode!(response.as_slice(), VTSResult<()>).unwrap()
+}
+
+async fn get_invoice(agent: &Agent, canister_id: Principal, invoice_id: u128) -> VTSResult<Invoice> {
+    let response = agent
+        .query(&canister_id, "get_invoice")
+        .with_arg(Encode!(&invoice_id).unwrap())
+        .call()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<Invoice>).unwrap()
+}
@@ -1,8 +1,9 @@
 use candid::{Decode, Encode, Principal};
-use ic_agent::Agent;
-use vts::{Error, VTSResult};
+use ic_agent::{Agent, Identity};
+use sha2::{Digest, Sha256};
+use vts::{Error, FirmwareChunk, VTSResult, VehicleSummary};
 
-use crate::agent::{init_agent, register_user, upload_firmware};
+use crate::agent::{generate_vehicle, init_agent, register_user, upload_firmware};
 
 mod agent;
 
@@ -36,6 +37,141 @@ async fn test_firmware() {
     assert_eq!(Error::AlreadyExists, err);
 }
 
+#[tokio::test]
+async fn test_get_vehicle_and_firmware() {
+    let (agent, canister_id) = init_agent().await;
+    let customer = agent.get_principal().unwrap();
+    register_user(&agent, canister_id, customer).await;
+
+    let (_, identity) = generate_vehicle();
+    let vehicle = identity.sender().unwrap();
+    upload_firmware(&agent, canister_id, customer, identity.public_key().unwrap()).await.unwrap();
+
+    // Vehicle summary doesn't contain firmware.
+    let summary = get_vehicle(&agent, canister_id, vehicle).await.unwrap();
+    assert_eq!(vehicle, summary.principal);
+    assert_eq!(customer, summary.customer);
+    assert_eq!(None, summary.provider);
+    assert_eq!("arm64", summary.arch);
+    assert!(summary.on_off);
+    assert_eq!(None, summary.last_seen);
+    assert_eq!(0, summary.invoice_count);
+
+    // Uploaded firmware is [0, 1, 2].
+    let chunk = get_firmware(&agent, canister_id, vehicle, 0, 2).await.unwrap();
+    assert_eq!(vec![0, 1], chunk.data);
+    assert_eq!(3, chunk.total_size);
+    let chunk = get_firmware(&agent, canister_id, vehicle, 2, 2).await.unwrap();
+    assert_eq!(vec![2], chunk.data);
+    let chunk = get_firmware(&agent, canister_id, vehicle, 3, 2).await.unwrap();
+    assert!(chunk.data.is_empty());
+    let err = get_firmware(&agent, canister_id, vehicle, 4, 2).await.unwrap_err();
+    assert_eq!(Error::InvalidData, err);
+}
+
+#[tokio::test]
+async fn test_chunked_firmware_upload() {
+    let (agent, canister_id) = init_agent().await;
+    let customer = agent.get_principal().unwrap();
+    register_user(&agent, canister_id, customer).await;
+
+    let (_, identity) = generate_vehicle();
+    let vehicle = identity.sender().unwrap();
+    let upload_id =
+        begin_firmware_upload(&agent, canister_id, customer, identity.public_key().unwrap()).await.unwrap();
+
+    append_firmware_chunk(&agent, canister_id, upload_id, 0, vec![0, 1]).await.unwrap();
+    // The same chunk can't be appended twice.
+    let err = append_firmware_chunk(&agent, canister_id, upload_id, 0, vec![0, 1]).await.unwrap_err();
+    assert_eq!(Error::InvalidData, err);
+    append_firmware_chunk(&agent, canister_id, upload_id, 2, vec![2]).await.unwrap();
+
+    let err = finish_firmware_upload(&agent, canister_id, upload_id, Sha256::digest([0, 1]).to_vec())
+        .await
+        .unwrap_err();
+    assert_eq!(Error::InvalidData, err);
+    let sha256 = Sha256::digest([0, 1, 2]).to_vec();
+    finish_firmware_upload(&agent, canister_id, upload_id, sha256.clone()).await.unwrap();
+    // Upload is removed after it is finished.
+    let err = finish_firmware_upload(&agent, canister_id, upload_id, sha256.clone()).await.unwrap_err();
+    assert_eq!(Error::NotFound, err);
+
+    let chunk = get_firmware(&agent, canister_id, vehicle, 0, 10).await.unwrap();
+    assert_eq!(vec![0, 1, 2], chunk.data);
+    assert_eq!(sha256, chunk.sha256);
+}
+
+async fn begin_firmware_upload(
+    agent: &Agent,
+    canister_id: Principal,
+    vh_customer: Principal,
+    public_key: Vec<u8>,
+) -> VTSResult<u128> {
+    let res = agent
+        .update(&canister_id, "begin_firmware_upload")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&vh_customer, &public_key, &"arm64".to_string()).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(res.as_slice(), VTSResult<u128>).unwrap()
+}
+
+async fn append_firmware_chunk(
+    agent: &Agent,
+    canister_id: Principal,
+    upload_id: u128,
+    offset: u64,
+    chunk: Vec<u8>,
+) -> VTSResult<()> {
+    let res = agent
+        .update(&canister_id, "append_firmware_chunk")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&upload_id, &offset, &chunk).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(res.as_slice(), VTSResult<()>).unwrap()
+}
+
+async fn finish_firmware_upload(
+    agent: &Agent,
+    canister_id: Principal,
+    upload_id: u128,
+    sha256: Vec<u8>,
+) -> VTSResult<()> {
+    let res = agent
+        .update(&canister_id, "finish_firmware_upload")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&upload_id, &sha256).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(res.as_slice(), VTSResult<()>).unwrap()
+}
+
+async fn get_vehicle(agent: &Agent, canister_id: Principal, vehicle: Principal) -> VTSResult<VehicleSummary> {
+    let res =
+        agent.query(&canister_id, "get_vehicle").with_arg(Encode!(&vehicle).unwrap()).call().await.unwrap();
+    Decode!(res.as_slice(), VTSResult<VehicleSummary>).unwrap()
+}
+
+async fn get_firmware(
+    agent: &Agent,
+    canister_id: Principal,
+    vehicle: Principal,
+    offset: u64,
+    length: u64,
+) -> VTSResult<FirmwareChunk> {
+    let res = agent
+        .query(&canister_id, "get_firmware")
+        .with_arg(Encode!(&vehicle, &offset, &length).unwrap())
+        .call()
+        .await
+        .unwrap();
+    Decode!(res.as_slice(), VTSResult<FirmwareChunk>).unwrap()
+}
+
 async fn request_firmware(agent: &Agent, canister_id: Principal) -> VTSResult<()> {
     let res = agent
         .update(&canister_id, "request_firmware")
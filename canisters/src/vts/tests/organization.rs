@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+
+use candid::{Decode, Encode, Principal};
+use ic_agent::Agent;
+use vts::{
+    AgreementConditions, BillingCycle, Error, LateFees, Organization, PaymentToken, Permission, Role,
+    VTSResult,
+};
+
+use crate::agent::{init_agent, init_unprivileged_agent, register_user, register_user_with_roles};
+
+mod agent;
+
+#[tokio::test]
+async fn test_organization_members() {
+    let (admin, canister_id) = init_agent().await;
+    let (organization_agent, _) = init_unprivileged_agent().await;
+    let (member_agent, _) = init_unprivileged_agent().await;
+    let customer = admin.get_principal().unwrap();
+    let organization = organization_agent.get_principal().unwrap();
+    let member = member_agent.get_principal().unwrap();
+    register_user(&admin, canister_id, customer).await;
+    register_user_with_roles(&admin, canister_id, organization, vec![Role::Provider]).await;
+    register_user_with_roles(&admin, canister_id, member, vec![Role::FleetOperator]).await;
+
+    // Only registered user can become organization.
+    assert_eq!(Err(Error::NotFound), create_organization(&admin, canister_id, Principal::anonymous()).await);
+    create_organization(&admin, canister_id, organization).await.unwrap();
+    assert_eq!(Err(Error::AlreadyExists), create_organization(&admin, canister_id, organization).await);
+
+    // Not a member can't act on behalf of organization.
+    let err = create_agreement(&member_agent, canister_id, customer, organization).await.unwrap_err();
+    assert_eq!(Error::Unauthorized, err);
+
+    // Member can act only with given permissions.
+    set_member(&organization_agent, canister_id, organization, member, vec![Permission::View])
+        .await
+        .unwrap();
+    let err = create_agreement(&member_agent, canister_id, customer, organization).await.unwrap_err();
+    assert_eq!(Error::Unauthorized, err);
+    set_member(
+        &organization_agent,
+        canister_id,
+        organization,
+        member,
+        vec![Permission::View, Permission::Sign],
+    )
+    .await
+    .unwrap();
+    let agreement_id = create_agreement(&member_agent, canister_id, customer, organization).await.unwrap();
+
+    // Members can see organization agreements.
+    get_vehicles_by_agreement(&member_agent, canister_id, agreement_id).await.unwrap();
+    let organization_data = get_organization(&member_agent, canister_id, organization).await.unwrap();
+    assert_eq!(Some(&vec![Permission::View, Permission::Sign]), organization_data.members.get(&member));
+
+    // Only organization itself can manage members.
+    let err = set_member(&member_agent, canister_id, organization, member, vec![Permission::Pay])
+        .await
+        .unwrap_err();
+    assert_eq!(Error::Unauthorized, err);
+
+    remove_member(&organization_agent, canister_id, organization, member).await.unwrap();
+    let err = get_vehicles_by_agreement(&member_agent, canister_id, agreement_id).await.unwrap_err();
+    assert_eq!(Error::InvalidSigner, err);
+}
+
+async fn create_organization(
+    agent: &Agent,
+    canister_id: Principal,
+    organization: Principal,
+) -> VTSResult<()> {
+    let response = agent
+        .update(&canister_id, "create_organization")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&organization, &"Staex".to_string()).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<()>).unwrap()
+}
+
+async fn set_member(
+    agent: &Agent,
+    canister_id: Principal,
+    organization: Principal,
+    member: Principal,
+    permissions: Vec<Permission>,
+) -> VTSResult<()> {
+    let response = agent
+        .update(&canister_id, "set_organization_member")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&organization, &member, &permissions).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<()>).unwrap()
+}
+
+async fn remove_member(
+    agent: &Agent,
+    canister_id: Principal,
+    organization: Principal,
+    member: Principal,
+) -> VTSResult<()> {
+    let response = agent
+        .update(&canister_id, "remove_organization_member")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&organization, &member).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<()>).unwrap()
+}
+
+async fn get_organization(
+    agent: &Agent,
+    canister_id: Principal,
+    organization: Principal,
+) -> VTSResult<Organization> {
+    let response = agent
+        .query(&canister_id, "get_organization")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&organization).unwrap())
+        .call()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<Organization>).unwrap()
+}
+
+async fn create_agreement(
+    agent: &Agent,
+    canister_id: Principal,
+    vh_customer: Principal,
+    organization: Principal,
+) -> VTSResult<u128> {
+    let conditions = AgreementConditions {
+        prices: Vec::new(),
+        fees: Vec::new(),
+        daily_minimum: None,
+        billing_cycle: BillingCycle::default(),
+        starts_at: None,
+        ends_at: None,
+        token: PaymentToken::default(),
+        payment_due_days: 30,
+        grace_period_days: 7,
+        late_fees: LateFees::default(),
+    };
+    let response = agent
+        .update(&canister_id, "create_agreement")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&"agreement".to_string(), &vh_customer, &conditions, &Some(organization)).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<u128>).unwrap()
+}
+
+async fn get_vehicles_by_agreement(
+    agent: &Agent,
+    canister_id: Principal,
+    agreement_id: u128,
+) -> VTSResult<HashMap<Principal, ()>> {
+    let response = agent
+        .query(&canister_id, "get_vehicles_by_agreement")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&agreement_id).unwrap())
+        .call()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<HashMap<Principal, ()>>).unwrap()
+}
@@ -0,0 +1,954 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
+use ic_agent::Agent;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::Memo;
+use vts::{
+    AdjustmentKind, AgreementConditions, BillingCycle, Dispute, DisputeAction, DisputeState, Error, Invoice,
+    InvoiceAdjustment, InvoicePayment, InvoiceStatus, LateFees, OnOffChange, OnOffReason, PaymentToken,
+    PendingForward, PendingPayment, PriceItem, TelemetryType, VTSResult, VehicleSummary,
+};
+
+use crate::agent::{
+    fill_predefined_telemetry, init_agent, init_unprivileged_agent, mock_ledger_id, register_user,
+};
+
+mod agent;
+
+#[derive(CandidType, Deserialize, Debug)]
+struct Transfer {
+    from: Account,
+    to: Account,
+    amount: Nat,
+    memo: Option<Memo>,
+    created_at_time: Option<u64>,
+}
+
+#[tokio::test]
+async fn test_pay_for_invoice_with_agreement_token() {
+    let (agent, canister_id) = init_agent().await;
+    let principal = agent.get_principal().unwrap();
+    let (invoice_id, _) = create_mock_ledger_invoice(&agent, canister_id, 7, LateFees::default()).await;
+    let ledger = mock_ledger_id();
+    let invoice = get_invoice(&agent, canister_id, invoice_id).await.unwrap();
+    assert_eq!(52_000_000, invoice.total_cost);
+    assert_eq!(ledger, invoice.token.ledger);
+
+    // Customer has no tokens yet.
+    assert_eq!(Error::Internal, pay_for_invoice(&agent, canister_id, invoice_id, None).await.unwrap_err());
+    let invoice = get_invoice(&agent, canister_id, invoice_id).await.unwrap();
+    assert_eq!(InvoiceStatus::Unpaid, invoice.status);
+    let created_at_time = invoice.payment_created_at;
+    assert!(created_at_time.is_some(), "creation time of the first attempt should be kept");
+
+    mint(&agent, ledger, Account::from(principal), 100_000_000).await;
+    pay_for_invoice(&agent, canister_id, invoice_id, None).await.unwrap();
+    let invoice = get_invoice(&agent, canister_id, invoice_id).await.unwrap();
+    assert_eq!(InvoiceStatus::Paid, invoice.status);
+
+    let transfers = get_transfers(&agent, ledger).await;
+    let transfer = transfers.last().unwrap();
+    assert_eq!(Account::from(principal), transfer.from);
+    assert_eq!(Nat::from(52_000_000u128), transfer.amount);
+    assert_eq!(Some(Memo::from(payment_memo(invoice_id, 0))), transfer.memo);
+    assert_eq!(created_at_time, transfer.created_at_time, "retry should reuse the creation time");
+
+    // Paid invoice is not charged twice.
+    pay_for_invoice(&agent, canister_id, invoice_id, None).await.unwrap();
+    assert_eq!(transfers.len(), get_transfers(&agent, ledger).await.len());
+}
+
+#[tokio::test]
+async fn test_pay_for_invoice_in_installments() {
+    let (agent, canister_id) = init_agent().await;
+    let principal = agent.get_principal().unwrap();
+    let (invoice_id, _) = create_mock_ledger_invoice(&agent, canister_id, 7, LateFees::default()).await;
+    let ledger = mock_ledger_id();
+    mint(&agent, ledger, Account::from(principal), 100_000_000).await;
+
+    let result = pay_for_invoice(&agent, canister_id, invoice_id, Some(60_000_000)).await;
+    assert_eq!(Error::InvalidData, result.unwrap_err(), "amount cannot exceed the outstanding balance");
+
+    pay_for_invoice(&agent, canister_id, invoice_id, Some(20_000_000)).await.unwrap();
+    let invoice = get_invoice(&agent, canister_id, invoice_id).await.unwrap();
+    assert_eq!(InvoiceStatus::PartiallyPaid, invoice.status);
+    assert_eq!(32_000_000, get_invoice_outstanding_balance(&agent, canister_id, invoice_id).await.unwrap());
+
+    // The same amount again is a new installment, not a retry.
+    pay_for_invoice(&agent, canister_id, invoice_id, Some(20_000_000)).await.unwrap();
+    assert_eq!(12_000_000, get_invoice_outstanding_balance(&agent, canister_id, invoice_id).await.unwrap());
+
+    pay_for_invoice(&agent, canister_id, invoice_id, None).await.unwrap();
+    let invoice = get_invoice(&agent, canister_id, invoice_id).await.unwrap();
+    assert_eq!(InvoiceStatus::Paid, invoice.status);
+
+    let transfers = get_transfers(&agent, ledger).await;
+    let payments = get_invoice_payments(&agent, canister_id, invoice_id).await.unwrap();
+    let amounts: Vec<u128> = payments.iter().map(|payment| payment.amount).collect();
+    assert_eq!(vec![20_000_000, 20_000_000, 12_000_000], amounts);
+    assert_eq!(Some(transfers.len() as u128 - 1), payments[2].block_index);
+    assert_eq!(principal, payments[2].paid_by);
+}
+
+#[tokio::test]
+async fn test_concurrent_payments_charge_once() {
+    let (agent, canister_id) = init_agent().await;
+    let principal = agent.get_principal().unwrap();
+    let (invoice_id, _) = create_mock_ledger_invoice(&agent, canister_id, 7, LateFees::default()).await;
+    let ledger = mock_ledger_id();
+    mint(&agent, ledger, Account::from(principal), 200_000_000).await;
+
+    // Only customer of the agreement can pay for the invoice.
+    let (other_agent, _) = init_unprivileged_agent().await;
+    register_user(&agent, canister_id, other_agent.get_principal().unwrap()).await;
+    let result = pay_for_invoice(&other_agent, canister_id, invoice_id, None).await;
+    assert_eq!(Error::InvalidSigner, result.unwrap_err());
+
+    let transfers = get_transfers(&agent, ledger).await.len();
+    let (first, second) = tokio::join!(
+        pay_for_invoice(&agent, canister_id, invoice_id, None),
+        pay_for_invoice(&agent, canister_id, invoice_id, None),
+    );
+    for result in [first, second] {
+        assert!(result.is_ok() || result == Err(Error::PaymentInProgress), "unexpected result {:?}", result);
+    }
+    assert_eq!(transfers + 1, get_transfers(&agent, ledger).await.len(), "invoice should be charged once");
+    let invoice = get_invoice(&agent, canister_id, invoice_id).await.unwrap();
+    assert_eq!(InvoiceStatus::Paid, invoice.status);
+    assert_eq!(1, get_invoice_payments(&agent, canister_id, invoice_id).await.unwrap().len());
+}
+
+#[tokio::test]
+async fn test_recover_payment_with_lost_reply() {
+    let (agent, canister_id) = init_agent().await;
+    let principal = agent.get_principal().unwrap();
+    let (invoice_id, _) = create_mock_ledger_invoice(&agent, canister_id, 7, LateFees::default()).await;
+    let ledger = mock_ledger_id();
+    mint(&agent, ledger, Account::from(principal), 100_000_000).await;
+
+    // Transfer is executed, but the canister doesn't know it.
+    set_lose_replies(&agent, ledger, true).await;
+    let result = pay_for_invoice(&agent, canister_id, invoice_id, None).await;
+    set_lose_replies(&agent, ledger, false).await;
+    assert_eq!(Error::Internal, result.unwrap_err());
+    let transfers = get_transfers(&agent, ledger).await.len();
+    assert_eq!(InvoiceStatus::Unpaid, get_invoice(&agent, canister_id, invoice_id).await.unwrap().status);
+
+    // Pending transfer is resubmitted and the ledger returns the block of the original one.
+    pay_for_invoice(&agent, canister_id, invoice_id, None).await.unwrap();
+    assert_eq!(transfers, get_transfers(&agent, ledger).await.len(), "customer should be charged once");
+    assert_eq!(InvoiceStatus::Paid, get_invoice(&agent, canister_id, invoice_id).await.unwrap().status);
+    let payments = get_invoice_payments(&agent, canister_id, invoice_id).await.unwrap();
+    assert_eq!(1, payments.len());
+    assert_eq!(Some(transfers as u128 - 1), payments[0].block_index);
+}
+
+#[tokio::test]
+async fn test_retry_installment_with_lost_reply() {
+    let (agent, canister_id) = init_agent().await;
+    let principal = agent.get_principal().unwrap();
+    let (invoice_id, _) = create_mock_ledger_invoice(&agent, canister_id, 7, LateFees::default()).await;
+    let ledger = mock_ledger_id();
+    mint(&agent, ledger, Account::from(principal), 100_000_000).await;
+
+    set_lose_replies(&agent, ledger, true).await;
+    let result = pay_for_invoice(&agent, canister_id, invoice_id, Some(20_000_000)).await;
+    set_lose_replies(&agent, ledger, false).await;
+    assert_eq!(Error::Internal, result.unwrap_err());
+    let transfers = get_transfers(&agent, ledger).await.len();
+
+    // Retry completes the lost installment and doesn't start another one.
+    pay_for_invoice(&agent, canister_id, invoice_id, Some(20_000_000)).await.unwrap();
+    assert_eq!(transfers, get_transfers(&agent, ledger).await.len(), "customer should be charged once");
+    assert_eq!(32_000_000, get_invoice_outstanding_balance(&agent, canister_id, invoice_id).await.unwrap());
+    let payments = get_invoice_payments(&agent, canister_id, invoice_id).await.unwrap();
+    assert_eq!(vec![20_000_000], payments.iter().map(|payment| payment.amount).collect::<Vec<u128>>());
+
+    // The next call is a new installment.
+    pay_for_invoice(&agent, canister_id, invoice_id, Some(20_000_000)).await.unwrap();
+    assert_eq!(transfers + 1, get_transfers(&agent, ledger).await.len());
+    assert_eq!(12_000_000, get_invoice_outstanding_balance(&agent, canister_id, invoice_id).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_expired_payment_needs_reconciliation() {
+    let (agent, canister_id) = init_agent().await;
+    let principal = agent.get_principal().unwrap();
+    let (invoice_id, _) = create_mock_ledger_invoice(&agent, canister_id, 7, LateFees::default()).await;
+    let ledger = mock_ledger_id();
+    mint(&agent, ledger, Account::from(principal), 200_000_000).await;
+
+    set_lose_replies(&agent, ledger, true).await;
+    let result = pay_for_invoice(&agent, canister_id, invoice_id, None).await;
+    set_lose_replies(&agent, ledger, false).await;
+    assert_eq!(Error::Internal, result.unwrap_err());
+    let transfers = get_transfers(&agent, ledger).await.len();
+
+    // Ledger wouldn't recognize the resubmitted transfer as a duplicate anymore.
+    update_canister(&agent, canister_id, "expire_pending_payments").await;
+    for _ in 0..2 {
+        let result = pay_for_invoice(&agent, canister_id, invoice_id, None).await;
+        assert_eq!(Error::PaymentNeedsReconciliation, result.unwrap_err());
+    }
+    assert_eq!(transfers, get_transfers(&agent, ledger).await.len(), "customer should not be charged again");
+    let unreconciled = get_unreconciled_payments(&agent, canister_id).await.unwrap();
+    let (_, payment) = unreconciled.iter().find(|(id, _)| *id == invoice_id).unwrap();
+    assert_eq!(52_000_000, payment.amount);
+
+    // Admin finds the transfer in the ledger.
+    reconcile_payment(&agent, canister_id, invoice_id, Some(transfers as u128 - 1)).await.unwrap();
+    assert_eq!(InvoiceStatus::Paid, get_invoice(&agent, canister_id, invoice_id).await.unwrap().status);
+    pay_for_invoice(&agent, canister_id, invoice_id, None).await.unwrap();
+    assert_eq!(transfers, get_transfers(&agent, ledger).await.len());
+    assert!(get_unreconciled_payments(&agent, canister_id)
+        .await
+        .unwrap()
+        .iter()
+        .all(|(id, _)| *id != invoice_id));
+}
+
+#[tokio::test]
+async fn test_pay_for_invoice_with_deposit() {
+    let (agent, canister_id) = init_agent().await;
+    let principal = agent.get_principal().unwrap();
+    let (invoice_id, agreement_id) =
+        create_mock_ledger_invoice(&agent, canister_id, 7, LateFees::default()).await;
+    let ledger = mock_ledger_id();
+    let deposit = get_invoice_deposit_account(&agent, canister_id, invoice_id).await.unwrap();
+    assert_eq!(canister_id, deposit.owner);
+    let fee = get_fee(&agent, ledger).await;
+
+    // Underpayment pays for the invoice partially.
+    mint(&agent, ledger, deposit, 20_000_000 + fee).await;
+    assert_eq!(
+        InvoiceStatus::PartiallyPaid,
+        check_invoice_payment(&agent, canister_id, invoice_id).await.unwrap()
+    );
+    assert_eq!(0, get_agreement_credit(&agent, canister_id, agreement_id).await.unwrap());
+    assert_eq!(32_000_000, get_invoice_outstanding_balance(&agent, canister_id, invoice_id).await.unwrap());
+    let transfer = get_transfers(&agent, ledger).await.pop().unwrap();
+    assert_eq!(deposit, transfer.from);
+    assert_eq!(Account::from(principal), transfer.to, "deposit should be forwarded to provider");
+
+    // Overpayment is kept as credit.
+    mint(&agent, ledger, deposit, 40_000_000 + fee).await;
+    assert_eq!(InvoiceStatus::Paid, check_invoice_payment(&agent, canister_id, invoice_id).await.unwrap());
+    assert_eq!(8_000_000, get_agreement_credit(&agent, canister_id, agreement_id).await.unwrap());
+    assert_eq!(InvoiceStatus::Paid, get_invoice(&agent, canister_id, invoice_id).await.unwrap().status);
+
+    // Nothing changes when deposit is empty.
+    assert_eq!(InvoiceStatus::Paid, check_invoice_payment(&agent, canister_id, invoice_id).await.unwrap());
+    assert_eq!(8_000_000, get_agreement_credit(&agent, canister_id, agreement_id).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_pay_for_invoice_uses_agreement_credit() {
+    let (agent, canister_id) = init_agent().await;
+    let principal = agent.get_principal().unwrap();
+    let (invoice_id, agreement_id) =
+        create_mock_ledger_invoice(&agent, canister_id, 7, LateFees::default()).await;
+    let vehicle = get_invoice(&agent, canister_id, invoice_id).await.unwrap().vehicle;
+    // Predefined gas usage in 2023-07 is 265.
+    let next_id = create_predefined_invoice(&agent, canister_id, vehicle, 2023, 7).await.unwrap();
+    let ledger = mock_ledger_id();
+    let deposit = get_invoice_deposit_account(&agent, canister_id, invoice_id).await.unwrap();
+    let fee = get_fee(&agent, ledger).await;
+    mint(&agent, ledger, deposit, 60_000_000 + fee).await;
+    assert_eq!(InvoiceStatus::Paid, check_invoice_payment(&agent, canister_id, invoice_id).await.unwrap());
+    assert_eq!(8_000_000, get_agreement_credit(&agent, canister_id, agreement_id).await.unwrap());
+
+    // Customer is charged only for the balance which credit doesn't cover.
+    mint(&agent, ledger, Account::from(principal), 200_000_000).await;
+    pay_for_invoice(&agent, canister_id, next_id, None).await.unwrap();
+    let transfer = get_transfers(&agent, ledger).await.pop().unwrap();
+    assert_eq!(Nat::from(124_500_000u128), transfer.amount);
+    assert_eq!(0, get_agreement_credit(&agent, canister_id, agreement_id).await.unwrap());
+    let payments = get_invoice_payments(&agent, canister_id, next_id).await.unwrap();
+    let amounts: Vec<u128> = payments.iter().map(|payment| payment.amount).collect();
+    assert_eq!(vec![8_000_000, 124_500_000], amounts);
+    assert_eq!(InvoiceStatus::Paid, get_invoice(&agent, canister_id, next_id).await.unwrap().status);
+}
+
+#[tokio::test]
+async fn test_recover_deposit_forward_with_lost_reply() {
+    let (agent, canister_id) = init_agent().await;
+    let (invoice_id, agreement_id) =
+        create_mock_ledger_invoice(&agent, canister_id, 7, LateFees::default()).await;
+    let ledger = mock_ledger_id();
+    let deposit = get_invoice_deposit_account(&agent, canister_id, invoice_id).await.unwrap();
+    let fee = get_fee(&agent, ledger).await;
+    mint(&agent, ledger, deposit, 60_000_000 + fee).await;
+
+    // Forward is executed, but the canister doesn't know it.
+    set_lose_replies(&agent, ledger, true).await;
+    let result = check_invoice_payment(&agent, canister_id, invoice_id).await;
+    set_lose_replies(&agent, ledger, false).await;
+    assert_eq!(Error::Internal, result.unwrap_err());
+    let transfers = get_transfers(&agent, ledger).await.len();
+    assert_eq!(0, get_agreement_credit(&agent, canister_id, agreement_id).await.unwrap());
+
+    // Pending forward is resubmitted and the ledger recognizes it as a duplicate.
+    assert_eq!(InvoiceStatus::Paid, check_invoice_payment(&agent, canister_id, invoice_id).await.unwrap());
+    assert_eq!(transfers, get_transfers(&agent, ledger).await.len(), "deposit should be forwarded once");
+    assert_eq!(8_000_000, get_agreement_credit(&agent, canister_id, agreement_id).await.unwrap());
+    assert!(get_pending_forwards(&agent, canister_id).await.unwrap().iter().all(|(id, _)| *id != invoice_id));
+}
+
+#[tokio::test]
+async fn test_expired_deposit_forward_needs_reconciliation() {
+    let (agent, canister_id) = init_agent().await;
+    let (invoice_id, agreement_id) =
+        create_mock_ledger_invoice(&agent, canister_id, 7, LateFees::default()).await;
+    let ledger = mock_ledger_id();
+    let deposit = get_invoice_deposit_account(&agent, canister_id, invoice_id).await.unwrap();
+    let fee = get_fee(&agent, ledger).await;
+    mint(&agent, ledger, deposit, 60_000_000 + fee).await;
+
+    set_lose_replies(&agent, ledger, true).await;
+    let result = check_invoice_payment(&agent, canister_id, invoice_id).await;
+    set_lose_replies(&agent, ledger, false).await;
+    assert_eq!(Error::Internal, result.unwrap_err());
+    let transfers = get_transfers(&agent, ledger).await.len();
+
+    // Ledger wouldn't recognize the resubmitted forward as a duplicate anymore.
+    update_canister(&agent, canister_id, "expire_pending_payments").await;
+    let result = check_invoice_payment(&agent, canister_id, invoice_id).await;
+    assert_eq!(Error::PaymentNeedsReconciliation, result.unwrap_err());
+    assert_eq!(transfers, get_transfers(&agent, ledger).await.len());
+    let forwards = get_pending_forwards(&agent, canister_id).await.unwrap();
+    let (_, forward) = forwards.iter().find(|(id, _)| *id == invoice_id).unwrap();
+    assert_eq!(60_000_000, forward.amount);
+
+    // Admin finds the forward in the ledger.
+    reconcile_deposit_forward(&agent, canister_id, invoice_id, true).await.unwrap();
+    assert_eq!(InvoiceStatus::Paid, get_invoice(&agent, canister_id, invoice_id).await.unwrap().status);
+    assert_eq!(8_000_000, get_agreement_credit(&agent, canister_id, agreement_id).await.unwrap());
+    assert_eq!(InvoiceStatus::Paid, check_invoice_payment(&agent, canister_id, invoice_id).await.unwrap());
+    assert_eq!(transfers, get_transfers(&agent, ledger).await.len());
+}
+
+#[tokio::test]
+async fn test_overdue_invoice_turns_vehicle_off() {
+    let (agent, canister_id) = init_agent().await;
+    let principal = agent.get_principal().unwrap();
+    let (invoice_id, _) = create_mock_ledger_invoice(&agent, canister_id, 0, LateFees::default()).await;
+    let vehicle = get_invoice(&agent, canister_id, invoice_id).await.unwrap().vehicle;
+    assert!(get_vehicle(&agent, canister_id, vehicle).await.unwrap().on_off);
+
+    check_overdue_invoices(&agent, canister_id).await;
+    assert!(!get_vehicle(&agent, canister_id, vehicle).await.unwrap().on_off);
+    let change = get_vehicle_on_off_history(&agent, canister_id, vehicle).await.unwrap().pop().unwrap();
+    assert!(!change.on_off);
+    assert_eq!(OnOffReason::InvoiceOverdue(invoice_id), change.reason);
+    assert_eq!(canister_id, change.changed_by, "vehicle should be turned off by the canister");
+
+    mint(&agent, mock_ledger_id(), Account::from(principal), 100_000_000).await;
+    pay_for_invoice(&agent, canister_id, invoice_id, None).await.unwrap();
+    assert!(get_vehicle(&agent, canister_id, vehicle).await.unwrap().on_off);
+    let change = get_vehicle_on_off_history(&agent, canister_id, vehicle).await.unwrap().pop().unwrap();
+    assert!(change.on_off);
+    assert_eq!(OnOffReason::InvoicePaid(invoice_id), change.reason);
+
+    // Paid invoice is not overdue anymore.
+    check_overdue_invoices(&agent, canister_id).await;
+    assert!(get_vehicle(&agent, canister_id, vehicle).await.unwrap().on_off);
+}
+
+#[tokio::test]
+async fn test_relinked_vehicle_stays_off_while_invoice_is_overdue() {
+    let (agent, canister_id) = init_agent().await;
+    let principal = agent.get_principal().unwrap();
+    let (invoice_id, agreement_id) =
+        create_mock_ledger_invoice(&agent, canister_id, 0, LateFees::default()).await;
+    let vehicle = get_invoice(&agent, canister_id, invoice_id).await.unwrap().vehicle;
+    check_overdue_invoices(&agent, canister_id).await;
+    assert!(!get_vehicle(&agent, canister_id, vehicle).await.unwrap().on_off);
+
+    unlink_vehicle(&agent, canister_id, vehicle).await.unwrap();
+    link_vehicle(&agent, canister_id, vehicle, agreement_id).await.unwrap();
+    assert!(!get_vehicle(&agent, canister_id, vehicle).await.unwrap().on_off);
+    check_overdue_invoices(&agent, canister_id).await;
+    assert!(!get_vehicle(&agent, canister_id, vehicle).await.unwrap().on_off);
+
+    mint(&agent, mock_ledger_id(), Account::from(principal), 100_000_000).await;
+    pay_for_invoice(&agent, canister_id, invoice_id, None).await.unwrap();
+    assert!(get_vehicle(&agent, canister_id, vehicle).await.unwrap().on_off);
+}
+
+#[tokio::test]
+async fn test_invoice_within_grace_period_is_not_overdue() {
+    let (agent, canister_id) = init_agent().await;
+    let (invoice_id, _) = create_mock_ledger_invoice(&agent, canister_id, 1, LateFees::default()).await;
+    let vehicle = get_invoice(&agent, canister_id, invoice_id).await.unwrap().vehicle;
+
+    check_overdue_invoices(&agent, canister_id).await;
+    assert!(get_vehicle(&agent, canister_id, vehicle).await.unwrap().on_off);
+}
+
+#[tokio::test]
+async fn test_invoice_discount_and_credit_note() {
+    let (agent, canister_id) = init_agent().await;
+    let principal = agent.get_principal().unwrap();
+    let (invoice_id, agreement_id) =
+        create_mock_ledger_invoice(&agent, canister_id, 7, LateFees::default()).await;
+    let ledger = mock_ledger_id();
+
+    let result = adjust_invoice(&agent, canister_id, invoice_id, AdjustmentKind::Discount, 60_000_000).await;
+    assert_eq!(Error::InvalidData, result.unwrap_err(), "discount cannot exceed the invoice total");
+    let result = adjust_invoice(&agent, canister_id, invoice_id, AdjustmentKind::LateFee, 1_000_000).await;
+    assert_eq!(Error::InvalidData, result.unwrap_err(), "late fees are charged only by the canister");
+
+    adjust_invoice(&agent, canister_id, invoice_id, AdjustmentKind::Discount, 2_000_000).await.unwrap();
+    adjust_invoice(&agent, canister_id, invoice_id, AdjustmentKind::CreditNote, 10_000_000)
+        .await
+        .unwrap();
+    let adjustments = get_invoice_adjustments(&agent, canister_id, invoice_id).await.unwrap();
+    assert_eq!(2, adjustments.len());
+    assert_eq!(AdjustmentKind::Discount, adjustments[0].kind);
+    assert_eq!(principal, adjustments[0].issued_by);
+    assert_eq!(40_000_000, get_invoice_outstanding_balance(&agent, canister_id, invoice_id).await.unwrap());
+
+    mint(&agent, ledger, Account::from(principal), 100_000_000).await;
+    pay_for_invoice(&agent, canister_id, invoice_id, None).await.unwrap();
+    let transfer = get_transfers(&agent, ledger).await.pop().unwrap();
+    assert_eq!(Nat::from(40_000_000u128), transfer.amount, "adjusted balance should be paid");
+    assert_eq!(0, get_invoice_outstanding_balance(&agent, canister_id, invoice_id).await.unwrap());
+
+    // Credit note for paid invoice becomes agreement credit.
+    let result = adjust_invoice(&agent, canister_id, invoice_id, AdjustmentKind::Discount, 1_000_000).await;
+    assert_eq!(Error::InvalidData, result.unwrap_err());
+    adjust_invoice(&agent, canister_id, invoice_id, AdjustmentKind::CreditNote, 5_000_000)
+        .await
+        .unwrap();
+    assert_eq!(5_000_000, get_agreement_credit(&agent, canister_id, agreement_id).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_overdue_invoice_late_fee() {
+    let (agent, canister_id) = init_agent().await;
+    let late_fees = LateFees {
+        fixed: Some("1.5".to_string()),
+        daily_interest_percent: Some("0.1".to_string()),
+    };
+    let (invoice_id, _) = create_mock_ledger_invoice(&agent, canister_id, 7, late_fees).await;
+
+    check_overdue_invoices(&agent, canister_id).await;
+    let adjustments = get_invoice_adjustments(&agent, canister_id, invoice_id).await.unwrap();
+    assert_eq!(1, adjustments.len(), "interest should not be charged before a full day passes");
+    assert_eq!(AdjustmentKind::LateFee, adjustments[0].kind);
+    assert_eq!(1_500_000, adjustments[0].amount);
+    assert_eq!(canister_id, adjustments[0].issued_by);
+    assert_eq!(53_500_000, get_invoice_outstanding_balance(&agent, canister_id, invoice_id).await.unwrap());
+
+    // Fixed fee is charged once.
+    check_overdue_invoices(&agent, canister_id).await;
+    assert_eq!(1, get_invoice_adjustments(&agent, canister_id, invoice_id).await.unwrap().len());
+}
+
+#[tokio::test]
+async fn test_invoice_dispute() {
+    let (agent, canister_id) = init_agent().await;
+    let (invoice_id, _) = create_mock_ledger_invoice(&agent, canister_id, 0, LateFees::default()).await;
+    let vehicle = get_invoice(&agent, canister_id, invoice_id).await.unwrap().vehicle;
+    check_overdue_invoices(&agent, canister_id).await;
+    assert!(!get_vehicle(&agent, canister_id, vehicle).await.unwrap().on_off);
+
+    // Disputed invoice is not enforced.
+    update_dispute(&agent, canister_id, "dispute_invoice", invoice_id, "Wrong usage").await.unwrap();
+    let result = update_dispute(&agent, canister_id, "dispute_invoice", invoice_id, "Again").await;
+    assert_eq!(Error::AlreadyExists, result.unwrap_err());
+    assert_eq!(InvoiceStatus::Disputed, get_invoice(&agent, canister_id, invoice_id).await.unwrap().status);
+    let change = get_vehicle_on_off_history(&agent, canister_id, vehicle).await.unwrap().pop().unwrap();
+    assert_eq!(OnOffReason::InvoiceDisputed(invoice_id), change.reason);
+    check_overdue_invoices(&agent, canister_id).await;
+    assert!(get_vehicle(&agent, canister_id, vehicle).await.unwrap().on_off);
+    let result = pay_for_invoice(&agent, canister_id, invoice_id, None).await;
+    assert_eq!(Error::InvalidData, result.unwrap_err(), "disputed invoice cannot be paid");
+
+    update_dispute(&agent, canister_id, "reject_dispute", invoice_id, "Usage is correct").await.unwrap();
+    assert_eq!(InvoiceStatus::Unpaid, get_invoice(&agent, canister_id, invoice_id).await.unwrap().status);
+    update_dispute(&agent, canister_id, "escalate_dispute", invoice_id, "Please check").await.unwrap();
+    assert_eq!(InvoiceStatus::Disputed, get_invoice(&agent, canister_id, invoice_id).await.unwrap().status);
+
+    resolve_dispute(&agent, canister_id, invoice_id, 2_000_000).await.unwrap();
+    let dispute = get_invoice_dispute(&agent, canister_id, invoice_id).await.unwrap();
+    assert_eq!(DisputeState::Resolved, dispute.state);
+    let actions: Vec<DisputeAction> = dispute.thread.into_iter().map(|entry| entry.action).collect();
+    assert_eq!(
+        vec![
+            DisputeAction::Open,
+            DisputeAction::Reject,
+            DisputeAction::Escalate,
+            DisputeAction::Resolve { credit: 2_000_000 }
+        ],
+        actions
+    );
+    assert_eq!(InvoiceStatus::Unpaid, get_invoice(&agent, canister_id, invoice_id).await.unwrap().status);
+    assert_eq!(50_000_000, get_invoice_outstanding_balance(&agent, canister_id, invoice_id).await.unwrap());
+    let result = update_dispute(&agent, canister_id, "escalate_dispute", invoice_id, "Again").await;
+    assert_eq!(Error::InvalidData, result.unwrap_err(), "resolved dispute cannot be escalated");
+}
+
+// Creates invoice for predefined telemetry under agreement which is paid with mock ledger token.
+// Returns invoice and agreement IDs, the invoice costs 52 tokens and it is due right away.
+async fn create_mock_ledger_invoice(
+    agent: &Agent,
+    canister_id: Principal,
+    grace_period_days: u32,
+    late_fees: LateFees,
+) -> (u128, u128) {
+    let principal = agent.get_principal().unwrap();
+    let vehicle = fill_predefined_telemetry(agent, canister_id, principal, principal).await;
+    let ledger = mock_ledger_id();
+
+    let conditions = AgreementConditions {
+        prices: vec![PriceItem {
+            t_type: TelemetryType::Gas,
+            price: "0.5".to_string(),
+        }],
+        fees: Vec::new(),
+        daily_minimum: None,
+        billing_cycle: BillingCycle::default(),
+        starts_at: None,
+        ends_at: None,
+        token: PaymentToken {
+            ledger,
+            symbol: "TEST".to_string(),
+            decimals: 6,
+        },
+        payment_due_days: 0,
+        grace_period_days,
+        late_fees,
+    };
+    let agreement_id = create_agreement(agent, canister_id, principal, conditions).await.unwrap();
+    update_agreement(agent, canister_id, "sign_agreement", agreement_id).await.unwrap();
+    transfer_vehicle(agent, canister_id, vehicle, agreement_id).await.unwrap();
+
+    // Predefined gas usage in 2024-05 is 104.
+    let invoice_id = create_predefined_invoice(agent, canister_id, vehicle, 2024, 5).await.unwrap();
+    (invoice_id, agreement_id)
+}
+
+// Memo of the invoice payment: invoice id and sequence number of the payment.
+fn payment_memo(invoice_id: u128, seq: u32) -> Vec<u8> {
+    let mut memo = invoice_id.to_be_bytes().to_vec();
+    memo.extend_from_slice(&seq.to_be_bytes());
+    memo
+}
+
+async fn create_agreement(
+    agent: &Agent,
+    canister_id: Principal,
+    vh_customer: Principal,
+    conditions: AgreementConditions,
+) -> VTSResult<u128> {
+    let response = agent
+        .update(&canister_id, "create_agreement")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&"payment".to_string(), &vh_customer, &conditions, &None::<Principal>).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<u128>).unwrap()
+}
+
+// Call agreement method which has agreement id and optional organization as arguments.
+async fn update_agreement(
+    agent: &Agent,
+    canister_id: Principal,
+    method: &str,
+    agreement_id: u128,
+) -> VTSResult<()> {
+    let response = agent
+        .update(&canister_id, method)
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&agreement_id, &None::<Principal>).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<()>).unwrap()
+}
+
+async fn transfer_vehicle(
+    agent: &Agent,
+    canister_id: Principal,
+    vehicle: Principal,
+    agreement_id: u128,
+) -> VTSResult<()> {
+    let response = agent
+        .update(&canister_id, "transfer_vehicle")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&vehicle, &agreement_id, &None::<Principal>).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<()>).unwrap()
+}
+
+async fn link_vehicle(
+    agent: &Agent,
+    canister_id: Principal,
+    vehicle: Principal,
+    agreement_id: u128,
+) -> VTSResult<()> {
+    let response = agent
+        .update(&canister_id, "link_vehicle")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&agreement_id, &vehicle, &None::<Principal>).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<()>).unwrap()
+}
+
+async fn unlink_vehicle(agent: &Agent, canister_id: Principal, vehicle: Principal) -> VTSResult<()> {
+    let response = agent
+        .update(&canister_id, "unlink_vehicle")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&vehicle, &None::<Principal>).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<()>).unwrap()
+}
+
+async fn create_predefined_invoice(
+    agent: &Agent,
+    canister_id: Principal,
+    vehicle: Principal,
+    year: i32,
+    month: u8,
+) -> VTSResult<u128> {
+    let response = agent
+        .update(&canister_id, "create_predefined_invoice")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&vehicle, &year, &month).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<u128>).unwrap()
+}
+
+async fn get_invoice(agent: &Agent, canister_id: Principal, invoice_id: u128) -> VTSResult<Invoice> {
+    let response = agent
+        .query(&canister_id, "get_invoice")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&invoice_id).unwrap())
+        .call()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<Invoice>).unwrap()
+}
+
+async fn pay_for_invoice(
+    agent: &Agent,
+    canister_id: Principal,
+    invoice_id: u128,
+    amount: Option<u128>,
+) -> VTSResult<()> {
+    let response = agent
+        .update(&canister_id, "pay_for_invoice")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&invoice_id, &amount, &None::<Principal>).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<()>).unwrap()
+}
+
+async fn get_invoice_payments(
+    agent: &Agent,
+    canister_id: Principal,
+    invoice_id: u128,
+) -> VTSResult<Vec<InvoicePayment>> {
+    let response = agent
+        .query(&canister_id, "get_invoice_payments")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&invoice_id).unwrap())
+        .call()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<Vec<InvoicePayment>>).unwrap()
+}
+
+// Call dispute method which has invoice id, message and optional organization as arguments.
+async fn update_dispute(
+    agent: &Agent,
+    canister_id: Principal,
+    method: &str,
+    invoice_id: u128,
+    message: &str,
+) -> VTSResult<()> {
+    let response = agent
+        .update(&canister_id, method)
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&invoice_id, &message.to_string(), &None::<Principal>).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<()>).unwrap()
+}
+
+async fn resolve_dispute(
+    agent: &Agent,
+    canister_id: Principal,
+    invoice_id: u128,
+    credit: u128,
+) -> VTSResult<()> {
+    let response = agent
+        .update(&canister_id, "resolve_dispute")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&invoice_id, &credit, &"Arbitration".to_string()).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<()>).unwrap()
+}
+
+async fn get_invoice_dispute(agent: &Agent, canister_id: Principal, invoice_id: u128) -> VTSResult<Dispute> {
+    let response = agent
+        .query(&canister_id, "get_invoice_dispute")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&invoice_id).unwrap())
+        .call()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<Dispute>).unwrap()
+}
+
+async fn adjust_invoice(
+    agent: &Agent,
+    canister_id: Principal,
+    invoice_id: u128,
+    kind: AdjustmentKind,
+    amount: u128,
+) -> VTSResult<()> {
+    let response = agent
+        .update(&canister_id, "adjust_invoice")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&invoice_id, &kind, &amount, &"test".to_string(), &None::<Principal>).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<()>).unwrap()
+}
+
+async fn get_invoice_adjustments(
+    agent: &Agent,
+    canister_id: Principal,
+    invoice_id: u128,
+) -> VTSResult<Vec<InvoiceAdjustment>> {
+    let response = agent
+        .query(&canister_id, "get_invoice_adjustments")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&invoice_id).unwrap())
+        .call()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<Vec<InvoiceAdjustment>>).unwrap()
+}
+
+async fn get_invoice_outstanding_balance(
+    agent: &Agent,
+    canister_id: Principal,
+    invoice_id: u128,
+) -> VTSResult<u128> {
+    let response = agent
+        .query(&canister_id, "get_invoice_outstanding_balance")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&invoice_id).unwrap())
+        .call()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<u128>).unwrap()
+}
+
+async fn check_overdue_invoices(agent: &Agent, canister_id: Principal) {
+    let response = agent
+        .update(&canister_id, "check_overdue_invoices")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&()).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<()>).unwrap().unwrap()
+}
+
+async fn get_vehicle(agent: &Agent, canister_id: Principal, vehicle: Principal) -> VTSResult<VehicleSummary> {
+    let response = agent
+        .query(&canister_id, "get_vehicle")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&vehicle).unwrap())
+        .call()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<VehicleSummary>).unwrap()
+}
+
+async fn get_vehicle_on_off_history(
+    agent: &Agent,
+    canister_id: Principal,
+    vehicle: Principal,
+) -> VTSResult<Vec<OnOffChange>> {
+    let response = agent
+        .query(&canister_id, "get_vehicle_on_off_history")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&vehicle).unwrap())
+        .call()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<Vec<OnOffChange>>).unwrap()
+}
+
+async fn get_invoice_deposit_account(
+    agent: &Agent,
+    canister_id: Principal,
+    invoice_id: u128,
+) -> VTSResult<Account> {
+    let response = agent
+        .query(&canister_id, "get_invoice_deposit_account")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&invoice_id).unwrap())
+        .call()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<Account>).unwrap()
+}
+
+async fn check_invoice_payment(
+    agent: &Agent,
+    canister_id: Principal,
+    invoice_id: u128,
+) -> VTSResult<InvoiceStatus> {
+    let response = agent
+        .update(&canister_id, "check_invoice_payment")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&invoice_id).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<InvoiceStatus>).unwrap()
+}
+
+async fn get_agreement_credit(agent: &Agent, canister_id: Principal, agreement_id: u128) -> VTSResult<u128> {
+    let response = agent
+        .query(&canister_id, "get_agreement_credit")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&agreement_id).unwrap())
+        .call()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<u128>).unwrap()
+}
+
+async fn set_lose_replies(agent: &Agent, ledger: Principal, lose: bool) {
+    agent
+        .update(&ledger, "set_lose_replies")
+        .with_effective_canister_id(ledger)
+        .with_arg(Encode!(&lose).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+}
+
+// Call canister method which has no arguments and result.
+async fn update_canister(agent: &Agent, canister_id: Principal, method: &str) {
+    agent
+        .update(&canister_id, method)
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&()).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+}
+
+async fn get_unreconciled_payments(
+    agent: &Agent,
+    canister_id: Principal,
+) -> VTSResult<Vec<(u128, PendingPayment)>> {
+    let response = agent
+        .query(&canister_id, "get_unreconciled_payments")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&()).unwrap())
+        .call()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<Vec<(u128, PendingPayment)>>).unwrap()
+}
+
+async fn reconcile_payment(
+    agent: &Agent,
+    canister_id: Principal,
+    invoice_id: u128,
+    block_index: Option<u128>,
+) -> VTSResult<()> {
+    let response = agent
+        .update(&canister_id, "reconcile_payment")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&invoice_id, &block_index).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<()>).unwrap()
+}
+
+async fn get_pending_forwards(
+    agent: &Agent,
+    canister_id: Principal,
+) -> VTSResult<Vec<(u128, PendingForward)>> {
+    let response = agent
+        .query(&canister_id, "get_pending_forwards")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&()).unwrap())
+        .call()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<Vec<(u128, PendingForward)>>).unwrap()
+}
+
+async fn reconcile_deposit_forward(
+    agent: &Agent,
+    canister_id: Principal,
+    invoice_id: u128,
+    executed: bool,
+) -> VTSResult<()> {
+    let response = agent
+        .update(&canister_id, "reconcile_deposit_forward")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&invoice_id, &executed).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<()>).unwrap()
+}
+
+async fn mint(agent: &Agent, ledger: Principal, to: Account, amount: u128) {
+    agent
+        .update(&ledger, "mint")
+        .with_effective_canister_id(ledger)
+        .with_arg(Encode!(&to, &Nat::from(amount)).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+}
+
+async fn get_fee(agent: &Agent, ledger: Principal) -> u128 {
+    let response = agent
+        .query(&ledger, "icrc1_fee")
+        .with_effective_canister_id(ledger)
+        .with_arg(Encode!(&()).unwrap())
+        .call()
+        .await
+        .unwrap();
+    u128::try_from(Decode!(response.as_slice(), Nat).unwrap().0).unwrap()
+}
+
+async fn get_transfers(agent: &Agent, ledger: Principal) -> Vec<Transfer> {
+    let response = agent
+        .query(&ledger, "get_transfers")
+        .with_effective_canister_id(ledger)
+        .with_arg(Encode!(&()).unwrap())
+        .call()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), Vec<Transfer>).unwrap()
+}
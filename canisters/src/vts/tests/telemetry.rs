@@ -1,8 +1,14 @@
+use std::time::SystemTime;
+
 use agent::{generate_vehicle, upload_firmware};
 use candid::{Decode, Encode, Principal};
-use ic_agent::{identity::Secp256k1Identity, Identity};
-use k256::ecdsa::{signature::SignerMut, Signature};
-use vts::{AccumulatedTelemetry, TelemetryType, VTSResult};
+use ic_agent::{identity::Secp256k1Identity, Agent, Identity};
+use k256::ecdsa::{signature::SignerMut, Signature, SigningKey};
+use vts::{
+    AccumulatedTelemetry, AggregationRule, Error, SignedTelemetry, StoreTelemetryBatchResponse,
+    StoreTelemetryResponse, TelemetryGranularity, TelemetryPage, TelemetryPoint, TelemetryQuery,
+    TelemetryType, TelemetryTypeInfo, VTSResult,
+};
 
 use crate::agent::{init_agent, register_user};
 
@@ -22,6 +28,8 @@ async fn test_telemetry() {
     let telemetry = vts::StoreTelemetryRequest {
         value: 88,
         t_type: TelemetryType::Gas,
+        timestamp: now_nanos(),
+        seq: 1,
     };
     let telemetry = bincode::encode_to_vec(telemetry, bincode::config::standard()).unwrap();
     let signature: Signature = signing_key.sign(&telemetry);
@@ -35,6 +43,107 @@ async fn test_telemetry() {
         .unwrap();
 }
 
+#[tokio::test]
+async fn test_telemetry_replay() {
+    let (agent, canister_id) = init_agent().await;
+    register_user(&agent, canister_id, agent.get_principal().unwrap()).await;
+
+    let (mut signing_key, identity) = generate_vehicle();
+    let vehicle = identity.sender().unwrap();
+    let public_key = identity.public_key().unwrap();
+
+    upload_firmware(&agent, canister_id, agent.get_principal().unwrap(), public_key).await.unwrap();
+
+    let telemetry = |seq: u64, timestamp: u64| vts::StoreTelemetryRequest {
+        value: 10,
+        t_type: TelemetryType::Gas,
+        timestamp,
+        seq,
+    };
+
+    store_telemetry(&agent, canister_id, &mut signing_key, vehicle, telemetry(5, now_nanos()))
+        .await
+        .unwrap();
+
+    // The same signed payload cannot be stored twice.
+    let err = store_telemetry(&agent, canister_id, &mut signing_key, vehicle, telemetry(5, now_nanos()))
+        .await
+        .unwrap_err();
+    assert_eq!(Error::AlreadyExists, err);
+
+    // Older sequence number means duplicate which came out of order.
+    let err = store_telemetry(&agent, canister_id, &mut signing_key, vehicle, telemetry(3, now_nanos()))
+        .await
+        .unwrap_err();
+    assert_eq!(Error::AlreadyExists, err);
+
+    // Device time cannot be far in the future.
+    let next_day = now_nanos() + 86400 * 1_000_000_000;
+    let err = store_telemetry(&agent, canister_id, &mut signing_key, vehicle, telemetry(6, next_day))
+        .await
+        .unwrap_err();
+    assert_eq!(Error::InvalidData, err);
+
+    // Buffered data from the past within skew window is accepted.
+    let hour_ago = now_nanos() - 3600 * 1_000_000_000;
+    store_telemetry(&agent, canister_id, &mut signing_key, vehicle, telemetry(7, hour_ago))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_telemetry_batch() {
+    let (agent, canister_id) = init_agent().await;
+    register_user(&agent, canister_id, agent.get_principal().unwrap()).await;
+
+    let (mut signing_key, identity) = generate_vehicle();
+    let vehicle = identity.sender().unwrap();
+    let public_key = identity.public_key().unwrap();
+
+    upload_firmware(&agent, canister_id, agent.get_principal().unwrap(), public_key).await.unwrap();
+
+    let mut sign = |seq: u64| {
+        let telemetry = vts::StoreTelemetryRequest {
+            value: 10,
+            t_type: TelemetryType::Gas,
+            timestamp: now_nanos(),
+            seq,
+        };
+        let data = bincode::encode_to_vec(telemetry, bincode::config::standard()).unwrap();
+        let signature: Signature = signing_key.sign(&data);
+        SignedTelemetry {
+            vehicle,
+            data,
+            signature: signature.to_vec(),
+        }
+    };
+    let mut invalid_signature = sign(3);
+    invalid_signature.data = sign(4).data;
+    let batch = vec![sign(1), sign(2), sign(2), invalid_signature, sign(5)];
+
+    let response = agent
+        .update(&canister_id, "store_telemetry_batch")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&batch).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    let response = Decode!(response.as_slice(), VTSResult<StoreTelemetryBatchResponse>).unwrap().unwrap();
+
+    // Every item is processed separately so rejected items don't affect others.
+    assert_eq!(
+        vec![
+            Ok(()),
+            Ok(()),
+            Err(Error::AlreadyExists),
+            Err(Error::InvalidSignature),
+            Ok(())
+        ],
+        response.results
+    );
+    assert_eq!(Some(&StoreTelemetryResponse::On), response.vehicles.get(&vehicle));
+}
+
 #[tokio::test]
 async fn test_get_aggregated_data() {
     let (agent, canister_id) = init_agent().await;
@@ -45,11 +154,12 @@ async fn test_get_aggregated_data() {
     let vehicle_identity = Secp256k1Identity::from_private_key(vehicle_secret_key);
     let vehicle_public_key = hex::encode(vehicle_identity.public_key().unwrap());
     let vehicle = vehicle_identity.sender().unwrap();
+    let principal = agent.get_principal().unwrap();
 
     agent
         .update(&canister_id, "fill_predefined_telemetry")
         .with_effective_canister_id(canister_id)
-        .with_arg(Encode!(&Principal::anonymous(), &Principal::anonymous(), &vehicle_public_key).unwrap())
+        .with_arg(Encode!(&principal, &principal, &vehicle_public_key).unwrap())
         .call_and_wait()
         .await
         .unwrap();
@@ -83,3 +193,219 @@ async fn test_get_aggregated_data() {
         346
     );
 }
+
+#[tokio::test]
+async fn test_get_telemetry_raw() {
+    let (agent, canister_id) = init_agent().await;
+    register_user(&agent, canister_id, agent.get_principal().unwrap()).await;
+
+    let (mut signing_key, identity) = generate_vehicle();
+    let vehicle = identity.sender().unwrap();
+    let public_key = identity.public_key().unwrap();
+
+    upload_firmware(&agent, canister_id, agent.get_principal().unwrap(), public_key).await.unwrap();
+
+    let from = now_nanos();
+    let readings = [
+        (TelemetryType::Gas, 10),
+        (TelemetryType::Distance, 7),
+        (TelemetryType::Gas, 20),
+        (TelemetryType::Gas, 30),
+    ];
+    for (seq, (t_type, value)) in readings.into_iter().enumerate() {
+        let telemetry = vts::StoreTelemetryRequest {
+            value,
+            t_type,
+            timestamp: now_nanos(),
+            seq: seq as u64,
+        };
+        store_telemetry(&agent, canister_id, &mut signing_key, vehicle, telemetry).await.unwrap();
+    }
+    let to = now_nanos() + 1;
+
+    let mut query = TelemetryQuery {
+        vehicle,
+        t_type: TelemetryType::Gas,
+        from,
+        to,
+        granularity: TelemetryGranularity::Raw,
+        cursor: None,
+        limit: Some(2),
+    };
+    let page = get_telemetry(&agent, canister_id, query).await.unwrap();
+    assert_eq!(vec![10, 20], page.points.iter().map(|point| point.value).collect::<Vec<u128>>());
+    assert!(page.next.is_some());
+
+    query.cursor = page.next;
+    let page = get_telemetry(&agent, canister_id, query).await.unwrap();
+    assert_eq!(vec![30], page.points.iter().map(|point| point.value).collect::<Vec<u128>>());
+    assert!(page.next.is_none());
+
+    query.from = to;
+    query.to = from;
+    assert_eq!(Error::InvalidData, get_telemetry(&agent, canister_id, query).await.unwrap_err());
+}
+
+#[tokio::test]
+async fn test_get_telemetry_aggregated() {
+    let (agent, canister_id) = init_agent().await;
+    register_user(&agent, canister_id, agent.get_principal().unwrap()).await;
+    let mut rng = rand::thread_rng();
+    let vehicle_secret_key = k256::SecretKey::random(&mut rng);
+    let vehicle_identity = Secp256k1Identity::from_private_key(vehicle_secret_key);
+    let vehicle_public_key = hex::encode(vehicle_identity.public_key().unwrap());
+    let vehicle = vehicle_identity.sender().unwrap();
+    let principal = agent.get_principal().unwrap();
+
+    agent
+        .update(&canister_id, "fill_predefined_telemetry")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&principal, &principal, &vehicle_public_key).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    agent
+        .update(&canister_id, "accumulate_telemetry_data")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&()).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+
+    let mut query = TelemetryQuery {
+        vehicle,
+        t_type: TelemetryType::Gas,
+        from: 0,
+        to: u64::MAX,
+        granularity: TelemetryGranularity::Year,
+        cursor: None,
+        limit: Some(1),
+    };
+    let page = get_telemetry(&agent, canister_id, query).await.unwrap();
+    // 2023-01-01T00:00:00Z as agreement time zone is UTC.
+    let point = TelemetryPoint {
+        timestamp: 1_672_531_200_000_000_000,
+        value: 361,
+    };
+    assert_eq!(vec![point], page.points);
+
+    query.cursor = page.next;
+    let page = get_telemetry(&agent, canister_id, query).await.unwrap();
+    assert_eq!(vec![744], page.points.iter().map(|point| point.value).collect::<Vec<u128>>());
+    assert!(page.next.is_none());
+
+    // Points are filtered by the time range: 2024-06-01..2024-08-01.
+    query.granularity = TelemetryGranularity::Month;
+    query.from = 1_717_200_000_000_000_000;
+    query.to = 1_722_470_400_000_000_000;
+    query.cursor = None;
+    query.limit = None;
+    let page = get_telemetry(&agent, canister_id, query).await.unwrap();
+    assert_eq!(vec![294, 346], page.points.iter().map(|point| point.value).collect::<Vec<u128>>());
+}
+
+#[tokio::test]
+async fn test_aggregation_rules() {
+    let (agent, canister_id) = init_agent().await;
+    register_user(&agent, canister_id, agent.get_principal().unwrap()).await;
+
+    let (mut signing_key, identity) = generate_vehicle();
+    let vehicle = identity.sender().unwrap();
+    let public_key = identity.public_key().unwrap();
+
+    upload_firmware(&agent, canister_id, agent.get_principal().unwrap(), public_key).await.unwrap();
+
+    for (seq, (value, t_type)) in [
+        (10, TelemetryType::Gas),
+        (15, TelemetryType::Gas),
+        (1200, TelemetryType::Distance),
+        (1250, TelemetryType::Distance),
+        (80, TelemetryType::BatteryCharge),
+        (65, TelemetryType::BatteryCharge),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let telemetry = vts::StoreTelemetryRequest {
+            value,
+            t_type,
+            timestamp: now_nanos(),
+            seq: seq as u64,
+        };
+        store_telemetry(&agent, canister_id, &mut signing_key, vehicle, telemetry).await.unwrap();
+    }
+
+    agent
+        .update(&canister_id, "accumulate_telemetry_data")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&()).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+
+    let response: Vec<u8> = agent
+        .query(&canister_id, "get_aggregated_data")
+        .with_arg(Encode!(&vehicle).unwrap())
+        .call()
+        .await
+        .unwrap();
+    let aggregated_data = Decode!(response.as_slice(), VTSResult<AccumulatedTelemetry>).unwrap().unwrap();
+
+    let yearly_value =
+        |t_type: TelemetryType| aggregated_data.get(&t_type).unwrap().values().next().unwrap().value;
+    assert_eq!(25, yearly_value(TelemetryType::Gas), "gas should be summed");
+    assert_eq!(1250, yearly_value(TelemetryType::Distance), "odometer should not be summed");
+    assert_eq!(65, yearly_value(TelemetryType::BatteryCharge), "battery charge should be the last value");
+}
+
+#[tokio::test]
+async fn test_get_telemetry_types() {
+    let (agent, canister_id) = init_agent().await;
+
+    let response: Vec<u8> = agent
+        .query(&canister_id, "get_telemetry_types")
+        .with_arg(Encode!(&()).unwrap())
+        .call()
+        .await
+        .unwrap();
+    let types = Decode!(response.as_slice(), Vec<TelemetryTypeInfo>).unwrap();
+
+    assert_eq!(TelemetryType::ALL.len(), types.len());
+    let distance = types.iter().find(|info| info.t_type == TelemetryType::Distance).unwrap();
+    assert_eq!("km", distance.unit);
+    assert_eq!(AggregationRule::Max, distance.aggregation_rule);
+}
+
+async fn store_telemetry(
+    agent: &Agent,
+    canister_id: Principal,
+    signing_key: &mut SigningKey,
+    vehicle: Principal,
+    telemetry: vts::StoreTelemetryRequest,
+) -> VTSResult<StoreTelemetryResponse> {
+    let telemetry = bincode::encode_to_vec(telemetry, bincode::config::standard()).unwrap();
+    let signature: Signature = signing_key.sign(&telemetry);
+    let signature = signature.to_vec();
+    let response = agent
+        .update(&canister_id, "store_telemetry")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&vehicle, &telemetry, &signature).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(response.as_slice(), VTSResult<StoreTelemetryResponse>).unwrap()
+}
+
+async fn get_telemetry(
+    agent: &Agent,
+    canister_id: Principal,
+    query: TelemetryQuery,
+) -> VTSResult<TelemetryPage> {
+    let response =
+        agent.query(&canister_id, "get_telemetry").with_arg(Encode!(&query).unwrap()).call().await.unwrap();
+    Decode!(response.as_slice(), VTSResult<TelemetryPage>).unwrap()
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos() as u64
+}
@@ -0,0 +1,227 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
+use ic_agent::{Agent, Identity};
+use serde::Serialize;
+use vts::{
+    AgreementConditions, BillingCycle, FirmwareChunk, Invoice, InvoiceStatus, LateFees, PaymentToken,
+    PriceItem, TelemetryType, VTSResult,
+};
+
+use crate::agent::{fill_predefined_telemetry, generate_vehicle, init_unprivileged_agent, upload_firmware};
+
+mod agent;
+
+// Built by `make build_baseline` from the release before records were versioned.
+const BASELINE_WASM: &str = "../../../target/vts_baseline.wasm.gz";
+const CURRENT_WASM: &str = "../../../target/wasm32-unknown-unknown/release/vts.wasm.gz";
+
+#[derive(CandidType)]
+struct CreateCanisterArgs {
+    amount: Option<Nat>,
+    settings: Option<CanisterSettings>,
+}
+
+#[derive(CandidType)]
+struct CanisterSettings {
+    controllers: Option<Vec<Principal>>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct CreateCanisterResult {
+    canister_id: Principal,
+}
+
+#[derive(CandidType)]
+struct InstallCodeArgs {
+    mode: InstallMode,
+    canister_id: Principal,
+    wasm_module: Vec<u8>,
+    arg: Vec<u8>,
+}
+
+#[derive(CandidType, Serialize)]
+enum InstallMode {
+    #[serde(rename = "install")]
+    Install,
+    #[serde(rename = "upgrade")]
+    Upgrade(Option<UpgradeOptions>),
+}
+
+#[derive(CandidType, Serialize)]
+struct UpgradeOptions {
+    skip_pre_upgrade: Option<bool>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Agreement {
+    id: u128,
+    name: String,
+}
+
+#[tokio::test]
+async fn test_upgrade_from_baseline() {
+    let (agent, vts_id) = init_unprivileged_agent().await;
+    let principal = agent.get_principal().unwrap();
+    let canister_id = create_canister(&agent, vts_id).await;
+    install_code(&agent, canister_id, InstallMode::Install, BASELINE_WASM).await;
+
+    // Records are created with the baseline API.
+    let res = agent
+        .update(&canister_id, "add_admin")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&principal).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(res.as_slice(), VTSResult<()>).unwrap().unwrap();
+    // Agreements 1 and 2 and invoices 1 and 2 of the vehicle.
+    let vehicle = fill_predefined_telemetry(&agent, canister_id, principal, principal).await;
+    let res = agent
+        .update(&canister_id, "create_agreement")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&"Baseline agreement".to_string(), &principal, &"2.5".to_string()).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    assert_eq!(3, Decode!(res.as_slice(), VTSResult<u128>).unwrap().unwrap());
+    let (_, identity) = generate_vehicle();
+    let public_key = identity.public_key().unwrap();
+    upload_firmware(&agent, canister_id, principal, public_key).await.unwrap();
+
+    install_code(&agent, canister_id, InstallMode::Upgrade(None), CURRENT_WASM).await;
+
+    // Records are migrated.
+    assert_eq!("Solar Energy GmbH", get_agreement(&agent, canister_id, 1).await.unwrap().name);
+    assert_eq!("Baseline agreement", get_agreement(&agent, canister_id, 3).await.unwrap().name);
+    let invoice = get_invoice(&agent, canister_id, 1).await.unwrap();
+    assert_eq!(InvoiceStatus::Paid, invoice.status);
+    assert_eq!(67_000_000_000, invoice.total_cost);
+    let invoice = get_invoice(&agent, canister_id, 2).await.unwrap();
+    assert_eq!(InvoiceStatus::Unpaid, invoice.status);
+    assert_eq!(23_000_000_000, invoice.total_cost);
+    assert_eq!(vehicle, invoice.vehicle);
+    let chunk = get_firmware(&agent, canister_id, identity.sender().unwrap()).await.unwrap();
+    assert_eq!(vec![0, 1, 2], chunk.data);
+    assert_eq!(3, chunk.total_size);
+
+    // Counters continue from the existing records.
+    let agreement_id = create_agreement(&agent, canister_id, principal).await.unwrap();
+    assert_eq!(4, agreement_id);
+    assert_eq!(agreement_id, get_agreement(&agent, canister_id, agreement_id).await.unwrap().id);
+    assert_eq!("Baseline agreement", get_agreement(&agent, canister_id, 3).await.unwrap().name);
+    let invoice_id = create_predefined_invoice(&agent, canister_id, vehicle, 2024, 5).await.unwrap();
+    assert_eq!(3, invoice_id);
+    // Migrated invoices are found by their period.
+    assert_eq!(1, create_predefined_invoice(&agent, canister_id, vehicle, 2024, 6).await.unwrap());
+    assert_eq!(67_000_000_000, get_invoice(&agent, canister_id, 1).await.unwrap().total_cost);
+    assert_eq!(23_000_000_000, get_invoice(&agent, canister_id, 2).await.unwrap().total_cost);
+}
+
+async fn create_canister(agent: &Agent, effective_canister_id: Principal) -> Principal {
+    let args = CreateCanisterArgs {
+        amount: None,
+        settings: None,
+    };
+    let res = agent
+        .update(&Principal::management_canister(), "provisional_create_canister_with_cycles")
+        .with_effective_canister_id(effective_canister_id)
+        .with_arg(Encode!(&args).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(res.as_slice(), CreateCanisterResult).unwrap().canister_id
+}
+
+async fn install_code(agent: &Agent, canister_id: Principal, mode: InstallMode, wasm: &str) {
+    let args = InstallCodeArgs {
+        mode,
+        canister_id,
+        wasm_module: std::fs::read(wasm).unwrap(),
+        arg: Encode!(&()).unwrap(),
+    };
+    agent
+        .update(&Principal::management_canister(), "install_code")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&args).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+}
+
+async fn get_agreement(agent: &Agent, canister_id: Principal, agreement_id: u128) -> VTSResult<Agreement> {
+    let res = agent
+        .query(&canister_id, "get_agreement")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&agreement_id).unwrap())
+        .call()
+        .await
+        .unwrap();
+    Decode!(res.as_slice(), VTSResult<Agreement>).unwrap()
+}
+
+async fn create_agreement(agent: &Agent, canister_id: Principal, vh_customer: Principal) -> VTSResult<u128> {
+    let conditions = AgreementConditions {
+        prices: vec![PriceItem {
+            t_type: TelemetryType::Gas,
+            price: "0.5".to_string(),
+        }],
+        fees: Vec::new(),
+        daily_minimum: None,
+        billing_cycle: BillingCycle::default(),
+        starts_at: None,
+        ends_at: None,
+        token: PaymentToken::default(),
+        payment_due_days: 30,
+        grace_period_days: 7,
+        late_fees: LateFees::default(),
+    };
+    let res = agent
+        .update(&canister_id, "create_agreement")
+        .with_effective_canister_id(canister_id)
+        .with_arg(
+            Encode!(&"Upgraded agreement".to_string(), &vh_customer, &conditions, &None::<Principal>)
+                .unwrap(),
+        )
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(res.as_slice(), VTSResult<u128>).unwrap()
+}
+
+async fn get_invoice(agent: &Agent, canister_id: Principal, invoice_id: u128) -> VTSResult<Invoice> {
+    let res = agent
+        .query(&canister_id, "get_invoice")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&invoice_id).unwrap())
+        .call()
+        .await
+        .unwrap();
+    Decode!(res.as_slice(), VTSResult<Invoice>).unwrap()
+}
+
+async fn create_predefined_invoice(
+    agent: &Agent,
+    canister_id: Principal,
+    vehicle: Principal,
+    year: i32,
+    month: u8,
+) -> VTSResult<u128> {
+    let res = agent
+        .update(&canister_id, "create_predefined_invoice")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&vehicle, &year, &month).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+    Decode!(res.as_slice(), VTSResult<u128>).unwrap()
+}
+
+async fn get_firmware(agent: &Agent, canister_id: Principal, vehicle: Principal) -> VTSResult<FirmwareChunk> {
+    let res = agent
+        .query(&canister_id, "get_firmware")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&vehicle, &0u64, &1024u64).unwrap())
+        .call()
+        .await
+        .unwrap();
+    Decode!(res.as_slice(), VTSResult<FirmwareChunk>).unwrap()
+}
@@ -29,44 +29,79 @@ fn main() {
 
     let mut vehicle_on = true;
 
-    // Let's generate fake gas data and send it to gateway.
+    // Sequence should grow even after restart, so we start from the current time.
+    let mut seq = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64;
+
+    // Counters should only grow so we keep them between iterations.
+    let mut odometer: u128 = 0;
+    let mut engine_hours: u128 = 0;
+    let mut battery_charge: u128 = 100;
+
+    // Let's generate fake telemetry data and send it to gateway.
     loop {
         sleep(Duration::from_secs(1));
-        let value: u128 = rng.gen_range(0..100);
-        let telemetry = vts::StoreTelemetryRequest {
-            value,
-            t_type: vts::TelemetryType::Gas,
-        };
-        let telemetry = bincode::encode_to_vec(telemetry, bincode::config::standard()).unwrap();
-        let signature: Signature = signing_key.sign(&telemetry);
-        let signature = signature.to_vec();
-        let res = client
-            .store_telemetry(gateway_tcp::StoreTelemetry {
+        odometer += rng.gen_range(0..5);
+        engine_hours += rng.gen_range(0..2);
+        battery_charge = battery_charge.saturating_sub(rng.gen_range(0..3));
+        if battery_charge == 0 {
+            battery_charge = 100;
+        }
+        // Every tick vehicle reports all its telemetry kinds in one batch.
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos() as u64;
+        let mut batch = Vec::with_capacity(vts::TelemetryType::ALL.len());
+        for t_type in vts::TelemetryType::ALL {
+            let value: u128 = match t_type {
+                vts::TelemetryType::Gas | vts::TelemetryType::ElectricEnergy => rng.gen_range(0..100),
+                vts::TelemetryType::Distance => odometer,
+                vts::TelemetryType::EngineHours => engine_hours,
+                vts::TelemetryType::BatteryCharge => battery_charge,
+            };
+            seq += 1;
+            let telemetry = vts::StoreTelemetryRequest {
+                value,
+                t_type,
+                timestamp,
+                seq,
+            };
+            let telemetry = bincode::encode_to_vec(telemetry, bincode::config::standard()).unwrap();
+            let signature: Signature = signing_key.sign(&telemetry);
+            let signature = signature.to_vec();
+            batch.push(gateway_tcp::StoreTelemetry {
                 principal: principal.as_slice().to_vec(),
                 telemetry,
                 signature,
-            })
-            .unwrap();
-        match res {
-            gateway_tcp::Response::TurnOn => {
+            });
+        }
+        let res = client.store_telemetry_batch(batch).unwrap();
+        let stored = res.stored.iter().filter(|stored| **stored).count();
+        if stored != res.stored.len() {
+            eprintln!("Store telemetry request failed for {} items", res.stored.len() - stored);
+        }
+        let vehicle_state = res
+            .vehicles
+            .into_iter()
+            .find(|(vehicle, _)| vehicle.as_slice() == principal.as_slice())
+            .map(|(_, state)| state);
+        match vehicle_state {
+            Some(gateway_tcp::Response::TurnOn) => {
                 if !vehicle_on {
                     eprintln!("TurnOn response; Vehicle is not working; Turn on vehicle");
                     vehicle_on = true;
                 }
             }
-            gateway_tcp::Response::TurnOff => {
+            Some(gateway_tcp::Response::TurnOff) => {
                 if vehicle_on {
                     eprintln!("TurnOff response; Vehicle is working; Turn off vehicle");
                     vehicle_on = false;
                 }
             }
-            gateway_tcp::Response::Failed => {
+            _ => {
                 eprintln!("Store telemetry request failed");
                 continue;
             }
         }
         eprintln!(
-            "{} telemetry successfully sent to the gateway",
+            "{} telemetry ({stored} items) successfully sent to the gateway",
             SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis()
         );
     }
@@ -8,9 +8,17 @@ use bincode::{Decode, Encode};
 
 pub type Res<T> = Result<T, String>;
 
+// Every message is prefixed with its length as big endian u32
+// because batches are too large to be read at once and binary data can contain any byte.
+pub const FRAME_LENGTH_SIZE: usize = 4;
+
+// To not allocate huge buffers because of malformed length.
+pub const MAX_FRAME_SIZE: usize = 2 * 1024 * 1024;
+
 #[derive(Encode, Decode)]
 pub enum Request {
     StoreTelemetry(StoreTelemetry),
+    StoreTelemetryBatch(Vec<StoreTelemetry>),
 }
 
 #[derive(Encode, Decode)]
@@ -18,6 +26,7 @@ pub enum Response {
     TurnOn,
     TurnOff,
     Failed,
+    StoreTelemetryBatch(StoreTelemetryBatchResult),
 }
 
 #[derive(Encode, Decode)]
@@ -27,6 +36,14 @@ pub struct StoreTelemetry {
     pub signature: Vec<u8>,
 }
 
+#[derive(Encode, Decode)]
+pub struct StoreTelemetryBatchResult {
+    // Whether telemetry with the same index in the request was stored.
+    pub stored: Vec<bool>,
+    // Vehicle principal and whether it should be turned on or off.
+    pub vehicles: Vec<(Vec<u8>, Response)>,
+}
+
 pub struct Client {
     stream: TcpStream,
 }
@@ -38,26 +55,46 @@ impl Client {
     }
 
     pub fn store_telemetry(&mut self, data: StoreTelemetry) -> Res<Response> {
-        let req = Request::StoreTelemetry(data);
-        let mut buf = bincode::encode_to_vec(req, bincode::config::standard()).map_err(map_err)?;
-        buf.push(b'\n');
+        self.request(Request::StoreTelemetry(data))
+    }
+
+    pub fn store_telemetry_batch(&mut self, data: Vec<StoreTelemetry>) -> Res<StoreTelemetryBatchResult> {
+        match self.request(Request::StoreTelemetryBatch(data))? {
+            Response::StoreTelemetryBatch(res) => Ok(res),
+            _ => Err("unexpected response for telemetry batch".to_string()),
+        }
+    }
+
+    fn request(&mut self, req: Request) -> Res<Response> {
+        let buf = bincode::encode_to_vec(req, bincode::config::standard()).map_err(map_err)?;
+        self.stream.write_all(&encode_frame_length(buf.len())?).map_err(map_err)?;
         self.stream.write_all(&buf).map_err(map_err)?;
 
-        let mut buf = vec![0; 8];
-        let n = loop {
-            let n = self.stream.read(&mut buf).map_err(map_err)?;
-            if n == 0 {
-                continue;
-            }
-            break n;
-        };
-        buf.truncate(n);
+        let mut len = [0; FRAME_LENGTH_SIZE];
+        self.stream.read_exact(&mut len).map_err(map_err)?;
+        let mut buf = vec![0; decode_frame_length(len)?];
+        self.stream.read_exact(&mut buf).map_err(map_err)?;
 
         let res: Response = bincode::decode_from_slice(&buf, bincode::config::standard()).map_err(map_err)?.0;
         Ok(res)
     }
 }
 
+pub fn encode_frame_length(len: usize) -> Res<[u8; FRAME_LENGTH_SIZE]> {
+    if len > MAX_FRAME_SIZE {
+        return Err(format!("frame is too large: {len}"));
+    }
+    Ok((len as u32).to_be_bytes())
+}
+
+pub fn decode_frame_length(len: [u8; FRAME_LENGTH_SIZE]) -> Res<usize> {
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(format!("frame is too large: {len}"));
+    }
+    Ok(len)
+}
+
 fn map_err<E: ToString>(e: E) -> String {
     e.to_string()
 }
@@ -6,22 +6,31 @@ use std::{
 };
 
 use candid::{Decode, Encode};
-use gateway_tcp::{Request, Response};
+use gateway_tcp::{Request, Response, StoreTelemetry, StoreTelemetryBatchResult, FRAME_LENGTH_SIZE};
 use ic_agent::{export::Principal, identity::Secp256k1Identity, Agent, Identity};
 use log::{debug, error, info, trace, LevelFilter};
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
     select,
-    sync::watch,
-    time::{sleep, timeout},
+    sync::{mpsc, oneshot, watch},
+    time::{interval, sleep, timeout},
 };
-use vts::{PendingInvoice, StoreTelemetryResponse, VTSResult};
+use vts::{PendingInvoice, SignedTelemetry, StoreTelemetryBatchResponse, StoreTelemetryResponse, VTSResult};
 use zip::write::SimpleFileOptions;
 
 const FIRMWARE_PATH: &str = "../target/debug/firmware";
 
+// Firmware is uploaded by chunks to fit into the ingress message size limit.
+const FIRMWARE_CHUNK_SIZE: usize = 1024 * 1024;
+
+// Telemetry from all devices is sent to the canister in batches
+// when batch is full or when interval is passed.
+const TELEMETRY_BATCH_SIZE: usize = 500;
+const TELEMETRY_BATCH_INTERVAL: Duration = Duration::from_millis(500);
+
 type Res<T> = Result<T, Error>;
 
 pub(crate) struct Error(String);
@@ -42,6 +51,17 @@ impl<T: ToString> From<T> for Error {
 struct State {
     agent: Agent,
     canister_id: Principal,
+    telemetry_s: mpsc::Sender<PendingTelemetry>,
+}
+
+struct PendingTelemetry {
+    telemetry: SignedTelemetry,
+    res_s: oneshot::Sender<TelemetryResult>,
+}
+
+struct TelemetryResult {
+    stored: bool,
+    vehicle_state: Option<StoreTelemetryResponse>,
 }
 
 #[derive(Deserialize)]
@@ -62,7 +82,15 @@ async fn main() -> Res<()> {
         .init();
     let (stop_s, stop_r) = watch::channel(());
     let (agent, canister_id) = init_agent().await?;
-    let state = State { agent, canister_id };
+    let (telemetry_s, telemetry_r) = mpsc::channel(TELEMETRY_BATCH_SIZE);
+    let state = State {
+        agent,
+        canister_id,
+        telemetry_s,
+    };
+    let state_ = state.clone();
+    let stop_r_ = stop_r.clone();
+    tokio::spawn(async move { batch_telemetry(state_, telemetry_r, stop_r_).await });
     let state_ = state.clone();
     let stop_r_ = stop_r.clone();
     tokio::spawn(async move { wait_for_paid_invoices(state_, stop_r_).await });
@@ -137,6 +165,101 @@ async fn wait_for_firmware_requests(state: State, mut stop_r: watch::Receiver<()
     }
 }
 
+async fn batch_telemetry(
+    state: State,
+    mut telemetry_r: mpsc::Receiver<PendingTelemetry>,
+    mut stop_r: watch::Receiver<()>,
+) {
+    let mut batch: Vec<PendingTelemetry> = Vec::with_capacity(TELEMETRY_BATCH_SIZE);
+    let mut flush_interval = interval(TELEMETRY_BATCH_INTERVAL);
+    loop {
+        select! {
+            _ = stop_r.changed() => {
+                trace!("received stop signal, exit telemetry batching loop");
+                return;
+            }
+            pending = telemetry_r.recv() => {
+                match pending {
+                    Some(pending) => batch.push(pending),
+                    None => return,
+                }
+                if batch.len() < TELEMETRY_BATCH_SIZE {
+                    continue;
+                }
+            }
+            _ = flush_interval.tick() => {
+                if batch.is_empty() {
+                    continue;
+                }
+            }
+        }
+        let batch = std::mem::replace(&mut batch, Vec::with_capacity(TELEMETRY_BATCH_SIZE));
+        trace!("sending telemetry batch of {} items", batch.len());
+        if let Err(e) = store_telemetry_batch(&state, batch).await {
+            error!("failed to store telemetry batch: {:?}", e)
+        }
+    }
+}
+
+async fn store_telemetry_batch(state: &State, batch: Vec<PendingTelemetry>) -> Res<()> {
+    let (telemetry, responders): (Vec<SignedTelemetry>, Vec<oneshot::Sender<TelemetryResult>>) =
+        batch.into_iter().map(|pending| (pending.telemetry, pending.res_s)).unzip();
+    let res = async {
+        let res = timeout(
+            Duration::from_secs(10),
+            state
+                .agent
+                .update(&state.canister_id, "store_telemetry_batch")
+                .with_effective_canister_id(state.canister_id)
+                .with_arg(Encode!(&telemetry)?)
+                .call_and_wait(),
+        )
+        .await??;
+        Ok::<StoreTelemetryBatchResponse, Error>(Decode!(
+            res.as_slice(),
+            VTSResult<StoreTelemetryBatchResponse>
+        )??)
+    }
+    .await;
+    let res = match res {
+        Ok(res) => res,
+        Err(e) => {
+            // Devices should know that their telemetry wasn't stored.
+            for res_s in responders {
+                let _ = res_s.send(TelemetryResult {
+                    stored: false,
+                    vehicle_state: None,
+                });
+            }
+            return Err(e);
+        }
+    };
+    for ((telemetry, res_s), stored) in telemetry.iter().zip(responders).zip(res.results) {
+        if let Err(e) = &stored {
+            error!("failed to store telemetry for {}: {e}", telemetry.vehicle);
+        }
+        let _ = res_s.send(TelemetryResult {
+            stored: stored.is_ok(),
+            vehicle_state: res.vehicles.get(&telemetry.vehicle).copied(),
+        });
+    }
+    Ok(())
+}
+
+async fn submit_telemetry(
+    state: &State,
+    telemetry: &StoreTelemetry,
+) -> Res<oneshot::Receiver<TelemetryResult>> {
+    let (res_s, res_r) = oneshot::channel();
+    let telemetry = SignedTelemetry {
+        vehicle: Principal::from_slice(&telemetry.principal),
+        data: telemetry.telemetry.clone(),
+        signature: telemetry.signature.clone(),
+    };
+    state.telemetry_s.send(PendingTelemetry { telemetry, res_s }).await?;
+    Ok(res_r)
+}
+
 async fn check_paid_invoices(state: State) -> Res<()> {
     process_pending_invoices(&state, "get_paid_invoices", "delete_paid_invoices", "paid").await
 }
@@ -256,12 +379,31 @@ async fn upload_firmware(
     firmware: Vec<u8>,
 ) -> Res<()> {
     let res = agent
-        .update(&canister_id, "upload_firmware")
+        .update(&canister_id, "begin_firmware_upload")
+        .with_effective_canister_id(canister_id)
+        .with_arg(Encode!(&vh_customer, &public_key, &std::env::consts::ARCH.to_string())?)
+        .call_and_wait()
+        .await?;
+    let upload_id = Decode!(res.as_slice(), VTSResult<u128>)?
+        .map_err(|_| "failed to begin firmware upload".to_string())?;
+    for (i, chunk) in firmware.chunks(FIRMWARE_CHUNK_SIZE).enumerate() {
+        let offset = (i * FIRMWARE_CHUNK_SIZE) as u64;
+        let res = agent
+            .update(&canister_id, "append_firmware_chunk")
+            .with_effective_canister_id(canister_id)
+            .with_arg(Encode!(&upload_id, &offset, &chunk)?)
+            .call_and_wait()
+            .await?;
+        Decode!(res.as_slice(), VTSResult<()>)?.map_err(|_| "failed to upload firmware chunk".to_string())?;
+    }
+    let sha256 = Sha256::digest(&firmware).to_vec();
+    let res = agent
+        .update(&canister_id, "finish_firmware_upload")
         .with_effective_canister_id(canister_id)
-        .with_arg(Encode!(&vh_customer, &public_key, &std::env::consts::ARCH.to_string(), &firmware)?)
+        .with_arg(Encode!(&upload_id, &sha256)?)
         .call_and_wait()
         .await?;
-    Ok(Decode!(res.as_slice(), VTSResult<()>)?.map_err(|_| "failed to upload firmware".to_string())?)
+    Ok(Decode!(res.as_slice(), VTSResult<()>)?.map_err(|_| "failed to finish firmware upload".to_string())?)
 }
 
 fn compress_firmware(vehicle: Principal, firmware: Vec<u8>) -> Res<Vec<u8>> {
@@ -305,70 +447,71 @@ async fn process_connection(connection: (TcpStream, SocketAddr), state: State) -
     trace!("new tcp client connected: {addr}");
 
     loop {
-        let mut buf: Vec<u8> = vec![0; 128];
-        let n = stream.read(&mut buf).await;
-        let buf = match n {
-            Ok(0) => {
+        let mut len = [0; FRAME_LENGTH_SIZE];
+        match stream.read_exact(&mut len).await {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {
                 trace!("rpc client disconnected: {}", addr);
                 return Ok(());
             }
-            Ok(n) => {
-                buf.truncate(n);
-                // Remove new line if exists.
-                if buf.last() == Some(&10) {
-                    buf.pop();
-                }
-                buf
-            }
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                // This error means that there are no data in socket buffer but it is not closed.
-                return Ok(());
-            }
             Err(e) => return Err(format!("failed to read from connection: {:?}: {:?}", addr, e).into()),
-        };
+        }
+        let mut buf = vec![0; gateway_tcp::decode_frame_length(len)?];
+        stream.read_exact(&mut buf).await?;
 
         let req: Request = bincode::decode_from_slice(&buf, bincode::config::standard())?.0;
         let res = handle_rpc_request(&req, &state).await?;
-        let mut buf: Vec<u8> = bincode::encode_to_vec(res, bincode::config::standard())?;
-        write(&mut stream, &mut buf).await?;
+        let buf: Vec<u8> = bincode::encode_to_vec(res, bincode::config::standard())?;
+        write(&mut stream, &buf).await?;
     }
 }
 
-async fn write(stream: &mut TcpStream, buf: &mut Vec<u8>) -> Res<()> {
-    // Add new line if not exists.
-    if buf.last() != Some(&10) {
-        buf.push(10);
-    }
+async fn write(stream: &mut TcpStream, buf: &[u8]) -> Res<()> {
+    stream.write_all(&gateway_tcp::encode_frame_length(buf.len())?).await?;
     Ok(stream.write_all(buf).await?)
 }
 
 async fn handle_rpc_request(req: &Request, state: &State) -> Res<Response> {
     match req {
         Request::StoreTelemetry(telemetry) => {
-            let principal = Principal::from_slice(&telemetry.principal);
-            let res = state
-                .agent
-                .update(&state.canister_id, "store_telemetry")
-                .with_effective_canister_id(state.canister_id)
-                .with_arg(Encode!(&principal, &telemetry.telemetry, &telemetry.signature)?)
-                .call_and_wait()
-                .await?;
-            let res = match Decode!(res.as_slice(), VTSResult<StoreTelemetryResponse>)? {
-                Ok(res) => res,
-                Err(e) => {
-                    error!("failed to store telemetry: {e}");
-                    return Ok(Response::Failed);
+            let res = submit_telemetry(state, telemetry).await?.await?;
+            if !res.stored {
+                return Ok(Response::Failed);
+            }
+            Ok(vehicle_state_to_response(res.vehicle_state))
+        }
+        Request::StoreTelemetryBatch(batch) => {
+            // Items are batched together with telemetry from other devices,
+            // so submit all of them first and only then wait for results.
+            let mut pending = Vec::with_capacity(batch.len());
+            for telemetry in batch {
+                pending.push(submit_telemetry(state, telemetry).await?);
+            }
+            let mut stored = Vec::with_capacity(batch.len());
+            let mut vehicles: Vec<(Vec<u8>, Response)> = Vec::new();
+            for (telemetry, res_r) in batch.iter().zip(pending) {
+                let res = res_r.await?;
+                stored.push(res.stored);
+                if res.vehicle_state.is_some()
+                    && !vehicles.iter().any(|(principal, _)| *principal == telemetry.principal)
+                {
+                    vehicles
+                        .push((telemetry.principal.clone(), vehicle_state_to_response(res.vehicle_state)));
                 }
-            };
-            let res = match res {
-                StoreTelemetryResponse::On => Response::TurnOn,
-                StoreTelemetryResponse::Off => Response::TurnOff,
-            };
-            Ok(res)
+            }
+            Ok(Response::StoreTelemetryBatch(StoreTelemetryBatchResult { stored, vehicles }))
         }
     }
 }
 
+fn vehicle_state_to_response(state: Option<StoreTelemetryResponse>) -> Response {
+    match state {
+        Some(StoreTelemetryResponse::On) => Response::TurnOn,
+        Some(StoreTelemetryResponse::Off) => Response::TurnOff,
+        None => Response::Failed,
+    }
+}
+
 fn sign_firmware(filepath: &str) -> Res<()> {
     if std::env::consts::OS != "macos" {
         return Ok(());
@@ -2,6 +2,10 @@ import type { Principal } from '@dfinity/principal';
 import type { ActorMethod } from '@dfinity/agent';
 import type { IDL } from '@dfinity/candid';
 
+export interface Account {
+  'owner' : Principal,
+  'subaccount' : [] | [Uint8Array | number[]],
+}
 export interface AccumulatedTelemetryMonthy {
   'value' : bigint,
   'daily' : Array<[number, bigint]>,
@@ -10,18 +14,71 @@ export interface AccumulatedTelemetryYearly {
   'value' : bigint,
   'monthly' : Array<[number, AccumulatedTelemetryMonthy]>,
 }
+export type AdjustmentKind = { 'CreditNote' : null } |
+  { 'LateFee' : null } |
+  { 'Discount' : null } |
+  { 'LateInterest' : { 'days' : number } };
+export type AggregationRule = { 'Max' : null } |
+  { 'Sum' : null } |
+  { 'Last' : null };
 export interface Agreement {
   'id' : bigint,
   'vehicles' : Array<[Principal, null]>,
   'name' : string,
   'state' : AgreementState,
+  'amendment' : [] | [AgreementConditions],
   'conditions' : AgreementConditions,
+  'ended_at' : [] | [bigint],
   'vh_provider' : Principal,
   'vh_customer' : Principal,
 }
-export interface AgreementConditions { 'gas_price' : string }
-export type AgreementState = { 'Unsigned' : null } |
-  { 'Signed' : null };
+export interface AgreementConditions {
+  'token' : PaymentToken,
+  'starts_at' : [] | [bigint],
+  'ends_at' : [] | [bigint],
+  'fees' : Array<Fee>,
+  'billing_cycle' : BillingCycle,
+  'daily_minimum' : [] | [string],
+  'grace_period_days' : number,
+  'prices' : Array<PriceItem>,
+  'payment_due_days' : number,
+  'late_fees' : LateFees,
+}
+export type AgreementState = { 'Terminated' : null } |
+  { 'Suspended' : null } |
+  { 'Rejected' : null } |
+  { 'Unsigned' : null } |
+  { 'Cancelled' : null } |
+  { 'Signed' : null } |
+  { 'Expired' : null };
+export interface BillingCycle {
+  'start_day' : number,
+  'period' : BillingPeriod,
+  'utc_offset_minutes' : number,
+}
+export type BillingPeriod = { 'Weekly' : null } |
+  { 'Monthly' : null };
+export interface Dispute {
+  'invoice' : bigint,
+  'state' : DisputeState,
+  'thread' : Array<DisputeEntry>,
+}
+export type DisputeAction = { 'Open' : null } |
+  { 'Resolve' : { 'credit' : bigint } } |
+  { 'Reject' : null } |
+  { 'Accept' : { 'credit' : bigint } } |
+  { 'Escalate' : null };
+export interface DisputeEntry {
+  'action' : DisputeAction,
+  'created_at' : bigint,
+  'author' : Principal,
+  'message' : string,
+}
+export type DisputeState = { 'Open' : null } |
+  { 'Rejected' : null } |
+  { 'Accepted' : null } |
+  { 'Escalated' : null } |
+  { 'Resolved' : null };
 export type Error = { 'InvalidSigner' : null } |
   { 'Internal' : null } |
   { 'InvalidSignatureFormat' : null } |
@@ -30,106 +87,373 @@ export type Error = { 'InvalidSigner' : null } |
   { 'InvalidData' : null } |
   { 'Unauthorized' : null } |
   { 'AlreadyExists' : null } |
-  { 'DecodeTelemetry' : null };
+  { 'PaymentInProgress' : null } |
+  { 'DecodeTelemetry' : null } |
+  { 'PaymentNeedsReconciliation' : null };
+export interface Fee { 'name' : string, 'amount' : string }
+export interface FirmwareChunk {
+  'sha256' : Uint8Array | number[],
+  'data' : Uint8Array | number[],
+  'total_size' : bigint,
+}
 export interface Invoice {
   'id' : bigint,
   'status' : InvoiceStatus,
-  'period' : [number, number],
+  'payment_created_at' : [] | [bigint],
+  'token' : PaymentToken,
+  'period' : InvoicePeriod,
   'agreement' : bigint,
   'total_cost' : bigint,
+  'due_at' : bigint,
   'vehicle' : Principal,
+  'items' : Array<InvoiceItem>,
+}
+export interface InvoiceAdjustment {
+  'issued_at' : bigint,
+  'issued_by' : Principal,
+  'kind' : AdjustmentKind,
+  'amount' : bigint,
+  'reason' : string,
+}
+export interface InvoiceItem {
+  'kind' : InvoiceItemKind,
+  'unit_price' : string,
+  'quantity' : bigint,
+  'subtotal' : bigint,
+}
+export type InvoiceItemKind = { 'Fee' : string } |
+  { 'Usage' : TelemetryType } |
+  { 'DailyMinimum' : null };
+export interface InvoicePayment {
+  'block_index' : [] | [bigint],
+  'paid_at' : bigint,
+  'paid_by' : Principal,
+  'amount' : bigint,
+}
+export interface InvoicePeriod {
+  'end' : [number, number, number],
+  'start' : [number, number, number],
 }
-export type InvoiceStatus = { 'Paid' : null } |
+export type InvoiceStatus = { 'Disputed' : null } |
+  { 'PartiallyPaid' : null } |
+  { 'Paid' : null } |
   { 'Unpaid' : null };
+export interface LateFees {
+  'fixed' : [] | [string],
+  'daily_interest_percent' : [] | [string],
+}
+export interface OnOffChange {
+  'changed_at' : bigint,
+  'changed_by' : Principal,
+  'on_off' : boolean,
+  'reason' : OnOffReason,
+}
+export type OnOffReason = { 'InvoiceOverdue' : bigint } |
+  { 'InvoiceDisputed' : bigint } |
+  { 'InvoicePaid' : bigint } |
+  { 'Agreement' : bigint } |
+  { 'Manual' : null };
+export interface Organization {
+  'members' : Array<[Principal, Array<Permission>]>,
+  'name' : string,
+}
+export interface PaymentToken {
+  'decimals' : number,
+  'ledger' : Principal,
+  'symbol' : string,
+}
+export interface PendingForward {
+  'fee' : bigint,
+  'provider' : Principal,
+  'created_at_time' : bigint,
+  'amount' : bigint,
+}
 export interface PendingInvoice {
   'id' : bigint,
   'vehicle' : Principal,
   'customer_email' : [] | [string],
 }
+export interface PendingPayment {
+  'provider' : Principal,
+  'memo' : Uint8Array | number[],
+  'payer' : Principal,
+  'created_at_time' : bigint,
+  'amount' : bigint,
+}
+export type Permission = { 'Pay' : null } |
+  { 'Sign' : null } |
+  { 'View' : null } |
+  { 'ManageVehicles' : null };
+export interface PriceItem { 't_type' : TelemetryType, 'price' : string }
+export interface RawTelemetry {
+  'seq' : bigint,
+  'signature' : Uint8Array | number[],
+  'value' : bigint,
+  't_type' : TelemetryType,
+  'timestamp' : bigint,
+}
+export interface RawTelemetryPage {
+  'telemetry' : Array<RawTelemetry>,
+  'next' : [] | [TelemetryCursor],
+}
 export type Result = { 'Ok' : null } |
   { 'Err' : Error };
 export type Result_1 = { 'Ok' : bigint } |
   { 'Err' : Error };
-export type Result_10 = { 'Ok' : StoreTelemetryResponse } |
+export type Result_10 = { 'Ok' : Account } |
+  { 'Err' : Error };
+export type Result_11 = { 'Ok' : Dispute } |
+  { 'Err' : Error };
+export type Result_12 = { 'Ok' : Array<InvoicePayment> } |
+  { 'Err' : Error };
+export type Result_13 = { 'Ok' : RawTelemetryPage } |
+  { 'Err' : Error };
+export type Result_14 = { 'Ok' : Organization } |
+  { 'Err' : Error };
+export type Result_15 = { 'Ok' : Array<Agreement> } |
+  { 'Err' : Error };
+export type Result_16 = { 'Ok' : Array<PendingInvoice> } |
+  { 'Err' : Error };
+export type Result_17 = { 'Ok' : Array<[bigint, PendingForward]> } |
+  { 'Err' : Error };
+export type Result_18 = { 'Ok' : TelemetryPage } |
+  { 'Err' : Error };
+export type Result_19 = { 'Ok' : Array<[bigint, PendingPayment]> } |
+  { 'Err' : Error };
+export type Result_2 = { 'Ok' : InvoiceStatus } |
+  { 'Err' : Error };
+export type Result_20 = { 'Ok' : User } |
+  { 'Err' : Error };
+export type Result_21 = { 'Ok' : VehicleSummary } |
+  { 'Err' : Error };
+export type Result_22 = { 'Ok' : Array<bigint> } |
+  { 'Err' : Error };
+export type Result_23 = { 'Ok' : Array<VehicleLink> } |
+  { 'Err' : Error };
+export type Result_24 = { 'Ok' : Array<OnOffChange> } |
+  { 'Err' : Error };
+export type Result_25 = { 'Ok' : Array<[Principal, null]> } |
+  { 'Err' : Error };
+export type Result_26 = { 'Ok' : StoreTelemetryResponse } |
   { 'Err' : Error };
-export type Result_2 = {
+export type Result_27 = { 'Ok' : StoreTelemetryBatchResponse } |
+  { 'Err' : Error };
+export type Result_3 = {
     'Ok' : Array<[TelemetryType, Array<[number, AccumulatedTelemetryYearly]>]>
   } |
   { 'Err' : Error };
-export type Result_3 = { 'Ok' : Principal } |
-  { 'Err' : Error };
-export type Result_4 = { 'Ok' : Invoice } |
+export type Result_4 = { 'Ok' : Agreement } |
   { 'Err' : Error };
-export type Result_5 = { 'Ok' : Array<PendingInvoice> } |
+export type Result_5 = { 'Ok' : Array<Dispute> } |
   { 'Err' : Error };
-export type Result_6 = { 'Ok' : User } |
+export type Result_6 = { 'Ok' : FirmwareChunk } |
   { 'Err' : Error };
-export type Result_7 = { 'Ok' : Array<Agreement> } |
+export type Result_7 = { 'Ok' : Principal } |
   { 'Err' : Error };
-export type Result_8 = { 'Ok' : Vehicle } |
+export type Result_8 = { 'Ok' : Invoice } |
   { 'Err' : Error };
-export type Result_9 = { 'Ok' : Array<[Principal, null]> } |
+export type Result_9 = { 'Ok' : Array<InvoiceAdjustment> } |
   { 'Err' : Error };
+export type Role = { 'Customer' : null } |
+  { 'Auditor' : null } |
+  { 'FleetOperator' : null } |
+  { 'Provider' : null };
+export interface SignedTelemetry {
+  'signature' : Uint8Array | number[],
+  'data' : Uint8Array | number[],
+  'vehicle' : Principal,
+}
+export interface StoreTelemetryBatchResponse {
+  'vehicles' : Array<[Principal, StoreTelemetryResponse]>,
+  'results' : Array<Result>,
+}
 export type StoreTelemetryResponse = { 'On' : null } |
   { 'Off' : null };
-export type TelemetryType = { 'Gas' : null };
+export interface TelemetryCursor { 'seq' : bigint, 'timestamp' : bigint }
+export type TelemetryGranularity = { 'Day' : null } |
+  { 'Raw' : null } |
+  { 'Year' : null } |
+  { 'Month' : null };
+export interface TelemetryPage {
+  'next' : [] | [TelemetryCursor],
+  'points' : Array<TelemetryPoint>,
+}
+export interface TelemetryPoint { 'value' : bigint, 'timestamp' : bigint }
+export interface TelemetryQuery {
+  'to' : bigint,
+  'cursor' : [] | [TelemetryCursor],
+  'from' : bigint,
+  'granularity' : TelemetryGranularity,
+  't_type' : TelemetryType,
+  'limit' : [] | [number],
+  'vehicle' : Principal,
+}
+export interface TelemetrySkewWindow {
+  'past_secs' : bigint,
+  'future_secs' : bigint,
+}
+export type TelemetryType = { 'Gas' : null } |
+  { 'Distance' : null } |
+  { 'ElectricEnergy' : null } |
+  { 'EngineHours' : null } |
+  { 'BatteryCharge' : null };
+export interface TelemetryTypeInfo {
+  'unit' : string,
+  't_type' : TelemetryType,
+  'aggregation_rule' : AggregationRule,
+}
 export interface User {
   'agreements' : Array<[bigint, null]>,
   'vehicles' : Array<[Principal, null]>,
   'email' : [] | [string],
+  'roles' : Array<Role>,
 }
-export interface Vehicle {
-  'telemetry' : Array<
-    [
-      TelemetryType,
-      Array<[number, Array<[number, Array<[number, Array<bigint>]>]>]>,
-    ]
-  >,
+export interface VehicleLink {
+  'unlinked_at' : [] | [bigint],
+  'agreement' : bigint,
+  'linked_at' : bigint,
+}
+export interface VehicleSummary {
+  'principal' : Principal,
   'provider' : [] | [Principal],
   'customer' : Principal,
-  'public_key' : Uint8Array | number[],
   'arch' : string,
   'agreement' : [] | [bigint],
-  'firmware' : Uint8Array | number[],
-  'accumulated_telemetry' : Array<
-    [TelemetryType, Array<[number, AccumulatedTelemetryYearly]>]
-  >,
-  'invoices' : Array<bigint>,
+  'last_seen' : [] | [bigint],
+  'invoice_count' : bigint,
   'on_off' : boolean,
 }
 export interface _SERVICE {
+  'accept_dispute' : ActorMethod<
+    [bigint, bigint, string, [] | [Principal]],
+    Result
+  >,
   'accumulate_telemetry_data' : ActorMethod<[], Result>,
   'add_admin' : ActorMethod<[Principal], Result>,
+  'add_gateway' : ActorMethod<[Principal], Result>,
+  'adjust_invoice' : ActorMethod<
+    [bigint, AdjustmentKind, bigint, string, [] | [Principal]],
+    Result
+  >,
+  'append_firmware_chunk' : ActorMethod<
+    [bigint, bigint, Uint8Array | number[]],
+    Result
+  >,
+  'begin_firmware_upload' : ActorMethod<
+    [Principal, Uint8Array | number[], string],
+    Result_1
+  >,
+  'cancel_agreement' : ActorMethod<[bigint, [] | [Principal]], Result>,
+  'check_invoice_payment' : ActorMethod<[bigint], Result_2>,
+  'check_overdue_invoices' : ActorMethod<[], Result>,
   'clean_state' : ActorMethod<[], undefined>,
-  'create_agreement' : ActorMethod<[string, Principal, string], Result_1>,
+  'create_agreement' : ActorMethod<
+    [string, Principal, AgreementConditions, [] | [Principal]],
+    Result_1
+  >,
+  'create_organization' : ActorMethod<[Principal, string], Result>,
+  'create_predefined_invoice' : ActorMethod<
+    [Principal, number, number],
+    Result_1
+  >,
   'delete_admin' : ActorMethod<[Principal], Result>,
   'delete_paid_invoices' : ActorMethod<[Array<bigint>], undefined>,
   'delete_pending_invoices' : ActorMethod<[Array<bigint>], undefined>,
   'delete_user' : ActorMethod<[Principal], Result>,
+  'dispute_invoice' : ActorMethod<[bigint, string, [] | [Principal]], Result>,
+  'escalate_dispute' : ActorMethod<[bigint, string, [] | [Principal]], Result>,
+  'expire_pending_payments' : ActorMethod<[], undefined>,
   'fill_predefined_telemetry' : ActorMethod<
     [Principal, Principal, string],
     undefined
   >,
-  'get_aggregated_data' : ActorMethod<[Principal], Result_2>,
-  'get_firmware_requests' : ActorMethod<[], Result_3>,
+  'finish_firmware_upload' : ActorMethod<
+    [bigint, Uint8Array | number[]],
+    Result
+  >,
+  'get_aggregated_data' : ActorMethod<[Principal], Result_3>,
+  'get_agreement' : ActorMethod<[bigint], Result_4>,
+  'get_agreement_credit' : ActorMethod<[bigint], Result_1>,
+  'get_escalated_disputes' : ActorMethod<[], Result_5>,
+  'get_firmware' : ActorMethod<[Principal, bigint, bigint], Result_6>,
+  'get_firmware_requests' : ActorMethod<[], Result_7>,
   'get_firmware_requests_by_user' : ActorMethod<[], Result>,
-  'get_invoice' : ActorMethod<[bigint], Result_4>,
-  'get_paid_invoices' : ActorMethod<[], Result_5>,
-  'get_pending_invoices' : ActorMethod<[], Result_5>,
-  'get_user' : ActorMethod<[], Result_6>,
-  'get_user_agreements' : ActorMethod<[], Result_7>,
-  'get_vehicle' : ActorMethod<[Principal], Result_8>,
-  'get_vehicles_by_agreement' : ActorMethod<[bigint], Result_9>,
-  'link_vehicle' : ActorMethod<[bigint, Principal], Result>,
-  'pay_for_invoice' : ActorMethod<[bigint], Result>,
-  'register_user' : ActorMethod<[Principal, [] | [string]], Result>,
+  'get_invoice' : ActorMethod<[bigint], Result_8>,
+  'get_invoice_adjustments' : ActorMethod<[bigint], Result_9>,
+  'get_invoice_deposit_account' : ActorMethod<[bigint], Result_10>,
+  'get_invoice_dispute' : ActorMethod<[bigint], Result_11>,
+  'get_invoice_outstanding_balance' : ActorMethod<[bigint], Result_1>,
+  'get_invoice_payments' : ActorMethod<[bigint], Result_12>,
+  'get_invoice_telemetry' : ActorMethod<
+    [bigint, [] | [TelemetryCursor]],
+    Result_13
+  >,
+  'get_organization' : ActorMethod<[Principal], Result_14>,
+  'get_organization_agreements' : ActorMethod<[Principal], Result_15>,
+  'get_paid_invoices' : ActorMethod<[], Result_16>,
+  'get_pending_forwards' : ActorMethod<[], Result_17>,
+  'get_pending_invoices' : ActorMethod<[], Result_16>,
+  'get_telemetry' : ActorMethod<[TelemetryQuery], Result_18>,
+  'get_telemetry_retention' : ActorMethod<[], number>,
+  'get_telemetry_skew_window' : ActorMethod<[], TelemetrySkewWindow>,
+  'get_telemetry_types' : ActorMethod<[], Array<TelemetryTypeInfo>>,
+  'get_unreconciled_payments' : ActorMethod<[], Result_19>,
+  'get_user' : ActorMethod<[], Result_20>,
+  'get_user_agreements' : ActorMethod<[], Result_15>,
+  'get_vehicle' : ActorMethod<[Principal], Result_21>,
+  'get_vehicle_invoices' : ActorMethod<[Principal], Result_22>,
+  'get_vehicle_links' : ActorMethod<[Principal], Result_23>,
+  'get_vehicle_on_off_history' : ActorMethod<[Principal], Result_24>,
+  'get_vehicles_by_agreement' : ActorMethod<[bigint], Result_25>,
+  'link_vehicle' : ActorMethod<[bigint, Principal, [] | [Principal]], Result>,
+  'list_gateways' : ActorMethod<[], Array<Principal>>,
+  'pay_for_invoice' : ActorMethod<
+    [bigint, [] | [bigint], [] | [Principal]],
+    Result
+  >,
+  'propose_amendment' : ActorMethod<
+    [bigint, AgreementConditions, [] | [Principal]],
+    Result
+  >,
+  'reconcile_deposit_forward' : ActorMethod<[bigint, boolean], Result>,
+  'reconcile_payment' : ActorMethod<[bigint, [] | [bigint]], Result>,
+  'register_user' : ActorMethod<
+    [Principal, [] | [string], Array<Role>],
+    Result
+  >,
+  'reject_agreement' : ActorMethod<[bigint, [] | [Principal]], Result>,
+  'reject_amendment' : ActorMethod<[bigint, [] | [Principal]], Result>,
+  'reject_dispute' : ActorMethod<[bigint, string, [] | [Principal]], Result>,
+  'remove_gateway' : ActorMethod<[Principal], Result>,
+  'remove_organization_member' : ActorMethod<[Principal, Principal], Result>,
   'request_firmware' : ActorMethod<[], Result>,
-  'sign_agreement' : ActorMethod<[bigint], Result>,
+  'resolve_dispute' : ActorMethod<[bigint, bigint, string], Result>,
+  'resume_agreement' : ActorMethod<[bigint, [] | [Principal]], Result>,
+  'set_organization_member' : ActorMethod<
+    [Principal, Principal, Array<Permission>],
+    Result
+  >,
+  'set_telemetry_retention' : ActorMethod<[number], Result>,
+  'set_telemetry_skew_window' : ActorMethod<[TelemetrySkewWindow], undefined>,
+  'set_user_roles' : ActorMethod<[Principal, Array<Role>], Result>,
+  'sign_agreement' : ActorMethod<[bigint, [] | [Principal]], Result>,
+  'sign_amendment' : ActorMethod<[bigint, [] | [Principal]], Result>,
   'store_telemetry' : ActorMethod<
     [Principal, Uint8Array | number[], Uint8Array | number[]],
-    Result_10
+    Result_26
+  >,
+  'store_telemetry_batch' : ActorMethod<[Array<SignedTelemetry>], Result_27>,
+  'suspend_agreement' : ActorMethod<[bigint, [] | [Principal]], Result>,
+  'terminate_agreement' : ActorMethod<[bigint, [] | [Principal]], Result>,
+  'transfer_vehicle' : ActorMethod<
+    [Principal, bigint, [] | [Principal]],
+    Result
+  >,
+  'turn_on_off_vehicle' : ActorMethod<
+    [Principal, boolean, [] | [Principal]],
+    Result
   >,
-  'turn_on_off_vehicle' : ActorMethod<[Principal, boolean], Result>,
+  'unlink_vehicle' : ActorMethod<[Principal, [] | [Principal]], Result>,
   'upload_firmware' : ActorMethod<
     [Principal, Uint8Array | number[], string, Uint8Array | number[]],
     Result
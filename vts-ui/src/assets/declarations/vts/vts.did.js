@@ -8,11 +8,67 @@ export const idlFactory = ({ IDL }) => {
     'InvalidData' : IDL.Null,
     'Unauthorized' : IDL.Null,
     'AlreadyExists' : IDL.Null,
+    'PaymentInProgress' : IDL.Null,
     'DecodeTelemetry' : IDL.Null,
+    'PaymentNeedsReconciliation' : IDL.Null,
   });
   const Result = IDL.Variant({ 'Ok' : IDL.Null, 'Err' : Error });
+  const AdjustmentKind = IDL.Variant({
+    'CreditNote' : IDL.Null,
+    'LateFee' : IDL.Null,
+    'Discount' : IDL.Null,
+    'LateInterest' : IDL.Record({ 'days' : IDL.Nat32 }),
+  });
   const Result_1 = IDL.Variant({ 'Ok' : IDL.Nat, 'Err' : Error });
-  const TelemetryType = IDL.Variant({ 'Gas' : IDL.Null });
+  const InvoiceStatus = IDL.Variant({
+    'Disputed' : IDL.Null,
+    'PartiallyPaid' : IDL.Null,
+    'Paid' : IDL.Null,
+    'Unpaid' : IDL.Null,
+  });
+  const Result_2 = IDL.Variant({ 'Ok' : InvoiceStatus, 'Err' : Error });
+  const PaymentToken = IDL.Record({
+    'decimals' : IDL.Nat8,
+    'ledger' : IDL.Principal,
+    'symbol' : IDL.Text,
+  });
+  const Fee = IDL.Record({ 'name' : IDL.Text, 'amount' : IDL.Text });
+  const BillingPeriod = IDL.Variant({
+    'Weekly' : IDL.Null,
+    'Monthly' : IDL.Null,
+  });
+  const BillingCycle = IDL.Record({
+    'start_day' : IDL.Nat8,
+    'period' : BillingPeriod,
+    'utc_offset_minutes' : IDL.Int16,
+  });
+  const TelemetryType = IDL.Variant({
+    'Gas' : IDL.Null,
+    'Distance' : IDL.Null,
+    'ElectricEnergy' : IDL.Null,
+    'EngineHours' : IDL.Null,
+    'BatteryCharge' : IDL.Null,
+  });
+  const PriceItem = IDL.Record({
+    't_type' : TelemetryType,
+    'price' : IDL.Text,
+  });
+  const LateFees = IDL.Record({
+    'fixed' : IDL.Opt(IDL.Text),
+    'daily_interest_percent' : IDL.Opt(IDL.Text),
+  });
+  const AgreementConditions = IDL.Record({
+    'token' : PaymentToken,
+    'starts_at' : IDL.Opt(IDL.Nat64),
+    'ends_at' : IDL.Opt(IDL.Nat64),
+    'fees' : IDL.Vec(Fee),
+    'billing_cycle' : BillingCycle,
+    'daily_minimum' : IDL.Opt(IDL.Text),
+    'grace_period_days' : IDL.Nat32,
+    'prices' : IDL.Vec(PriceItem),
+    'payment_due_days' : IDL.Nat32,
+    'late_fees' : LateFees,
+  });
   const AccumulatedTelemetryMonthy = IDL.Record({
     'value' : IDL.Nat,
     'daily' : IDL.Vec(IDL.Tuple(IDL.Nat8, IDL.Nat)),
@@ -21,7 +77,7 @@ export const idlFactory = ({ IDL }) => {
     'value' : IDL.Nat,
     'monthly' : IDL.Vec(IDL.Tuple(IDL.Nat8, AccumulatedTelemetryMonthy)),
   });
-  const Result_2 = IDL.Variant({
+  const Result_3 = IDL.Variant({
     'Ok' : IDL.Vec(
       IDL.Tuple(
         TelemetryType,
@@ -30,81 +86,257 @@ export const idlFactory = ({ IDL }) => {
     ),
     'Err' : Error,
   });
-  const Result_3 = IDL.Variant({ 'Ok' : IDL.Principal, 'Err' : Error });
-  const InvoiceStatus = IDL.Variant({ 'Paid' : IDL.Null, 'Unpaid' : IDL.Null });
+  const AgreementState = IDL.Variant({
+    'Terminated' : IDL.Null,
+    'Suspended' : IDL.Null,
+    'Rejected' : IDL.Null,
+    'Unsigned' : IDL.Null,
+    'Cancelled' : IDL.Null,
+    'Signed' : IDL.Null,
+    'Expired' : IDL.Null,
+  });
+  const Agreement = IDL.Record({
+    'id' : IDL.Nat,
+    'vehicles' : IDL.Vec(IDL.Tuple(IDL.Principal, IDL.Null)),
+    'name' : IDL.Text,
+    'state' : AgreementState,
+    'amendment' : IDL.Opt(AgreementConditions),
+    'conditions' : AgreementConditions,
+    'ended_at' : IDL.Opt(IDL.Nat64),
+    'vh_provider' : IDL.Principal,
+    'vh_customer' : IDL.Principal,
+  });
+  const Result_4 = IDL.Variant({ 'Ok' : Agreement, 'Err' : Error });
+  const DisputeState = IDL.Variant({
+    'Open' : IDL.Null,
+    'Rejected' : IDL.Null,
+    'Accepted' : IDL.Null,
+    'Escalated' : IDL.Null,
+    'Resolved' : IDL.Null,
+  });
+  const DisputeAction = IDL.Variant({
+    'Open' : IDL.Null,
+    'Resolve' : IDL.Record({ 'credit' : IDL.Nat }),
+    'Reject' : IDL.Null,
+    'Accept' : IDL.Record({ 'credit' : IDL.Nat }),
+    'Escalate' : IDL.Null,
+  });
+  const DisputeEntry = IDL.Record({
+    'action' : DisputeAction,
+    'created_at' : IDL.Nat64,
+    'author' : IDL.Principal,
+    'message' : IDL.Text,
+  });
+  const Dispute = IDL.Record({
+    'invoice' : IDL.Nat,
+    'state' : DisputeState,
+    'thread' : IDL.Vec(DisputeEntry),
+  });
+  const Result_5 = IDL.Variant({ 'Ok' : IDL.Vec(Dispute), 'Err' : Error });
+  const FirmwareChunk = IDL.Record({
+    'sha256' : IDL.Vec(IDL.Nat8),
+    'data' : IDL.Vec(IDL.Nat8),
+    'total_size' : IDL.Nat64,
+  });
+  const Result_6 = IDL.Variant({ 'Ok' : FirmwareChunk, 'Err' : Error });
+  const Result_7 = IDL.Variant({ 'Ok' : IDL.Principal, 'Err' : Error });
+  const InvoicePeriod = IDL.Record({
+    'end' : IDL.Tuple(IDL.Int32, IDL.Nat8, IDL.Nat8),
+    'start' : IDL.Tuple(IDL.Int32, IDL.Nat8, IDL.Nat8),
+  });
+  const InvoiceItemKind = IDL.Variant({
+    'Fee' : IDL.Text,
+    'Usage' : TelemetryType,
+    'DailyMinimum' : IDL.Null,
+  });
+  const InvoiceItem = IDL.Record({
+    'kind' : InvoiceItemKind,
+    'unit_price' : IDL.Text,
+    'quantity' : IDL.Nat,
+    'subtotal' : IDL.Nat,
+  });
   const Invoice = IDL.Record({
     'id' : IDL.Nat,
     'status' : InvoiceStatus,
-    'period' : IDL.Tuple(IDL.Int32, IDL.Nat8),
+    'payment_created_at' : IDL.Opt(IDL.Nat64),
+    'token' : PaymentToken,
+    'period' : InvoicePeriod,
     'agreement' : IDL.Nat,
     'total_cost' : IDL.Nat,
+    'due_at' : IDL.Nat64,
     'vehicle' : IDL.Principal,
+    'items' : IDL.Vec(InvoiceItem),
+  });
+  const Result_8 = IDL.Variant({ 'Ok' : Invoice, 'Err' : Error });
+  const InvoiceAdjustment = IDL.Record({
+    'issued_at' : IDL.Nat64,
+    'issued_by' : IDL.Principal,
+    'kind' : AdjustmentKind,
+    'amount' : IDL.Nat,
+    'reason' : IDL.Text,
+  });
+  const Result_9 = IDL.Variant({
+    'Ok' : IDL.Vec(InvoiceAdjustment),
+    'Err' : Error,
+  });
+  const Account = IDL.Record({
+    'owner' : IDL.Principal,
+    'subaccount' : IDL.Opt(IDL.Vec(IDL.Nat8)),
   });
-  const Result_4 = IDL.Variant({ 'Ok' : Invoice, 'Err' : Error });
+  const Result_10 = IDL.Variant({ 'Ok' : Account, 'Err' : Error });
+  const Result_11 = IDL.Variant({ 'Ok' : Dispute, 'Err' : Error });
+  const InvoicePayment = IDL.Record({
+    'block_index' : IDL.Opt(IDL.Nat),
+    'paid_at' : IDL.Nat64,
+    'paid_by' : IDL.Principal,
+    'amount' : IDL.Nat,
+  });
+  const Result_12 = IDL.Variant({
+    'Ok' : IDL.Vec(InvoicePayment),
+    'Err' : Error,
+  });
+  const TelemetryCursor = IDL.Record({
+    'seq' : IDL.Nat64,
+    'timestamp' : IDL.Nat64,
+  });
+  const RawTelemetry = IDL.Record({
+    'seq' : IDL.Nat64,
+    'signature' : IDL.Vec(IDL.Nat8),
+    'value' : IDL.Nat,
+    't_type' : TelemetryType,
+    'timestamp' : IDL.Nat64,
+  });
+  const RawTelemetryPage = IDL.Record({
+    'telemetry' : IDL.Vec(RawTelemetry),
+    'next' : IDL.Opt(TelemetryCursor),
+  });
+  const Result_13 = IDL.Variant({ 'Ok' : RawTelemetryPage, 'Err' : Error });
+  const Permission = IDL.Variant({
+    'Pay' : IDL.Null,
+    'Sign' : IDL.Null,
+    'View' : IDL.Null,
+    'ManageVehicles' : IDL.Null,
+  });
+  const Organization = IDL.Record({
+    'members' : IDL.Vec(IDL.Tuple(IDL.Principal, IDL.Vec(Permission))),
+    'name' : IDL.Text,
+  });
+  const Result_14 = IDL.Variant({ 'Ok' : Organization, 'Err' : Error });
+  const Result_15 = IDL.Variant({ 'Ok' : IDL.Vec(Agreement), 'Err' : Error });
   const PendingInvoice = IDL.Record({
     'id' : IDL.Nat,
     'vehicle' : IDL.Principal,
     'customer_email' : IDL.Opt(IDL.Text),
   });
-  const Result_5 = IDL.Variant({
+  const Result_16 = IDL.Variant({
     'Ok' : IDL.Vec(PendingInvoice),
     'Err' : Error,
   });
+  const PendingForward = IDL.Record({
+    'fee' : IDL.Nat,
+    'provider' : IDL.Principal,
+    'created_at_time' : IDL.Nat64,
+    'amount' : IDL.Nat,
+  });
+  const Result_17 = IDL.Variant({
+    'Ok' : IDL.Vec(IDL.Tuple(IDL.Nat, PendingForward)),
+    'Err' : Error,
+  });
+  const TelemetryGranularity = IDL.Variant({
+    'Day' : IDL.Null,
+    'Raw' : IDL.Null,
+    'Year' : IDL.Null,
+    'Month' : IDL.Null,
+  });
+  const TelemetryQuery = IDL.Record({
+    'to' : IDL.Nat64,
+    'cursor' : IDL.Opt(TelemetryCursor),
+    'from' : IDL.Nat64,
+    'granularity' : TelemetryGranularity,
+    't_type' : TelemetryType,
+    'limit' : IDL.Opt(IDL.Nat32),
+    'vehicle' : IDL.Principal,
+  });
+  const TelemetryPoint = IDL.Record({
+    'value' : IDL.Nat,
+    'timestamp' : IDL.Nat64,
+  });
+  const TelemetryPage = IDL.Record({
+    'next' : IDL.Opt(TelemetryCursor),
+    'points' : IDL.Vec(TelemetryPoint),
+  });
+  const Result_18 = IDL.Variant({ 'Ok' : TelemetryPage, 'Err' : Error });
+  const TelemetrySkewWindow = IDL.Record({
+    'past_secs' : IDL.Nat64,
+    'future_secs' : IDL.Nat64,
+  });
+  const AggregationRule = IDL.Variant({
+    'Max' : IDL.Null,
+    'Sum' : IDL.Null,
+    'Last' : IDL.Null,
+  });
+  const TelemetryTypeInfo = IDL.Record({
+    'unit' : IDL.Text,
+    't_type' : TelemetryType,
+    'aggregation_rule' : AggregationRule,
+  });
+  const PendingPayment = IDL.Record({
+    'provider' : IDL.Principal,
+    'memo' : IDL.Vec(IDL.Nat8),
+    'payer' : IDL.Principal,
+    'created_at_time' : IDL.Nat64,
+    'amount' : IDL.Nat,
+  });
+  const Result_19 = IDL.Variant({
+    'Ok' : IDL.Vec(IDL.Tuple(IDL.Nat, PendingPayment)),
+    'Err' : Error,
+  });
+  const Role = IDL.Variant({
+    'Customer' : IDL.Null,
+    'Auditor' : IDL.Null,
+    'FleetOperator' : IDL.Null,
+    'Provider' : IDL.Null,
+  });
   const User = IDL.Record({
     'agreements' : IDL.Vec(IDL.Tuple(IDL.Nat, IDL.Null)),
     'vehicles' : IDL.Vec(IDL.Tuple(IDL.Principal, IDL.Null)),
     'email' : IDL.Opt(IDL.Text),
+    'roles' : IDL.Vec(Role),
   });
-  const Result_6 = IDL.Variant({ 'Ok' : User, 'Err' : Error });
-  const AgreementState = IDL.Variant({
-    'Unsigned' : IDL.Null,
-    'Signed' : IDL.Null,
-  });
-  const AgreementConditions = IDL.Record({ 'gas_price' : IDL.Text });
-  const Agreement = IDL.Record({
-    'id' : IDL.Nat,
-    'vehicles' : IDL.Vec(IDL.Tuple(IDL.Principal, IDL.Null)),
-    'name' : IDL.Text,
-    'state' : AgreementState,
-    'conditions' : AgreementConditions,
-    'vh_provider' : IDL.Principal,
-    'vh_customer' : IDL.Principal,
-  });
-  const Result_7 = IDL.Variant({ 'Ok' : IDL.Vec(Agreement), 'Err' : Error });
-  const Vehicle = IDL.Record({
-    'telemetry' : IDL.Vec(
-      IDL.Tuple(
-        TelemetryType,
-        IDL.Vec(
-          IDL.Tuple(
-            IDL.Int32,
-            IDL.Vec(
-              IDL.Tuple(
-                IDL.Nat8,
-                IDL.Vec(IDL.Tuple(IDL.Nat8, IDL.Vec(IDL.Nat))),
-              )
-            ),
-          )
-        ),
-      )
-    ),
+  const Result_20 = IDL.Variant({ 'Ok' : User, 'Err' : Error });
+  const VehicleSummary = IDL.Record({
+    'principal' : IDL.Principal,
     'provider' : IDL.Opt(IDL.Principal),
     'customer' : IDL.Principal,
-    'public_key' : IDL.Vec(IDL.Nat8),
     'arch' : IDL.Text,
     'agreement' : IDL.Opt(IDL.Nat),
-    'firmware' : IDL.Vec(IDL.Nat8),
-    'accumulated_telemetry' : IDL.Vec(
-      IDL.Tuple(
-        TelemetryType,
-        IDL.Vec(IDL.Tuple(IDL.Int32, AccumulatedTelemetryYearly)),
-      )
-    ),
-    'invoices' : IDL.Vec(IDL.Nat),
+    'last_seen' : IDL.Opt(IDL.Nat64),
+    'invoice_count' : IDL.Nat64,
     'on_off' : IDL.Bool,
   });
-  const Result_8 = IDL.Variant({ 'Ok' : Vehicle, 'Err' : Error });
-  const Result_9 = IDL.Variant({
+  const Result_21 = IDL.Variant({ 'Ok' : VehicleSummary, 'Err' : Error });
+  const Result_22 = IDL.Variant({ 'Ok' : IDL.Vec(IDL.Nat), 'Err' : Error });
+  const VehicleLink = IDL.Record({
+    'unlinked_at' : IDL.Opt(IDL.Nat64),
+    'agreement' : IDL.Nat,
+    'linked_at' : IDL.Nat64,
+  });
+  const Result_23 = IDL.Variant({ 'Ok' : IDL.Vec(VehicleLink), 'Err' : Error });
+  const OnOffReason = IDL.Variant({
+    'InvoiceOverdue' : IDL.Nat,
+    'InvoiceDisputed' : IDL.Nat,
+    'InvoicePaid' : IDL.Nat,
+    'Agreement' : IDL.Nat,
+    'Manual' : IDL.Null,
+  });
+  const OnOffChange = IDL.Record({
+    'changed_at' : IDL.Nat64,
+    'changed_by' : IDL.Principal,
+    'on_off' : IDL.Bool,
+    'reason' : OnOffReason,
+  });
+  const Result_24 = IDL.Variant({ 'Ok' : IDL.Vec(OnOffChange), 'Err' : Error });
+  const Result_25 = IDL.Variant({
     'Ok' : IDL.Vec(IDL.Tuple(IDL.Principal, IDL.Null)),
     'Err' : Error,
   });
@@ -112,16 +344,63 @@ export const idlFactory = ({ IDL }) => {
     'On' : IDL.Null,
     'Off' : IDL.Null,
   });
-  const Result_10 = IDL.Variant({
+  const Result_26 = IDL.Variant({
     'Ok' : StoreTelemetryResponse,
     'Err' : Error,
   });
+  const SignedTelemetry = IDL.Record({
+    'signature' : IDL.Vec(IDL.Nat8),
+    'data' : IDL.Vec(IDL.Nat8),
+    'vehicle' : IDL.Principal,
+  });
+  const StoreTelemetryBatchResponse = IDL.Record({
+    'vehicles' : IDL.Vec(IDL.Tuple(IDL.Principal, StoreTelemetryResponse)),
+    'results' : IDL.Vec(Result),
+  });
+  const Result_27 = IDL.Variant({
+    'Ok' : StoreTelemetryBatchResponse,
+    'Err' : Error,
+  });
   return IDL.Service({
+    'accept_dispute' : IDL.Func(
+        [IDL.Nat, IDL.Nat, IDL.Text, IDL.Opt(IDL.Principal)],
+        [Result],
+        [],
+      ),
     'accumulate_telemetry_data' : IDL.Func([], [Result], []),
     'add_admin' : IDL.Func([IDL.Principal], [Result], []),
+    'add_gateway' : IDL.Func([IDL.Principal], [Result], []),
+    'adjust_invoice' : IDL.Func(
+        [IDL.Nat, AdjustmentKind, IDL.Nat, IDL.Text, IDL.Opt(IDL.Principal)],
+        [Result],
+        [],
+      ),
+    'append_firmware_chunk' : IDL.Func(
+        [IDL.Nat, IDL.Nat64, IDL.Vec(IDL.Nat8)],
+        [Result],
+        [],
+      ),
+    'begin_firmware_upload' : IDL.Func(
+        [IDL.Principal, IDL.Vec(IDL.Nat8), IDL.Text],
+        [Result_1],
+        [],
+      ),
+    'cancel_agreement' : IDL.Func(
+        [IDL.Nat, IDL.Opt(IDL.Principal)],
+        [Result],
+        [],
+      ),
+    'check_invoice_payment' : IDL.Func([IDL.Nat], [Result_2], []),
+    'check_overdue_invoices' : IDL.Func([], [Result], []),
     'clean_state' : IDL.Func([], [], []),
     'create_agreement' : IDL.Func(
-        [IDL.Text, IDL.Principal, IDL.Text],
+        [IDL.Text, IDL.Principal, AgreementConditions, IDL.Opt(IDL.Principal)],
+        [Result_1],
+        [],
+      ),
+    'create_organization' : IDL.Func([IDL.Principal, IDL.Text], [Result], []),
+    'create_predefined_invoice' : IDL.Func(
+        [IDL.Principal, IDL.Int32, IDL.Nat8],
         [Result_1],
         [],
       ),
@@ -129,36 +408,190 @@ export const idlFactory = ({ IDL }) => {
     'delete_paid_invoices' : IDL.Func([IDL.Vec(IDL.Nat)], [], []),
     'delete_pending_invoices' : IDL.Func([IDL.Vec(IDL.Nat)], [], []),
     'delete_user' : IDL.Func([IDL.Principal], [Result], []),
+    'dispute_invoice' : IDL.Func(
+        [IDL.Nat, IDL.Text, IDL.Opt(IDL.Principal)],
+        [Result],
+        [],
+      ),
+    'escalate_dispute' : IDL.Func(
+        [IDL.Nat, IDL.Text, IDL.Opt(IDL.Principal)],
+        [Result],
+        [],
+      ),
+    'expire_pending_payments' : IDL.Func([], [], []),
     'fill_predefined_telemetry' : IDL.Func(
         [IDL.Principal, IDL.Principal, IDL.Text],
         [],
         [],
       ),
-    'get_aggregated_data' : IDL.Func([IDL.Principal], [Result_2], ['query']),
-    'get_firmware_requests' : IDL.Func([], [Result_3], ['query']),
+    'finish_firmware_upload' : IDL.Func(
+        [IDL.Nat, IDL.Vec(IDL.Nat8)],
+        [Result],
+        [],
+      ),
+    'get_aggregated_data' : IDL.Func([IDL.Principal], [Result_3], ['query']),
+    'get_agreement' : IDL.Func([IDL.Nat], [Result_4], ['query']),
+    'get_agreement_credit' : IDL.Func([IDL.Nat], [Result_1], ['query']),
+    'get_escalated_disputes' : IDL.Func([], [Result_5], ['query']),
+    'get_firmware' : IDL.Func(
+        [IDL.Principal, IDL.Nat64, IDL.Nat64],
+        [Result_6],
+        ['query'],
+      ),
+    'get_firmware_requests' : IDL.Func([], [Result_7], ['query']),
     'get_firmware_requests_by_user' : IDL.Func([], [Result], ['query']),
-    'get_invoice' : IDL.Func([IDL.Nat], [Result_4], ['query']),
-    'get_paid_invoices' : IDL.Func([], [Result_5], ['query']),
-    'get_pending_invoices' : IDL.Func([], [Result_5], ['query']),
-    'get_user' : IDL.Func([], [Result_6], ['query']),
-    'get_user_agreements' : IDL.Func([], [Result_7], ['query']),
-    'get_vehicle' : IDL.Func([IDL.Principal], [Result_8], ['query']),
-    'get_vehicles_by_agreement' : IDL.Func([IDL.Nat], [Result_9], ['query']),
-    'link_vehicle' : IDL.Func([IDL.Nat, IDL.Principal], [Result], []),
-    'pay_for_invoice' : IDL.Func([IDL.Nat], [Result], []),
+    'get_invoice' : IDL.Func([IDL.Nat], [Result_8], ['query']),
+    'get_invoice_adjustments' : IDL.Func([IDL.Nat], [Result_9], ['query']),
+    'get_invoice_deposit_account' : IDL.Func([IDL.Nat], [Result_10], ['query']),
+    'get_invoice_dispute' : IDL.Func([IDL.Nat], [Result_11], ['query']),
+    'get_invoice_outstanding_balance' : IDL.Func(
+        [IDL.Nat],
+        [Result_1],
+        ['query'],
+      ),
+    'get_invoice_payments' : IDL.Func([IDL.Nat], [Result_12], ['query']),
+    'get_invoice_telemetry' : IDL.Func(
+        [IDL.Nat, IDL.Opt(TelemetryCursor)],
+        [Result_13],
+        ['query'],
+      ),
+    'get_organization' : IDL.Func([IDL.Principal], [Result_14], ['query']),
+    'get_organization_agreements' : IDL.Func(
+        [IDL.Principal],
+        [Result_15],
+        ['query'],
+      ),
+    'get_paid_invoices' : IDL.Func([], [Result_16], ['query']),
+    'get_pending_forwards' : IDL.Func([], [Result_17], ['query']),
+    'get_pending_invoices' : IDL.Func([], [Result_16], ['query']),
+    'get_telemetry' : IDL.Func([TelemetryQuery], [Result_18], ['query']),
+    'get_telemetry_retention' : IDL.Func([], [IDL.Nat32], ['query']),
+    'get_telemetry_skew_window' : IDL.Func(
+        [],
+        [TelemetrySkewWindow],
+        ['query'],
+      ),
+    'get_telemetry_types' : IDL.Func(
+        [],
+        [IDL.Vec(TelemetryTypeInfo)],
+        ['query'],
+      ),
+    'get_unreconciled_payments' : IDL.Func([], [Result_19], ['query']),
+    'get_user' : IDL.Func([], [Result_20], ['query']),
+    'get_user_agreements' : IDL.Func([], [Result_15], ['query']),
+    'get_vehicle' : IDL.Func([IDL.Principal], [Result_21], ['query']),
+    'get_vehicle_invoices' : IDL.Func([IDL.Principal], [Result_22], ['query']),
+    'get_vehicle_links' : IDL.Func([IDL.Principal], [Result_23], ['query']),
+    'get_vehicle_on_off_history' : IDL.Func(
+        [IDL.Principal],
+        [Result_24],
+        ['query'],
+      ),
+    'get_vehicles_by_agreement' : IDL.Func([IDL.Nat], [Result_25], ['query']),
+    'link_vehicle' : IDL.Func(
+        [IDL.Nat, IDL.Principal, IDL.Opt(IDL.Principal)],
+        [Result],
+        [],
+      ),
+    'list_gateways' : IDL.Func([], [IDL.Vec(IDL.Principal)], ['query']),
+    'pay_for_invoice' : IDL.Func(
+        [IDL.Nat, IDL.Opt(IDL.Nat), IDL.Opt(IDL.Principal)],
+        [Result],
+        [],
+      ),
+    'propose_amendment' : IDL.Func(
+        [IDL.Nat, AgreementConditions, IDL.Opt(IDL.Principal)],
+        [Result],
+        [],
+      ),
+    'reconcile_deposit_forward' : IDL.Func([IDL.Nat, IDL.Bool], [Result], []),
+    'reconcile_payment' : IDL.Func([IDL.Nat, IDL.Opt(IDL.Nat)], [Result], []),
     'register_user' : IDL.Func(
-        [IDL.Principal, IDL.Opt(IDL.Text)],
+        [IDL.Principal, IDL.Opt(IDL.Text), IDL.Vec(Role)],
+        [Result],
+        [],
+      ),
+    'reject_agreement' : IDL.Func(
+        [IDL.Nat, IDL.Opt(IDL.Principal)],
+        [Result],
+        [],
+      ),
+    'reject_amendment' : IDL.Func(
+        [IDL.Nat, IDL.Opt(IDL.Principal)],
+        [Result],
+        [],
+      ),
+    'reject_dispute' : IDL.Func(
+        [IDL.Nat, IDL.Text, IDL.Opt(IDL.Principal)],
+        [Result],
+        [],
+      ),
+    'remove_gateway' : IDL.Func([IDL.Principal], [Result], []),
+    'remove_organization_member' : IDL.Func(
+        [IDL.Principal, IDL.Principal],
         [Result],
         [],
       ),
     'request_firmware' : IDL.Func([], [Result], []),
-    'sign_agreement' : IDL.Func([IDL.Nat], [Result], []),
+    'resolve_dispute' : IDL.Func([IDL.Nat, IDL.Nat, IDL.Text], [Result], []),
+    'resume_agreement' : IDL.Func(
+        [IDL.Nat, IDL.Opt(IDL.Principal)],
+        [Result],
+        [],
+      ),
+    'set_organization_member' : IDL.Func(
+        [IDL.Principal, IDL.Principal, IDL.Vec(Permission)],
+        [Result],
+        [],
+      ),
+    'set_telemetry_retention' : IDL.Func([IDL.Nat32], [Result], []),
+    'set_telemetry_skew_window' : IDL.Func([TelemetrySkewWindow], [], []),
+    'set_user_roles' : IDL.Func([IDL.Principal, IDL.Vec(Role)], [Result], []),
+    'sign_agreement' : IDL.Func(
+        [IDL.Nat, IDL.Opt(IDL.Principal)],
+        [Result],
+        [],
+      ),
+    'sign_amendment' : IDL.Func(
+        [IDL.Nat, IDL.Opt(IDL.Principal)],
+        [Result],
+        [],
+      ),
     'store_telemetry' : IDL.Func(
         [IDL.Principal, IDL.Vec(IDL.Nat8), IDL.Vec(IDL.Nat8)],
-        [Result_10],
+        [Result_26],
+        [],
+      ),
+    'store_telemetry_batch' : IDL.Func(
+        [IDL.Vec(SignedTelemetry)],
+        [Result_27],
+        [],
+      ),
+    'suspend_agreement' : IDL.Func(
+        [IDL.Nat, IDL.Opt(IDL.Principal)],
+        [Result],
+        [],
+      ),
+    'terminate_agreement' : IDL.Func(
+        [IDL.Nat, IDL.Opt(IDL.Principal)],
+        [Result],
+        [],
+      ),
+    'transfer_vehicle' : IDL.Func(
+        [IDL.Principal, IDL.Nat, IDL.Opt(IDL.Principal)],
+        [Result],
+        [],
+      ),
+    'turn_on_off_vehicle' : IDL.Func(
+        [IDL.Principal, IDL.Bool, IDL.Opt(IDL.Principal)],
+        [Result],
+        [],
+      ),
+    'unlink_vehicle' : IDL.Func(
+        [IDL.Principal, IDL.Opt(IDL.Principal)],
+        [Result],
         [],
       ),
-    'turn_on_off_vehicle' : IDL.Func([IDL.Principal, IDL.Bool], [Result], []),
     'upload_firmware' : IDL.Func(
         [IDL.Principal, IDL.Vec(IDL.Nat8), IDL.Text, IDL.Vec(IDL.Nat8)],
         [Result],
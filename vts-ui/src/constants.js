@@ -25,4 +25,19 @@ export const monthIndexToName = (month) => {
   return names[month - 1]
 }
 
-export const TokenMultiplier = 1_000_000_000
+const prettyDate = (date) =>
+  `${date[0]}-${String(date[1]).padStart(2, '0')}-${String(date[2]).padStart(2, '0')}`
+
+export const prettyPeriod = (period) => {
+  const { start, end } = period
+  // Show calendar months in a short form.
+  if (start[2] === 1 && end[2] === 1 && end[1] === (start[1] % 12) + 1) {
+    return `${start[0]} ${monthIndexToName(start[1])}`
+  }
+  return `${prettyDate(start)} - ${prettyDate(end)}`
+}
+
+export const ICPDecimals = 8
+
+// Converts amount in the smallest token units to tokens.
+export const tokenAmount = (amount, decimals) => Number(amount) / 10 ** decimals
@@ -1,7 +1,30 @@
-export const downloadFirmware = (identity, arch, firmware) => {
-  const firmwareUrl = URL.createObjectURL(
-    new Blob([new Uint8Array(firmware).buffer], { type: 'application/zip' }),
-  )
+import { initVTSClient } from '@/icp'
+
+// Firmware can be larger than the canister response limit so we download it by chunks.
+const FirmwareChunkSize = 1024 * 1024
+
+export const downloadFirmware = async (identity, arch) => {
+  const vtsClient = await initVTSClient()
+  const chunks = []
+  let offset = 0
+  let sha256 = []
+  for (;;) {
+    const res = await vtsClient.get_firmware(identity, BigInt(offset), BigInt(FirmwareChunkSize))
+    if (res.Ok === undefined) throw `failed to download firmware: ${Object.keys(res.Err)[0]}`
+    const chunk = res.Ok
+    sha256 = chunk.sha256
+    if (chunk.data.length === 0) break
+    chunks.push(new Uint8Array(chunk.data))
+    offset += chunk.data.length
+    if (offset >= Number(chunk.total_size)) break
+  }
+
+  const firmware = new Blob(chunks, { type: 'application/zip' })
+  const digest = new Uint8Array(await crypto.subtle.digest('SHA-256', await firmware.arrayBuffer()))
+  if (digest.length !== sha256.length || digest.some((byte, i) => byte !== sha256[i]))
+    throw 'downloaded firmware is corrupted'
+
+  const firmwareUrl = URL.createObjectURL(firmware)
   const link = document.createElement('a')
   link.href = firmwareUrl
   link.download = `${identity}.firmware.${arch}.zip`